@@ -205,9 +205,13 @@ in {
         # Use Type=notify for proper systemd integration
         # The daemon sends READY=1 when it's ready to accept connections
         Type = "notify";
+        # Accept notifications from the new process after `ssh-agent-mux upgrade`
+        NotifyAccess = "all";
         ExecStart = startScript;
         Restart = "on-failure";
         RestartSec = "5s";
+        # SIGHUP reloads the configuration file
+        ExecReload = "${pkgs.coreutils}/bin/kill -HUP $MAINPID";
 
         # Watchdog: systemd will restart the service if it doesn't receive
         # periodic pings within this interval (the daemon pings during health checks)
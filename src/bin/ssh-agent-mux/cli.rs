@@ -1,7 +1,6 @@
 use std::{
     env,
-    fs::File,
-    io::Read,
+    ffi::OsString,
     path::{Path, PathBuf},
 };
 
@@ -10,13 +9,27 @@ use clap_serde_derive::{
     serde::{self, Deserialize, Serialize},
     ClapSerde,
 };
-use color_eyre::eyre::Result as EyreResult;
+use color_eyre::eyre::{eyre, Result as EyreResult};
 use expand_tilde::ExpandTilde;
 use log::LevelFilter;
+use ssh_agent_mux::{
+    config_file::{self, ConfigSource, LayeredConfig},
+    expand,
+    socket_manager::{OrderingStrategy, Upstream},
+    state_file::StateFile,
+    watcher::{Discovery, ForwardedAgentPattern},
+};
 
 const APP_VERSION: &str = env!("SSH_AGENT_MUX_BUILD_VERSION");
 
+/// Prefix of the environment variables that override configuration file settings
+const ENV_PREFIX: &str = "SSH_AGENT_MUX_";
+
 fn default_config_path() -> PathBuf {
+    if let Some(path) = env::var_os("SSH_AGENT_MUX_CONFIG") {
+        return PathBuf::from(path);
+    }
+
     let config_dir = env::var_os("XDG_CONFIG_HOME")
         .or_else(|| Some("~/.config".into()))
         .map(PathBuf::from)
@@ -28,10 +41,26 @@ fn default_config_path() -> PathBuf {
         .join(concat!(env!("CARGO_PKG_NAME"), ".toml"))
 }
 
+fn default_state_path() -> PathBuf {
+    let state_dir = env::var_os("XDG_STATE_HOME")
+        .or_else(|| Some("~/.local/state".into()))
+        .map(PathBuf::from)
+        .and_then(|p| p.expand_tilde_owned().ok())
+        .expect("HOME not defined in environment");
+
+    state_dir.join(env!("CARGO_PKG_NAME")).join("state.json")
+}
+
 fn default_listen_path() -> PathBuf {
     PathBuf::from(concat!("~/.ssh/", env!("CARGO_PKG_NAME"), ".sock"))
 }
 
+/// Expand environment variables and `~` in a configured upstream socket path; glob patterns are
+/// expanded by the socket manager whenever it checks its sockets
+fn expand_socket_path(path: &Path) -> EyreResult<PathBuf> {
+    Ok(expand::expand_env(path)?.expand_tilde_owned()?)
+}
+
 /// Derive control socket path from listen path
 pub fn derive_control_path(listen_path: &Path) -> PathBuf {
     ssh_agent_mux::control::default_control_path(listen_path)
@@ -99,12 +128,103 @@ pub enum Command {
 
     /// Remove a socket from the watched list
     Remove {
-        /// Path to the socket to remove
-        path: PathBuf,
+        /// Path or configured name of the socket to remove
+        socket: String,
+    },
+
+    /// Pin a socket to a position in the order, until it's unpinned
+    Pin {
+        /// Path or configured name of the socket to pin
+        socket: String,
+
+        /// Position to pin the socket to (1 = first)
+        #[arg(long, default_value_t = 1)]
+        position: usize,
+    },
+
+    /// Undo pinning a socket
+    Unpin {
+        /// Path or configured name of the socket to unpin
+        socket: String,
+    },
+
+    /// Move a socket up or down the order, pinning it at its new position
+    Move {
+        /// Path or configured name of the socket to move
+        socket: String,
+
+        /// Direction to move the socket in
+        #[arg(value_enum)]
+        direction: MoveDirection,
+
+        /// Number of places to move the socket
+        #[arg(default_value_t = 1)]
+        places: usize,
+    },
+
+    /// Override the priority of a socket, putting it before (positive) or after (negative) the
+    /// sockets without an override
+    Prioritize {
+        /// Path or configured name of the socket
+        socket: String,
+
+        /// Priority to give the socket
+        #[arg(allow_negative_numbers = true, required_unless_present = "clear")]
+        priority: Option<i32>,
+
+        /// Drop the override after this many seconds
+        #[arg(long = "for", value_name = "SECONDS")]
+        duration_secs: Option<u64>,
+
+        /// Drop the socket's priority override
+        #[arg(long, conflicts_with_all = ["priority", "duration_secs"])]
+        clear: bool,
+    },
+
+    /// Stop offering a socket's keys without removing it
+    Disable {
+        /// Path or configured name of the socket to disable
+        socket: String,
+    },
+
+    /// Offer the keys of a disabled socket again
+    Enable {
+        /// Path or configured name of the socket to enable
+        socket: String,
     },
 
     /// Full health check of all sockets
     Health,
+
+    /// Show how much each upstream agent and key was used
+    Stats,
+
+    /// Restart the daemon on a new binary without dropping connections
+    Upgrade {
+        /// Binary to start (defaults to the one the daemon was started from)
+        #[arg(long)]
+        binary: Option<PathBuf>,
+    },
+
+    /// Validate the configuration and show the settings the daemon would use
+    CheckConfig {
+        /// Config file
+        #[arg(short, long = "config", default_value_os_t = default_config_path())]
+        config_path: PathBuf,
+
+        /// Config from file or args
+        #[command(flatten)]
+        config: <Config as ClapSerde>::Opt,
+    },
+}
+
+/// Direction to move a socket in the order
+#[derive(Clone, Copy, ValueEnum)]
+pub enum MoveDirection {
+    /// Towards the front, where sockets are tried first
+    Up,
+    /// Towards the back
+    Down,
 }
 
 #[derive(ClapSerde, Clone, Serialize)]
@@ -118,6 +238,10 @@ pub struct Config {
     #[arg(skip)]
     pub control_socket_path: Option<PathBuf>,
 
+    /// Group (name or GID) whose members may use the control socket
+    #[arg(long, num_args = 1)]
+    pub control_socket_group: Option<String>,
+
     /// Log level for agent
     #[default(LogLevel::Warn)]
     #[arg(long, value_enum)]
@@ -131,39 +255,116 @@ pub struct Config {
     #[arg()]
     pub agent_sock_paths: Vec<PathBuf>,
 
+    /// Agents to multiplex, with per-agent settings (from config file)
+    #[arg(skip)]
+    pub upstream: Vec<Upstream>,
+
     /// Watch /tmp for SSH forwarded agents
     #[default(false)]
-    #[arg(long, action = clap::ArgAction::SetTrue)]
+    #[arg(long, num_args = 0, default_missing_value = "true")]
     pub watch_for_ssh_forward: bool,
 
+    /// Directories to look for SSH forwarded agents in, besides /tmp
+    #[arg(long = "forward-agent-root", num_args = 1)]
+    pub forward_agent_roots: Vec<PathBuf>,
+
+    /// Forwarded agent socket patterns to look for, besides ssh-*/agent.* and
+    /// auth-agent*/listener.sock, as DIR/FILE globs
+    #[arg(long = "forward-agent-pattern", num_args = 1)]
+    pub forward_agent_patterns: Vec<ForwardedAgentPattern>,
+
+    /// Forwarded agent sockets to use even if other users could have created them or can access
+    /// them; may be glob patterns
+    #[arg(long = "trust-forwarded-agent", num_args = 1)]
+    pub trusted_forwarded_agents: Vec<PathBuf>,
+
+    /// Seconds a vanished forwarded agent keeps its place, in case its session reconnects (0 to
+    /// drop it right away)
+    #[default(30u64)]
+    #[arg(long)]
+    pub forward_agent_grace_period: u64,
+
+    /// Reload automatically when the config file changes
+    #[default(false)]
+    #[arg(long, num_args = 0, default_missing_value = "true")]
+    pub watch_config: bool,
+
+    /// How to order upstream agents: newest-first, oldest-first, configured-first, priority or
+    /// recent-sign
+    #[default(OrderingStrategy::NewestFirst)]
+    #[arg(long)]
+    pub socket_order: OrderingStrategy,
+
     /// Health check interval in seconds (0 to disable)
     #[default(60u64)]
     #[arg(long)]
     pub health_check_interval: u64,
 
+    /// Keep sockets added at runtime, pins and priority overrides in a state file, so that they
+    /// survive restarts
+    #[default(false)]
+    #[arg(long, num_args = 0, default_missing_value = "true")]
+    pub persist_state: bool,
+
+    /// State file path (defaults to ssh-agent-mux/state.json in $XDG_STATE_HOME)
+    #[arg(long, num_args = 1)]
+    pub state_file: Option<PathBuf>,
+
     // Following are part of command line args, but
     // not in configuration file
     /// Config file path (not an arg; copied from struct Args)
     #[arg(skip)]
     #[serde(skip_deserializing, skip_serializing)]
     pub config_path: PathBuf,
+
+    /// Configuration files that were read, in the order they were merged
+    #[arg(skip)]
+    #[serde(skip_deserializing, skip_serializing)]
+    pub config_files: Vec<PathBuf>,
+
+    /// Command line the daemon was started with, applied again on reload
+    #[arg(skip)]
+    #[serde(skip_deserializing, skip_serializing)]
+    pub command_line: Vec<OsString>,
 }
 
 impl Config {
     pub fn from_serve_args(
         config_path: PathBuf,
-        mut config_opt: <Config as ClapSerde>::Opt,
+        config_opt: <Config as ClapSerde>::Opt,
     ) -> EyreResult<Self> {
-        let mut config = if let Ok(mut f) = File::open(&config_path) {
-            log::info!("Read configuration from {}", config_path.display());
-            let mut config_text = String::new();
-            f.read_to_string(&mut config_text)?;
-            let file_config = toml::from_str::<<Config as ClapSerde>::Opt>(&config_text)?;
-            Config::from(file_config).merge(&mut config_opt)
-        } else {
-            Config::from(&mut config_opt)
-        };
+        Ok(Self::load(config_path, config_opt)?.0)
+    }
 
+    /// Read the configuration file with its includes and drop-in files, then apply the command
+    /// line options `config_opt`, noting where each setting came from
+    pub fn load(
+        config_path: PathBuf,
+        mut config_opt: <Config as ClapSerde>::Opt,
+    ) -> EyreResult<(Self, LayeredConfig)> {
+        let mut layers = config_file::load(&config_path)?;
+        for file in &layers.files {
+            log::info!("Read configuration from {}", file.display());
+        }
+        apply_env_overrides(&mut layers)?;
+        let file_config = Config::from(parse_layers(&layers)?);
+        let mut config = file_config.clone().merge(&mut config_opt);
+
+        if let (Ok(serde_json::Value::Object(from_files)), Ok(serde_json::Value::Object(merged))) = (
+            serde_json::to_value(&file_config),
+            serde_json::to_value(&config),
+        ) {
+            for (key, value) in merged {
+                if from_files.get(&key) != Some(&value) {
+                    let count = value.as_array().map_or(1, Vec::len);
+                    layers
+                        .sources
+                        .insert(key, vec![ConfigSource::CommandLine; count]);
+                }
+            }
+        }
+
+        config.config_files = layers.files.clone();
         config.config_path = config_path;
         config.listen_path = config.listen_path.expand_tilde_owned()?;
         config.log_file = config
@@ -172,16 +373,127 @@ impl Config {
             .transpose()?;
         config.agent_sock_paths = config
             .agent_sock_paths
-            .into_iter()
-            .map(|p| p.expand_tilde_owned())
+            .iter()
+            .map(|p| expand_socket_path(p))
+            .collect::<Result<_, _>>()?;
+
+        for upstream in &mut config.upstream {
+            upstream.path = expand_socket_path(&upstream.path)?;
+        }
+        config.forward_agent_roots = config
+            .forward_agent_roots
+            .iter()
+            .map(|p| expand_socket_path(p))
+            .collect::<Result<_, _>>()?;
+        config.trusted_forwarded_agents = config
+            .trusted_forwarded_agents
+            .iter()
+            .map(|p| expand_socket_path(p))
             .collect::<Result<_, _>>()?;
 
         // Expand control socket path if set in config
         if let Some(ref path) = config.control_socket_path {
             config.control_socket_path = Some(path.expand_tilde_owned()?);
         }
+        config.state_file = config
+            .state_file
+            .map(|p| p.expand_tilde_owned())
+            .transpose()?;
+
+        let upstreams = config.upstreams();
+        for (i, upstream) in upstreams.iter().enumerate() {
+            let earlier = &upstreams[..i];
+            if earlier.iter().any(|u| u.path == upstream.path) {
+                return Err(eyre!(
+                    "Upstream agent {} is configured more than once",
+                    upstream.path.display()
+                ));
+            }
+            if let Some(name) = &upstream.name {
+                if earlier.iter().any(|u| u.name.as_ref() == Some(name)) {
+                    return Err(eyre!("Upstream agent name {name} is used more than once"));
+                }
+                // A glob could match several sockets, which the name can't tell apart
+                if expand::is_glob(&upstream.path) {
+                    return Err(eyre!(
+                        "Upstream agent {name} has glob path {}; only single sockets can be named",
+                        upstream.path.display()
+                    ));
+                }
+            }
+        }
 
-        Ok(config)
+        Ok((config, layers))
+    }
+
+    /// Files and directories whose contents make up the configuration
+    pub fn watched_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.config_path.clone()];
+        paths.extend(self.config_files.iter().cloned());
+        paths.push(config_file::drop_in_dir(&self.config_path));
+        paths
+    }
+
+    /// All configured upstream agents: the plain `agent_sock_paths` list, followed by the
+    /// `[[upstream]]` tables
+    pub fn upstreams(&self) -> Vec<Upstream> {
+        self.agent_sock_paths
+            .iter()
+            .cloned()
+            .map(Upstream::from)
+            .chain(self.upstream.iter().cloned())
+            .collect()
+    }
+
+    /// Where to look for SSH forwarded agents
+    pub fn discovery(&self) -> Discovery {
+        Discovery {
+            trusted: self.trusted_forwarded_agents.clone(),
+            ..Discovery::with_extra(&self.forward_agent_roots, &self.forward_agent_patterns)
+        }
+    }
+
+    /// Re-read the configuration file, with the command line options the daemon was started with
+    pub fn reload(&self) -> EyreResult<Self> {
+        match <Args as Parser>::try_parse_from(&self.command_line)?.command {
+            Command::Serve { config, .. } => {
+                let mut reloaded = Self::from_serve_args(self.config_path.clone(), config)?;
+                reloaded.command_line = self.command_line.clone();
+                Ok(reloaded)
+            }
+            _ => Err(eyre!("Not running as a daemon")),
+        }
+    }
+
+    /// Top-level keys in the merged configuration files `table` that aren't settings
+    pub fn unknown_keys(&self, table: &toml::Table) -> EyreResult<Vec<String>> {
+        let serde_json::Value::Object(known) = serde_json::to_value(self)? else {
+            return Ok(vec![]);
+        };
+        Ok(table
+            .keys()
+            .filter(|key| !known.contains_key(*key))
+            .cloned()
+            .collect())
+    }
+
+    /// Describe each setting that differs in `other`, as `name: old -> new`
+    pub fn diff(&self, other: &Self) -> Vec<String> {
+        let (Ok(serde_json::Value::Object(old)), Ok(serde_json::Value::Object(new))) =
+            (serde_json::to_value(self), serde_json::to_value(other))
+        else {
+            return vec![];
+        };
+        old.iter()
+            .filter(|(name, value)| new.get(*name) != Some(value))
+            .map(|(name, value)| format!("{name}: {value} -> {}", new[name]))
+            .collect()
+    }
+
+    /// The file to keep runtime socket state in, if it's to be kept
+    pub fn state_file(&self) -> Option<StateFile> {
+        self.persist_state
+            .then(|| StateFile::new(self.state_file.clone().unwrap_or_else(default_state_path)))
     }
 
     /// Get the control socket path, deriving from listen_path if not set
@@ -192,7 +504,79 @@ impl Config {
     }
 }
 
-#[derive(ValueEnum, Clone, Copy, Deserialize, Serialize)]
+/// Override settings with `SSH_AGENT_MUX_*` environment variables, named after the settings in
+/// upper case
+///
+/// Lists are colon-separated paths, and replace the lists from configuration files; empty
+/// variables are ignored.
+fn apply_env_overrides(layers: &mut LayeredConfig) -> EyreResult<()> {
+    let serde_json::Value::Object(defaults) = serde_json::to_value(Config::default())? else {
+        return Ok(());
+    };
+
+    for (key, default) in defaults {
+        let var = format!("{ENV_PREFIX}{}", key.to_uppercase());
+        let Some(text) = env::var_os(&var) else {
+            continue;
+        };
+        let text = text
+            .into_string()
+            .map_err(|_| eyre!("{var} is not valid UTF-8"))?;
+        if text.is_empty() {
+            continue;
+        }
+
+        let value: toml::Value = match default {
+            serde_json::Value::Array(_) => toml::Value::Array(
+                text.split(':')
+                    .filter(|path| !path.is_empty())
+                    .map(|path| match key.as_str() {
+                        // Upstreams from the environment have default settings
+                        "upstream" => toml::Value::Table(toml::Table::from_iter([(
+                            "path".to_string(),
+                            path.into(),
+                        )])),
+                        _ => path.into(),
+                    })
+                    .collect(),
+            ),
+            serde_json::Value::Bool(_) => match text.as_str() {
+                "true" | "1" => true.into(),
+                "false" | "0" => false.into(),
+                _ => return Err(eyre!("{var} must be true or false, not {text:?}")),
+            },
+            serde_json::Value::Number(_) => text
+                .parse::<i64>()
+                .map_err(|_| eyre!("{var} must be a number, not {text:?}"))?
+                .into(),
+            _ => text.into(),
+        };
+
+        let setting = toml::Table::from_iter([(key.clone(), value.clone())]);
+        toml::Value::Table(setting)
+            .try_into::<<Config as ClapSerde>::Opt>()
+            .map_err(|e| eyre!("Invalid {var}: {e}"))?;
+        layers.set(key, value, ConfigSource::Environment(var));
+    }
+    Ok(())
+}
+
+/// Settings from the merged configuration files
+fn parse_layers(layers: &LayeredConfig) -> EyreResult<<Config as ClapSerde>::Opt> {
+    toml::Value::Table(layers.table.clone())
+        .try_into()
+        .or_else(|e| {
+            // Find the file with the invalid setting, to report where it is
+            for file in &layers.files {
+                let text = std::fs::read_to_string(file)?;
+                toml::from_str::<<Config as ClapSerde>::Opt>(&text)
+                    .map_err(|e| eyre!("Invalid configuration in {}: {e}", file.display()))?;
+            }
+            Err(e.into())
+        })
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum LogLevel {
     Error = 1,
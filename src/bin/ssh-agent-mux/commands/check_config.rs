@@ -0,0 +1,241 @@
+//! `check-config`: validate a configuration without a running daemon.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap_serde_derive::ClapSerde;
+use color_eyre::eyre::Result as EyreResult;
+use serde::Serialize;
+use ssh_agent_mux::config_file::ConfigSource;
+use ssh_agent_mux::control::{SocketHealthInfo, SocketHealthStatus};
+use ssh_agent_mux::health;
+use ssh_agent_mux::socket_manager::Upstream;
+
+use super::OutputFormat;
+use crate::cli::Config;
+
+/// Outcome of checking a configuration
+#[derive(Serialize)]
+struct ConfigReport {
+    /// Configuration file that was checked
+    config_file: PathBuf,
+    /// Whether the configuration file exists; defaults are used otherwise
+    found: bool,
+    /// Configuration files that were read, in the order they were merged
+    config_files: Vec<PathBuf>,
+    /// Keys in the configuration file that aren't settings, and are ignored
+    unknown_keys: Vec<String>,
+    /// Result of talking to each enabled upstream agent
+    upstreams: Vec<SocketHealthInfo>,
+    /// Glob patterns that currently match no sockets
+    unmatched_patterns: Vec<PathBuf>,
+    /// Settings the daemon would run with, after merging command line options
+    config: Config,
+    /// Where each setting that isn't a default came from, including unknown ones; list settings
+    /// have a source for each entry
+    sources: BTreeMap<String, Vec<ConfigSource>>,
+}
+
+impl ConfigReport {
+    fn problem_count(&self) -> usize {
+        self.unknown_keys.len()
+            + self
+                .upstreams
+                .iter()
+                .filter(|u| u.status != SocketHealthStatus::Healthy)
+                .count()
+    }
+}
+
+/// Check the configuration `serve` would run with, exiting with failure on any problem
+pub fn cmd_check_config(
+    config_path: PathBuf,
+    config_opt: <Config as ClapSerde>::Opt,
+    format: OutputFormat,
+) -> ExitCode {
+    let report = match check_config(config_path, config_opt) {
+        Ok(report) => report,
+        Err(e) => {
+            match format {
+                OutputFormat::Json => {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "success": false,
+                            "error": e.to_string()
+                        })
+                    );
+                }
+                OutputFormat::Human => {
+                    eprintln!("Error: {e}");
+                }
+            }
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match format {
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        }
+        OutputFormat::Human => {
+            if let Err(e) = print_report_human(&report) {
+                eprintln!("Error: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if report.problem_count() > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn check_config(
+    config_path: PathBuf,
+    config_opt: <Config as ClapSerde>::Opt,
+) -> EyreResult<ConfigReport> {
+    let (config, layers) = Config::load(config_path.clone(), config_opt)?;
+
+    let found = config_path.exists();
+    let unknown_keys = config.unknown_keys(&layers.table)?;
+
+    let mut unmatched_patterns = vec![];
+    let mut upstreams = vec![];
+    for upstream in config.upstreams().into_iter().filter(|u| u.enabled) {
+        let matches = upstream.expand();
+        if matches.is_empty() {
+            unmatched_patterns.push(upstream.path);
+        }
+        upstreams.extend(matches);
+    }
+    let upstreams = probe_agents(upstreams);
+
+    Ok(ConfigReport {
+        config_file: config_path,
+        found,
+        config_files: layers.files,
+        unknown_keys,
+        upstreams,
+        unmatched_patterns,
+        config,
+        sources: layers.sources,
+    })
+}
+
+fn print_report_human(report: &ConfigReport) -> EyreResult<()> {
+    print!("Configuration file: {}", report.config_file.display());
+    if report.found {
+        println!();
+    } else {
+        println!(" (not found, using defaults)");
+    }
+
+    if report
+        .config_files
+        .iter()
+        .any(|file| *file != report.config_file)
+    {
+        println!();
+        println!("Files read, in merge order:");
+        for file in &report.config_files {
+            println!("  {}", file.display());
+        }
+    }
+
+    if !report.unknown_keys.is_empty() {
+        println!();
+        println!("Unknown settings (ignored):");
+        for key in &report.unknown_keys {
+            match report.sources.get(key).and_then(|sources| sources.last()) {
+                Some(source) => println!("  ✗ {key} (in {source})"),
+                None => println!("  ✗ {key}"),
+            }
+        }
+    }
+
+    if !report.upstreams.is_empty() || !report.unmatched_patterns.is_empty() {
+        println!();
+        println!("Upstream agents:");
+        for upstream in &report.upstreams {
+            let label = match upstream.name {
+                Some(ref name) => format!("{name} ({})", upstream.path),
+                None => upstream.path.clone(),
+            };
+            match (upstream.status, upstream.key_count) {
+                (SocketHealthStatus::Healthy, Some(count)) => {
+                    println!("  ✓ {label} ({count} keys)")
+                }
+                (status, _) => {
+                    println!("  ✗ {label}: {status}");
+                    if let Some(ref error) = upstream.error {
+                        println!("        Error: {error}");
+                    }
+                }
+            }
+        }
+        for pattern in &report.unmatched_patterns {
+            println!("  - {} (no matching sockets yet)", pattern.display());
+        }
+    }
+
+    println!();
+    println!("Effective configuration:");
+    println!();
+    print!("{}", toml::to_string(&report.config)?);
+
+    let effective = toml::Table::try_from(&report.config)?;
+    let known_sources: Vec<_> = report
+        .sources
+        .iter()
+        .filter(|(key, _)| effective.contains_key(*key))
+        .collect();
+    if !known_sources.is_empty() {
+        println!();
+        println!("Setting sources (other settings are defaults):");
+        for (key, sources) in known_sources {
+            let is_list = effective.get(key).is_some_and(toml::Value::is_array);
+            for (i, source) in sources.iter().enumerate() {
+                let setting = if is_list {
+                    format!("{key}[{i}]")
+                } else {
+                    key.clone()
+                };
+                println!("  {setting:<24} {source}");
+            }
+        }
+    }
+
+    println!();
+    match report.problem_count() {
+        0 => println!("Configuration OK."),
+        n => println!("{n} problem(s) found."),
+    }
+    Ok(())
+}
+
+/// Check that each upstream agent answers a request for its keys
+#[tokio::main(flavor = "current_thread")]
+async fn probe_agents(upstreams: Vec<Upstream>) -> Vec<SocketHealthInfo> {
+    let mut results = Vec::with_capacity(upstreams.len());
+    for upstream in upstreams {
+        let timeout = upstream
+            .timeout
+            .map_or(health::PROBE_TIMEOUT, Duration::from_secs);
+        let probe = health::probe(&upstream.path, timeout).await;
+        results.push(SocketHealthInfo {
+            path: upstream.path.display().to_string(),
+            name: upstream.name,
+            status: probe.status,
+            key_count: probe.key_count,
+            latency_ms: probe.latency.map(|latency| latency.as_millis() as u64),
+            error: probe.error,
+            breaker: Default::default(),
+        });
+    }
+    results
+}
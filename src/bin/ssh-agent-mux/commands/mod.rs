@@ -4,8 +4,16 @@ use std::path::Path;
 use std::process::ExitCode;
 
 use ssh_agent_mux::control::{
-    ControlClient, HealthCheckResult, SocketHealthStatus, SocketInfo, StatusInfo,
+    BreakerInfo, BreakerState, ControlClient, ControlClientError, HealthCheckResult, MetricsReport,
+    SocketHealthStatus, SocketInfo, StatusInfo, UsageStats,
 };
+use ssh_agent_mux::session::SshSession;
+
+use crate::cli::MoveDirection;
+
+mod check_config;
+
+pub use check_config::cmd_check_config;
 
 /// Output format for CLI commands
 pub enum OutputFormat {
@@ -30,9 +38,9 @@ pub fn run_command(
     };
 
     match command {
-        crate::cli::Command::Serve { .. } => {
-            // Should never reach here - serve is handled in main
-            unreachable!("Serve command should be handled in main")
+        crate::cli::Command::Serve { .. } | crate::cli::Command::CheckConfig { .. } => {
+            // Should never reach here - these don't talk to the daemon and are handled in main
+            unreachable!("Command should be handled in main")
         }
         crate::cli::Command::Status => cmd_status(&mut client, format),
         crate::cli::Command::List => cmd_list(&mut client, format),
@@ -40,8 +48,42 @@ pub fn run_command(
         crate::cli::Command::Reload => cmd_reload(&mut client, format),
         crate::cli::Command::Validate => cmd_validate(&mut client, format),
         crate::cli::Command::Add { path } => cmd_add(&mut client, path, format),
-        crate::cli::Command::Remove { path } => cmd_remove(&mut client, path, format),
+        crate::cli::Command::Remove { socket } => cmd_remove(&mut client, socket, format),
+        crate::cli::Command::Pin { socket, position } => {
+            report(client.pin_socket(socket, *position), format)
+        }
+        crate::cli::Command::Unpin { socket } => report(client.unpin_socket(socket), format),
+        crate::cli::Command::Move {
+            socket,
+            direction,
+            places,
+        } => {
+            let places = isize::try_from(*places).unwrap_or(isize::MAX);
+            let offset = match direction {
+                MoveDirection::Up => -places,
+                MoveDirection::Down => places,
+            };
+            report(client.move_socket(socket, offset), format)
+        }
+        crate::cli::Command::Prioritize {
+            socket,
+            priority,
+            duration_secs,
+            clear,
+        } => match priority {
+            Some(priority) if !clear => report(
+                client.set_priority(socket, *priority, *duration_secs),
+                format,
+            ),
+            _ => report(client.clear_priority(socket), format),
+        },
+        crate::cli::Command::Disable { socket } => report(client.disable_socket(socket), format),
+        crate::cli::Command::Enable { socket } => report(client.enable_socket(socket), format),
         crate::cli::Command::Health => cmd_health(&mut client, format),
+        crate::cli::Command::Stats => cmd_stats(&mut client, format),
+        crate::cli::Command::Upgrade { binary } => {
+            cmd_upgrade(&mut client, binary.as_deref(), format)
+        }
     }
 }
 
@@ -116,8 +158,8 @@ fn print_sockets_human(sockets: &[SocketInfo]) {
 
     // Header
     println!(
-        "{:<6} {:<12} {:<8} {:<20} PATH",
-        "ORDER", "SOURCE", "HEALTHY", "ADDED"
+        "{:<6} {:<12} {:<8} {:<20} {:<16} PATH",
+        "ORDER", "SOURCE", "HEALTHY", "ADDED", "NAME"
     );
 
     for socket in sockets {
@@ -127,12 +169,80 @@ fn print_sockets_human(sockets: &[SocketInfo]) {
             .map(|s| format_timestamp(s))
             .unwrap_or_else(|| "-".to_string());
 
-        let healthy = if socket.healthy { "yes" } else { "no" };
+        let healthy = match (socket.gone, socket.healthy) {
+            (true, _) => "gone",
+            (false, true) => "yes",
+            (false, false) => "no",
+        };
+        let name = socket.name.as_deref().unwrap_or("-");
 
         println!(
-            "{:<6} {:<12} {:<8} {:<20} {}",
-            socket.order, socket.source, healthy, added, socket.path
+            "{:<6} {:<12} {:<8} {:<20} {:<16} {}",
+            socket.order, socket.source, healthy, added, name, socket.path
         );
+        if let Some(session) = &socket.session {
+            println!("{:<6} {}", "", describe_session(session));
+        }
+        if let Some(placement) = describe_placement(socket) {
+            println!("{:<6} {placement}", "");
+        }
+        if let Some(breaker) = describe_breaker(&socket.breaker) {
+            println!("{:<6} {breaker}", "");
+        }
+    }
+}
+
+/// One line about the SSH session of a forwarded agent socket, for `list`
+fn describe_session(session: &SshSession) -> String {
+    let mut description = match session.remote_addr {
+        Some(remote_addr) => format!("session from {remote_addr}"),
+        None => "session".to_string(),
+    };
+    if let Some(tty) = &session.tty {
+        description.push_str(&format!(" on {tty}"));
+    }
+    if let Some(login_time) = session.login_time {
+        description.push_str(&format!(
+            ", logged in {}",
+            login_time.format("%Y-%m-%d %H:%M:%S")
+        ));
+    }
+    description.push_str(&format!(" (PID {})", session.pid));
+    description
+}
+
+/// One line about runtime disabling, pins and priority overrides of a socket, for `list`
+fn describe_placement(socket: &SocketInfo) -> Option<String> {
+    let mut parts = Vec::new();
+    if socket.disabled {
+        parts.push("disabled".to_string());
+    }
+    if let Some(position) = socket.pinned {
+        parts.push(format!("pinned at position {position}"));
+    }
+    if let Some(priority) = socket.priority_override {
+        let until = match &socket.priority_override_until {
+            Some(until) => format!(" until {}", format_timestamp(until)),
+            None => String::new(),
+        };
+        parts.push(format!("priority {priority}{until}"));
+    }
+    (!parts.is_empty()).then(|| parts.join(", "))
+}
+
+/// One line about the circuit breaker of a socket whose agent failed, for `list` and `health`
+fn describe_breaker(breaker: &BreakerInfo) -> Option<String> {
+    let failures = breaker.failures;
+    match breaker.state {
+        BreakerState::Closed => None,
+        BreakerState::Open => {
+            let retry = match &breaker.next_retry {
+                Some(next_retry) => format!(" until {}", format_timestamp(next_retry)),
+                None => String::new(),
+            };
+            Some(format!("skipped{retry} after {failures} failure(s)"))
+        }
+        BreakerState::HalfOpen => Some(format!("retrying after {failures} failure(s)")),
     }
 }
 
@@ -219,6 +329,59 @@ fn cmd_reload(client: &mut ControlClient, format: OutputFormat) -> ExitCode {
     }
 }
 
+fn cmd_upgrade(
+    client: &mut ControlClient,
+    binary: Option<&Path>,
+    format: OutputFormat,
+) -> ExitCode {
+    // The daemon may run from a different working directory
+    let binary = match binary.map(std::path::absolute).transpose() {
+        Ok(binary) => binary,
+        Err(e) => {
+            eprintln!("Error: Invalid binary path: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let binary = binary.as_deref().map(|p| p.to_string_lossy());
+
+    match client.upgrade(binary.as_deref()) {
+        Ok(message) => {
+            match format {
+                OutputFormat::Json => {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "success": true,
+                            "message": message
+                        })
+                    );
+                }
+                OutputFormat::Human => {
+                    println!("{message}");
+                }
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            match format {
+                OutputFormat::Json => {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "success": false,
+                            "error": e.to_string()
+                        })
+                    );
+                }
+                OutputFormat::Human => {
+                    eprintln!("Error: {e}");
+                }
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
 fn cmd_validate(client: &mut ControlClient, format: OutputFormat) -> ExitCode {
     match client.validate() {
         Ok(message) => {
@@ -297,8 +460,48 @@ fn cmd_add(client: &mut ControlClient, path: &Path, format: OutputFormat) -> Exi
     }
 }
 
-fn cmd_remove(client: &mut ControlClient, path: &Path, format: OutputFormat) -> ExitCode {
-    match client.remove_socket(&path.display().to_string()) {
+fn cmd_remove(client: &mut ControlClient, socket: &str, format: OutputFormat) -> ExitCode {
+    match client.remove_socket(socket) {
+        Ok(message) => {
+            match format {
+                OutputFormat::Json => {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "success": true,
+                            "message": message
+                        })
+                    );
+                }
+                OutputFormat::Human => {
+                    println!("{message}");
+                }
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            match format {
+                OutputFormat::Json => {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "success": false,
+                            "error": e.to_string()
+                        })
+                    );
+                }
+                OutputFormat::Human => {
+                    eprintln!("Error: {e}");
+                }
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Print the outcome of a command answered with a message
+fn report(result: Result<String, ControlClientError>, format: OutputFormat) -> ExitCode {
+    match result {
         Ok(message) => {
             match format {
                 OutputFormat::Json => {
@@ -384,16 +587,32 @@ fn print_health_human(result: &HealthCheckResult) {
             _ => "✗",
         };
 
-        println!("  [{}/{}] {}", i + 1, result.sockets.len(), socket.path);
+        match socket.name {
+            Some(ref name) => println!(
+                "  [{}/{}] {name} ({})",
+                i + 1,
+                result.sockets.len(),
+                socket.path
+            ),
+            None => println!("  [{}/{}] {}", i + 1, result.sockets.len(), socket.path),
+        }
         println!("        Status: {} {}", status_icon, socket.status);
 
         if let Some(count) = socket.key_count {
             println!("        Keys: {count}");
         }
 
+        if let Some(latency) = socket.latency_ms {
+            println!("        Latency: {latency} ms");
+        }
+
         if let Some(ref error) = socket.error {
             println!("        Error: {error}");
         }
+
+        if let Some(breaker) = describe_breaker(&socket.breaker) {
+            println!("        Breaker: {} ({breaker})", socket.breaker.state);
+        }
     }
 
     println!();
@@ -415,6 +634,79 @@ fn print_health_human(result: &HealthCheckResult) {
     }
 }
 
+fn cmd_stats(client: &mut ControlClient, format: OutputFormat) -> ExitCode {
+    match client.metrics() {
+        Ok(report) => {
+            match format {
+                OutputFormat::Json => {
+                    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                }
+                OutputFormat::Human => {
+                    print_stats_human(&report);
+                }
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn print_stats_human(report: &MetricsReport) {
+    println!("Since {}", format_timestamp(&report.since));
+
+    println!();
+    if report.upstreams.is_empty() {
+        println!("No upstream agents used yet.");
+    } else {
+        print_usage_header("AGENT");
+        for upstream in &report.upstreams {
+            let agent = match &upstream.name {
+                Some(name) => format!("{name} ({})", upstream.path),
+                None => upstream.path.clone(),
+            };
+            print_usage(&upstream.usage, &agent);
+        }
+    }
+
+    println!();
+    if report.keys.is_empty() {
+        println!("No keys used yet.");
+    } else {
+        print_usage_header("KEY");
+        for key in &report.keys {
+            let description = match &key.comment {
+                Some(comment) if !comment.is_empty() => format!("{} {comment}", key.fingerprint),
+                _ => key.fingerprint.clone(),
+            };
+            print_usage(&key.usage, &description);
+        }
+    }
+}
+
+fn print_usage_header(subject: &str) {
+    println!(
+        "{:<8} {:<8} {:<8} {:<8} {:<8} {:<8} {subject}",
+        "LISTED", "SIGNS", "OK", "FAILED", "AVG MS", "MAX MS"
+    );
+}
+
+/// One line of request counts and latency, for `stats`
+fn print_usage(usage: &UsageStats, subject: &str) {
+    let ms = |ms: Option<u64>| ms.map_or_else(|| "-".to_string(), |ms| ms.to_string());
+    println!(
+        "{:<8} {:<8} {:<8} {:<8} {:<8} {:<8} {subject}",
+        usage.identity_requests,
+        usage.sign_attempts,
+        usage.sign_successes,
+        usage.sign_failures,
+        ms(usage.avg_latency_ms),
+        ms(usage.max_latency_ms)
+    );
+}
+
 /// Format a duration in seconds as human-readable
 fn format_duration(secs: u64) -> String {
     if secs < 60 {
@@ -0,0 +1,596 @@
+//! Parts of the running daemon that configuration reloads and upgrades act on
+
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Result as EyreResult};
+use flexi_logger::LoggerHandle;
+use ssh_agent_mux::control::{ControlServerState, WatcherStatus};
+use ssh_agent_mux::instance::{self, InstanceError, InstanceLock, SocketCleanup, SocketProtocol};
+use ssh_agent_mux::{health, session, socket_manager::SocketManager, watcher, MuxAgent};
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::{cli, logging, systemd, upgrade};
+
+/// Number of consecutive health checks that may fail to rebind a deleted socket before exiting
+const MAX_REBIND_FAILURES: u32 = 3;
+
+/// Interval between scans of /tmp when file watching isn't possible
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait after a change before saving the state file, so that bursts of changes are
+/// written once
+const STATE_SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// How often to look for upstream agents that are due for a retry after failing
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+type SharedSocketManager = Arc<Mutex<SocketManager>>;
+
+/// The daemon's own listening sockets: the agent socket and the control socket
+pub type DaemonSockets = Arc<std::sync::Mutex<[DaemonSocket; 2]>>;
+
+/// Settings, background tasks and sockets of a running daemon
+pub struct Daemon {
+    pub config: cli::Config,
+    pub logger: LoggerHandle,
+    pub socket_manager: SharedSocketManager,
+    pub control_state: Arc<ControlServerState>,
+    pub sockets: DaemonSockets,
+    pub instance_lock: InstanceLock,
+    /// Health check interval imposed by the systemd watchdog, overriding the configured one
+    pub watchdog_interval: Option<Duration>,
+    pub watcher: Option<ForwardWatcher>,
+    pub health_task: Option<JoinHandle<()>>,
+    /// Retries upstream agents that are skipped after failing once they're due
+    pub retry_task: Option<JoinHandle<()>>,
+    /// Saves the socket manager state when it changes, if `persist_state` is set
+    pub state_task: Option<JoinHandle<()>>,
+    pub config_watcher: Option<watcher::ConfigWatcher>,
+    /// Where the config file watcher reports changes
+    pub config_changes: mpsc::UnboundedSender<()>,
+}
+
+impl Daemon {
+    /// Start watching for forwarded agents and checking health, as configured
+    pub async fn start_tasks(&mut self) {
+        if self.config.watch_for_ssh_forward {
+            self.start_watcher().await;
+        }
+        self.start_health_task();
+        self.start_retry_task();
+        self.start_state_task();
+        if self.config.watch_config {
+            self.start_config_watcher();
+        }
+    }
+
+    /// Start the task probing upstream agents skipped after failing, so that they're used again as
+    /// soon as they answer rather than when a client request happens to try them
+    fn start_retry_task(&mut self) {
+        let manager = self.socket_manager.clone();
+        self.retry_task = Some(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(RETRY_INTERVAL);
+            loop {
+                ticker.tick().await;
+                for (path, probe) in health::retry_failed(&manager).await {
+                    log::debug!(
+                        "Retried upstream agent {}: {}",
+                        path.display(),
+                        probe.status
+                    );
+                }
+            }
+        }));
+    }
+
+    /// (Re)start the task saving the socket manager state to the state file on changes
+    fn start_state_task(&mut self) {
+        if let Some(task) = self.state_task.take() {
+            task.abort();
+        }
+        let Some(state_file) = self.config.state_file() else {
+            return;
+        };
+        let manager = self.socket_manager.clone();
+
+        log::info!("Keeping socket state in {}", state_file.path().display());
+        self.state_task = Some(tokio::spawn(async move {
+            let changes = manager.lock().await.changes();
+            // Save right away, in case the state predates this task
+            changes.notify_one();
+            loop {
+                changes.notified().await;
+                tokio::time::sleep(STATE_SAVE_DELAY).await;
+                let state = manager.lock().await.export_state();
+                match state_file.save(&state) {
+                    Ok(()) => log::debug!("Saved socket state to {}", state_file.path().display()),
+                    Err(e) => log::error!("Failed to save socket state: {e}"),
+                }
+            }
+        }));
+    }
+
+    /// Save the socket manager state one last time before exiting, if it's being kept
+    pub async fn save_state(&mut self) {
+        if let Some(task) = self.state_task.take() {
+            task.abort();
+        }
+        if let Some(state_file) = self.config.state_file() {
+            let state = self.socket_manager.lock().await.export_state();
+            if let Err(e) = state_file.save(&state) {
+                log::error!("Failed to save socket state: {e}");
+            }
+        }
+    }
+
+    fn start_config_watcher(&mut self) {
+        match watcher::watch_config_file(&self.config.config_path, self.config_changes.clone()) {
+            Ok(mut watcher) => {
+                watcher.set_paths(self.config.watched_paths());
+                self.config_watcher = Some(watcher);
+            }
+            Err(e) => log::error!(
+                "Cannot watch {} for changes: {e}",
+                self.config.config_path.display()
+            ),
+        }
+    }
+
+    async fn start_watcher(&mut self) {
+        log::info!("SSH forwarding watch enabled");
+        let discovery = self.config.discovery();
+        let (watcher, status) =
+            ForwardWatcher::start(self.socket_manager.clone(), discovery.clone()).await;
+        self.watcher = Some(watcher);
+        let mut settings = self.control_state.settings.write().unwrap();
+        settings.watch_enabled = true;
+        settings.watcher_status = status;
+        settings.discovery = discovery;
+    }
+
+    fn stop_watcher(&mut self) {
+        log::info!("SSH forwarding watch disabled");
+        self.watcher = None;
+        let mut settings = self.control_state.settings.write().unwrap();
+        settings.watch_enabled = false;
+        settings.watcher_status = WatcherStatus::Disabled;
+    }
+
+    /// (Re)start the health check task, which also pings the systemd watchdog
+    fn start_health_task(&mut self) {
+        if let Some(task) = self.health_task.take() {
+            task.abort();
+        }
+
+        // Use half the watchdog timeout if systemd expects pings, so that they happen after real
+        // health checks; otherwise the configured interval
+        let interval = match self.watchdog_interval {
+            Some(interval) => interval,
+            None if self.config.health_check_interval > 0 => {
+                Duration::from_secs(self.config.health_check_interval)
+            }
+            None => {
+                log::info!("Health checks disabled");
+                return;
+            }
+        };
+        let manager = self.socket_manager.clone();
+        let sockets = self.sockets.clone();
+
+        log::info!("Health check task started (interval: {interval:?})");
+        self.health_task = Some(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // Skip the first tick (immediate)
+            ticker.tick().await;
+
+            loop {
+                ticker.tick().await;
+
+                // Check daemon health: our own sockets must exist, and are rebound if they don't
+                for socket in sockets.lock().unwrap().iter_mut() {
+                    if !socket.ensure_bound() {
+                        log::error!(
+                            "Giving up on {} after {MAX_REBIND_FAILURES} failed rebinds, exiting",
+                            socket.path.display()
+                        );
+                        std::process::exit(1);
+                    }
+                }
+
+                // Run upstream socket health check: drop sockets that are gone, then ask the
+                // others for their keys
+                let mut mgr = manager.lock().await;
+                let removed = mgr.validate_and_cleanup();
+                if !removed.is_empty() {
+                    log::info!("Health check removed {} stale socket(s)", removed.len());
+                }
+                drop(mgr);
+                let probes = health::check_sockets(&manager).await;
+                let unhealthy = probes.iter().filter(|(_, probe)| !probe.healthy()).count();
+                log::debug!(
+                    "Health check: {} healthy, {unhealthy} unhealthy upstream agent(s)",
+                    probes.len() - unhealthy
+                );
+
+                // Ping watchdog after successful health check
+                systemd::notify_watchdog();
+            }
+        }));
+    }
+
+    /// Re-read the configuration and apply what changed
+    ///
+    /// An invalid configuration is rejected as a whole, leaving the running one in place.
+    pub async fn reload(&mut self) {
+        log::info!(
+            "Reloading configuration from {}",
+            self.config.config_path.display()
+        );
+        // The file may now be behind different symlinks
+        if let Some(watcher) = &mut self.config_watcher {
+            watcher.refresh();
+        }
+        let mut config = match self.config.reload() {
+            Ok(config) => {
+                // Files may have been added to or removed from the includes and drop-ins
+                if let Some(watcher) = &mut self.config_watcher {
+                    watcher.set_paths(config.watched_paths());
+                }
+                config
+            }
+            Err(e) => {
+                log::error!("Keeping current configuration; failed to load new one: {e}");
+                return;
+            }
+        };
+        if let Some(group) = &config.control_socket_group {
+            if let Err(e) = instance::resolve_group(group) {
+                log::error!("Keeping current configuration; invalid control_socket_group: {e}");
+                return;
+            }
+        }
+
+        let changes = self.config.diff(&config);
+        if changes.is_empty() {
+            log::info!("Configuration unchanged");
+            return;
+        }
+        for change in &changes {
+            log::info!("Configuration changed: {change}");
+        }
+
+        if config.log_level != self.config.log_level {
+            logging::set_level(&self.logger, config.log_level.into());
+        }
+        if config.log_file != self.config.log_file {
+            log::warn!("log_file takes effect when the daemon is restarted");
+        }
+        if config.control_socket_group != self.config.control_socket_group {
+            log::warn!("control_socket_group takes effect when the daemon is restarted");
+            config.control_socket_group = self.config.control_socket_group.clone();
+        }
+
+        if config.upstreams() != self.config.upstreams() {
+            let mut manager = self.socket_manager.lock().await;
+            manager.update_configured(config.upstreams());
+        }
+        if config.forward_agent_grace_period != self.config.forward_agent_grace_period {
+            let mut manager = self.socket_manager.lock().await;
+            manager.set_grace_period(Duration::from_secs(config.forward_agent_grace_period));
+        }
+        if config.socket_order != self.config.socket_order {
+            self.socket_manager
+                .lock()
+                .await
+                .set_ordering(config.socket_order);
+        }
+
+        // Move sockets whose configured path changed, keeping the old paths if that fails
+        if config.listen_path != self.config.listen_path {
+            if let Err(e) = self.move_listen_socket(config.listen_path.clone()) {
+                log::error!("Keeping listen socket in place: {e}");
+                config.listen_path = self.config.listen_path.clone();
+            }
+        }
+        if config.get_control_socket_path() != self.config.get_control_socket_path() {
+            if let Err(e) = self.move_socket(1, config.get_control_socket_path()) {
+                log::error!("Keeping control socket in place: {e}");
+                config.control_socket_path = self.config.control_socket_path.clone();
+                if config.get_control_socket_path() != self.config.get_control_socket_path() {
+                    // The old path was derived from the old listen path
+                    config.control_socket_path = Some(self.config.get_control_socket_path());
+                }
+            }
+        }
+
+        let restart_watcher = config.watch_for_ssh_forward != self.config.watch_for_ssh_forward
+            || (config.watch_for_ssh_forward && config.discovery() != self.config.discovery());
+        let restart_health = config.health_check_interval != self.config.health_check_interval;
+        let restart_config_watcher = config.watch_config != self.config.watch_config;
+        let restart_state = config.state_file() != self.config.state_file();
+        self.config = config;
+
+        if restart_watcher {
+            if self.config.watch_for_ssh_forward {
+                self.start_watcher().await;
+            } else {
+                self.stop_watcher();
+            }
+        }
+        if restart_health && self.watchdog_interval.is_none() {
+            self.start_health_task();
+        }
+        if restart_state {
+            self.start_state_task();
+        }
+        if restart_config_watcher {
+            if self.config.watch_config {
+                self.start_config_watcher();
+            } else {
+                log::info!("Stopped watching {}", self.config.config_path.display());
+                self.config_watcher = None;
+            }
+        }
+    }
+
+    /// Serve the agent on a new path, taking the instance lock for it
+    fn move_listen_socket(&mut self, path: PathBuf) -> EyreResult<()> {
+        let lock = InstanceLock::acquire(instance::default_lock_path(&path))?;
+        self.move_socket(0, path)?;
+        // Releases the lock for the old path
+        self.instance_lock = lock;
+        Ok(())
+    }
+
+    fn move_socket(&mut self, index: usize, path: PathBuf) -> EyreResult<()> {
+        self.sockets.lock().unwrap()[index].move_to(path.clone())?;
+        let mut settings = self.control_state.settings.write().unwrap();
+        match index {
+            0 => settings.listen_path = path,
+            _ => settings.control_path = path,
+        }
+        Ok(())
+    }
+
+    /// Start the successor daemon for an upgrade, returning its PID once it's serving
+    pub async fn start_upgrade(&self, binary: Option<PathBuf>, mux: &MuxAgent) -> EyreResult<u32> {
+        // Lifetimes and confirmation the mux enforces itself exist only in this process
+        let constrained = mux.mux_constrained_key_count().await;
+        if constrained > 0 {
+            return Err(eyre!(
+                "{constrained} key(s) added with constraints enforced by ssh-agent-mux; remove \
+                 them or wait for them to expire"
+            ));
+        }
+
+        let binary = match binary {
+            Some(binary) => binary,
+            None => upgrade::default_binary()?,
+        };
+
+        let socket_manager = self.socket_manager.lock().await.export_state();
+        let [agent, control] = {
+            let sockets = self.sockets.lock().unwrap();
+            [0, 1].map(|i| {
+                sockets[i]
+                    .listener
+                    .try_clone()
+                    .map(|listener| upgrade::InheritedListener {
+                        listener,
+                        // Sockets we bound are for the successor to remove on exit
+                        remove_on_exit: sockets[i].cleanup.is_some(),
+                    })
+            })
+        };
+        upgrade::Handover {
+            agent: agent?,
+            control: control?,
+            lock: self.instance_lock.try_clone_fd()?,
+            socket_manager,
+        }
+        .start(binary)
+        .await
+    }
+
+    /// Leave the sockets and instance lock to a successor daemon that's now serving them
+    pub fn hand_over(self, pid: u32) {
+        // The successor keeps the state file from now on
+        for task in [self.health_task, self.retry_task, self.state_task]
+            .into_iter()
+            .flatten()
+        {
+            task.abort();
+        }
+        for socket in self.sockets.lock().unwrap().iter_mut() {
+            if let Some(cleanup) = &mut socket.cleanup {
+                cleanup.disarm();
+            }
+        }
+        self.instance_lock.hand_over();
+        systemd::notify_main_pid(pid);
+    }
+}
+
+/// Running watcher for forwarded agents, which stops when dropped
+pub struct ForwardWatcher {
+    _smart: Option<watcher::SmartWatcher>,
+    /// Stops the polling loop when dropped
+    _shutdown: broadcast::Sender<()>,
+    events: JoinHandle<()>,
+}
+
+impl ForwardWatcher {
+    /// Add existing forwarded agents and start watching for new ones
+    async fn start(
+        socket_manager: SharedSocketManager,
+        discovery: watcher::Discovery,
+    ) -> (Self, WatcherStatus) {
+        // Scan for existing forwarded agents
+        match watcher::scan_existing_agents(&discovery).await {
+            Ok(agents) => {
+                log::info!("Found {} existing SSH forwarded agents", agents.len());
+                let agents = session::find_sessions(agents).await;
+                let mut manager = socket_manager.lock().await;
+                for (agent, session) in agents {
+                    manager.add_watched(agent, session);
+                }
+            }
+            Err(e) => {
+                log::warn!("Failed to scan for existing agents: {e}");
+            }
+        }
+
+        // Start watching for new agents
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (shutdown, _) = broadcast::channel::<()>(1);
+
+        // Try smart watcher with automatic fallback
+        let watch_result = watcher::start_watching(discovery.clone(), tx.clone()).await;
+        let (smart, status) = match watch_result.mode {
+            watcher::WatchMode::Smart(w) => {
+                log::info!("Smart file watcher started successfully");
+                (Some(w), WatcherStatus::Active)
+            }
+            watcher::WatchMode::Polling => {
+                let reason = watch_result
+                    .fallback_reason
+                    .unwrap_or_else(|| "Unknown error".to_string());
+                log::warn!("Using polling fallback: {reason}");
+                tokio::spawn(watcher::run_polling_loop(
+                    discovery,
+                    tx,
+                    POLL_INTERVAL,
+                    shutdown.subscribe(),
+                ));
+                (None, WatcherStatus::PollingFallback(reason))
+            }
+        };
+
+        // Spawn event handler task
+        let events = tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                match event {
+                    watcher::WatchEvent::Added(path) => {
+                        let session = session::find_session_async(path.clone()).await;
+                        let mut manager = socket_manager.lock().await;
+                        if manager.add_watched(path.clone(), session) {
+                            log::info!("Added forwarded agent: {}", path.display());
+                        }
+                    }
+                    watcher::WatchEvent::Removed(path) => {
+                        let mut manager = socket_manager.lock().await;
+                        if manager.mark_gone(&path) {
+                            log::info!("Forwarded agent vanished: {}", path.display());
+                        }
+                    }
+                }
+            }
+        });
+
+        let watcher = Self {
+            _smart: smart,
+            _shutdown: shutdown,
+            events,
+        };
+        (watcher, status)
+    }
+}
+
+impl Drop for ForwardWatcher {
+    fn drop(&mut self) {
+        self.events.abort();
+    }
+}
+
+/// One of the daemon's own listening sockets, which the health task rebinds if its file is deleted
+pub struct DaemonSocket {
+    pub name: &'static str,
+    pub path: PathBuf,
+    /// Protocol served on the socket
+    pub protocol: SocketProtocol,
+    /// Group allowed to search the socket's directory
+    pub dir_group: Option<u32>,
+    /// Group allowed to connect to the socket
+    pub socket_group: Option<u32>,
+    /// Listener currently being served, kept to hand over on upgrade
+    pub listener: StdUnixListener,
+    /// Where to send the listener for a rebound socket
+    pub replacements: mpsc::UnboundedSender<StdUnixListener>,
+    /// Removes the socket file on exit; `None` for sockets created by systemd
+    pub cleanup: Option<SocketCleanup>,
+    /// Consecutive failed attempts to rebind
+    pub failures: u32,
+}
+
+impl DaemonSocket {
+    /// Rebind the socket if its file has disappeared
+    ///
+    /// Connected clients are unaffected. Returns `false` once rebinding has failed
+    /// [`MAX_REBIND_FAILURES`] times in a row.
+    fn ensure_bound(&mut self) -> bool {
+        if self.path.exists() {
+            self.failures = 0;
+            return true;
+        }
+
+        log::warn!(
+            "{} socket {} was deleted, rebinding",
+            self.name,
+            self.path.display()
+        );
+        match self.bind(self.path.clone()) {
+            Ok(()) => {
+                log::info!("Rebound {} socket {}", self.name, self.path.display());
+                self.failures = 0;
+            }
+            Err(e) => {
+                self.failures += 1;
+                log::error!(
+                    "Failed to rebind {} socket (attempt {}/{MAX_REBIND_FAILURES}): {e}",
+                    self.name,
+                    self.failures
+                );
+            }
+        }
+        self.failures < MAX_REBIND_FAILURES
+    }
+
+    /// Serve the socket on a new path, removing the old socket file
+    fn move_to(&mut self, path: PathBuf) -> EyreResult<()> {
+        if self.cleanup.is_none() {
+            return Err(eyre!(
+                "{} socket {} was created by systemd; change it in the socket unit instead",
+                self.name,
+                self.path.display()
+            ));
+        }
+        self.bind(path)?;
+        log::info!("Moved {} socket to {}", self.name, self.path.display());
+        Ok(())
+    }
+
+    /// Bind a new socket at `path` and hand it to whoever accepts connections for this socket
+    fn bind(&mut self, path: PathBuf) -> Result<(), InstanceError> {
+        let io_err = |e| InstanceError::Io(path.clone(), e);
+        // The whole directory may have been cleaned up
+        if let Some(dir) = path.parent() {
+            instance::check_socket_dir(dir, self.dir_group)?;
+        }
+        let listener = instance::bind_socket(&path, self.socket_group, self.protocol)?;
+        let served = listener.try_clone().map_err(io_err)?;
+        self.replacements
+            .send(served)
+            .map_err(|_| io_err(std::io::Error::other("socket is no longer being served")))?;
+        self.listener = listener;
+        if self.cleanup.is_some() && path != self.path {
+            // Dropping the old cleanup removes the socket file at the old path
+            self.cleanup = Some(SocketCleanup::new(path.clone()));
+        }
+        self.path = path;
+        Ok(())
+    }
+}
@@ -49,11 +49,7 @@ pub fn setup_logger(
     let logger = if env::var_os("RUST_LOG").is_some() {
         Logger::try_with_env()?
     } else {
-        let logspec = LogSpecification::builder()
-            .default(LevelFilter::Error)
-            .module(env!("CARGO_CRATE_NAME"), level)
-            .build();
-        Logger::with(logspec).filter(Box::new(SuppressExtensionFailure))
+        Logger::with(log_spec(level)).filter(Box::new(SuppressExtensionFailure))
     };
 
     if let Some(f) = log_file {
@@ -63,3 +59,17 @@ pub fn setup_logger(
         logger.log_to_stdout().start()
     }
 }
+
+/// Change the log level of a running logger; a level set with RUST_LOG takes precedence
+pub fn set_level(logger: &LoggerHandle, level: LevelFilter) {
+    if env::var_os("RUST_LOG").is_none() {
+        logger.set_new_spec(log_spec(level));
+    }
+}
+
+fn log_spec(level: LevelFilter) -> LogSpecification {
+    LogSpecification::builder()
+        .default(LevelFilter::Error)
+        .module(env!("CARGO_CRATE_NAME"), level)
+        .build()
+}
@@ -1,24 +1,33 @@
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 use std::sync::Arc;
 use std::time::Duration;
 
-use color_eyre::eyre::Result as EyreResult;
+use color_eyre::eyre::{eyre, Result as EyreResult};
+use daemon::{Daemon, DaemonSocket};
 use ssh_agent_mux::control::{
-    ControlServer, ControlServerState, SelfDeletingControlSocket, WatcherStatus,
+    ControlServer, ControlServerState, DaemonSettings, UpgradeRequest, WatcherStatus,
 };
-use ssh_agent_mux::{socket_manager::SocketManager, watcher, MuxAgent};
+use ssh_agent_mux::instance::{self, InstanceError, InstanceLock, SocketCleanup, SocketProtocol};
+use ssh_agent_mux::{session, socket_manager::SocketManager, MuxAgent};
 use tokio::select;
 use tokio::signal::{self, unix::SignalKind};
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
 
 mod cli;
 mod commands;
+mod daemon;
 mod logging;
 mod systemd;
+mod upgrade;
 
 const BUILD_VERSION: &str = env!("SSH_AGENT_MUX_BUILD_VERSION");
 const GIT_DESCRIBE: &str = env!("SSH_AGENT_MUX_GIT_DESCRIBE");
 
+/// How long a daemon that has handed over to its successor waits for its clients to disconnect
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+
 #[cfg(debug_assertions)]
 fn install_eyre_hook() -> EyreResult<()> {
     color_eyre::config::HookBuilder::default()
@@ -64,6 +73,10 @@ fn main() -> ExitCode {
                 }
             }
         }
+        cli::Command::CheckConfig {
+            config_path,
+            config,
+        } => commands::cmd_check_config(config_path, config, format),
         ref cmd => {
             // Client command - use auto-detected or specified control socket
             let control_socket = args.get_control_socket();
@@ -80,237 +93,327 @@ async fn run_daemon(
     config_path: std::path::PathBuf,
     config_opt: <cli::Config as clap_serde_derive::ClapSerde>::Opt,
 ) -> EyreResult<()> {
-    let config = cli::Config::from_serve_args(config_path, config_opt)?;
+    let mut config = cli::Config::from_serve_args(config_path, config_opt)?;
+    config.command_line = std::env::args_os().collect();
 
-    // LoggerHandle must be held until program termination so file logging takes place
-    let _logger = logging::setup_logger(config.log_level.into(), config.log_file.as_deref())?;
+    // LoggerHandle must be held until program termination so file logging takes place; the
+    // daemon keeps it to change the log level on reload
+    let logger = logging::setup_logger(config.log_level.into(), config.log_file.as_deref())?;
     log::info!("Starting ssh-agent-mux version {BUILD_VERSION}; commit {GIT_DESCRIBE}");
 
-    let mut sigterm = signal::unix::signal(SignalKind::terminate())?;
-    let mut sighup = signal::unix::signal(SignalKind::hangup())?;
-
-    // Create shared socket manager
-    let socket_manager = Arc::new(Mutex::new(SocketManager::new(
-        config.agent_sock_paths.clone(),
-    )));
-
-    // Track watcher status
-    let mut watcher_status = if config.watch_for_ssh_forward {
-        WatcherStatus::Active
-    } else {
-        WatcherStatus::Disabled
+    // Pick up sockets handed over by a daemon being upgraded, or passed in by systemd socket
+    // activation, before binding our own
+    let (inherited_agent, inherited_control, handed_over) = match upgrade::inherited()? {
+        Some(upgrade::Inherited {
+            agent,
+            control,
+            lock,
+            socket_manager,
+            ready,
+        }) => (
+            Some(agent),
+            Some(control),
+            Some((lock, socket_manager, ready)),
+        ),
+        None => {
+            let activated = systemd::activated_listeners()?;
+            // Sockets created by systemd are left in place on exit
+            let from_systemd = |listener| upgrade::InheritedListener {
+                listener,
+                remove_on_exit: false,
+            };
+            (
+                activated.agent.map(from_systemd),
+                activated.control.map(from_systemd),
+                None,
+            )
+        }
     };
 
-    // Create shutdown channel for polling fallback
-    let (shutdown_tx, _shutdown_rx) = tokio::sync::broadcast::channel::<()>(1);
-
-    // Start file watcher if enabled
-    let _watcher = if config.watch_for_ssh_forward {
-        log::info!("SSH forwarding watch enabled");
-
-        // Scan for existing forwarded agents
-        match watcher::scan_existing_agents().await {
-            Ok(agents) => {
-                log::info!("Found {} existing SSH forwarded agents", agents.len());
-                let mut manager = socket_manager.lock().await;
-                for agent in agents {
-                    manager.add_watched(agent);
-                }
-            }
-            Err(e) => {
-                log::warn!("Failed to scan for existing agents: {e}");
-            }
+    // Get paths for sockets; inherited listeners take precedence over configured paths
+    let listen_sock = inherited_agent
+        .as_ref()
+        .and_then(|i| listener_path(&i.listener))
+        .unwrap_or_else(|| config.listen_path.clone());
+    let control_sock = inherited_control
+        .as_ref()
+        .and_then(|i| listener_path(&i.listener))
+        .unwrap_or_else(|| config.get_control_socket_path());
+
+    // Refuse to start with sockets in directories other users can get into
+    let control_group = config
+        .control_socket_group
+        .as_deref()
+        .map(instance::resolve_group)
+        .transpose()
+        .map_err(|e| eyre!("Invalid control_socket_group: {e}"))?;
+    if inherited_agent.is_none() {
+        if let Some(dir) = listen_sock.parent() {
+            // A directory shared with the control socket may grant the control group search
+            // permission; the agent socket itself stays 0600
+            let group = control_group.filter(|_| control_sock.parent() == Some(dir));
+            instance::check_socket_dir(dir, group)?;
         }
+    }
+    if inherited_control.is_none() {
+        if let Some(dir) = control_sock.parent() {
+            instance::check_socket_dir(dir, control_group)?;
+        }
+    }
 
-        // Start watching for new agents
-        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-        let manager_clone = socket_manager.clone();
-
-        // Try smart watcher with automatic fallback
-        let watch_result = watcher::start_watching(tx.clone()).await;
-
-        // Update watcher status based on result
-        let watcher_handle = match watch_result.mode {
-            watcher::WatchMode::Smart(w) => {
-                log::info!("Smart file watcher started successfully");
-                watcher_status = WatcherStatus::Active;
-                Some(w)
-            }
-            watcher::WatchMode::Polling => {
-                let reason = watch_result
-                    .fallback_reason
-                    .unwrap_or_else(|| "Unknown error".to_string());
-                log::warn!("Using polling fallback: {reason}");
-                watcher_status = WatcherStatus::PollingFallback(reason);
-
-                // Start the polling loop
-                let poll_interval = Duration::from_secs(30); // Default 30s polling
-                let shutdown_rx = shutdown_tx.subscribe();
-                tokio::spawn(watcher::run_polling_loop(
-                    tx.clone(),
-                    poll_interval,
-                    shutdown_rx,
-                ));
-
-                None
-            }
-        };
-
-        // Spawn event handler task
-        tokio::spawn(async move {
-            while let Some(event) = rx.recv().await {
-                let mut manager = manager_clone.lock().await;
-                match event {
-                    watcher::WatchEvent::Added(path) => {
-                        if manager.add_watched(path.clone()) {
-                            log::info!("Added forwarded agent: {}", path.display());
-                        }
-                    }
-                    watcher::WatchEvent::Removed(path) => {
-                        if manager.remove_watched(&path) {
-                            log::info!("Removed forwarded agent: {}", path.display());
-                        }
-                    }
-                }
-            }
-        });
-
-        watcher_handle
-    } else {
-        None
+    // Refuse to start if another daemon is already serving these sockets; held until exit, or
+    // until handed over to a successor
+    let lock_path = instance::default_lock_path(&listen_sock);
+    let (instance_lock, restored_state, ready) = match handed_over {
+        Some((lock, state, ready)) => (
+            InstanceLock::inherit(lock_path, lock)?,
+            Some(state),
+            Some(ready),
+        ),
+        None => (InstanceLock::acquire(lock_path)?, None, None),
     };
 
-    // Determine health check interval:
-    // - If systemd watchdog is enabled, use half the watchdog timeout
-    // - Otherwise use the configured interval (if any)
-    // This ensures watchdog pings happen after real health checks
-    let health_interval = if let Some(watchdog_usec) = systemd::watchdog_enabled() {
-        let watchdog_interval = Duration::from_micros(watchdog_usec / 2);
-        log::info!("systemd watchdog enabled, health check interval: {watchdog_interval:?}");
-        Some(watchdog_interval)
-    } else if config.health_check_interval > 0 {
-        Some(Duration::from_secs(config.health_check_interval))
-    } else {
-        None
-    };
+    // Sockets we bind ourselves are removed on exit, unless handed over to a successor. The
+    // control socket comes first, so that it's there once clients see the agent socket
+    let (control_listener, control_cleanup) = take_or_bind(
+        inherited_control,
+        &control_sock,
+        control_group,
+        SocketProtocol::Control,
+    )?;
+    let (agent_listener, agent_cleanup) =
+        take_or_bind(inherited_agent, &listen_sock, None, SocketProtocol::Agent)?;
 
-    // Get paths for sockets
-    let listen_sock = config.listen_path.clone();
-    let control_sock = config.get_control_socket_path();
-
-    // Start health check task that also pings systemd watchdog
-    if let Some(interval) = health_interval {
-        let manager = socket_manager.clone();
-        let listen_path = listen_sock.clone();
-        let control_path = control_sock.clone();
-
-        tokio::spawn(async move {
-            let mut ticker = tokio::time::interval(interval);
-            // Skip the first tick (immediate)
-            ticker.tick().await;
-
-            loop {
-                ticker.tick().await;
-
-                // Check daemon health: our own sockets must exist
-                if !listen_path.exists() {
-                    log::error!("Listen socket {} gone, exiting", listen_path.display());
-                    std::process::exit(1);
-                }
-                if !control_path.exists() {
-                    log::error!("Control socket {} gone, exiting", control_path.display());
-                    std::process::exit(1);
-                }
-
-                // Check we can still write to socket directory
-                let socket_dir = listen_path.parent().unwrap_or(&listen_path);
-                if let Err(e) = std::fs::metadata(socket_dir) {
-                    log::error!(
-                        "Cannot access socket dir {}: {}, exiting",
-                        socket_dir.display(),
-                        e
-                    );
-                    std::process::exit(1);
-                }
-
-                // Run upstream socket health check
-                let mut mgr = manager.lock().await;
-                let removed = mgr.validate_and_cleanup();
-                if !removed.is_empty() {
-                    log::info!("Health check removed {} stale socket(s)", removed.len());
-                }
-                drop(mgr);
+    let mut sigterm = signal::unix::signal(SignalKind::terminate())?;
+    let mut sighup = signal::unix::signal(SignalKind::hangup())?;
 
-                // Ping watchdog after successful health check
-                systemd::notify_watchdog();
+    // Create shared socket manager
+    let socket_manager = Arc::new(Mutex::new(SocketManager::new(config.upstreams())));
+    {
+        let mut manager = socket_manager.lock().await;
+        manager.set_grace_period(Duration::from_secs(config.forward_agent_grace_period));
+        manager.set_ordering(config.socket_order);
+    }
+    if let Some(state) = restored_state {
+        socket_manager.lock().await.restore_state(state);
+    } else if let Some(state_file) = config.state_file() {
+        // Sockets saved by an earlier daemon are checked again, as when they were first added
+        match state_file.load() {
+            Ok(Some(state)) => {
+                let discovery = config.discovery();
+                let sessions = session::find_sessions(state.watched_paths())
+                    .await
+                    .into_iter()
+                    .filter_map(|(path, session)| Some((path, session?)))
+                    .collect();
+                let accept = |path: &Path| {
+                    discovery
+                        .verify(path)
+                        .inspect_err(|e| log::warn!("Not reinstating saved socket: {e}"))
+                        .is_ok()
+                };
+                let reinstated = socket_manager
+                    .lock()
+                    .await
+                    .reinstate_state(state, sessions, accept);
+                log::info!(
+                    "Reinstated {reinstated} socket(s) from {}",
+                    state_file.path().display()
+                );
             }
-        });
-
-        log::info!("Health check task started (interval: {interval:?})");
+            Ok(None) => {}
+            Err(e) => log::warn!("Ignoring saved socket state: {e}"),
+        }
     }
 
+    // Our own sockets are rebound by the health task if a tmp cleaner or similar deletes them
+    let (agent_replacements_tx, agent_replacements) = mpsc::unbounded_channel();
+    let (control_replacements_tx, control_replacements) = mpsc::unbounded_channel();
+    let sockets = Arc::new(std::sync::Mutex::new([
+        DaemonSocket {
+            name: "Listen",
+            path: listen_sock.clone(),
+            protocol: SocketProtocol::Agent,
+            dir_group: control_group.filter(|_| control_sock.parent() == listen_sock.parent()),
+            socket_group: None,
+            listener: agent_listener.try_clone()?,
+            replacements: agent_replacements_tx,
+            cleanup: agent_cleanup,
+            failures: 0,
+        },
+        DaemonSocket {
+            name: "Control",
+            path: control_sock.clone(),
+            protocol: SocketProtocol::Control,
+            dir_group: control_group,
+            socket_group: control_group,
+            listener: control_listener.try_clone()?,
+            replacements: control_replacements_tx,
+            cleanup: control_cleanup,
+            failures: 0,
+        },
+    ]));
+
+    // Upgrade requests from the control server are handled below, since they end this daemon
+    let (upgrade_tx, mut upgrade_rx) = mpsc::unbounded_channel::<UpgradeRequest>();
+
+    // The mux agent records usage metrics, which the control server reports
+    let mux = MuxAgent::new_with_manager(socket_manager.clone());
+
     // Create control server state
     let control_state = Arc::new(ControlServerState {
         socket_manager: socket_manager.clone(),
-        listen_path: listen_sock.clone(),
-        control_path: control_sock.clone(),
-        watch_enabled: config.watch_for_ssh_forward,
-        watcher_status,
+        metrics: mux.metrics(),
+        settings: std::sync::RwLock::new(DaemonSettings {
+            listen_path: listen_sock.clone(),
+            control_path: control_sock.clone(),
+            watch_enabled: false,
+            watcher_status: WatcherStatus::Disabled,
+            discovery: config.discovery(),
+        }),
         version: BUILD_VERSION.to_string(),
         git_commit: GIT_DESCRIBE.to_string(),
         pid: std::process::id(),
+        upgrade_requests: Some(upgrade_tx),
     });
 
-    // Start control server
-    let control_server = ControlServer::bind(&control_sock, control_state).await?;
+    // If systemd watchdog is enabled, health checks run at half the watchdog timeout
+    let watchdog_interval = systemd::watchdog_enabled().map(|watchdog_usec| {
+        let watchdog_interval = Duration::from_micros(watchdog_usec / 2);
+        log::info!("systemd watchdog enabled, health check interval: {watchdog_interval:?}");
+        watchdog_interval
+    });
 
-    // Create self-deleting wrapper for control socket cleanup
-    let _control_socket_cleanup = SelfDeletingControlSocket::new(control_sock.clone());
+    let (config_changes_tx, mut config_changes) = mpsc::unbounded_channel();
+    let mut daemon = Daemon {
+        config,
+        logger,
+        socket_manager: socket_manager.clone(),
+        control_state: control_state.clone(),
+        sockets,
+        instance_lock,
+        watchdog_interval,
+        watcher: None,
+        health_task: None,
+        retry_task: None,
+        state_task: None,
+        config_watcher: None,
+        config_changes: config_changes_tx,
+    };
+    daemon.start_tasks().await;
+
+    // Start control server
+    let mut control_server = ControlServer::from_std(control_listener, control_state)?
+        .with_replacements(control_replacements);
 
     log::info!("Control server listening on {}", control_sock.display());
 
     // Spawn control server task
-    tokio::spawn(async move {
+    let control_task = tokio::spawn(async move {
         if let Err(e) = control_server.run().await {
             log::error!("Control server error: {e}");
         }
     });
 
-    // Notify systemd that we're ready (for Type=notify services)
+    // Run the mux agent with shared socket manager. The agent future lives across loop
+    // iterations so that handling SIGHUP doesn't close and rebind the listening socket
+    let mut agent = Box::pin(mux.clone().serve(agent_listener, Some(agent_replacements)));
+
+    // Notify systemd that we're ready (for Type=notify services), and the daemon we're
+    // replacing, if any, that it can stop
     systemd::notify_ready();
     systemd::notify_status("Running");
+    if let Some(ready) = ready {
+        ready.notify();
+    }
 
-    // Run the mux agent with shared socket manager
+    let mut successor = None;
     loop {
         select! {
-            res = MuxAgent::run_with_manager(&listen_sock, socket_manager.clone()) => { res?; break },
+            res = &mut agent => { res?; break },
+            Some(request) = upgrade_rx.recv() => {
+                match daemon.start_upgrade(request.binary, &mux).await {
+                    Ok(pid) => {
+                        log::info!("Handed over to new daemon with PID {pid}");
+                        let _ = request.reply.send(Ok(pid));
+                        successor = Some(pid);
+                        break;
+                    }
+                    Err(e) => {
+                        log::error!("Upgrade failed: {e}");
+                        let _ = request.reply.send(Err(e.to_string()));
+                    }
+                }
+            },
             // Cleanly exit on interrupt and SIGTERM, allowing
             // MuxAgent to clean up
             _ = signal::ctrl_c() => { log::info!("Exiting on SIGINT"); break },
             Some(_) = sigterm.recv() => { log::info!("Exiting on SIGTERM"); break },
-            Some(_) = sighup.recv() => {
-                log::info!("Reloading configuration from {}", config.config_path.display());
-                // Re-read config file, keeping existing CLI options
-                if let Ok(mut f) = std::fs::File::open(&config.config_path) {
-                    use std::io::Read;
-                    let mut config_text = String::new();
-                    if f.read_to_string(&mut config_text).is_ok() {
-                        if let Ok(file_config) = toml::from_str::<toml::Value>(&config_text) {
-                            if let Some(sockets) = file_config.get("agent_sock_paths").and_then(|v| v.as_array()) {
-                                let new_sockets: Vec<std::path::PathBuf> = sockets
-                                    .iter()
-                                    .filter_map(|v| v.as_str())
-                                    .filter_map(|s| expand_tilde::ExpandTilde::expand_tilde_owned(&std::path::PathBuf::from(s)).ok())
-                                    .collect();
-                                let mut manager = socket_manager.lock().await;
-                                manager.update_configured(new_sockets);
-                            }
-                        }
-                    }
-                }
-            }
+            Some(_) = sighup.recv() => daemon.reload().await,
+            Some(()) = config_changes.recv() => daemon.reload().await,
         }
     }
 
+    if let Some(pid) = successor {
+        // Stop accepting connections; the successor is serving the same sockets
+        drop(agent);
+        control_task.abort();
+        daemon.hand_over(pid);
+        drain(&mux, &mut sigterm).await;
+    } else {
+        daemon.save_state().await;
+    }
+
     Ok(())
 }
+
+/// Let client sessions that were connected before the handover finish
+async fn drain(mux: &MuxAgent, sigterm: &mut signal::unix::Signal) {
+    let start = tokio::time::Instant::now();
+    loop {
+        // Also gives the control connection that requested the upgrade time to get its reply
+        select! {
+            _ = tokio::time::sleep(Duration::from_millis(100)) => {},
+            _ = signal::ctrl_c() => { log::info!("Exiting on SIGINT"); return },
+            Some(_) = sigterm.recv() => { log::info!("Exiting on SIGTERM"); return },
+        }
+        let sessions = mux.active_sessions();
+        if sessions == 0 {
+            break;
+        }
+        if start.elapsed() >= DRAIN_TIMEOUT {
+            log::warn!("Exiting with {sessions} client session(s) still connected");
+            break;
+        }
+    }
+    log::info!("Exiting after handing over to new daemon");
+}
+
+/// Use an inherited listener, or bind a new socket that's removed on exit
+fn take_or_bind(
+    inherited: Option<upgrade::InheritedListener>,
+    path: &Path,
+    group: Option<u32>,
+    protocol: SocketProtocol,
+) -> Result<(StdUnixListener, Option<SocketCleanup>), InstanceError> {
+    match inherited {
+        Some(inherited) => {
+            let cleanup = inherited
+                .remove_on_exit
+                .then(|| SocketCleanup::new(path.to_path_buf()));
+            Ok((inherited.listener, cleanup))
+        }
+        None => {
+            let listener = instance::bind_socket(path, group, protocol)?;
+            Ok((listener, Some(SocketCleanup::new(path.to_path_buf()))))
+        }
+    }
+}
+
+/// Filesystem path a Unix listener is bound to, if any
+fn listener_path(listener: &StdUnixListener) -> Option<PathBuf> {
+    listener
+        .local_addr()
+        .ok()
+        .and_then(|addr| addr.as_pathname().map(|p| p.to_path_buf()))
+}
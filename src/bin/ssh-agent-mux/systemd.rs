@@ -1,7 +1,9 @@
 //! systemd notification support
 //!
 //! This module provides integration with systemd's service notification protocol,
-//! enabling Type=notify services and watchdog functionality.
+//! enabling Type=notify services and watchdog functionality, and socket activation.
+
+use std::os::unix::net::UnixListener;
 
 /// Notify systemd that the service is ready.
 ///
@@ -58,6 +60,23 @@ pub fn notify_status(_status: &str) {
     // No-op when systemd support is not compiled in
 }
 
+/// Tell systemd that another process has taken over as the service's main process.
+///
+/// Used when handing over to a new daemon on upgrade, so that systemd keeps supervising the
+/// service instead of considering it stopped when this process exits.
+#[cfg(feature = "systemd")]
+pub fn notify_main_pid(pid: u32) {
+    match sd_notify::notify(false, &[sd_notify::NotifyState::MainPid(pid)]) {
+        Ok(()) => log::debug!("Sent MAINPID={pid} to systemd"),
+        Err(e) => log::debug!("Failed to notify systemd of new main PID: {e}"),
+    }
+}
+
+#[cfg(not(feature = "systemd"))]
+pub fn notify_main_pid(_pid: u32) {
+    // No-op when systemd support is not compiled in
+}
+
 /// Check if we're running under systemd with watchdog enabled.
 ///
 /// Returns the watchdog interval in microseconds if enabled, None otherwise.
@@ -76,3 +95,52 @@ pub fn watchdog_enabled() -> Option<u64> {
 pub fn watchdog_enabled() -> Option<u64> {
     None
 }
+
+/// Listening sockets passed in by systemd socket activation.
+#[derive(Default)]
+pub struct ActivatedListeners {
+    /// SSH agent socket (`FileDescriptorName=agent`)
+    pub agent: Option<UnixListener>,
+    /// Control socket (`FileDescriptorName=control`)
+    pub control: Option<UnixListener>,
+}
+
+/// Collect listening sockets passed via `LISTEN_FDS`/`LISTEN_FDNAMES`.
+///
+/// Sockets are identified by their `FileDescriptorName=` in the `.socket` unit: `agent` for the
+/// SSH agent socket and `control` for the control socket. Either may be omitted, in which case the
+/// daemon binds that socket itself. The environment variables are unset so child processes don't
+/// inherit them.
+#[cfg(feature = "systemd")]
+pub fn activated_listeners() -> std::io::Result<ActivatedListeners> {
+    use std::os::fd::FromRawFd;
+
+    let mut listeners = ActivatedListeners::default();
+    for (fd, name) in sd_notify::listen_fds_with_names(true)? {
+        // SAFETY: systemd hands us ownership of every fd in the LISTEN_FDS range, and each is
+        // visited exactly once
+        let listener = unsafe { UnixListener::from_raw_fd(fd) };
+        let slot = match name.as_str() {
+            "agent" => &mut listeners.agent,
+            "control" => &mut listeners.control,
+            other => {
+                log::warn!("Ignoring socket-activated fd {fd} with unknown name {other:?}");
+                continue;
+            }
+        };
+        if slot.is_some() {
+            log::warn!("Ignoring duplicate socket-activated fd {fd} named {name:?}");
+            continue;
+        }
+        // Reject anything that isn't a Unix domain socket
+        listener.local_addr()?;
+        log::info!("Using socket-activated {name} socket (fd {fd})");
+        *slot = Some(listener);
+    }
+    Ok(listeners)
+}
+
+#[cfg(not(feature = "systemd"))]
+pub fn activated_listeners() -> std::io::Result<ActivatedListeners> {
+    Ok(ActivatedListeners::default())
+}
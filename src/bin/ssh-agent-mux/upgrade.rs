@@ -0,0 +1,255 @@
+//! Zero-downtime upgrades
+//!
+//! The running daemon starts the new binary with its listening sockets, its instance lock and the
+//! write end of a pipe as inherited file descriptors, and its socket manager state in the
+//! environment. The new daemon serves on the same sockets and reports readiness over the pipe;
+//! only then does the old daemon stop accepting connections, let its in-flight sessions finish
+//! and exit. Clients never see the sockets go away.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Result as EyreResult};
+use serde::{Deserialize, Serialize};
+use ssh_agent_mux::socket_manager::SocketManagerState;
+
+/// Environment variable carrying the handed-over state to the new daemon
+const STATE_ENV: &str = "SSH_AGENT_MUX_UPGRADE_STATE";
+
+/// File descriptors the new daemon inherits, starting at 3 like systemd socket activation
+const AGENT_FD: RawFd = 3;
+const CONTROL_FD: RawFd = 4;
+const LOCK_FD: RawFd = 5;
+const READY_FD: RawFd = 6;
+
+/// How long the new daemon may take to start serving before the upgrade is abandoned
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// State passed to the new daemon alongside its file descriptors
+#[derive(Serialize, Deserialize)]
+struct HandoverState {
+    /// PID of the daemon handing over
+    pid: u32,
+    /// Whether the daemon bound the agent socket itself and must remove it on exit
+    remove_agent_socket: bool,
+    /// Whether the daemon bound the control socket itself and must remove it on exit
+    remove_control_socket: bool,
+    socket_manager: SocketManagerState,
+}
+
+/// A listening socket passed in by another process
+pub struct InheritedListener {
+    pub listener: UnixListener,
+    /// Whether the socket file is ours to remove on exit; sockets created by a service manager
+    /// belong to it
+    pub remove_on_exit: bool,
+}
+
+/// Everything handed over by the daemon this process is replacing
+pub struct Inherited {
+    pub agent: InheritedListener,
+    pub control: InheritedListener,
+    /// File descriptor holding the instance lock
+    pub lock: OwnedFd,
+    pub socket_manager: SocketManagerState,
+    pub ready: ReadyNotifier,
+}
+
+/// Tells the daemon being replaced that we're serving, so it can stop
+pub struct ReadyNotifier(File);
+
+impl ReadyNotifier {
+    pub fn notify(mut self) {
+        if let Err(e) = self.0.write_all(b"1") {
+            log::warn!("Failed to notify previous daemon of readiness: {e}");
+        }
+    }
+}
+
+/// Pick up the sockets and state handed over by a daemon being upgraded, if it started us
+///
+/// The environment variable is unset so child processes don't inherit it.
+pub fn inherited() -> EyreResult<Option<Inherited>> {
+    let Ok(state) = std::env::var(STATE_ENV) else {
+        return Ok(None);
+    };
+    std::env::remove_var(STATE_ENV);
+    let state: HandoverState =
+        serde_json::from_str(&state).map_err(|e| eyre!("Invalid upgrade state: {e}"))?;
+
+    // SAFETY: the previous daemon placed these fds for us, and nothing else in this process has
+    // taken ownership of them
+    let [agent, control, lock, ready] =
+        [AGENT_FD, CONTROL_FD, LOCK_FD, READY_FD].map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+    for fd in [&agent, &control, &lock, &ready] {
+        set_cloexec(fd.as_raw_fd())?;
+    }
+
+    // systemd's watchdog is ours to feed now
+    if std::env::var("WATCHDOG_PID").is_ok_and(|pid| pid == state.pid.to_string()) {
+        std::env::set_var("WATCHDOG_PID", std::process::id().to_string());
+    }
+
+    log::info!("Taking over from ssh-agent-mux PID {}", state.pid);
+    Ok(Some(Inherited {
+        agent: InheritedListener {
+            listener: agent.into(),
+            remove_on_exit: state.remove_agent_socket,
+        },
+        control: InheritedListener {
+            listener: control.into(),
+            remove_on_exit: state.remove_control_socket,
+        },
+        lock,
+        socket_manager: state.socket_manager,
+        ready: ReadyNotifier(ready.into()),
+    }))
+}
+
+/// Binary to start when no other is requested: the file we were started from, which is where a
+/// package manager puts the new version
+pub fn default_binary() -> std::io::Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    // Linux reports a binary that has been replaced on disk as "/path/to/binary (deleted)"
+    Ok(exe
+        .to_str()
+        .and_then(|s| s.strip_suffix(" (deleted)"))
+        .map(PathBuf::from)
+        .unwrap_or(exe))
+}
+
+/// What a running daemon hands over to its successor
+pub struct Handover {
+    pub agent: InheritedListener,
+    pub control: InheritedListener,
+    pub lock: OwnedFd,
+    pub socket_manager: SocketManagerState,
+}
+
+impl Handover {
+    /// Start `binary` as the successor daemon and wait until it's serving, returning its PID
+    ///
+    /// The successor is killed if it doesn't become ready in time.
+    pub async fn start(self, binary: PathBuf) -> EyreResult<u32> {
+        tokio::task::spawn_blocking(move || self.start_blocking(&binary))
+            .await
+            .map_err(|e| eyre!("Upgrade task failed: {e}"))?
+    }
+
+    fn start_blocking(self, binary: &Path) -> EyreResult<u32> {
+        let state = serde_json::to_string(&HandoverState {
+            pid: std::process::id(),
+            remove_agent_socket: self.agent.remove_on_exit,
+            remove_control_socket: self.control.remove_on_exit,
+            socket_manager: self.socket_manager,
+        })?;
+        let (ready_read, ready_write) = pipe()?;
+
+        let fds = [
+            self.agent.listener.as_raw_fd(),
+            self.control.listener.as_raw_fd(),
+            self.lock.as_raw_fd(),
+            ready_write.as_raw_fd(),
+        ];
+        let mut command = Command::new(binary);
+        // Same subcommand and options as we were started with
+        command
+            .args(std::env::args_os().skip(1))
+            .env(STATE_ENV, state);
+        // SAFETY: only async-signal-safe libc calls are made between fork and exec
+        unsafe {
+            command.pre_exec(move || place_fds(&fds));
+        }
+
+        log::info!("Starting {} to take over", binary.display());
+        let mut child = command
+            .spawn()
+            .map_err(|e| eyre!("Failed to start {}: {e}", binary.display()))?;
+        // Only the child may hold the write end, so that we see EOF if it dies
+        drop(ready_write);
+
+        match wait_ready(ready_read, &mut child) {
+            Ok(()) => Ok(child.id()),
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Wait for the successor to report readiness on the pipe
+fn wait_ready(ready: OwnedFd, child: &mut Child) -> EyreResult<()> {
+    let mut pollfd = libc::pollfd {
+        fd: ready.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: pollfd is a valid array of one element
+    let rc = unsafe { libc::poll(&mut pollfd, 1, READY_TIMEOUT.as_millis() as libc::c_int) };
+    match rc {
+        0 => {
+            return Err(eyre!(
+                "new daemon didn't become ready within {READY_TIMEOUT:?}"
+            ))
+        }
+        rc if rc < 0 => return Err(std::io::Error::last_os_error().into()),
+        _ => {}
+    }
+
+    let mut buf = [0; 1];
+    if File::from(ready).read(&mut buf)? == 1 {
+        return Ok(());
+    }
+    match child.wait() {
+        Ok(status) => Err(eyre!("new daemon exited before it was ready ({status})")),
+        Err(e) => Err(eyre!("new daemon exited before it was ready: {e}")),
+    }
+}
+
+/// Move `fds` to consecutive descriptors starting at [`AGENT_FD`], clearing close-on-exec
+///
+/// Runs in the forked child, so it must stay async-signal-safe.
+fn place_fds(fds: &[RawFd; 4]) -> std::io::Result<()> {
+    // Move the fds out of the way first so that placing them can't clobber one another
+    let mut moved = [0; 4];
+    for (slot, fd) in moved.iter_mut().zip(fds) {
+        // SAFETY: plain fcntl on an fd we own
+        *slot = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, 100) };
+        if *slot < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    for (target, fd) in (AGENT_FD..).zip(moved) {
+        // SAFETY: plain dup2 on fds we own; it clears FD_CLOEXEC on the target
+        if unsafe { libc::dup2(fd, target) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn pipe() -> std::io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for the two descriptors pipe2 writes
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: pipe2 just created these and nothing else owns them
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+fn set_cloexec(fd: RawFd) -> std::io::Result<()> {
+    // SAFETY: plain fcntl on an fd we own
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
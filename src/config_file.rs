@@ -0,0 +1,453 @@
+//! Reading a configuration file together with the files it includes and its drop-in directory.
+//!
+//! Files are merged in order: the files a file includes come before the file itself, and the
+//! drop-in files come after the main configuration file, sorted by name. A later file
+//! overrides settings with single values; list settings are combined, keeping the entries of
+//! earlier files first. A table in a list replaces an earlier table with the same `name`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use expand_tilde::ExpandTilde;
+use serde::Serialize;
+
+use crate::expand::{self, ExpandError};
+
+/// Setting listing the files a configuration file includes
+const INCLUDE_KEY: &str = "include";
+
+/// Errors from reading configuration files
+#[derive(Debug)]
+pub enum ConfigFileError {
+    /// A configuration file couldn't be read
+    Read { path: PathBuf, error: io::Error },
+    /// A configuration file isn't valid TOML
+    Parse {
+        path: PathBuf,
+        error: Box<toml::de::Error>,
+    },
+    /// `include` isn't a list of paths
+    InvalidInclude { path: PathBuf },
+    /// An included file doesn't exist
+    MissingInclude {
+        path: PathBuf,
+        included_from: PathBuf,
+    },
+    /// A file includes itself, directly or through other files
+    IncludeCycle { path: PathBuf },
+    /// An included path couldn't be expanded
+    Expand(ExpandError),
+    /// `~` in an included path couldn't be expanded
+    Tilde {
+        path: PathBuf,
+        error: expand_tilde::Error,
+    },
+}
+
+impl fmt::Display for ConfigFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigFileError::Read { path, error } => {
+                write!(f, "Cannot read {}: {error}", path.display())
+            }
+            ConfigFileError::Parse { path, error } => {
+                write!(f, "Invalid configuration in {}: {error}", path.display())
+            }
+            ConfigFileError::InvalidInclude { path } => write!(
+                f,
+                "{INCLUDE_KEY} in {} must be a list of file paths",
+                path.display()
+            ),
+            ConfigFileError::MissingInclude {
+                path,
+                included_from,
+            } => write!(
+                f,
+                "{} (included from {}) does not exist",
+                path.display(),
+                included_from.display()
+            ),
+            ConfigFileError::IncludeCycle { path } => {
+                write!(f, "{} includes itself", path.display())
+            }
+            ConfigFileError::Expand(e) => write!(f, "{e}"),
+            ConfigFileError::Tilde { path, error } => {
+                write!(f, "Cannot expand {}: {error}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigFileError {}
+
+impl From<ExpandError> for ConfigFileError {
+    fn from(e: ExpandError) -> Self {
+        ConfigFileError::Expand(e)
+    }
+}
+
+/// Where the value of a setting came from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigSource {
+    /// A configuration file
+    File(PathBuf),
+    /// An environment variable
+    Environment(String),
+    /// A command line option
+    CommandLine,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::File(path) => write!(f, "{}", path.display()),
+            ConfigSource::Environment(var) => write!(f, "environment variable {var}"),
+            ConfigSource::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// Settings merged from a configuration file, the files it includes and its drop-in files
+#[derive(Debug, Default)]
+pub struct LayeredConfig {
+    /// Merged settings, without `include`
+    pub table: toml::Table,
+    /// Files that were read, in the order they were merged
+    pub files: Vec<PathBuf>,
+    /// Where each setting came from; list settings have a source for each entry
+    pub sources: BTreeMap<String, Vec<ConfigSource>>,
+    /// Canonical paths of the files merged so far, and of those being merged
+    merged: Vec<PathBuf>,
+    loading: Vec<PathBuf>,
+}
+
+/// Directory of drop-in files for the configuration file at `config_path`: the path with a
+/// `.d` extension instead of `.toml`
+pub fn drop_in_dir(config_path: &Path) -> PathBuf {
+    config_path.with_extension("d")
+}
+
+/// Read the configuration file at `config_path`, its includes and its drop-in files
+///
+/// A missing configuration file or drop-in directory contributes no settings.
+pub fn load(config_path: &Path) -> Result<LayeredConfig, ConfigFileError> {
+    let mut config = LayeredConfig::default();
+    if config_path.exists() {
+        config.merge_file(config_path)?;
+    }
+    for path in drop_in_files(&drop_in_dir(config_path))? {
+        config.merge_file(&path)?;
+    }
+    Ok(config)
+}
+
+/// `*.toml` files in `dir`, sorted by name, skipping hidden files
+fn drop_in_files(dir: &Path) -> Result<Vec<PathBuf>, ConfigFileError> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(error) => {
+            return Err(ConfigFileError::Read {
+                path: dir.to_path_buf(),
+                error,
+            })
+        }
+    };
+
+    let mut files: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| !name.starts_with('.') && name.ends_with(".toml"))
+                && path.is_file()
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+impl LayeredConfig {
+    fn merge_file(&mut self, path: &Path) -> Result<(), ConfigFileError> {
+        let read_error = |error| ConfigFileError::Read {
+            path: path.to_path_buf(),
+            error,
+        };
+        let canonical = path.canonicalize().map_err(read_error)?;
+        if self.loading.contains(&canonical) {
+            return Err(ConfigFileError::IncludeCycle {
+                path: path.to_path_buf(),
+            });
+        }
+        // A file included more than once is merged the first time only
+        if self.merged.contains(&canonical) {
+            return Ok(());
+        }
+
+        let text = std::fs::read_to_string(path).map_err(read_error)?;
+        let mut table: toml::Table =
+            toml::from_str(&text).map_err(|error| ConfigFileError::Parse {
+                path: path.to_path_buf(),
+                error: Box::new(error),
+            })?;
+
+        if let Some(include) = table.remove(INCLUDE_KEY) {
+            self.loading.push(canonical.clone());
+            let result = include_paths(path, include).and_then(|included| {
+                included
+                    .iter()
+                    .try_for_each(|included| self.merge_file(included))
+            });
+            self.loading.pop();
+            result?;
+        }
+
+        self.merged.push(canonical);
+        self.files.push(path.to_path_buf());
+        let source = ConfigSource::File(path.to_path_buf());
+        for (key, value) in table {
+            self.merge_value(key, value, &source);
+        }
+        Ok(())
+    }
+
+    /// Replace the value of the setting `key`, including all entries of a list
+    pub fn set(&mut self, key: String, value: toml::Value, source: ConfigSource) {
+        let count = value.as_array().map_or(1, Vec::len);
+        self.sources.insert(key.clone(), vec![source; count]);
+        self.table.insert(key, value);
+    }
+
+    fn merge_value(&mut self, key: String, value: toml::Value, source: &ConfigSource) {
+        let (Some(toml::Value::Array(existing)), toml::Value::Array(entries)) =
+            (self.table.get_mut(&key), &value)
+        else {
+            self.set(key, value, source.clone());
+            return;
+        };
+
+        let sources = self.sources.entry(key).or_default();
+        for entry in entries {
+            let same_name = entry_name(entry).and_then(|name| {
+                existing
+                    .iter()
+                    .position(|other| entry_name(other) == Some(name))
+            });
+            if let Some(i) = same_name {
+                existing[i] = entry.clone();
+                sources[i] = source.clone();
+            } else if !existing.contains(entry) {
+                existing.push(entry.clone());
+                sources.push(source.clone());
+            }
+        }
+    }
+}
+
+/// The `name` of a table in a list setting
+fn entry_name(entry: &toml::Value) -> Option<&str> {
+    entry.get("name").and_then(toml::Value::as_str)
+}
+
+/// Files named by the `include` setting of the file at `path`
+///
+/// Relative paths are relative to the including file's directory. Environment variables and
+/// `~` are expanded, and glob patterns may match any number of files.
+fn include_paths(path: &Path, include: toml::Value) -> Result<Vec<PathBuf>, ConfigFileError> {
+    let invalid = || ConfigFileError::InvalidInclude {
+        path: path.to_path_buf(),
+    };
+    let toml::Value::Array(entries) = include else {
+        return Err(invalid());
+    };
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut paths = vec![];
+    for entry in entries {
+        let included = PathBuf::from(entry.as_str().ok_or_else(invalid)?);
+        let included = expand::expand_env(&included)?;
+        let included =
+            dir.join(
+                included
+                    .expand_tilde_owned()
+                    .map_err(|error| ConfigFileError::Tilde {
+                        path: included.clone(),
+                        error,
+                    })?,
+            );
+
+        if expand::is_glob(&included) {
+            paths.extend(expand::expand_glob(&included));
+        } else if included.exists() {
+            paths.push(included);
+        } else {
+            return Err(ConfigFileError::MissingInclude {
+                path: included,
+                included_from: path.to_path_buf(),
+            });
+        }
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &Path) -> ConfigSource {
+        ConfigSource::File(path.to_path_buf())
+    }
+
+    #[test]
+    fn test_merge_order_and_semantics() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let config = dir.join("ssh-agent-mux.toml");
+        let base = dir.join("base.toml");
+        std::fs::create_dir(dir.join("ssh-agent-mux.d")).unwrap();
+        let laptop = dir.join("ssh-agent-mux.d/20-laptop.toml");
+        let work = dir.join("ssh-agent-mux.d/10-work.toml");
+
+        std::fs::write(
+            &base,
+            r#"
+            log_level = "info"
+            agent_sock_paths = ["/run/a.sock", "/run/b.sock"]
+            [[upstream]]
+            name = "work"
+            path = "/run/work.sock"
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            &config,
+            r#"
+            include = ["base.toml"]
+            log_level = "debug"
+            agent_sock_paths = ["/run/b.sock", "/run/c.sock"]
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            &work,
+            r#"
+            [[upstream]]
+            name = "work"
+            path = "/run/work.sock"
+            enabled = false
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            &laptop,
+            r#"
+            health_check_interval = 10
+            [[upstream]]
+            name = "yubikey"
+            path = "/run/yubikey.sock"
+            "#,
+        )
+        .unwrap();
+        // Not a drop-in file
+        std::fs::write(dir.join("ssh-agent-mux.d/notes.txt"), "not = 'toml").unwrap();
+
+        let mut layers = load(&config).unwrap();
+        assert_eq!(
+            layers.files,
+            vec![base.clone(), config.clone(), work.clone(), laptop.clone()]
+        );
+
+        let expected: toml::Table = toml::from_str(
+            r#"
+            log_level = "debug"
+            agent_sock_paths = ["/run/a.sock", "/run/b.sock", "/run/c.sock"]
+            health_check_interval = 10
+            [[upstream]]
+            name = "work"
+            path = "/run/work.sock"
+            enabled = false
+            [[upstream]]
+            name = "yubikey"
+            path = "/run/yubikey.sock"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(layers.table, expected);
+
+        assert_eq!(layers.sources["log_level"], vec![file(&config)]);
+        assert_eq!(
+            layers.sources["agent_sock_paths"],
+            vec![file(&base), file(&base), file(&config)]
+        );
+        assert_eq!(layers.sources["upstream"], vec![file(&work), file(&laptop)]);
+        assert_eq!(layers.sources["health_check_interval"], vec![file(&laptop)]);
+
+        // Overrides replace lists as a whole
+        let env = ConfigSource::Environment("SSH_AGENT_MUX_AGENT_SOCK_PATHS".to_string());
+        layers.set(
+            "agent_sock_paths".to_string(),
+            toml::Value::Array(vec!["/run/d.sock".into()]),
+            env.clone(),
+        );
+        assert_eq!(
+            layers.table["agent_sock_paths"],
+            toml::Value::Array(vec!["/run/d.sock".into()])
+        );
+        assert_eq!(layers.sources["agent_sock_paths"], vec![env]);
+    }
+
+    #[test]
+    fn test_missing_files() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = temp_dir.path().join("ssh-agent-mux.toml");
+
+        let layers = load(&config).unwrap();
+        assert!(layers.files.is_empty());
+        assert!(layers.table.is_empty());
+
+        // Drop-in files apply without a main configuration file
+        std::fs::create_dir(drop_in_dir(&config)).unwrap();
+        let drop_in = drop_in_dir(&config).join("local.toml");
+        std::fs::write(&drop_in, "watch_config = true").unwrap();
+        assert_eq!(load(&config).unwrap().files, vec![drop_in]);
+
+        std::fs::write(&config, "include = ['missing.toml']").unwrap();
+        assert!(matches!(
+            load(&config),
+            Err(ConfigFileError::MissingInclude { .. })
+        ));
+
+        // Globs may match nothing
+        std::fs::write(&config, "include = ['local-*.toml']").unwrap();
+        assert!(load(&config).is_ok());
+
+        std::fs::write(&config, "include = 'base.toml'").unwrap();
+        assert!(matches!(
+            load(&config),
+            Err(ConfigFileError::InvalidInclude { .. })
+        ));
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let a = temp_dir.path().join("a.toml");
+        let b = temp_dir.path().join("b.toml");
+        std::fs::write(&a, "include = ['b.toml']").unwrap();
+        std::fs::write(&b, "include = ['./a.toml']").unwrap();
+
+        assert!(matches!(
+            load(&a),
+            Err(ConfigFileError::IncludeCycle { .. })
+        ));
+
+        // Including the same file twice isn't a cycle
+        std::fs::write(&b, "log_level = 'info'").unwrap();
+        std::fs::write(&a, "include = ['b.toml', 'b.toml']").unwrap();
+        assert_eq!(load(&a).unwrap().files, vec![b.clone(), a.clone()]);
+    }
+}
@@ -0,0 +1,273 @@
+//! Key constraints enforced by the mux itself.
+//!
+//! When a client adds a key with `ssh-add -t` or `ssh-add -c`, the constrained add is forwarded
+//! to an upstream agent. If that upstream refuses constrained adds (many hardware-token and
+//! password-manager agents do), the key is added unconstrained and the mux tracks the lifetime and
+//! confirmation requirements here instead.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+use ssh_agent_lib::{
+    proto::{Credential, KeyConstraint},
+    ssh_key::public::KeyData as PubKeyData,
+};
+
+/// Default askpass program used for confirmation prompts when `SSH_ASKPASS` is not set
+const DEFAULT_ASKPASS: &str = "ssh-askpass";
+
+/// Constraints the mux is enforcing for a single key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstrainedKey {
+    /// Upstream agent socket the key was added to
+    pub socket: PathBuf,
+    /// When the key expires and must be removed from the upstream agent
+    pub expires_at: Option<SystemTime>,
+    /// Whether each signature needs explicit user confirmation
+    pub confirm: bool,
+}
+
+/// Registry of keys whose constraints are enforced by the mux
+#[derive(Debug, Default)]
+pub struct KeyConstraints {
+    keys: HashMap<PubKeyData, ConstrainedKey>,
+}
+
+impl KeyConstraints {
+    /// Start enforcing `constraints` for `pubkey`, which was added to `socket`
+    ///
+    /// Returns the key's lifetime, if one was requested, so the caller can schedule its expiry.
+    pub fn insert(
+        &mut self,
+        pubkey: PubKeyData,
+        socket: PathBuf,
+        constraints: &[KeyConstraint],
+    ) -> Option<Duration> {
+        let mut lifetime = None;
+        let mut confirm = false;
+        for constraint in constraints {
+            match constraint {
+                KeyConstraint::Lifetime(secs) => {
+                    lifetime = Some(Duration::from_secs((*secs).into()));
+                }
+                KeyConstraint::Confirm => confirm = true,
+                KeyConstraint::Extension(_) => {}
+            }
+        }
+
+        let expires_at = lifetime.map(|l| SystemTime::now() + l);
+        self.keys.insert(
+            pubkey,
+            ConstrainedKey {
+                socket,
+                expires_at,
+                confirm,
+            },
+        );
+        lifetime
+    }
+
+    /// Stop enforcing constraints for `pubkey`
+    pub fn remove(&mut self, pubkey: &PubKeyData) -> Option<ConstrainedKey> {
+        self.keys.remove(pubkey)
+    }
+
+    /// Get the constraints enforced for `pubkey`, if any
+    pub fn get(&self, pubkey: &PubKeyData) -> Option<&ConstrainedKey> {
+        self.keys.get(pubkey)
+    }
+
+    /// Check whether `pubkey` has outlived its lifetime constraint
+    pub fn is_expired(&self, pubkey: &PubKeyData) -> bool {
+        self.keys
+            .get(pubkey)
+            .and_then(|k| k.expires_at)
+            .is_some_and(|expires_at| expires_at <= SystemTime::now())
+    }
+
+    /// Check whether `pubkey` requires confirmation before signing
+    pub fn requires_confirmation(&self, pubkey: &PubKeyData) -> bool {
+        self.keys.get(pubkey).is_some_and(|k| k.confirm)
+    }
+
+    /// Remove and return all keys whose lifetime has elapsed
+    pub fn take_expired(&mut self) -> Vec<(PubKeyData, ConstrainedKey)> {
+        let now = SystemTime::now();
+        let expired: Vec<_> = self
+            .keys
+            .iter()
+            .filter(|(_, k)| k.expires_at.is_some_and(|e| e <= now))
+            .map(|(pubkey, _)| pubkey.clone())
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|pubkey| self.keys.remove_entry(&pubkey))
+            .collect()
+    }
+
+    /// Keep enforcing constraints for a key taken by [`Self::take_expired`]
+    ///
+    /// Used when an expired key couldn't be removed from its upstream agent, so that the mux keeps
+    /// hiding it and refusing to sign with it.
+    pub fn reinstate(&mut self, pubkey: PubKeyData, key: ConstrainedKey) {
+        self.keys.insert(pubkey, key);
+    }
+
+    /// Number of keys with mux-enforced constraints
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Whether no keys have mux-enforced constraints
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// Check whether the mux knows how to enforce every constraint in the list
+///
+/// Only lifetime and confirmation constraints are supported; extension constraints (e.g.
+/// destination restrictions) must be enforced by the upstream agent or the add is refused.
+pub fn can_enforce(constraints: &[KeyConstraint]) -> bool {
+    constraints
+        .iter()
+        .all(|c| matches!(c, KeyConstraint::Lifetime(_) | KeyConstraint::Confirm))
+}
+
+/// Get the public key for a credential being added to an agent
+pub fn credential_pubkey(credential: &Credential) -> Option<PubKeyData> {
+    match credential {
+        Credential::Key { privkey, .. } => PubKeyData::try_from(privkey).ok(),
+        Credential::Cert { certificate, .. } => Some(certificate.public_key().clone()),
+    }
+}
+
+/// Ask the user to confirm use of a key, the same way OpenSSH's `ssh-agent` does
+///
+/// Runs `$SSH_ASKPASS` (or `ssh-askpass`) with `SSH_ASKPASS_PROMPT=confirm`; the key may be used
+/// only if the program exits successfully. Any failure to run the program is treated as a refusal.
+pub async fn ask_confirmation(prompt: String) -> bool {
+    let askpass = std::env::var_os("SSH_ASKPASS").unwrap_or_else(|| DEFAULT_ASKPASS.into());
+
+    let result = tokio::task::spawn_blocking(move || {
+        Command::new(&askpass)
+            .arg(&prompt)
+            .env("SSH_ASKPASS_PROMPT", "confirm")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status()
+    })
+    .await;
+
+    match result {
+        Ok(Ok(status)) => status.success(),
+        Ok(Err(e)) => {
+            log::error!("Failed to run askpass program for key confirmation: {e}");
+            false
+        }
+        Err(e) => {
+            log::error!("Key confirmation task failed: {e}");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ssh_agent_lib::ssh_key::public::Ed25519PublicKey;
+
+    fn test_key(byte: u8) -> PubKeyData {
+        PubKeyData::Ed25519(Ed25519PublicKey([byte; 32]))
+    }
+
+    #[test]
+    fn test_can_enforce() {
+        assert!(can_enforce(&[]));
+        assert!(can_enforce(&[KeyConstraint::Lifetime(10)]));
+        assert!(can_enforce(&[
+            KeyConstraint::Lifetime(10),
+            KeyConstraint::Confirm
+        ]));
+        assert!(!can_enforce(&[KeyConstraint::Extension(
+            ssh_agent_lib::proto::Extension {
+                name: "restrict-destination-v00@openssh.com".to_string(),
+                details: Vec::new().into(),
+            }
+        )]));
+    }
+
+    #[test]
+    fn test_insert_lifetime_and_confirm() {
+        let mut constraints = KeyConstraints::default();
+        let key = test_key(1);
+
+        let lifetime = constraints.insert(
+            key.clone(),
+            PathBuf::from("/tmp/agent.sock"),
+            &[KeyConstraint::Lifetime(60), KeyConstraint::Confirm],
+        );
+        assert_eq!(lifetime, Some(Duration::from_secs(60)));
+        assert!(constraints.requires_confirmation(&key));
+        assert!(!constraints.is_expired(&key));
+        assert_eq!(constraints.len(), 1);
+
+        let entry = constraints.get(&key).unwrap();
+        assert_eq!(entry.socket, PathBuf::from("/tmp/agent.sock"));
+        assert!(entry.expires_at.is_some());
+    }
+
+    #[test]
+    fn test_take_expired() {
+        let mut constraints = KeyConstraints::default();
+        let expiring = test_key(1);
+        let confirm_only = test_key(2);
+
+        constraints.insert(
+            expiring.clone(),
+            PathBuf::from("/tmp/a.sock"),
+            &[KeyConstraint::Lifetime(0)],
+        );
+        constraints.insert(
+            confirm_only.clone(),
+            PathBuf::from("/tmp/b.sock"),
+            &[KeyConstraint::Confirm],
+        );
+
+        assert!(constraints.is_expired(&expiring));
+        assert!(!constraints.is_expired(&confirm_only));
+
+        let expired = constraints.take_expired();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, expiring);
+        assert_eq!(expired[0].1.socket, PathBuf::from("/tmp/a.sock"));
+
+        // Only the unexpired key remains
+        assert_eq!(constraints.len(), 1);
+        assert!(constraints.get(&expiring).is_none());
+        assert!(constraints.take_expired().is_empty());
+
+        // A key put back after a failed removal stays expired
+        let (pubkey, key) = expired.into_iter().next().unwrap();
+        constraints.reinstate(pubkey, key);
+        assert!(constraints.is_expired(&expiring));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut constraints = KeyConstraints::default();
+        let key = test_key(3);
+
+        constraints.insert(
+            key.clone(),
+            PathBuf::from("/tmp/a.sock"),
+            &[KeyConstraint::Confirm],
+        );
+        assert!(constraints.remove(&key).is_some());
+        assert!(constraints.is_empty());
+        assert!(!constraints.requires_confirmation(&key));
+    }
+}
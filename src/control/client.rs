@@ -7,6 +7,9 @@ use std::time::Duration;
 
 use crate::control::protocol::*;
 
+/// How long to wait for the daemon to start its successor during an upgrade
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// Error type for control client operations
 #[derive(Debug)]
 pub enum ControlClientError {
@@ -212,6 +215,106 @@ impl ControlClient {
         }
     }
 
+    /// Pin a socket to `position` in the order (1 = first)
+    pub fn pin_socket(
+        &mut self,
+        path: &str,
+        position: usize,
+    ) -> Result<String, ControlClientError> {
+        self.send_for_message(
+            ControlRequest::PinSocket {
+                path: path.to_string(),
+                position,
+            },
+            "pin_socket",
+        )
+    }
+
+    /// Unpin a socket
+    pub fn unpin_socket(&mut self, path: &str) -> Result<String, ControlClientError> {
+        self.send_for_message(
+            ControlRequest::UnpinSocket {
+                path: path.to_string(),
+            },
+            "unpin_socket",
+        )
+    }
+
+    /// Move a socket `offset` places down the order (up if negative)
+    pub fn move_socket(&mut self, path: &str, offset: isize) -> Result<String, ControlClientError> {
+        self.send_for_message(
+            ControlRequest::MoveSocket {
+                path: path.to_string(),
+                offset,
+            },
+            "move_socket",
+        )
+    }
+
+    /// Override the priority of a socket, for `duration_secs` or until cleared
+    pub fn set_priority(
+        &mut self,
+        path: &str,
+        priority: i32,
+        duration_secs: Option<u64>,
+    ) -> Result<String, ControlClientError> {
+        self.send_for_message(
+            ControlRequest::SetPriority {
+                path: path.to_string(),
+                priority,
+                duration_secs,
+            },
+            "set_priority",
+        )
+    }
+
+    /// Drop the priority override of a socket
+    pub fn clear_priority(&mut self, path: &str) -> Result<String, ControlClientError> {
+        self.send_for_message(
+            ControlRequest::ClearPriority {
+                path: path.to_string(),
+            },
+            "clear_priority",
+        )
+    }
+
+    /// Stop using a socket without removing it
+    pub fn disable_socket(&mut self, path: &str) -> Result<String, ControlClientError> {
+        self.send_for_message(
+            ControlRequest::DisableSocket {
+                path: path.to_string(),
+            },
+            "disable_socket",
+        )
+    }
+
+    /// Use a disabled socket again
+    pub fn enable_socket(&mut self, path: &str) -> Result<String, ControlClientError> {
+        self.send_for_message(
+            ControlRequest::EnableSocket {
+                path: path.to_string(),
+            },
+            "enable_socket",
+        )
+    }
+
+    /// Send a request answered with a success message
+    fn send_for_message(
+        &mut self,
+        request: ControlRequest,
+        name: &str,
+    ) -> Result<String, ControlClientError> {
+        match self.send(request)? {
+            ControlResponse::Success { message } => {
+                Ok(message.unwrap_or_else(|| "Done".to_string()))
+            }
+            ControlResponse::Error { error } => Err(ControlClientError::DaemonError(error)),
+            _ => Err(ControlClientError::DaemonError(format!(
+                "Unexpected response to {name}"
+            ))),
+        }
+    }
+
     /// Perform a full health check
     pub fn health_check(&mut self) -> Result<HealthCheckResult, ControlClientError> {
         match self.send(ControlRequest::HealthCheck)? {
@@ -222,6 +325,37 @@ impl ControlClient {
             )),
         }
     }
+
+    /// Get usage metrics of upstream agents and keys
+    pub fn metrics(&mut self) -> Result<MetricsReport, ControlClientError> {
+        match self.send(ControlRequest::Metrics)? {
+            ControlResponse::Metrics(report) => Ok(report),
+            ControlResponse::Error { error } => Err(ControlClientError::DaemonError(error)),
+            _ => Err(ControlClientError::DaemonError(
+                "Unexpected response to metrics".to_string(),
+            )),
+        }
+    }
+
+    /// Upgrade the daemon to a new process running `binary` (or the daemon's own binary)
+    pub fn upgrade(&mut self, binary: Option<&str>) -> Result<String, ControlClientError> {
+        // The daemon only answers once its successor is up and running
+        self.stream
+            .set_read_timeout(Some(UPGRADE_TIMEOUT))
+            .map_err(ControlClientError::ConnectionFailed)?;
+
+        match self.send(ControlRequest::Upgrade {
+            binary: binary.map(str::to_string),
+        })? {
+            ControlResponse::Success { message } => {
+                Ok(message.unwrap_or_else(|| "Upgrade complete".to_string()))
+            }
+            ControlResponse::Error { error } => Err(ControlClientError::DaemonError(error)),
+            _ => Err(ControlClientError::DaemonError(
+                "Unexpected response to upgrade".to_string(),
+            )),
+        }
+    }
 }
 
 /// Derive the default control socket path from the listen socket path
@@ -13,4 +13,4 @@ pub mod server;
 
 pub use client::{default_control_path, ControlClient, ControlClientError};
 pub use protocol::*;
-pub use server::{ControlServer, ControlServerState, SelfDeletingControlSocket};
+pub use server::{ControlServer, ControlServerState, DaemonSettings, UpgradeRequest};
@@ -8,6 +8,8 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::session::SshSession;
+
 /// Request types sent from CLI client to daemon
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(tag = "type", content = "data")]
@@ -27,15 +29,49 @@ pub enum ControlRequest {
     /// Validate all sockets, remove unreachable ones
     ValidateSockets,
 
-    /// Remove a specific socket from the watched list
+    /// Remove a specific socket from the watched list, by path or configured name
     RemoveSocket { path: String },
 
-    /// Add a socket to the watched list
+    /// Add a socket to the watched list, by path or configured name
     AddSocket { path: String },
 
+    /// Pin a socket, by path or configured name, to a position in the order (1 = first)
+    PinSocket { path: String, position: usize },
+
+    /// Undo pinning a socket, by path or configured name
+    UnpinSocket { path: String },
+
+    /// Move a socket, by path or configured name, `offset` places down the order (up if
+    /// negative) and pin it there
+    MoveSocket { path: String, offset: isize },
+
+    /// Override the priority of a socket, by path or configured name, for `duration_secs` or
+    /// until cleared
+    SetPriority {
+        path: String,
+        priority: i32,
+        duration_secs: Option<u64>,
+    },
+
+    /// Drop the priority override of a socket, by path or configured name
+    ClearPriority { path: String },
+
+    /// Stop using a socket, by path or configured name, without removing it
+    DisableSocket { path: String },
+
+    /// Use a disabled socket again, by path or configured name
+    EnableSocket { path: String },
+
     /// Full health check: validate + query keys from each socket
     HealthCheck,
 
+    /// How much each upstream agent and key was used
+    Metrics,
+
+    /// Hand the listening sockets and runtime state over to a freshly started daemon process
+    /// (optionally a different binary), then exit once in-flight sessions are done
+    Upgrade { binary: Option<String> },
+
     /// Ping (for connection testing / liveness check)
     Ping,
 }
@@ -56,6 +92,9 @@ pub enum ControlResponse {
     /// Health check results
     HealthCheck(HealthCheckResult),
 
+    /// Usage of upstream agents and keys
+    Metrics(MetricsReport),
+
     /// Generic success with optional message
     Success { message: Option<String> },
 
@@ -108,6 +147,9 @@ pub enum WatcherStatus {
 pub struct SocketInfo {
     /// Path to the socket
     pub path: String,
+    /// Name given to the socket in the configuration
+    #[serde(default)]
+    pub name: Option<String>,
     /// How this socket was added (configured vs watched)
     pub source: SocketSource,
     /// When this socket was added (ISO 8601 timestamp), None for configured sockets
@@ -118,7 +160,42 @@ pub struct SocketInfo {
     pub last_health_check: Option<String>,
     /// Number of keys from this socket (if known)
     pub key_count: Option<usize>,
-    /// Priority order (1 = highest priority)
+    /// How long the socket's agent took to list its keys at the last health check
+    #[serde(default)]
+    pub latency_ms: Option<u64>,
+    /// Why the last health check failed
+    #[serde(default)]
+    pub health_error: Option<String>,
+    /// Whether the socket is skipped after failing
+    #[serde(default)]
+    pub breaker: BreakerInfo,
+    /// Labels given to the socket in the configuration
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// SSH session a forwarded agent socket belongs to, if it could be found
+    #[serde(default)]
+    pub session: Option<SshSession>,
+    /// Whether the forwarded agent socket vanished; it keeps its place for a while in case its
+    /// session reconnects
+    #[serde(default)]
+    pub gone: bool,
+    /// Whether the socket was disabled at runtime; it keeps its place, but its keys aren't
+    /// offered
+    #[serde(default)]
+    pub disabled: bool,
+    /// When the socket's agent last signed successfully (ISO 8601 timestamp)
+    #[serde(default)]
+    pub last_signed: Option<String>,
+    /// Position the socket was pinned to at runtime
+    #[serde(default)]
+    pub pinned: Option<usize>,
+    /// Priority set for the socket at runtime
+    #[serde(default)]
+    pub priority_override: Option<i32>,
+    /// When the priority override lapses (ISO 8601 timestamp), None if it doesn't
+    #[serde(default)]
+    pub priority_override_until: Option<String>,
+    /// Priority order (1 = highest priority), including runtime pins and priority overrides
     pub order: usize,
 }
 
@@ -165,12 +242,93 @@ pub struct HealthCheckResult {
 pub struct SocketHealthInfo {
     /// Socket path
     pub path: String,
+    /// Name given to the socket in the configuration
+    #[serde(default)]
+    pub name: Option<String>,
     /// Health status
     pub status: SocketHealthStatus,
     /// Number of keys (if healthy)
     pub key_count: Option<usize>,
+    /// How long the agent took to list its keys (if healthy)
+    #[serde(default)]
+    pub latency_ms: Option<u64>,
     /// Error message (if unhealthy)
     pub error: Option<String>,
+    /// Whether the socket is skipped after failing, as of after the check
+    #[serde(default)]
+    pub breaker: BreakerInfo,
+}
+
+/// Circuit breaker of an upstream agent, which skips the agent for a while after it fails
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BreakerInfo {
+    pub state: BreakerState,
+    /// Number of times in a row the agent failed
+    pub failures: u32,
+    /// When the agent is tried again (ISO 8601 timestamp), while the breaker is open
+    pub next_retry: Option<String>,
+}
+
+/// State of the circuit breaker of an upstream agent
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BreakerState {
+    /// The agent is used
+    #[default]
+    Closed,
+    /// The agent failed and is skipped until its next retry
+    Open,
+    /// The agent failed but is due for a retry, which decides whether it's used again
+    HalfOpen,
+}
+
+/// How much upstream agents and keys were used since the daemon started
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MetricsReport {
+    /// When counting started (ISO 8601 timestamp)
+    pub since: String,
+    /// Upstream agents, most used first
+    pub upstreams: Vec<UpstreamMetrics>,
+    /// Keys, most used first
+    pub keys: Vec<KeyMetrics>,
+}
+
+/// Usage of an upstream agent
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UpstreamMetrics {
+    /// Socket path
+    pub path: String,
+    /// Name given to the socket in the configuration
+    #[serde(default)]
+    pub name: Option<String>,
+    pub usage: UsageStats,
+}
+
+/// Usage of a key
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeyMetrics {
+    /// Key fingerprint (SHA256:...)
+    pub fingerprint: String,
+    /// Key comment, if the key was listed
+    pub comment: Option<String>,
+    /// Path to the socket that last provided the key
+    pub upstream: Option<String>,
+    pub usage: UsageStats,
+}
+
+/// Request counts and latency of an upstream agent or key
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UsageStats {
+    /// Times the agent was asked for its keys, or the key was listed
+    pub identity_requests: u64,
+    pub sign_attempts: u64,
+    pub sign_successes: u64,
+    pub sign_failures: u64,
+    /// Average time answered requests took: key listings and signatures for agents, signatures
+    /// for keys
+    pub avg_latency_ms: Option<u64>,
+    /// Longest time an answered request took
+    pub max_latency_ms: Option<u64>,
 }
 
 /// Health status of a socket
@@ -201,6 +359,16 @@ impl std::fmt::Display for SocketHealthStatus {
     }
 }
 
+impl std::fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BreakerState::Closed => write!(f, "closed"),
+            BreakerState::Open => write!(f, "open"),
+            BreakerState::HalfOpen => write!(f, "half-open"),
+        }
+    }
+}
+
 impl std::fmt::Display for SocketSource {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -272,6 +440,49 @@ mod tests {
         assert_eq!(parsed, req);
     }
 
+    #[test]
+    fn test_request_serialization_placement() {
+        let req = ControlRequest::SetPriority {
+            path: "yubikey".to_string(),
+            priority: 100,
+            duration_secs: Some(3600),
+        };
+        let json = serde_json::to_string(&req).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"SetPriority","data":{"path":"yubikey","priority":100,"duration_secs":3600}}"#
+        );
+
+        let requests = [
+            req,
+            ControlRequest::ClearPriority {
+                path: "yubikey".to_string(),
+            },
+            ControlRequest::PinSocket {
+                path: "/tmp/test.sock".to_string(),
+                position: 2,
+            },
+            ControlRequest::UnpinSocket {
+                path: "/tmp/test.sock".to_string(),
+            },
+            ControlRequest::MoveSocket {
+                path: "/tmp/test.sock".to_string(),
+                offset: -1,
+            },
+            ControlRequest::DisableSocket {
+                path: "yubikey".to_string(),
+            },
+            ControlRequest::EnableSocket {
+                path: "yubikey".to_string(),
+            },
+        ];
+        for req in requests {
+            let json = serde_json::to_string(&req).unwrap();
+            let parsed: ControlRequest = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed, req);
+        }
+    }
+
     #[test]
     fn test_response_serialization_pong() {
         let resp = ControlResponse::Pong;
@@ -362,11 +573,23 @@ mod tests {
     fn test_socket_info_serialization() {
         let socket = SocketInfo {
             path: "/tmp/auth-agent123/listener.sock".to_string(),
+            name: None,
             source: SocketSource::Watched,
             added_at: Some("2024-12-05T13:28:10Z".to_string()),
             healthy: true,
             last_health_check: Some("2024-12-05T14:00:00Z".to_string()),
             key_count: Some(2),
+            latency_ms: None,
+            health_error: None,
+            breaker: BreakerInfo::default(),
+            labels: vec![],
+            session: None,
+            gone: false,
+            disabled: false,
+            last_signed: None,
+            pinned: Some(1),
+            priority_override: None,
+            priority_override_until: None,
             order: 1,
         };
 
@@ -407,15 +630,25 @@ mod tests {
             sockets: vec![
                 SocketHealthInfo {
                     path: "/tmp/agent1.sock".to_string(),
+                    name: Some("agent1".to_string()),
                     status: SocketHealthStatus::Healthy,
                     key_count: Some(2),
+                    latency_ms: Some(3),
                     error: None,
+                    breaker: BreakerInfo::default(),
                 },
                 SocketHealthInfo {
                     path: "/tmp/agent2.sock".to_string(),
+                    name: None,
                     status: SocketHealthStatus::ConnectionFailed,
                     key_count: None,
+                    latency_ms: None,
                     error: Some("Connection refused".to_string()),
+                    breaker: BreakerInfo {
+                        state: BreakerState::Open,
+                        failures: 3,
+                        next_retry: Some("2024-12-05T14:00:08Z".to_string()),
+                    },
                 },
             ],
             healthy_count: 1,
@@ -429,6 +662,44 @@ mod tests {
         assert_eq!(parsed, resp);
     }
 
+    #[test]
+    fn test_breaker_state_kebab_case() {
+        let json = serde_json::to_string(&BreakerState::HalfOpen).unwrap();
+        assert_eq!(json, r#""half-open""#);
+        assert_eq!(BreakerState::HalfOpen.to_string(), "half-open");
+    }
+
+    #[test]
+    fn test_metrics_response() {
+        let resp = ControlResponse::Metrics(MetricsReport {
+            since: "2024-12-05T13:28:10Z".to_string(),
+            upstreams: vec![UpstreamMetrics {
+                path: "/tmp/agent1.sock".to_string(),
+                name: Some("agent1".to_string()),
+                usage: UsageStats {
+                    identity_requests: 4,
+                    sign_attempts: 2,
+                    sign_successes: 1,
+                    sign_failures: 1,
+                    avg_latency_ms: Some(3),
+                    max_latency_ms: Some(5),
+                },
+            }],
+            keys: vec![KeyMetrics {
+                fingerprint: "SHA256:abc123def456".to_string(),
+                comment: Some("user@laptop".to_string()),
+                upstream: Some("/tmp/agent1.sock".to_string()),
+                usage: UsageStats::default(),
+            }],
+        });
+
+        let json = serde_json::to_string(&ControlRequest::Metrics).unwrap();
+        assert_eq!(json, r#"{"type":"Metrics"}"#);
+        let json = serde_json::to_string(&resp).unwrap();
+        let parsed: ControlResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, resp);
+    }
+
     #[test]
     fn test_socket_health_status_snake_case() {
         let healthy = SocketHealthStatus::Healthy;
@@ -450,20 +721,44 @@ mod tests {
             sockets: vec![
                 SocketInfo {
                     path: "/tmp/sock1".to_string(),
+                    name: None,
                     source: SocketSource::Watched,
                     added_at: Some("2024-12-05T10:00:00Z".to_string()),
                     healthy: true,
                     last_health_check: None,
                     key_count: Some(1),
+                    latency_ms: None,
+                    health_error: None,
+                    breaker: BreakerInfo::default(),
+                    labels: vec![],
+                    session: None,
+                    gone: false,
+                    disabled: false,
+                    last_signed: None,
+                    pinned: None,
+                    priority_override: None,
+                    priority_override_until: None,
                     order: 1,
                 },
                 SocketInfo {
                     path: "/home/user/.agent.sock".to_string(),
+                    name: Some("laptop".to_string()),
                     source: SocketSource::Configured,
                     added_at: None,
                     healthy: true,
                     last_health_check: None,
                     key_count: Some(2),
+                    latency_ms: None,
+                    health_error: None,
+                    breaker: BreakerInfo::default(),
+                    labels: vec!["work".to_string()],
+                    session: None,
+                    gone: false,
+                    disabled: false,
+                    last_signed: None,
+                    pinned: None,
+                    priority_override: Some(100),
+                    priority_override_until: Some("2024-12-05T11:00:00Z".to_string()),
                     order: 2,
                 },
             ],
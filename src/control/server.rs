@@ -1,20 +1,43 @@
 //! Control server that listens on a Unix socket for management commands.
 
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{UnixListener, UnixStream};
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, Mutex};
 
 use crate::control::protocol::*;
-use crate::socket_manager::SocketManager;
+use crate::health;
+use crate::instance::SocketProtocol;
+use crate::metrics::Metrics;
+use crate::session;
+use crate::socket_manager::{PriorityOverrideError, SocketManager};
 use crate::watcher;
+use crate::ListenerReplacements;
 
 /// Shared state for the control server
 pub struct ControlServerState {
     /// Socket manager (shared with MuxAgent)
     pub socket_manager: Arc<Mutex<SocketManager>>,
+    /// Usage metrics (shared with MuxAgent)
+    pub metrics: Arc<Mutex<Metrics>>,
+    /// Settings that change when the daemon's configuration is reloaded
+    pub settings: RwLock<DaemonSettings>,
+    /// Software version
+    pub version: String,
+    /// Git commit
+    pub git_commit: String,
+    /// Process ID
+    pub pid: u32,
+    /// Where to send upgrade requests; upgrades are refused if not set
+    pub upgrade_requests: Option<mpsc::UnboundedSender<UpgradeRequest>>,
+}
+
+/// Daemon settings reported by the control server
+#[derive(Debug, Clone)]
+pub struct DaemonSettings {
     /// Path to the SSH agent listen socket
     pub listen_path: PathBuf,
     /// Path to the control socket
@@ -23,18 +46,23 @@ pub struct ControlServerState {
     pub watch_enabled: bool,
     /// Current watcher status
     pub watcher_status: WatcherStatus,
-    /// Software version
-    pub version: String,
-    /// Git commit
-    pub git_commit: String,
-    /// Process ID
-    pub pid: u32,
+    /// Where forwarded agents are looked for
+    pub discovery: watcher::Discovery,
+}
+
+/// Request to hand the daemon over to a new process, answered with the new process's PID
+#[derive(Debug)]
+pub struct UpgradeRequest {
+    /// Binary to run instead of the daemon's own
+    pub binary: Option<PathBuf>,
+    pub reply: oneshot::Sender<Result<u32, String>>,
 }
 
 /// Control server that accepts commands over a Unix socket
 pub struct ControlServer {
     listener: UnixListener,
     state: Arc<ControlServerState>,
+    replacements: Option<ListenerReplacements>,
 }
 
 impl ControlServer {
@@ -43,29 +71,69 @@ impl ControlServer {
         control_path: impl AsRef<Path>,
         state: Arc<ControlServerState>,
     ) -> std::io::Result<Self> {
-        let control_path = control_path.as_ref();
+        Self::bind_with_group(control_path, state, None).await
+    }
 
-        // Remove existing socket if present
-        if control_path.exists() {
-            std::fs::remove_file(control_path)?;
-        }
+    /// Bind a new control server to the given path, optionally letting members of `group` connect
+    ///
+    /// The socket is created with mode 0600, or 0660 owned by `group`.
+    pub async fn bind_with_group(
+        control_path: impl AsRef<Path>,
+        state: Arc<ControlServerState>,
+        group: Option<u32>,
+    ) -> std::io::Result<Self> {
+        let control_path = control_path.as_ref();
 
-        // Ensure parent directory exists
+        // Ensure parent directory exists and is private
         if let Some(parent) = control_path.parent() {
-            std::fs::create_dir_all(parent)?;
+            crate::instance::check_socket_dir(parent, group)?;
         }
 
-        let listener = UnixListener::bind(control_path)?;
+        // Remove a stale socket left by a crashed daemon, but never a live or foreign one
+        let listener = crate::instance::bind_socket(control_path, group, SocketProtocol::Control)?;
+        listener.set_nonblocking(true)?;
+        let listener = UnixListener::from_std(listener)?;
         log::info!("Control server listening on {}", control_path.display());
 
-        Ok(Self { listener, state })
+        Ok(Self {
+            listener,
+            state,
+            replacements: None,
+        })
+    }
+
+    /// Create a control server on an already-listening socket, e.g. one passed in by a service
+    /// manager for socket activation
+    pub fn from_std(
+        listener: std::os::unix::net::UnixListener,
+        state: Arc<ControlServerState>,
+    ) -> std::io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        let listener = UnixListener::from_std(listener)?;
+        log::debug!(
+            "Control server using listening socket {}",
+            state.settings.read().unwrap().control_path.display()
+        );
+
+        Ok(Self {
+            listener,
+            state,
+            replacements: None,
+        })
+    }
+
+    /// Switch over to listeners received on `replacements` while running, without disturbing
+    /// connected clients
+    pub fn with_replacements(mut self, replacements: ListenerReplacements) -> Self {
+        self.replacements = Some(replacements);
+        self
     }
 
     /// Run the control server, accepting and handling connections
-    pub async fn run(&self) -> std::io::Result<()> {
+    pub async fn run(&mut self) -> std::io::Result<()> {
         loop {
-            match self.listener.accept().await {
-                Ok((stream, _)) => {
+            match crate::accept_replaceable(&mut self.listener, &mut self.replacements).await {
+                Ok(stream) => {
                     let state = self.state.clone();
                     tokio::spawn(async move {
                         if let Err(e) = handle_connection(stream, state).await {
@@ -133,16 +201,17 @@ async fn handle_request(request: ControlRequest, state: &ControlServerState) ->
         ControlRequest::Ping => ControlResponse::Pong,
 
         ControlRequest::Status => {
+            let settings = state.settings.read().unwrap().clone();
             let manager = state.socket_manager.lock().await;
             ControlResponse::Status(StatusInfo {
                 version: state.version.clone(),
                 git_commit: state.git_commit.clone(),
                 uptime_secs: manager.uptime_secs(),
                 pid: state.pid,
-                listening_on: state.listen_path.display().to_string(),
-                control_socket: state.control_path.display().to_string(),
-                watch_enabled: state.watch_enabled,
-                watcher_status: state.watcher_status.clone(),
+                listening_on: settings.listen_path.display().to_string(),
+                control_socket: settings.control_path.display().to_string(),
+                watch_enabled: settings.watch_enabled,
+                watcher_status: settings.watcher_status,
                 socket_count: manager.total_count(),
                 key_count: None, // Would need to query upstream agents
             })
@@ -164,19 +233,24 @@ async fn handle_request(request: ControlRequest, state: &ControlServerState) ->
         }
 
         ControlRequest::Reload => {
-            if !state.watch_enabled {
-                return ControlResponse::Error {
-                    error: "SSH forwarding watch is not enabled".to_string(),
-                };
-            }
+            let discovery = {
+                let settings = state.settings.read().unwrap();
+                if !settings.watch_enabled {
+                    return ControlResponse::Error {
+                        error: "SSH forwarding watch is not enabled".to_string(),
+                    };
+                }
+                settings.discovery.clone()
+            };
 
             // Scan for existing agents
-            match watcher::scan_existing_agents().await {
+            match watcher::scan_existing_agents(&discovery).await {
                 Ok(agents) => {
+                    let agents = session::find_sessions(agents).await;
                     let mut manager = state.socket_manager.lock().await;
                     let mut added = 0;
-                    for agent in agents {
-                        if manager.add_watched(agent) {
+                    for (agent, session) in agents {
+                        if manager.add_watched(agent, session) {
                             added += 1;
                         }
                     }
@@ -222,7 +296,7 @@ async fn handle_request(request: ControlRequest, state: &ControlServerState) ->
         }
 
         ControlRequest::AddSocket { path } => {
-            let path = PathBuf::from(&path);
+            let path = state.socket_manager.lock().await.resolve(&path);
 
             // Validate the socket exists
             if !path.exists() {
@@ -231,6 +305,15 @@ async fn handle_request(request: ControlRequest, state: &ControlServerState) ->
                 };
             }
 
+            let verified = state.settings.read().unwrap().discovery.verify(&path);
+            if let Err(e) = verified {
+                log::warn!("Refusing to add socket {}: {e}", path.display());
+                return ControlResponse::Error {
+                    error: format!("Refusing to add socket: {e}"),
+                };
+            }
+
+            let session = session::find_session_async(path.clone()).await;
             let mut manager = state.socket_manager.lock().await;
 
             // Check if already tracked
@@ -240,7 +323,7 @@ async fn handle_request(request: ControlRequest, state: &ControlServerState) ->
                 };
             }
 
-            if manager.add_watched(path.clone()) {
+            if manager.add_watched(path.clone(), session) {
                 ControlResponse::Success {
                     message: Some(format!("Added socket: {}", path.display())),
                 }
@@ -252,8 +335,8 @@ async fn handle_request(request: ControlRequest, state: &ControlServerState) ->
         }
 
         ControlRequest::RemoveSocket { path } => {
-            let path = PathBuf::from(&path);
             let mut manager = state.socket_manager.lock().await;
+            let path = manager.resolve(&path);
 
             // Can only remove watched sockets, not configured ones
             if manager.is_configured(&path) {
@@ -276,19 +359,127 @@ async fn handle_request(request: ControlRequest, state: &ControlServerState) ->
             }
         }
 
+        ControlRequest::PinSocket { path, position } => {
+            let mut manager = state.socket_manager.lock().await;
+            let path = manager.resolve(&path);
+            match manager.pin(&path, position) {
+                Some(position) => ControlResponse::Success {
+                    message: Some(format!(
+                        "Pinned socket at position {position}: {}",
+                        path.display()
+                    )),
+                },
+                None => not_in_order(&path),
+            }
+        }
+
+        ControlRequest::UnpinSocket { path } => {
+            let mut manager = state.socket_manager.lock().await;
+            let path = manager.resolve(&path);
+            if manager.unpin(&path) {
+                ControlResponse::Success {
+                    message: Some(format!("Unpinned socket: {}", path.display())),
+                }
+            } else {
+                ControlResponse::Error {
+                    error: format!("Socket is not pinned: {}", path.display()),
+                }
+            }
+        }
+
+        ControlRequest::MoveSocket { path, offset } => {
+            let mut manager = state.socket_manager.lock().await;
+            let path = manager.resolve(&path);
+            match manager.move_by(&path, offset) {
+                Some(position) => ControlResponse::Success {
+                    message: Some(format!(
+                        "Moved socket to position {position}: {}",
+                        path.display()
+                    )),
+                },
+                None => not_in_order(&path),
+            }
+        }
+
+        ControlRequest::SetPriority {
+            path,
+            priority,
+            duration_secs,
+        } => {
+            let mut manager = state.socket_manager.lock().await;
+            let path = manager.resolve(&path);
+            let duration = duration_secs.map(Duration::from_secs);
+            match manager.set_priority_override(&path, priority, duration) {
+                Ok(position) => {
+                    let until = match duration_secs {
+                        Some(secs) => format!(" for {secs}s"),
+                        None => String::new(),
+                    };
+                    ControlResponse::Success {
+                        message: Some(format!(
+                            "Set priority {priority}{until}, now at position {position}: {}",
+                            path.display()
+                        )),
+                    }
+                }
+                Err(PriorityOverrideError::NotInOrder) => not_in_order(&path),
+                Err(e) => ControlResponse::Error {
+                    error: format!("Failed to set priority of {}: {e}", path.display()),
+                },
+            }
+        }
+
+        ControlRequest::ClearPriority { path } => {
+            let mut manager = state.socket_manager.lock().await;
+            let path = manager.resolve(&path);
+            if manager.clear_priority_override(&path) {
+                ControlResponse::Success {
+                    message: Some(format!("Cleared priority override: {}", path.display())),
+                }
+            } else {
+                ControlResponse::Error {
+                    error: format!("Socket has no priority override: {}", path.display()),
+                }
+            }
+        }
+
+        ControlRequest::DisableSocket { path } => {
+            let mut manager = state.socket_manager.lock().await;
+            let path = manager.resolve(&path);
+            match manager.disable(&path) {
+                Some(true) => ControlResponse::Success {
+                    message: Some(format!("Disabled socket: {}", path.display())),
+                },
+                Some(false) => ControlResponse::Error {
+                    error: format!("Socket is already disabled: {}", path.display()),
+                },
+                None => not_in_order(&path),
+            }
+        }
+
+        ControlRequest::EnableSocket { path } => {
+            let mut manager = state.socket_manager.lock().await;
+            let path = manager.resolve(&path);
+            if manager.enable(&path) {
+                ControlResponse::Success {
+                    message: Some(format!("Enabled socket: {}", path.display())),
+                }
+            } else {
+                ControlResponse::Error {
+                    error: format!("Socket is not disabled: {}", path.display()),
+                }
+            }
+        }
+
         ControlRequest::HealthCheck => {
-            let manager = state.socket_manager.lock().await;
-            let sockets = manager.get_ordered_sockets();
-            drop(manager); // Release lock during health checks
+            let probes = health::check_sockets(&state.socket_manager).await;
 
+            let mut manager = state.socket_manager.lock().await;
             let mut results = Vec::new();
             let mut healthy_count = 0;
             let mut unhealthy_count = 0;
-
-            for socket_path in &sockets {
-                let (status, key_count, error) = check_socket_health(socket_path).await;
-
-                if status == SocketHealthStatus::Healthy {
+            for (socket_path, probe) in probes {
+                if probe.healthy() {
                     healthy_count += 1;
                 } else {
                     unhealthy_count += 1;
@@ -296,14 +487,16 @@ async fn handle_request(request: ControlRequest, state: &ControlServerState) ->
 
                 results.push(SocketHealthInfo {
                     path: socket_path.display().to_string(),
-                    status,
-                    key_count,
-                    error,
+                    name: manager.upstream_name(&socket_path),
+                    status: probe.status,
+                    key_count: probe.key_count,
+                    latency_ms: probe.latency.map(|latency| latency.as_millis() as u64),
+                    error: probe.error,
+                    breaker: manager.breaker(&socket_path),
                 });
             }
 
             // Remove unhealthy sockets
-            let mut manager = state.socket_manager.lock().await;
             let removed = manager.validate_and_cleanup();
 
             ControlResponse::HealthCheck(HealthCheckResult {
@@ -313,91 +506,87 @@ async fn handle_request(request: ControlRequest, state: &ControlServerState) ->
                 removed: removed.iter().map(|p| p.display().to_string()).collect(),
             })
         }
-    }
-}
 
-/// Check the health of a single socket
-async fn check_socket_health(path: &Path) -> (SocketHealthStatus, Option<usize>, Option<String>) {
-    // Check if file exists
-    if !path.exists() {
-        return (
-            SocketHealthStatus::Missing,
-            None,
-            Some("Socket file does not exist".to_string()),
-        );
-    }
-
-    // Try to connect
-    let stream = match std::os::unix::net::UnixStream::connect(path) {
-        Ok(s) => s,
-        Err(e) => {
-            return (
-                SocketHealthStatus::ConnectionFailed,
-                None,
-                Some(format!("Connection failed: {e}")),
-            );
+        ControlRequest::Metrics => {
+            let manager = state.socket_manager.lock().await;
+            let mut metrics = state.metrics.lock().await;
+            metrics.retain_upstreams(|path| manager.is_known(path));
+            let report = metrics.report(|path| manager.upstream_name(path));
+            ControlResponse::Metrics(report)
         }
-    };
 
-    // Try to create a client using ssh-agent-lib
-    // This validates the socket responds to the SSH agent protocol
-    use ssh_agent_lib::client;
-    match client::connect(stream.into()) {
-        Ok(_client) => {
-            // Successfully connected and established protocol
-            // Note: We could query keys here with _client.request_identities().await
-            // but that requires more async refactoring. For now, a successful
-            // connection is sufficient for health checking.
-            (SocketHealthStatus::Healthy, None, None)
-        }
-        Err(e) => (
-            SocketHealthStatus::ProtocolError,
-            None,
-            Some(format!("Protocol error: {e}")),
-        ),
+        ControlRequest::Upgrade { binary } => request_upgrade(binary, state).await,
     }
 }
 
-/// Self-deleting Unix listener for the control socket
-pub struct SelfDeletingControlSocket {
-    path: PathBuf,
+/// Error for a socket that isn't among the sockets being ordered
+fn not_in_order(path: &Path) -> ControlResponse {
+    ControlResponse::Error {
+        error: format!("Socket not found among active sockets: {}", path.display()),
+    }
 }
 
-impl SelfDeletingControlSocket {
-    pub fn new(path: PathBuf) -> Self {
-        Self { path }
+/// Ask the daemon's main loop to upgrade and wait for the outcome
+async fn request_upgrade(binary: Option<String>, state: &ControlServerState) -> ControlResponse {
+    let Some(upgrade_requests) = &state.upgrade_requests else {
+        return ControlResponse::Error {
+            error: "Upgrades are not supported by this daemon".to_string(),
+        };
+    };
+
+    let (reply, outcome) = oneshot::channel();
+    let request = UpgradeRequest {
+        binary: binary.map(PathBuf::from),
+        reply,
+    };
+    if upgrade_requests.send(request).is_err() {
+        return ControlResponse::Error {
+            error: "Daemon is shutting down".to_string(),
+        };
     }
-}
 
-impl Drop for SelfDeletingControlSocket {
-    fn drop(&mut self) {
-        log::debug!("Cleaning up control socket {}", self.path.display());
-        let _ = std::fs::remove_file(&self.path);
+    match outcome.await {
+        Ok(Ok(pid)) => ControlResponse::Success {
+            message: Some(format!("Upgraded; new daemon is running as PID {pid}")),
+        },
+        Ok(Err(error)) => ControlResponse::Error {
+            error: format!("Upgrade failed: {error}"),
+        },
+        Err(_) => ControlResponse::Error {
+            error: "Upgrade was abandoned".to_string(),
+        },
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::os::unix::fs::PermissionsExt;
     use tempfile::TempDir;
 
     #[tokio::test]
     async fn test_control_server_ping() {
         let temp_dir = TempDir::new().unwrap();
-        let control_path = temp_dir.path().join("test.ctl");
-        let listen_path = temp_dir.path().join("test.sock");
+        // bind creates the missing socket directory with private permissions
+        let control_path = temp_dir.path().join("run/test.ctl");
+        let listen_path = temp_dir.path().join("run/test.sock");
 
         let socket_manager = Arc::new(Mutex::new(SocketManager::new(vec![])));
 
         let state = Arc::new(ControlServerState {
             socket_manager,
-            listen_path: listen_path.clone(),
-            control_path: control_path.clone(),
-            watch_enabled: false,
-            watcher_status: WatcherStatus::Disabled,
+            metrics: Default::default(),
+            settings: RwLock::new(DaemonSettings {
+                listen_path: listen_path.clone(),
+                control_path: control_path.clone(),
+                watch_enabled: false,
+                watcher_status: WatcherStatus::Disabled,
+                discovery: watcher::Discovery::default(),
+            }),
             version: "test".to_string(),
             git_commit: "test".to_string(),
             pid: std::process::id(),
+            upgrade_requests: None,
         });
 
         let server = ControlServer::bind(&control_path, state).await.unwrap();
@@ -406,7 +595,7 @@ mod tests {
         let server_handle = tokio::spawn(async move { server.accept_one().await });
 
         // Give server time to start
-        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
 
         // Connect as client
         let mut stream = UnixStream::connect(&control_path).await.unwrap();
@@ -433,19 +622,67 @@ mod tests {
         let _ = server_handle.await;
     }
 
+    #[tokio::test]
+    async fn test_control_server_from_std_listener() {
+        let temp_dir = TempDir::new().unwrap();
+        let control_path = temp_dir.path().join("inherited.ctl");
+
+        let state = Arc::new(ControlServerState {
+            socket_manager: Arc::new(Mutex::new(SocketManager::new(vec![]))),
+            metrics: Default::default(),
+            settings: RwLock::new(DaemonSettings {
+                listen_path: temp_dir.path().join("test.sock"),
+                control_path: control_path.clone(),
+                watch_enabled: false,
+                watcher_status: WatcherStatus::Disabled,
+                discovery: watcher::Discovery::default(),
+            }),
+            version: "test".to_string(),
+            git_commit: "test".to_string(),
+            pid: std::process::id(),
+            upgrade_requests: None,
+        });
+
+        // Simulate a listener created by the service manager
+        let listener = std::os::unix::net::UnixListener::bind(&control_path).unwrap();
+        let server = ControlServer::from_std(listener, state).unwrap();
+        let server_handle = tokio::spawn(async move { server.accept_one().await });
+
+        let mut stream = UnixStream::connect(&control_path).await.unwrap();
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let request = serde_json::to_string(&ControlRequest::Ping).unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        stream.write_all(b"\n").await.unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        let parsed: ControlResponse =
+            serde_json::from_str(std::str::from_utf8(&buf[..n]).unwrap().trim()).unwrap();
+        assert_eq!(parsed, ControlResponse::Pong);
+
+        drop(stream);
+        let _ = server_handle.await;
+    }
+
     #[tokio::test]
     async fn test_handle_status_request() {
         let socket_manager = Arc::new(Mutex::new(SocketManager::new(vec![])));
 
         let state = Arc::new(ControlServerState {
             socket_manager,
-            listen_path: PathBuf::from("/test/listen.sock"),
-            control_path: PathBuf::from("/test/control.ctl"),
-            watch_enabled: true,
-            watcher_status: WatcherStatus::Active,
+            metrics: Default::default(),
+            settings: RwLock::new(DaemonSettings {
+                listen_path: PathBuf::from("/test/listen.sock"),
+                control_path: PathBuf::from("/test/control.ctl"),
+                watch_enabled: true,
+                watcher_status: WatcherStatus::Active,
+                discovery: watcher::Discovery::default(),
+            }),
             version: "1.0.0".to_string(),
             git_commit: "abc123".to_string(),
             pid: 12345,
+            upgrade_requests: None,
         });
 
         let response = handle_request(ControlRequest::Status, &state).await;
@@ -464,18 +701,23 @@ mod tests {
 
     #[tokio::test]
     async fn test_handle_list_sockets_request() {
-        let mut manager = SocketManager::new(vec![PathBuf::from("/tmp/configured.sock")]);
-        manager.add_watched(PathBuf::from("/tmp/watched.sock"));
+        let mut manager = SocketManager::new(vec![PathBuf::from("/tmp/configured.sock").into()]);
+        manager.add_watched(PathBuf::from("/tmp/watched.sock"), None);
 
         let state = Arc::new(ControlServerState {
             socket_manager: Arc::new(Mutex::new(manager)),
-            listen_path: PathBuf::from("/test/listen.sock"),
-            control_path: PathBuf::from("/test/control.ctl"),
-            watch_enabled: false,
-            watcher_status: WatcherStatus::Disabled,
+            metrics: Default::default(),
+            settings: RwLock::new(DaemonSettings {
+                listen_path: PathBuf::from("/test/listen.sock"),
+                control_path: PathBuf::from("/test/control.ctl"),
+                watch_enabled: false,
+                watcher_status: WatcherStatus::Disabled,
+                discovery: watcher::Discovery::default(),
+            }),
             version: "test".to_string(),
             git_commit: "test".to_string(),
             pid: 1,
+            upgrade_requests: None,
         });
 
         let response = handle_request(ControlRequest::ListSockets, &state).await;
@@ -491,23 +733,116 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_handle_set_priority_request() {
+        let manager = SocketManager::new(vec![PathBuf::from("/tmp/configured.sock").into()]);
+
+        let state = Arc::new(ControlServerState {
+            socket_manager: Arc::new(Mutex::new(manager)),
+            metrics: Default::default(),
+            settings: RwLock::new(DaemonSettings {
+                listen_path: PathBuf::from("/test/listen.sock"),
+                control_path: PathBuf::from("/test/control.ctl"),
+                watch_enabled: false,
+                watcher_status: WatcherStatus::Disabled,
+                discovery: watcher::Discovery::default(),
+            }),
+            version: "test".to_string(),
+            git_commit: "test".to_string(),
+            pid: 1,
+            upgrade_requests: None,
+        });
+        let set_priority = |duration_secs| ControlRequest::SetPriority {
+            path: "/tmp/configured.sock".to_string(),
+            priority: 10,
+            duration_secs,
+        };
+
+        match handle_request(set_priority(Some(60)), &state).await {
+            ControlResponse::Success { message } => {
+                assert!(message.unwrap().contains("for 60s"));
+            }
+            _ => panic!("Expected Success response"),
+        }
+
+        // A duration too long to represent is refused rather than overflowing
+        match handle_request(set_priority(Some(u64::MAX)), &state).await {
+            ControlResponse::Error { error } => {
+                assert!(error.contains("is too long"));
+            }
+            _ => panic!("Expected Error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_upgrade_request() {
+        let (upgrade_tx, mut upgrade_rx) = mpsc::unbounded_channel::<UpgradeRequest>();
+        let mut state = ControlServerState {
+            socket_manager: Arc::new(Mutex::new(SocketManager::new(vec![]))),
+            metrics: Default::default(),
+            settings: RwLock::new(DaemonSettings {
+                listen_path: PathBuf::from("/test/listen.sock"),
+                control_path: PathBuf::from("/test/control.ctl"),
+                watch_enabled: false,
+                watcher_status: WatcherStatus::Disabled,
+                discovery: watcher::Discovery::default(),
+            }),
+            version: "test".to_string(),
+            git_commit: "test".to_string(),
+            pid: 1,
+            upgrade_requests: None,
+        };
+        let request = ControlRequest::Upgrade {
+            binary: Some("/usr/bin/ssh-agent-mux".to_string()),
+        };
+
+        // Refused when nothing handles upgrades
+        let response = handle_request(request.clone(), &state).await;
+        assert!(matches!(response, ControlResponse::Error { .. }));
+
+        // Otherwise passed on to the main loop, which reports the new PID
+        state.upgrade_requests = Some(upgrade_tx);
+        tokio::spawn(async move {
+            let upgrade = upgrade_rx.recv().await.unwrap();
+            assert_eq!(
+                upgrade.binary,
+                Some(PathBuf::from("/usr/bin/ssh-agent-mux"))
+            );
+            upgrade.reply.send(Ok(4242)).unwrap();
+        });
+
+        match handle_request(request, &state).await {
+            ControlResponse::Success { message } => {
+                assert!(message.unwrap().contains("PID 4242"));
+            }
+            other => panic!("Expected Success response, got {other:?}"),
+        }
+    }
+
     #[tokio::test]
     async fn test_handle_add_remove_socket() {
         let temp_dir = TempDir::new().unwrap();
         let socket_path = temp_dir.path().join("test.sock");
 
-        // Create a fake socket file
+        // Create a fake socket file, accessible only to us
+        std::fs::set_permissions(temp_dir.path(), PermissionsExt::from_mode(0o700)).unwrap();
         std::fs::File::create(&socket_path).unwrap();
+        std::fs::set_permissions(&socket_path, PermissionsExt::from_mode(0o600)).unwrap();
 
         let state = Arc::new(ControlServerState {
             socket_manager: Arc::new(Mutex::new(SocketManager::new(vec![]))),
-            listen_path: PathBuf::from("/test/listen.sock"),
-            control_path: PathBuf::from("/test/control.ctl"),
-            watch_enabled: false,
-            watcher_status: WatcherStatus::Disabled,
+            metrics: Default::default(),
+            settings: RwLock::new(DaemonSettings {
+                listen_path: PathBuf::from("/test/listen.sock"),
+                control_path: PathBuf::from("/test/control.ctl"),
+                watch_enabled: false,
+                watcher_status: WatcherStatus::Disabled,
+                discovery: watcher::Discovery::default(),
+            }),
             version: "test".to_string(),
             git_commit: "test".to_string(),
             pid: 1,
+            upgrade_requests: None,
         });
 
         // Add socket
@@ -566,5 +901,24 @@ mod tests {
         // Verify it was removed
         let manager = state.socket_manager.lock().await;
         assert!(!manager.is_watched(&socket_path));
+        drop(manager);
+
+        // Sockets other users can access are refused
+        std::fs::set_permissions(&socket_path, PermissionsExt::from_mode(0o666)).unwrap();
+        let response = handle_request(
+            ControlRequest::AddSocket {
+                path: socket_path.display().to_string(),
+            },
+            &state,
+        )
+        .await;
+
+        match response {
+            ControlResponse::Error { error } => {
+                assert!(error.contains("accessible by other users"));
+            }
+            _ => panic!("Expected Error response"),
+        }
+        assert!(!state.socket_manager.lock().await.is_watched(&socket_path));
     }
 }
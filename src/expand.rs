@@ -0,0 +1,226 @@
+//! Environment variable and glob expansion for configured socket paths.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use glob::{MatchOptions, Pattern};
+
+/// Errors from expanding a configured path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExpandError {
+    /// The path refers to an environment variable that isn't set
+    UndefinedVariable { name: String, path: PathBuf },
+}
+
+impl fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpandError::UndefinedVariable { name, path } => write!(
+                f,
+                "environment variable {name} in {} is not set",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ExpandError {}
+
+/// Replace `$NAME` and `${NAME}` in `path` with the values of environment variables
+///
+/// A `$` that isn't followed by a variable name is kept as is. Shells don't export `UID`, so
+/// `$UID` is the current user ID unless it is set.
+pub fn expand_env(path: &Path) -> Result<PathBuf, ExpandError> {
+    let Some(text) = path.to_str() else {
+        return Ok(path.to_path_buf());
+    };
+
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, remainder) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => ("", after),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+
+        if name.is_empty() {
+            result.push('$');
+            rest = after;
+            continue;
+        }
+        let value = match std::env::var(name) {
+            Ok(value) => value,
+            Err(_) if name == "UID" => crate::instance::current_uid().to_string(),
+            Err(_) => {
+                return Err(ExpandError::UndefinedVariable {
+                    name: name.to_string(),
+                    path: path.to_path_buf(),
+                })
+            }
+        };
+        result.push_str(&value);
+        rest = remainder;
+    }
+    result.push_str(rest);
+
+    Ok(PathBuf::from(result))
+}
+
+/// Whether `path` contains glob wildcards (`*`, `?` or `[`)
+pub fn is_glob(path: &Path) -> bool {
+    path.to_str().is_some_and(|p| p.contains(['*', '?', '[']))
+}
+
+/// Existing paths matching the glob pattern `pattern`, sorted
+///
+/// Wildcards don't match `/`, and only match a leading `.` if the pattern component starts
+/// with one. Directories that can't be read contribute no matches.
+pub fn expand_glob(pattern: &Path) -> Vec<PathBuf> {
+    let Some(pattern) = pattern.to_str() else {
+        return vec![];
+    };
+    let options = MatchOptions {
+        require_literal_separator: true,
+        require_literal_leading_dot: true,
+        ..MatchOptions::new()
+    };
+    match glob::glob_with(pattern, options) {
+        Ok(paths) => paths.flatten().collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Match `name` against the shell-style wildcard pattern `glob`
+///
+/// Supports `*`, `?` and bracket expressions like `[abc]`, `[a-z]` and `[!0-9]`. A pattern that
+/// isn't valid only matches itself.
+pub fn glob_match(glob: &str, name: &str) -> bool {
+    match Pattern::new(glob) {
+        Ok(pattern) => pattern.matches(name),
+        Err(_) => glob == name,
+    }
+}
+
+/// Match `path` against `pattern`, whose components may be shell-style wildcard patterns
+///
+/// Wildcards don't match `/`.
+pub fn path_matches(pattern: &Path, path: &Path) -> bool {
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+    match pattern.to_str().map(Pattern::new) {
+        Some(Ok(glob)) => glob.matches_path_with(path, options),
+        _ => pattern == path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_env() {
+        std::env::set_var("SSH_AGENT_MUX_TEST_DIR", "/run/user/1000");
+        assert_eq!(
+            expand_env(Path::new("$SSH_AGENT_MUX_TEST_DIR/gnupg/S.gpg-agent.ssh")).unwrap(),
+            PathBuf::from("/run/user/1000/gnupg/S.gpg-agent.ssh")
+        );
+        assert_eq!(
+            expand_env(Path::new("${SSH_AGENT_MUX_TEST_DIR}.d/agent")).unwrap(),
+            PathBuf::from("/run/user/1000.d/agent")
+        );
+
+        // Lone dollar signs are kept
+        assert_eq!(
+            expand_env(Path::new("/tmp/$/a$")).unwrap(),
+            PathBuf::from("/tmp/$/a$")
+        );
+
+        if std::env::var_os("UID").is_none() {
+            assert_eq!(
+                expand_env(Path::new("/run/user/$UID")).unwrap(),
+                PathBuf::from(format!("/run/user/{}", crate::instance::current_uid()))
+            );
+        }
+
+        assert_eq!(
+            expand_env(Path::new("$SSH_AGENT_MUX_TEST_UNSET/agent")),
+            Err(ExpandError::UndefinedVariable {
+                name: "SSH_AGENT_MUX_TEST_UNSET".to_string(),
+                path: PathBuf::from("$SSH_AGENT_MUX_TEST_UNSET/agent"),
+            })
+        );
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("agent.*", "agent.12345"));
+        assert!(!glob_match("agent.*", "agent"));
+        assert!(glob_match("S.gpg-agent.?sh", "S.gpg-agent.ssh"));
+        assert!(glob_match("*.sock", "a.b.sock"));
+        assert!(!glob_match("*.sock", "a.sock.bak"));
+        assert!(glob_match("[0-9]*", "1000"));
+        assert!(!glob_match("[!0-9]*", "1000"));
+        assert!(glob_match("[]a]", "]"));
+        assert!(glob_match("a[", "a["));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+
+        assert!(path_matches(
+            Path::new("/tmp/ssh-*/agent.*"),
+            Path::new("/tmp/ssh-abc/agent.1")
+        ));
+        assert!(path_matches(
+            Path::new("/run/agent.sock"),
+            Path::new("/run/agent.sock")
+        ));
+        assert!(!path_matches(
+            Path::new("/tmp/*"),
+            Path::new("/tmp/ssh-abc/agent.1")
+        ));
+        assert!(!path_matches(
+            Path::new("/tmp/ssh-*/agent.*"),
+            Path::new("/tmp/ssh-abc")
+        ));
+    }
+
+    #[test]
+    fn test_expand_glob() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path();
+        for dir in ["1000/keyring", "1001/keyring", "1002", ".hidden/keyring"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "1000/keyring/ssh",
+            "1001/keyring/ssh",
+            ".hidden/keyring/ssh",
+        ] {
+            std::fs::File::create(root.join(file)).unwrap();
+        }
+
+        assert!(is_glob(&root.join("*/keyring/ssh")));
+        assert!(!is_glob(&root.join("1000/keyring/ssh")));
+        assert_eq!(
+            expand_glob(&root.join("*/keyring/ssh")),
+            vec![root.join("1000/keyring/ssh"), root.join("1001/keyring/ssh")]
+        );
+        assert_eq!(
+            expand_glob(&root.join("100[1-9]/keyring/ssh")),
+            vec![root.join("1001/keyring/ssh")]
+        );
+        assert!(expand_glob(&root.join("*/missing/ssh")).is_empty());
+    }
+}
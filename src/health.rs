@@ -0,0 +1,198 @@
+//! Checking the health of upstream agents by connecting to them and asking for their keys.
+
+use std::io;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use ssh_agent_lib::client;
+use tokio::sync::Mutex;
+
+use crate::control::SocketHealthStatus;
+use crate::socket_manager::SocketManager;
+
+/// How long an upstream agent without a configured timeout may take to list its keys
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of asking an upstream agent for its keys
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthProbe {
+    pub status: SocketHealthStatus,
+    /// Number of keys the agent listed
+    pub key_count: Option<usize>,
+    /// How long the agent took to answer, from connecting to listing its keys
+    pub latency: Option<Duration>,
+    pub error: Option<String>,
+    pub checked_at: SystemTime,
+}
+
+impl HealthProbe {
+    fn failed(status: SocketHealthStatus, error: String) -> Self {
+        Self {
+            status,
+            key_count: None,
+            latency: None,
+            error: Some(error),
+            checked_at: SystemTime::now(),
+        }
+    }
+
+    pub fn healthy(&self) -> bool {
+        self.status == SocketHealthStatus::Healthy
+    }
+}
+
+/// Connect to the agent at `path` and ask it for its keys, giving up after `timeout`
+pub async fn probe(path: &Path, timeout: Duration) -> HealthProbe {
+    if !path.exists() {
+        return HealthProbe::failed(
+            SocketHealthStatus::Missing,
+            "Socket file does not exist".to_string(),
+        );
+    }
+
+    let start = Instant::now();
+    let stream = match UnixStream::connect(path) {
+        Ok(s) => s,
+        // Left behind by an agent that's gone, or not a socket at all
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            return HealthProbe::failed(
+                SocketHealthStatus::ConnectionFailed,
+                "Socket file exists but refuses connections".to_string(),
+            );
+        }
+        Err(e) => {
+            return HealthProbe::failed(
+                SocketHealthStatus::ConnectionFailed,
+                format!("Connection failed: {e}"),
+            );
+        }
+    };
+    let mut client = match client::connect(stream.into()) {
+        Ok(c) => c,
+        Err(e) => {
+            return HealthProbe::failed(
+                SocketHealthStatus::ProtocolError,
+                format!("Protocol error: {e}"),
+            );
+        }
+    };
+
+    match tokio::time::timeout(timeout, client.request_identities()).await {
+        Ok(Ok(identities)) => HealthProbe {
+            status: SocketHealthStatus::Healthy,
+            key_count: Some(identities.len()),
+            latency: Some(start.elapsed()),
+            error: None,
+            checked_at: SystemTime::now(),
+        },
+        Ok(Err(e)) => HealthProbe::failed(
+            SocketHealthStatus::QueryFailed,
+            format!("Failed to list keys: {e}"),
+        ),
+        Err(_) => HealthProbe::failed(
+            SocketHealthStatus::QueryFailed,
+            format!("No answer within {timeout:?}"),
+        ),
+    }
+}
+
+/// Probe every active upstream agent, configured or watched, recording the outcomes in
+/// `socket_manager`; returns the outcomes in socket order
+///
+/// Agents skipped after failing are probed too. The socket manager isn't locked while waiting
+/// for agents.
+pub async fn check_sockets(socket_manager: &Mutex<SocketManager>) -> Vec<(PathBuf, HealthProbe)> {
+    let sockets = socket_manager.lock().await.get_active_sockets();
+    probe_all(socket_manager, sockets).await
+}
+
+/// Probe the upstream agents that were skipped after failing and are due for a retry, recording
+/// the outcomes in `socket_manager`
+///
+/// An agent that answers is used again; one that doesn't is skipped for twice as long as before.
+pub async fn retry_failed(socket_manager: &Mutex<SocketManager>) -> Vec<(PathBuf, HealthProbe)> {
+    let sockets = socket_manager.lock().await.due_for_retry();
+    probe_all(socket_manager, sockets).await
+}
+
+async fn probe_all(
+    socket_manager: &Mutex<SocketManager>,
+    sockets: Vec<PathBuf>,
+) -> Vec<(PathBuf, HealthProbe)> {
+    let mut results = Vec::with_capacity(sockets.len());
+    for path in sockets {
+        let timeout = socket_manager
+            .lock()
+            .await
+            .upstream_timeout(&path)
+            .unwrap_or(PROBE_TIMEOUT);
+        let probe = probe(&path, timeout).await;
+        socket_manager
+            .lock()
+            .await
+            .record_health(&path, probe.clone());
+        results.push((path, probe));
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_probe_missing_and_refusing_sockets() {
+        let temp_dir = TempDir::new().unwrap();
+
+        let missing = probe(&temp_dir.path().join("missing.sock"), PROBE_TIMEOUT).await;
+        assert_eq!(missing.status, SocketHealthStatus::Missing);
+        assert!(!missing.healthy());
+
+        // A socket file whose listener is gone
+        let stale = temp_dir.path().join("stale.sock");
+        drop(UnixListener::bind(&stale).unwrap());
+        let refused = probe(&stale, PROBE_TIMEOUT).await;
+        assert_eq!(refused.status, SocketHealthStatus::ConnectionFailed);
+        assert_eq!(
+            refused.error.as_deref(),
+            Some("Socket file exists but refuses connections")
+        );
+        assert_eq!(refused.latency, None);
+    }
+
+    #[tokio::test]
+    async fn test_probe_unresponsive_agent() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("hung.sock");
+        // Accepts connections (into the backlog), but never answers
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        let hung = probe(&path, Duration::from_millis(50)).await;
+        assert_eq!(hung.status, SocketHealthStatus::QueryFailed);
+        assert_eq!(hung.error.as_deref(), Some("No answer within 50ms"));
+    }
+
+    #[tokio::test]
+    async fn test_check_sockets_records_configured_sockets() {
+        let temp_dir = TempDir::new().unwrap();
+        let stale = temp_dir.path().join("stale.sock");
+        drop(UnixListener::bind(&stale).unwrap());
+        let manager = Mutex::new(SocketManager::new(vec![stale.clone().into()]));
+
+        let results = check_sockets(&manager).await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, stale);
+
+        let info = manager.lock().await.get_socket_info();
+        assert!(!info[0].healthy);
+        assert!(info[0].last_health_check.is_some());
+        assert_eq!(
+            info[0].health_error.as_deref(),
+            Some("Socket file exists but refuses connections")
+        );
+    }
+}
@@ -0,0 +1,699 @@
+//! Single-instance guard and safe preparation of listen socket paths.
+//!
+//! A daemon holds an exclusive `flock` on a lock file next to its sockets for as long as it runs.
+//! Before binding a socket, any file already at the socket path is inspected: stale sockets left
+//! behind by a crashed daemon are removed, but live sockets, sockets owned by other users and
+//! non-socket files are never touched. A socket only counts as stale if connecting to it is
+//! refused; one that accepts connections is live, even if nobody answers on it.
+//!
+//! Sockets are only created inside directories owned by the current user with mode 0700 (or 0710
+//! when a group is allowed to reach the control socket), and are restricted to mode 0600 (0660)
+//! right after binding. Since the directory is private, nobody else can connect in between.
+
+use std::ffi::CString;
+use std::fs::{DirBuilder, File, OpenOptions, Permissions};
+use std::io::{Read, Seek, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::control::{ControlClient, ControlRequest};
+
+/// Number of times to retry taking the lock if the lock file is replaced underneath us
+const LOCK_ATTEMPTS: usize = 3;
+
+/// Largest buffer to try for a group entry when resolving a group name
+const MAX_GROUP_BUFFER: usize = 1 << 20;
+
+/// How long a process listening on a socket path gets to answer, to tell a daemon from a silent
+/// listener
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// SSH agent protocol message numbers used to probe agent sockets
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+
+/// Protocol served on a listening socket, used to check whether a live daemon is behind it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketProtocol {
+    /// SSH agent protocol
+    Agent,
+    /// The daemon's control protocol
+    Control,
+}
+
+/// Error type for single-instance and socket path checks
+#[derive(Debug)]
+pub enum InstanceError {
+    /// Another daemon holds the lock file
+    AlreadyRunning {
+        lock_path: PathBuf,
+        pid: Option<u32>,
+    },
+    /// A process is accepting connections on the socket path
+    SocketInUse(PathBuf),
+    /// The socket path belongs to another user
+    ForeignOwner { path: PathBuf, uid: u32 },
+    /// Something other than a socket exists at the socket path
+    NotASocket(PathBuf),
+    /// A socket directory could be accessed by other users
+    InsecureDirectory { path: PathBuf, reason: String },
+    /// I/O error while inspecting or locking a path
+    Io(PathBuf, std::io::Error),
+}
+
+impl std::fmt::Display for InstanceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InstanceError::AlreadyRunning {
+                lock_path,
+                pid: Some(pid),
+            } => write!(
+                f,
+                "ssh-agent-mux is already running (PID {pid}; lock file {})",
+                lock_path.display()
+            ),
+            InstanceError::AlreadyRunning {
+                lock_path,
+                pid: None,
+            } => write!(
+                f,
+                "ssh-agent-mux is already running (lock file {} is held)",
+                lock_path.display()
+            ),
+            InstanceError::SocketInUse(path) => write!(
+                f,
+                "Another process is listening on {}; refusing to replace it",
+                path.display()
+            ),
+            InstanceError::ForeignOwner { path, uid } => write!(
+                f,
+                "Socket {} is owned by another user (uid {uid}); refusing to replace it",
+                path.display()
+            ),
+            InstanceError::NotASocket(path) => write!(
+                f,
+                "{} exists and is not a socket; refusing to replace it",
+                path.display()
+            ),
+            InstanceError::InsecureDirectory { path, reason } => write!(
+                f,
+                "Refusing to use insecure socket directory {}: {reason} (fix with `chmod 700 {}`)",
+                path.display(),
+                path.display()
+            ),
+            InstanceError::Io(path, e) => write!(f, "{}: {e}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for InstanceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InstanceError::Io(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<InstanceError> for std::io::Error {
+    fn from(e: InstanceError) -> Self {
+        let kind = match &e {
+            InstanceError::AlreadyRunning { .. } | InstanceError::SocketInUse(_) => {
+                std::io::ErrorKind::AddrInUse
+            }
+            InstanceError::ForeignOwner { .. } | InstanceError::InsecureDirectory { .. } => {
+                std::io::ErrorKind::PermissionDenied
+            }
+            InstanceError::NotASocket(_) => std::io::ErrorKind::AlreadyExists,
+            InstanceError::Io(_, e) => e.kind(),
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
+/// Exclusive lock held by the running daemon; released (and the lock file removed) on drop
+#[derive(Debug)]
+pub struct InstanceLock {
+    path: PathBuf,
+    file: File,
+}
+
+impl InstanceLock {
+    /// Take the single-instance lock at `path`, recording our PID in it
+    pub fn acquire(path: impl AsRef<Path>) -> Result<Self, InstanceError> {
+        let path = path.as_ref().to_path_buf();
+        let io_err = |e| InstanceError::Io(path.clone(), e);
+
+        // Ensure parent directory exists
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(parent)
+                    .map_err(io_err)?;
+            }
+        }
+
+        for _ in 0..LOCK_ATTEMPTS {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .mode(0o600)
+                .open(&path)
+                .map_err(io_err)?;
+
+            // SAFETY: flock on a valid, owned fd
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() != std::io::ErrorKind::WouldBlock {
+                    return Err(io_err(err));
+                }
+                let mut contents = String::new();
+                let _ = file.read_to_string(&mut contents);
+                return Err(InstanceError::AlreadyRunning {
+                    lock_path: path,
+                    pid: contents.trim().parse().ok(),
+                });
+            }
+
+            // A previous holder may have unlinked the file between our open and flock; if so we
+            // locked an orphaned inode and must try again with the new file
+            let locked_ino = file.metadata().map_err(io_err)?.ino();
+            match std::fs::metadata(&path) {
+                Ok(meta) if meta.ino() == locked_ino => {}
+                Ok(_) => continue,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(io_err(e)),
+            }
+
+            write_pid(&mut file).map_err(io_err)?;
+
+            log::debug!("Acquired instance lock {}", path.display());
+            return Ok(Self { path, file });
+        }
+
+        Err(io_err(std::io::Error::other(
+            "lock file kept changing while acquiring it",
+        )))
+    }
+
+    /// Take over the lock at `path` from the daemon this process is replacing, which passed us
+    /// its lock file descriptor
+    pub fn inherit(path: impl AsRef<Path>, fd: OwnedFd) -> Result<Self, InstanceError> {
+        let path = path.as_ref().to_path_buf();
+        let io_err = |e| InstanceError::Io(path.clone(), e);
+        let mut file = File::from(fd);
+
+        // The lock belongs to the open file description we share, so this succeeds unless the fd
+        // doesn't actually hold it
+        // SAFETY: flock on a valid, owned fd
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            return Err(io_err(std::io::Error::last_os_error()));
+        }
+        write_pid(&mut file).map_err(io_err)?;
+
+        log::debug!("Inherited instance lock {}", path.display());
+        Ok(Self { path, file })
+    }
+
+    /// Duplicate the lock's file descriptor, so that a successor process can share the lock
+    pub fn try_clone_fd(&self) -> std::io::Result<OwnedFd> {
+        self.file.try_clone().map(OwnedFd::from)
+    }
+
+    /// Leave the lock to a successor process that shares it, without releasing it or removing
+    /// the lock file
+    pub fn hand_over(self) {
+        log::debug!("Handing over instance lock {}", self.path.display());
+        // Our descriptor stays open until exit; closing it wouldn't release the shared lock anyway
+        std::mem::forget(self);
+    }
+
+    /// Path of the lock file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Replace the contents of a lock file with our PID
+fn write_pid(file: &mut File) -> std::io::Result<()> {
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{}", std::process::id())?;
+    file.flush()
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        log::debug!("Releasing instance lock {}", self.path.display());
+        // Unlink while still holding the lock, so a new daemon can't lock the orphaned inode
+        let _ = std::fs::remove_file(&self.path);
+        // SAFETY: flock on a valid, owned fd; the lock is released on close regardless
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// Removes a socket file the daemon bound itself when dropped
+#[derive(Debug)]
+pub struct SocketCleanup {
+    path: Option<PathBuf>,
+}
+
+impl SocketCleanup {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path: Some(path) }
+    }
+
+    /// Leave the socket file in place, e.g. because another process is now serving it
+    pub fn disarm(&mut self) {
+        self.path = None;
+    }
+}
+
+impl Drop for SocketCleanup {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            log::debug!("Cleaning up socket {}", path.display());
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Derive the lock file path from the listen socket path
+pub fn default_lock_path(listen_path: &Path) -> PathBuf {
+    let listen_str = listen_path.to_string_lossy();
+
+    // Replace .sock with .lock
+    if let Some(base) = listen_str.strip_suffix(".sock") {
+        PathBuf::from(format!("{base}.lock"))
+    } else {
+        PathBuf::from(format!("{listen_str}.lock"))
+    }
+}
+
+/// Make `path` available for binding a listening socket
+///
+/// Nothing at the path is fine. A socket owned by us that refuses connections is stale and gets
+/// removed. Anything else — a socket something listens on, whether or not it answers a `protocol`
+/// request, a socket owned by another user, or a non-socket file — is left alone and reported as
+/// an error.
+pub fn prepare_socket_path(
+    path: impl AsRef<Path>,
+    protocol: SocketProtocol,
+) -> Result<(), InstanceError> {
+    let path = path.as_ref();
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(InstanceError::Io(path.to_path_buf(), e)),
+    };
+
+    if !meta.file_type().is_socket() {
+        return Err(InstanceError::NotASocket(path.to_path_buf()));
+    }
+
+    if meta.uid() != current_uid() {
+        return Err(InstanceError::ForeignOwner {
+            path: path.to_path_buf(),
+            uid: meta.uid(),
+        });
+    }
+
+    match UnixStream::connect(path) {
+        Ok(stream) => {
+            if !answers(stream, path, protocol) {
+                // Could be a daemon that's busy or stuck, which mustn't lose its socket
+                log::warn!(
+                    "Socket {} accepts connections but doesn't answer; leaving it alone",
+                    path.display()
+                );
+            }
+            return Err(InstanceError::SocketInUse(path.to_path_buf()));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            log::info!("Removing stale socket {}", path.display());
+        }
+        Err(e) => return Err(InstanceError::Io(path.to_path_buf(), e)),
+    }
+    std::fs::remove_file(path).map_err(|e| InstanceError::Io(path.to_path_buf(), e))
+}
+
+/// Check whether whoever accepted `stream` gives a valid answer to a `protocol` request
+fn answers(stream: UnixStream, path: &Path, protocol: SocketProtocol) -> bool {
+    match protocol {
+        SocketProtocol::Agent => answers_identities_request(stream).unwrap_or(false),
+        SocketProtocol::Control => {
+            drop(stream);
+            ControlClient::connect_with_timeout(path, PROBE_TIMEOUT)
+                .and_then(|mut client| client.send(ControlRequest::Ping))
+                .is_ok()
+        }
+    }
+}
+
+/// Ask an SSH agent for its identities and check that the reply is an agent message
+fn answers_identities_request(mut stream: UnixStream) -> std::io::Result<bool> {
+    stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    stream.set_write_timeout(Some(PROBE_TIMEOUT))?;
+    stream.write_all(&[0, 0, 0, 1, SSH_AGENTC_REQUEST_IDENTITIES])?;
+
+    let mut header = [0; 5];
+    stream.read_exact(&mut header)?;
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    Ok(len >= 1 && matches!(header[4], SSH_AGENT_FAILURE | SSH_AGENT_IDENTITIES_ANSWER))
+}
+
+/// Verify that `dir` is a private directory owned by the current user, creating it (mode 0700) if
+/// it doesn't exist
+///
+/// The directory may additionally grant search permission to `allowed_group`, so that members of
+/// that group can reach a control socket inside it.
+pub fn check_socket_dir(
+    dir: impl AsRef<Path>,
+    allowed_group: Option<u32>,
+) -> Result<(), InstanceError> {
+    let dir = dir.as_ref();
+    let io_err = |e| InstanceError::Io(dir.to_path_buf(), e);
+    if dir.as_os_str().is_empty() {
+        return Ok(());
+    }
+
+    if !dir.exists() {
+        log::info!("Creating socket directory {}", dir.display());
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .map_err(io_err)?;
+    }
+
+    let meta = std::fs::metadata(dir).map_err(io_err)?;
+    let insecure = |reason: String| InstanceError::InsecureDirectory {
+        path: dir.to_path_buf(),
+        reason,
+    };
+
+    if !meta.is_dir() {
+        return Err(insecure("not a directory".to_string()));
+    }
+    if meta.uid() != current_uid() {
+        return Err(insecure(format!("owned by uid {}", meta.uid())));
+    }
+
+    let mode = meta.mode() & 0o777;
+    let group_search_allowed = allowed_group.is_some_and(|gid| gid == meta.gid());
+    let extra = if group_search_allowed {
+        mode & 0o067
+    } else {
+        mode & 0o077
+    };
+    if extra != 0 {
+        return Err(insecure(format!(
+            "mode {mode:o} grants access to other users"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Restrict a freshly bound socket to its owner, or to its owner and `group`
+pub fn restrict_socket(path: impl AsRef<Path>, group: Option<u32>) -> Result<(), InstanceError> {
+    let path = path.as_ref();
+    let io_err = |e| InstanceError::Io(path.to_path_buf(), e);
+
+    let mode = match group {
+        Some(gid) => {
+            std::os::unix::fs::chown(path, None, Some(gid)).map_err(io_err)?;
+            0o660
+        }
+        None => 0o600,
+    };
+    std::fs::set_permissions(path, Permissions::from_mode(mode)).map_err(io_err)
+}
+
+/// Bind a listening socket at `path`, replacing a stale socket but never a live or foreign one
+///
+/// The socket is restricted with [`restrict_socket`] before it is returned. The listener is in
+/// blocking mode.
+pub fn bind_socket(
+    path: impl AsRef<Path>,
+    group: Option<u32>,
+    protocol: SocketProtocol,
+) -> Result<UnixListener, InstanceError> {
+    let path = path.as_ref();
+    prepare_socket_path(path, protocol)?;
+    let listener =
+        UnixListener::bind(path).map_err(|e| InstanceError::Io(path.to_path_buf(), e))?;
+    restrict_socket(path, group)?;
+    Ok(listener)
+}
+
+/// Resolve a group name (or numeric GID) to a GID
+pub fn resolve_group(group: &str) -> std::io::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group).map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "group name contains NUL")
+    })?;
+    let mut grp = std::mem::MaybeUninit::<libc::group>::uninit();
+    // SAFETY: sysconf has no preconditions
+    let size_hint = unsafe { libc::sysconf(libc::_SC_GETGR_R_SIZE_MAX) };
+    let mut buf = vec![0 as libc::c_char; usize::try_from(size_hint).unwrap_or(1024).max(1024)];
+    let mut result = std::ptr::null_mut();
+    loop {
+        // SAFETY: all pointers are valid for the duration of the call and buf's length is passed
+        let rc = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                grp.as_mut_ptr(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        match rc {
+            0 => break,
+            // Groups with many members don't fit the suggested size
+            libc::ERANGE if buf.len() < MAX_GROUP_BUFFER => buf.resize(buf.len() * 2, 0),
+            _ => return Err(std::io::Error::from_raw_os_error(rc)),
+        }
+    }
+    if result.is_null() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("unknown group {group:?}"),
+        ));
+    }
+    // SAFETY: getgrnam_r succeeded and initialized grp
+    Ok(unsafe { grp.assume_init() }.gr_gid)
+}
+
+pub(crate) fn current_uid() -> u32 {
+    // SAFETY: getuid cannot fail
+    unsafe { libc::getuid() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_default_lock_path() {
+        assert_eq!(
+            default_lock_path(Path::new("/home/user/.ssh/ssh-agent-mux.sock")),
+            PathBuf::from("/home/user/.ssh/ssh-agent-mux.lock")
+        );
+        assert_eq!(
+            default_lock_path(Path::new("/tmp/agent")),
+            PathBuf::from("/tmp/agent.lock")
+        );
+    }
+
+    #[test]
+    fn test_lock_excludes_second_instance() {
+        let temp_dir = TempDir::new().unwrap();
+        let lock_path = temp_dir.path().join("mux.lock");
+
+        let lock = InstanceLock::acquire(&lock_path).unwrap();
+        let contents = std::fs::read_to_string(&lock_path).unwrap();
+        assert_eq!(contents.trim(), std::process::id().to_string());
+
+        match InstanceLock::acquire(&lock_path) {
+            Err(InstanceError::AlreadyRunning { pid, .. }) => {
+                assert_eq!(pid, Some(std::process::id()));
+            }
+            other => panic!("Expected AlreadyRunning, got {other:?}"),
+        }
+
+        // Released on drop
+        drop(lock);
+        assert!(!lock_path.exists());
+        let _lock = InstanceLock::acquire(&lock_path).unwrap();
+    }
+
+    #[test]
+    fn test_lock_hand_over() {
+        let temp_dir = TempDir::new().unwrap();
+        let lock_path = temp_dir.path().join("mux.lock");
+
+        let lock = InstanceLock::acquire(&lock_path).unwrap();
+        let fd = lock.try_clone_fd().unwrap();
+        lock.hand_over();
+
+        // The successor holds the lock; the file is still there and still locked
+        let inherited = InstanceLock::inherit(&lock_path, fd).unwrap();
+        assert!(matches!(
+            InstanceLock::acquire(&lock_path),
+            Err(InstanceError::AlreadyRunning { .. })
+        ));
+
+        drop(inherited);
+        assert!(!lock_path.exists());
+    }
+
+    #[test]
+    fn test_prepare_missing_path() {
+        let temp_dir = TempDir::new().unwrap();
+        assert!(
+            prepare_socket_path(temp_dir.path().join("missing.sock"), SocketProtocol::Agent)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_prepare_removes_stale_socket() {
+        let temp_dir = TempDir::new().unwrap();
+        let sock_path = temp_dir.path().join("stale.sock");
+
+        // Dropping a std listener closes it but leaves the socket file behind
+        drop(UnixListener::bind(&sock_path).unwrap());
+        assert!(sock_path.exists());
+
+        prepare_socket_path(&sock_path, SocketProtocol::Agent).unwrap();
+        assert!(!sock_path.exists());
+    }
+
+    #[test]
+    fn test_prepare_refuses_live_socket() {
+        let temp_dir = TempDir::new().unwrap();
+        let sock_path = temp_dir.path().join("live.sock");
+        let listener = UnixListener::bind(&sock_path).unwrap();
+
+        // An agent without keys
+        let agent = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 5];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(request[4], SSH_AGENTC_REQUEST_IDENTITIES);
+            stream
+                .write_all(&[0, 0, 0, 5, SSH_AGENT_IDENTITIES_ANSWER, 0, 0, 0, 0])
+                .unwrap();
+        });
+
+        assert!(matches!(
+            prepare_socket_path(&sock_path, SocketProtocol::Agent),
+            Err(InstanceError::SocketInUse(_))
+        ));
+        assert!(sock_path.exists());
+        agent.join().unwrap();
+    }
+
+    #[test]
+    fn test_prepare_refuses_unresponsive_socket() {
+        let temp_dir = TempDir::new().unwrap();
+        let sock_path = temp_dir.path().join("hung.sock");
+        // Connections are queued but never answered
+        let _listener = UnixListener::bind(&sock_path).unwrap();
+
+        assert!(matches!(
+            prepare_socket_path(&sock_path, SocketProtocol::Control),
+            Err(InstanceError::SocketInUse(_))
+        ));
+        assert!(sock_path.exists());
+    }
+
+    #[test]
+    fn test_prepare_refuses_regular_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("not-a-socket");
+        std::fs::write(&path, "important").unwrap();
+
+        assert!(matches!(
+            prepare_socket_path(&path, SocketProtocol::Agent),
+            Err(InstanceError::NotASocket(_))
+        ));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "important");
+    }
+
+    fn mode_of(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().mode() & 0o777
+    }
+
+    #[test]
+    fn test_check_socket_dir_creates_private_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("nested").join("sockets");
+
+        check_socket_dir(&dir, None).unwrap();
+        assert_eq!(mode_of(&dir), 0o700);
+    }
+
+    #[test]
+    fn test_check_socket_dir_refuses_open_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("open");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::set_permissions(&dir, Permissions::from_mode(0o755)).unwrap();
+
+        assert!(matches!(
+            check_socket_dir(&dir, None),
+            Err(InstanceError::InsecureDirectory { .. })
+        ));
+    }
+
+    #[test]
+    fn test_check_socket_dir_allows_group_search() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("shared");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::set_permissions(&dir, Permissions::from_mode(0o710)).unwrap();
+        let gid = std::fs::metadata(&dir).unwrap().gid();
+
+        assert!(check_socket_dir(&dir, None).is_err());
+        assert!(check_socket_dir(&dir, Some(gid)).is_ok());
+        // Only search permission may be granted to the group
+        std::fs::set_permissions(&dir, Permissions::from_mode(0o750)).unwrap();
+        assert!(check_socket_dir(&dir, Some(gid)).is_err());
+    }
+
+    #[test]
+    fn test_restrict_socket() {
+        let temp_dir = TempDir::new().unwrap();
+        let sock_path = temp_dir.path().join("test.sock");
+        let _listener = std::os::unix::net::UnixListener::bind(&sock_path).unwrap();
+
+        restrict_socket(&sock_path, None).unwrap();
+        assert_eq!(mode_of(&sock_path), 0o600);
+
+        let gid = std::fs::metadata(&sock_path).unwrap().gid();
+        restrict_socket(&sock_path, Some(gid)).unwrap();
+        assert_eq!(mode_of(&sock_path), 0o660);
+    }
+
+    #[test]
+    fn test_resolve_group() {
+        assert_eq!(resolve_group("1234").unwrap(), 1234);
+        assert_eq!(resolve_group("root").unwrap(), 0);
+        assert!(resolve_group("no-such-group-ssh-agent-mux").is_err());
+    }
+}
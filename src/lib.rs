@@ -1,15 +1,23 @@
 use std::{
     collections::HashMap,
+    future::Future,
     os::unix::net::UnixStream,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use ssh_agent_lib::{
     agent::{self, Agent, ListeningSocket, Session},
     client,
     error::AgentError,
-    proto::{extension::QueryResponse, Extension, Identity, SignRequest},
+    proto::{
+        extension::QueryResponse, AddIdentity, AddIdentityConstrained, Extension, Identity,
+        RemoveIdentity, SignRequest,
+    },
     ssh_key::{public::KeyData as PubKeyData, Signature},
 };
 use tokio::{
@@ -17,18 +25,41 @@ use tokio::{
     sync::{Mutex, OwnedMutexGuard},
 };
 
+pub mod config_file;
+pub mod constraints;
 pub mod control;
+pub mod expand;
+pub mod health;
+pub mod instance;
+pub mod metrics;
+pub mod session;
 pub mod socket_manager;
+pub mod state_file;
 pub mod watcher;
 
-use socket_manager::SocketManager;
+use constraints::KeyConstraints;
+use metrics::Metrics;
+use socket_manager::{SocketManager, Upstream};
 
 type KnownPubKeysMap = HashMap<PubKeyData, PathBuf>;
 type KnownPubKeys = Arc<Mutex<KnownPubKeysMap>>;
 type SharedSocketManager = Arc<Mutex<SocketManager>>;
+type SharedKeyConstraints = Arc<Mutex<KeyConstraints>>;
+type SharedMetrics = Arc<Mutex<Metrics>>;
 
-/// Only the `request_identities`, `sign`, and `extension` commands are implemented. For
-/// `extension`, only the `session-bind@openssh.com` and `query` extensions are supported.
+/// Time limit for signing by an upstream agent without a configured `timeout`, leaving time to
+/// touch a hardware key or confirm the signature
+const SIGN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Listeners that take over from a running listening socket, e.g. after its socket file was
+/// deleted and the path had to be bound again
+pub type ListenerReplacements =
+    tokio::sync::mpsc::UnboundedReceiver<std::os::unix::net::UnixListener>;
+
+/// Only the `request_identities`, `sign`, `add_identity`, `add_identity_constrained`,
+/// `remove_identity` and `extension` commands are implemented. Added keys go to the
+/// highest-priority upstream agent. For `extension`, only the `session-bind@openssh.com` and
+/// `query` extensions are supported.
 #[ssh_agent_lib::async_trait]
 impl Session for MuxAgent {
     async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
@@ -42,14 +73,33 @@ impl Session for MuxAgent {
         log::trace!("incoming: sign({})", &fingerprint);
 
         if let Some(agent_sock_path) = self.get_agent_sock_for_pubkey(&request.pubkey).await? {
+            if let Err(e) = self.check_key_constraints(&request.pubkey).await {
+                self.metrics
+                    .lock()
+                    .await
+                    .record_sign(fingerprint.to_string(), None, None);
+                return Err(e);
+            }
+
             log::info!(
                 "Requesting signature with key {} from upstream agent <{}>",
                 &fingerprint,
                 agent_sock_path.display()
             );
 
-            let mut client = self.connect_upstream_agent(agent_sock_path)?;
-            client.sign(request).await
+            let start = Instant::now();
+            let signature = self.sign_upstream(&agent_sock_path, request).await;
+            self.metrics.lock().await.record_sign(
+                fingerprint.to_string(),
+                Some(&agent_sock_path),
+                signature.is_ok().then(|| start.elapsed()),
+            );
+            let signature = signature?;
+            self.socket_manager
+                .lock()
+                .await
+                .record_sign(&agent_sock_path);
+            Ok(signature)
         } else {
             log::error!("No upstream agent found for public key {}", &fingerprint);
             log::trace!("Known keys:\n{:#?}", self.known_keys);
@@ -59,6 +109,121 @@ impl Session for MuxAgent {
         }
     }
 
+    async fn add_identity(&mut self, identity: AddIdentity) -> Result<(), AgentError> {
+        log::trace!("incoming: add_identity");
+        let sock_path = self.get_agent_sock_for_add().await?;
+
+        let mut client = self.connect_upstream_agent(&sock_path)?;
+        self.timed_request(&sock_path, client.add_identity(identity.clone()))
+            .await?;
+        log::info!("Added key to upstream agent <{}>", sock_path.display());
+
+        if let Some(pubkey) = constraints::credential_pubkey(&identity.credential) {
+            // A plain re-add replaces any constraints previously enforced by the mux
+            self.key_constraints.lock().await.remove(&pubkey);
+            self.known_keys.lock().await.insert(pubkey, sock_path);
+        }
+        Ok(())
+    }
+
+    async fn add_identity_constrained(
+        &mut self,
+        identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        log::trace!(
+            "incoming: add_identity_constrained({:?})",
+            identity.constraints
+        );
+        let sock_path = self.get_agent_sock_for_add().await?;
+        let pubkey = constraints::credential_pubkey(&identity.identity.credential);
+
+        let mut client = self.connect_upstream_agent(&sock_path)?;
+        match self
+            .timed_request(
+                &sock_path,
+                client.add_identity_constrained(identity.clone()),
+            )
+            .await
+        {
+            Ok(()) => {
+                log::info!(
+                    "Added constrained key to upstream agent <{}>",
+                    sock_path.display()
+                );
+                if let Some(pubkey) = pubkey {
+                    self.key_constraints.lock().await.remove(&pubkey);
+                    self.known_keys.lock().await.insert(pubkey, sock_path);
+                }
+                Ok(())
+            }
+            Err(e) if constraints::can_enforce(&identity.constraints) => {
+                // The mux needs the public key to track the constraints; refuse rather than
+                // add a key that would silently outlive its lifetime
+                let Some(pubkey) = pubkey else {
+                    log::error!("Cannot determine public key of constrained key; refusing add");
+                    return Err(e);
+                };
+
+                log::info!(
+                    "Upstream agent <{}> rejected constrained key ({e}); enforcing constraints in mux",
+                    sock_path.display()
+                );
+                let mut client = self.connect_upstream_agent(&sock_path)?;
+                self.timed_request(&sock_path, client.add_identity(identity.identity))
+                    .await?;
+
+                let lifetime = self.key_constraints.lock().await.insert(
+                    pubkey.clone(),
+                    sock_path.clone(),
+                    &identity.constraints,
+                );
+                self.known_keys.lock().await.insert(pubkey, sock_path);
+
+                if let Some(lifetime) = lifetime {
+                    let mut this = self.clone();
+                    // The timer must not keep this session counted as active
+                    this.session = None;
+                    tokio::spawn(async move {
+                        tokio::time::sleep(lifetime).await;
+                        this.expire_constrained_keys().await;
+                    });
+                }
+                Ok(())
+            }
+            Err(e) => {
+                log::error!(
+                    "Upstream agent <{}> rejected constrained key and the mux cannot enforce {:?}: {e}",
+                    sock_path.display(),
+                    identity.constraints
+                );
+                Err(e)
+            }
+        }
+    }
+
+    async fn remove_identity(&mut self, identity: RemoveIdentity) -> Result<(), AgentError> {
+        let fingerprint = identity.pubkey.fingerprint(Default::default());
+        log::trace!("incoming: remove_identity({})", &fingerprint);
+
+        let Some(sock_path) = self.get_agent_sock_for_pubkey(&identity.pubkey).await? else {
+            log::error!("No upstream agent found for public key {}", &fingerprint);
+            return Err(AgentError::Failure);
+        };
+
+        let mut client = self.connect_upstream_agent(&sock_path)?;
+        self.timed_request(&sock_path, client.remove_identity(identity.clone()))
+            .await?;
+        log::info!(
+            "Removed key {} from upstream agent <{}>",
+            &fingerprint,
+            sock_path.display()
+        );
+
+        self.key_constraints.lock().await.remove(&identity.pubkey);
+        self.known_keys.lock().await.remove(&identity.pubkey);
+        Ok(())
+    }
+
     async fn extension(&mut self, request: Extension) -> Result<Option<Extension>, AgentError> {
         log::trace!("incoming: extension({})", request.name);
         match request.name.as_str() {
@@ -76,7 +241,10 @@ impl Session for MuxAgent {
                     // that don't support the extension (but the default is Failure if there are no
                     // successful upstream responses)
                     if let Ok(mut client) = self.connect_upstream_agent(sock_path) {
-                        match client.extension(request.clone()).await {
+                        match self
+                            .timed_request(sock_path, client.extension(request.clone()))
+                            .await
+                        {
                             // Any agent succeeding is an overall success
                             Ok(v) => {
                                 session_bind_suceeded = true;
@@ -109,6 +277,29 @@ impl Session for MuxAgent {
 pub struct MuxAgent {
     socket_manager: SharedSocketManager,
     known_keys: KnownPubKeys,
+    key_constraints: SharedKeyConstraints,
+    /// Usage of upstream agents and keys, kept alongside the socket manager
+    metrics: SharedMetrics,
+    /// Number of connected client sessions
+    sessions: Arc<AtomicUsize>,
+    /// Set on the per-connection clones created for each session
+    session: Option<Arc<SessionGuard>>,
+}
+
+/// Counts a client session as active for as long as it's alive
+struct SessionGuard(Arc<AtomicUsize>);
+
+impl SessionGuard {
+    fn new(sessions: Arc<AtomicUsize>) -> Self {
+        sessions.fetch_add(1, Ordering::Relaxed);
+        Self(sessions)
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl MuxAgent {
@@ -134,15 +325,21 @@ impl MuxAgent {
         );
         log::debug!("Upstream agent sockets: {:?}", &socket_paths);
 
-        let socket_manager = Arc::new(Mutex::new(SocketManager::new(socket_paths)));
-        Self::run_with_manager(listen_sock, socket_manager).await
+        let socket_manager = Arc::new(Mutex::new(SocketManager::new(
+            socket_paths.into_iter().map(Upstream::from).collect(),
+        )));
+        Self::run_with_manager(listen_sock, socket_manager, None).await
     }
 
     /// Run a MuxAgent with a shared SocketManager, listening for SSH agent protocol requests
     /// This variant allows external control of the socket list via the shared manager
+    ///
+    /// Listeners received on `replacements` take over from the listening socket without
+    /// disturbing sessions that are already connected.
     pub async fn run_with_manager(
         listen_sock: impl AsRef<Path>,
         socket_manager: SharedSocketManager,
+        replacements: Option<ListenerReplacements>,
     ) -> Result<(), AgentError> {
         let listen_sock = listen_sock.as_ref();
 
@@ -151,7 +348,7 @@ impl MuxAgent {
             listen_sock.display()
         );
 
-        let listen_sock = match SelfDeletingUnixListener::bind(listen_sock) {
+        let mut listen_sock = match SelfDeletingUnixListener::bind(listen_sock) {
             Ok(s) => s,
             err => {
                 log::error!(
@@ -161,27 +358,66 @@ impl MuxAgent {
                 err?
             }
         };
+        listen_sock.replacements = replacements;
 
-        let this = Self {
-            socket_manager,
-            known_keys: Default::default(),
-        };
+        let this = Self::new_with_manager(socket_manager);
         agent::listen(listen_sock, this).await
     }
 
+    /// Serve SSH agent protocol requests on an already-listening socket, e.g. one passed in by a
+    /// service manager for socket activation
+    ///
+    /// The socket file is left in place on exit, since it belongs to whoever created the listener.
+    /// Listeners received on `replacements` take over from the listening socket without
+    /// disturbing sessions that are already connected.
+    pub async fn serve(
+        self,
+        listener: std::os::unix::net::UnixListener,
+        replacements: Option<ListenerReplacements>,
+    ) -> Result<(), AgentError> {
+        let mut listen_sock = SelfDeletingUnixListener::from_std(listener)?;
+        listen_sock.replacements = replacements;
+
+        log::info!(
+            "Starting agent with shared socket manager on <{}>",
+            listen_sock.path.display()
+        );
+
+        agent::listen(listen_sock, self).await
+    }
+
     /// Create a new MuxAgent with a shared SocketManager (for use with watcher)
     pub fn new_with_manager(socket_manager: SharedSocketManager) -> Self {
         Self {
             socket_manager,
             known_keys: Default::default(),
+            key_constraints: Default::default(),
+            metrics: Default::default(),
+            sessions: Default::default(),
+            session: None,
         }
     }
 
+    /// Number of client sessions currently being served by this agent
+    pub fn active_sessions(&self) -> usize {
+        self.sessions.load(Ordering::Relaxed)
+    }
+
+    /// Number of keys whose lifetime or confirmation constraints are enforced by the mux itself
+    pub async fn mux_constrained_key_count(&self) -> usize {
+        self.key_constraints.lock().await.len()
+    }
+
     /// Get a clone of the shared socket manager
     pub fn socket_manager(&self) -> SharedSocketManager {
         self.socket_manager.clone()
     }
 
+    /// Get a clone of the shared usage metrics
+    pub fn metrics(&self) -> SharedMetrics {
+        self.metrics.clone()
+    }
+
     fn connect_upstream_agent(
         &self,
         sock_path: impl AsRef<Path>,
@@ -209,10 +445,17 @@ impl MuxAgent {
         &mut self,
         pubkey: &PubKeyData,
     ) -> Result<Option<PathBuf>, AgentError> {
-        // Refresh available identities if the public key isn't found;
-        // hold lock for duration of signing operation
+        // Refresh available identities if the public key isn't found, or its agent was disabled
+        // or skipped after failing since; hold lock for duration of signing operation
         let mut known_keys = self.known_keys.clone().lock_owned().await;
-        if !known_keys.contains_key(pubkey) {
+        let unavailable = match known_keys.get(pubkey) {
+            Some(sock_path) => {
+                let manager = self.socket_manager.lock().await;
+                manager.is_disabled(sock_path) || manager.is_skipped(sock_path)
+            }
+            None => false,
+        };
+        if !known_keys.contains_key(pubkey) || unavailable {
             log::debug!("Key not found, re-requesting keys from upstream agents");
             let _ = self.refresh_identities(&mut known_keys).await?;
         }
@@ -220,6 +463,143 @@ impl MuxAgent {
         Ok(maybe_agent)
     }
 
+    /// Pick the upstream agent that receives keys added through the mux
+    async fn get_agent_sock_for_add(&self) -> Result<PathBuf, AgentError> {
+        let manager = self.socket_manager.lock().await;
+        manager
+            .get_ordered_sockets()
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                log::error!("Cannot add key: no upstream agents available");
+                AgentError::Failure
+            })
+    }
+
+    /// Time limit for requests to the upstream agent at `sock_path`: the configured one, or else
+    /// `default`
+    async fn upstream_timeout(&self, sock_path: &Path, default: Duration) -> Duration {
+        self.socket_manager
+            .lock()
+            .await
+            .upstream_timeout(sock_path)
+            .unwrap_or(default)
+    }
+
+    /// Wait for `request` to the upstream agent at `sock_path`, failing once its time limit passes
+    async fn timed_request<T>(
+        &self,
+        sock_path: &Path,
+        request: impl Future<Output = Result<T, AgentError>>,
+    ) -> Result<T, AgentError> {
+        self.timed_request_or(sock_path, health::PROBE_TIMEOUT, request)
+            .await
+    }
+
+    /// Like `timed_request`, waiting for `default` if the upstream agent has no time limit
+    async fn timed_request_or<T>(
+        &self,
+        sock_path: &Path,
+        default: Duration,
+        request: impl Future<Output = Result<T, AgentError>>,
+    ) -> Result<T, AgentError> {
+        let timeout = self.upstream_timeout(sock_path, default).await;
+        match tokio::time::timeout(timeout, request).await {
+            Ok(result) => result,
+            Err(_) => {
+                log::error!(
+                    "Upstream agent <{}> didn't answer within {:?}",
+                    sock_path.display(),
+                    timeout
+                );
+                self.record_failure(sock_path).await;
+                Err(AgentError::Failure)
+            }
+        }
+    }
+
+    /// Ask the upstream agent at `sock_path` for a signature
+    async fn sign_upstream(
+        &self,
+        sock_path: &Path,
+        request: SignRequest,
+    ) -> Result<Signature, AgentError> {
+        let mut client = match self.connect_upstream_agent(sock_path) {
+            Ok(client) => client,
+            Err(e) => {
+                self.record_failure(sock_path).await;
+                return Err(e);
+            }
+        };
+        self.timed_request_or(sock_path, SIGN_TIMEOUT, client.sign(request))
+            .await
+    }
+
+    /// Skip the upstream agent at `sock_path` for a while, since it failed to answer
+    async fn record_failure(&self, sock_path: &Path) {
+        self.socket_manager.lock().await.record_failure(sock_path);
+    }
+
+    /// Refuse to sign with keys whose mux-enforced constraints are not satisfied
+    async fn check_key_constraints(&self, pubkey: &PubKeyData) -> Result<(), AgentError> {
+        let fingerprint = pubkey.fingerprint(Default::default());
+        let requires_confirmation = {
+            let key_constraints = self.key_constraints.lock().await;
+            if key_constraints.is_expired(pubkey) {
+                log::warn!("Refusing to sign with expired key {}", &fingerprint);
+                return Err(AgentError::Failure);
+            }
+            key_constraints.requires_confirmation(pubkey)
+        };
+
+        if requires_confirmation {
+            let prompt = format!("Allow use of key {}?", &fingerprint);
+            if !constraints::ask_confirmation(prompt).await {
+                log::warn!("User refused use of key {}", &fingerprint);
+                return Err(AgentError::Failure);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove keys whose mux-enforced lifetime has elapsed from their upstream agents
+    async fn expire_constrained_keys(&mut self) {
+        let expired = self.key_constraints.lock().await.take_expired();
+        for (pubkey, key) in expired {
+            let fingerprint = pubkey.fingerprint(Default::default());
+            log::info!(
+                "Lifetime of key {} expired; removing it from upstream agent <{}>",
+                &fingerprint,
+                key.socket.display()
+            );
+
+            let removal = match self.connect_upstream_agent(&key.socket) {
+                Ok(mut client) => {
+                    let request = client.remove_identity(RemoveIdentity {
+                        pubkey: pubkey.clone(),
+                    });
+                    self.timed_request(&key.socket, request).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = removal {
+                log::error!(
+                    "Failed to remove expired key {} from upstream agent <{}>: {}",
+                    &fingerprint,
+                    key.socket.display(),
+                    e
+                );
+                // Keep hiding the key and refusing to sign with it
+                self.key_constraints
+                    .lock()
+                    .await
+                    .reinstate(pubkey.clone(), key);
+            }
+
+            self.known_keys.lock().await.remove(&pubkey);
+        }
+    }
+
     // Factored out so that the known_keys lock can be held across a total request that includes a
     // refresh of keys from upstream agents
     async fn refresh_identities(
@@ -231,13 +611,19 @@ impl MuxAgent {
 
         log::debug!("Refreshing identities");
 
-        // Get current ordered socket list from manager
+        // Get current ordered socket list from manager, forgetting the usage of sockets it no
+        // longer knows
         let socket_paths = {
             let manager = self.socket_manager.lock().await;
+            self.metrics
+                .lock()
+                .await
+                .retain_upstreams(|path| manager.is_known(path));
             manager.get_ordered_sockets()
         };
 
         for sock_path in &socket_paths {
+            let start = Instant::now();
             let mut client = match self.connect_upstream_agent(sock_path) {
                 Ok(c) => c,
                 Err(_) => {
@@ -245,10 +631,65 @@ impl MuxAgent {
                         "Ignoring missing upstream agent socket: {}",
                         sock_path.display()
                     );
+                    self.record_failure(sock_path).await;
+                    self.metrics
+                        .lock()
+                        .await
+                        .record_identities(sock_path, &[], None);
+                    continue;
+                }
+            };
+            // A hung agent mustn't hide the keys of all the others
+            let request = client.request_identities();
+            let timeout = self
+                .upstream_timeout(sock_path, health::PROBE_TIMEOUT)
+                .await;
+            let agent_identities = match tokio::time::timeout(timeout, request).await {
+                Ok(result) => result,
+                Err(_) => {
+                    log::warn!(
+                        "Ignoring upstream agent <{}>, which didn't list its keys within {:?}",
+                        sock_path.display(),
+                        timeout
+                    );
+                    self.record_failure(sock_path).await;
+                    self.metrics
+                        .lock()
+                        .await
+                        .record_identities(sock_path, &[], None);
+                    continue;
+                }
+            };
+            let latency = start.elapsed();
+            let mut agent_identities = match agent_identities {
+                Ok(identities) => {
+                    self.socket_manager.lock().await.record_success(sock_path);
+                    identities
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Ignoring upstream agent <{}>, which failed to list its keys: {}",
+                        sock_path.display(),
+                        e
+                    );
+                    self.record_failure(sock_path).await;
+                    self.metrics
+                        .lock()
+                        .await
+                        .record_identities(sock_path, &[], None);
                     continue;
                 }
             };
-            let agent_identities = client.request_identities().await?;
+            {
+                // Hide keys that outlived a mux-enforced lifetime but couldn't be removed upstream
+                let key_constraints = self.key_constraints.lock().await;
+                agent_identities.retain(|id| !key_constraints.is_expired(&id.pubkey));
+            }
+            self.metrics.lock().await.record_identities(
+                sock_path,
+                &agent_identities,
+                Some(latency),
+            );
             {
                 for id in &agent_identities {
                     known_keys.insert(id.pubkey.clone(), sock_path.clone());
@@ -272,7 +713,9 @@ impl Agent<SelfDeletingUnixListener> for MuxAgent {
         &mut self,
         _socket: &<SelfDeletingUnixListener as ListeningSocket>::Stream,
     ) -> impl Session {
-        self.clone()
+        let mut session = self.clone();
+        session.session = Some(Arc::new(SessionGuard::new(self.sessions.clone())));
+        session
     }
 }
 
@@ -281,17 +724,45 @@ impl Agent<SelfDeletingUnixListener> for MuxAgent {
 struct SelfDeletingUnixListener {
     path: PathBuf,
     listener: UnixListener,
+    /// Inherited listeners are owned by the service manager, which cleans up the socket file
+    delete_on_drop: bool,
+    replacements: Option<ListenerReplacements>,
 }
 
 impl SelfDeletingUnixListener {
     fn bind(path: impl AsRef<Path>) -> std::io::Result<Self> {
         let path = path.as_ref().to_path_buf();
-        UnixListener::bind(&path).map(|listener| Self { path, listener })
+        let listener = instance::bind_socket(&path, None, instance::SocketProtocol::Agent)?;
+        listener.set_nonblocking(true)?;
+        UnixListener::from_std(listener).map(|listener| Self {
+            path,
+            listener,
+            delete_on_drop: true,
+            replacements: None,
+        })
+    }
+
+    fn from_std(listener: std::os::unix::net::UnixListener) -> std::io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        let path = listener
+            .local_addr()?
+            .as_pathname()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        UnixListener::from_std(listener).map(|listener| Self {
+            path,
+            listener,
+            delete_on_drop: false,
+            replacements: None,
+        })
     }
 }
 
 impl Drop for SelfDeletingUnixListener {
     fn drop(&mut self) {
+        if !self.delete_on_drop {
+            return;
+        }
         log::debug!("Cleaning up socket {}", self.path.display());
         let _ = std::fs::remove_file(&self.path);
     }
@@ -302,8 +773,35 @@ impl ListeningSocket for SelfDeletingUnixListener {
     type Stream = tokio::net::UnixStream;
 
     async fn accept(&mut self) -> std::io::Result<Self::Stream> {
-        UnixListener::accept(&self.listener)
-            .await
-            .map(|(s, _addr)| s)
+        accept_replaceable(&mut self.listener, &mut self.replacements).await
+    }
+}
+
+/// Accept a connection on `listener`, switching over to any replacement listener that arrives
+/// in the meantime
+pub(crate) async fn accept_replaceable(
+    listener: &mut UnixListener,
+    replacements: &mut Option<ListenerReplacements>,
+) -> std::io::Result<tokio::net::UnixStream> {
+    loop {
+        let Some(rx) = replacements.as_mut() else {
+            return UnixListener::accept(listener).await.map(|(s, _addr)| s);
+        };
+        let replacement = tokio::select! {
+            res = UnixListener::accept(listener) => return res.map(|(s, _addr)| s),
+            replacement = rx.recv() => replacement,
+        };
+
+        match replacement {
+            Some(new) => match new
+                .set_nonblocking(true)
+                .and_then(|()| UnixListener::from_std(new))
+            {
+                Ok(new) => *listener = new,
+                Err(e) => log::error!("Failed to switch to replacement listener: {e}"),
+            },
+            // Nothing can send replacements anymore
+            None => *replacements = None,
+        }
     }
 }
@@ -0,0 +1,257 @@
+//! Counting how often upstream agents and keys are used, and how long they take to answer.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use ssh_agent_lib::proto::Identity;
+
+use crate::control::{KeyMetrics, MetricsReport, UpstreamMetrics, UsageStats};
+
+/// Usage of upstream agents and keys since the metrics were created
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    since: SystemTime,
+    upstreams: HashMap<PathBuf, Usage>,
+    /// By key fingerprint
+    keys: HashMap<String, KeyUsage>,
+}
+
+/// Request counts and latency of an upstream agent or key
+#[derive(Debug, Clone, Default)]
+struct Usage {
+    identity_requests: u64,
+    sign_attempts: u64,
+    sign_successes: u64,
+    sign_failures: u64,
+    /// Number of answered requests whose latency is counted
+    answered: u64,
+    total_latency: Duration,
+    max_latency: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+struct KeyUsage {
+    comment: Option<String>,
+    /// Socket that last provided the key
+    upstream: Option<PathBuf>,
+    usage: Usage,
+}
+
+impl Usage {
+    fn record_latency(&mut self, latency: Duration) {
+        self.answered += 1;
+        self.total_latency = self.total_latency.saturating_add(latency);
+        self.max_latency = self.max_latency.max(latency);
+    }
+
+    fn record_sign(&mut self, latency: Option<Duration>) {
+        self.sign_attempts += 1;
+        match latency {
+            Some(latency) => {
+                self.sign_successes += 1;
+                self.record_latency(latency);
+            }
+            None => self.sign_failures += 1,
+        }
+    }
+
+    fn stats(&self) -> UsageStats {
+        let answered = (self.answered > 0).then_some(self.answered);
+        UsageStats {
+            identity_requests: self.identity_requests,
+            sign_attempts: self.sign_attempts,
+            sign_successes: self.sign_successes,
+            sign_failures: self.sign_failures,
+            avg_latency_ms: answered
+                .map(|answered| (self.total_latency.as_millis() / u128::from(answered)) as u64),
+            max_latency_ms: answered.map(|_| self.max_latency.as_millis() as u64),
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            since: SystemTime::now(),
+            upstreams: HashMap::new(),
+            keys: HashMap::new(),
+        }
+    }
+}
+
+impl Metrics {
+    /// Note that the agent at `upstream` was asked for its keys, listing `identities` after
+    /// `latency`; None if it didn't answer
+    pub fn record_identities(
+        &mut self,
+        upstream: &Path,
+        identities: &[Identity],
+        latency: Option<Duration>,
+    ) {
+        let usage = self.upstreams.entry(upstream.to_path_buf()).or_default();
+        usage.identity_requests += 1;
+        if let Some(latency) = latency {
+            usage.record_latency(latency);
+        }
+
+        for identity in identities {
+            let fingerprint = identity.pubkey.fingerprint(Default::default()).to_string();
+            let key = self.keys.entry(fingerprint).or_default();
+            key.comment = Some(identity.comment.clone());
+            key.upstream = Some(upstream.to_path_buf());
+            key.usage.identity_requests += 1;
+        }
+    }
+
+    /// Note a request to sign with the key with `fingerprint`, sent to the agent at `upstream`,
+    /// which signed after `latency`; None if it failed
+    ///
+    /// Without `upstream`, the mux refused the request itself, which isn't held against the agent
+    /// providing the key, and only keys listed before are counted.
+    pub fn record_sign(
+        &mut self,
+        fingerprint: String,
+        upstream: Option<&Path>,
+        latency: Option<Duration>,
+    ) {
+        let Some(upstream) = upstream else {
+            if let Some(key) = self.keys.get_mut(&fingerprint) {
+                key.usage.record_sign(latency);
+            }
+            return;
+        };
+        let key = self.keys.entry(fingerprint).or_default();
+        key.usage.record_sign(latency);
+        key.upstream = Some(upstream.to_path_buf());
+        self.upstreams
+            .entry(upstream.to_path_buf())
+            .or_default()
+            .record_sign(latency);
+    }
+
+    /// Drop the usage of upstream agents for which `keep` is false, along with the keys they
+    /// last provided
+    pub fn retain_upstreams(&mut self, keep: impl Fn(&Path) -> bool) {
+        self.upstreams.retain(|path, _| keep(path));
+        self.keys
+            .retain(|_, key| key.upstream.as_deref().is_none_or(&keep));
+    }
+
+    /// Report usage, most used (by sign attempts, then listings) first, naming upstream agents
+    /// with `upstream_name`
+    pub fn report(&self, upstream_name: impl Fn(&Path) -> Option<String>) -> MetricsReport {
+        let most_used = |usage: &Usage| Reverse((usage.sign_attempts, usage.identity_requests));
+
+        let mut upstreams: Vec<_> = self.upstreams.iter().collect();
+        upstreams.sort_by_key(|(path, usage)| (most_used(usage), *path));
+        let mut keys: Vec<_> = self.keys.iter().collect();
+        keys.sort_by_key(|(fingerprint, key)| (most_used(&key.usage), *fingerprint));
+
+        MetricsReport {
+            since: DateTime::<Utc>::from(self.since).to_rfc3339(),
+            upstreams: upstreams
+                .into_iter()
+                .map(|(path, usage)| UpstreamMetrics {
+                    path: path.display().to_string(),
+                    name: upstream_name(path),
+                    usage: usage.stats(),
+                })
+                .collect(),
+            keys: keys
+                .into_iter()
+                .map(|(fingerprint, key)| KeyMetrics {
+                    fingerprint: fingerprint.clone(),
+                    comment: key.comment.clone(),
+                    upstream: key.upstream.as_ref().map(|p| p.display().to_string()),
+                    usage: key.usage.stats(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ssh_agent_lib::ssh_key::PublicKey;
+
+    const KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA7kYrS3/ZJXCKBGS5t8t4eD1UrTDcbwwdOP9Nu2ypLu test-key";
+
+    fn identity() -> Identity {
+        let key = PublicKey::from_openssh(KEY).unwrap();
+        Identity {
+            pubkey: key.key_data().clone(),
+            comment: key.comment().to_string(),
+        }
+    }
+
+    #[test]
+    fn test_record_and_report() {
+        let busy = PathBuf::from("/tmp/busy.sock");
+        let idle = PathBuf::from("/tmp/idle.sock");
+        let identity = identity();
+        let fingerprint = identity.pubkey.fingerprint(Default::default()).to_string();
+        let mut metrics = Metrics::default();
+
+        metrics.record_identities(&idle, &[], None);
+        metrics.record_identities(&busy, &[identity], Some(Duration::from_millis(2)));
+        metrics.record_sign(
+            fingerprint.clone(),
+            Some(&busy),
+            Some(Duration::from_millis(10)),
+        );
+        metrics.record_sign(fingerprint.clone(), Some(&busy), None);
+        // Refused by the mux: counted for the key, but not for its agent
+        metrics.record_sign(fingerprint.clone(), None, None);
+        // Never listed
+        metrics.record_sign("SHA256:unknown".to_string(), None, None);
+
+        let report = metrics.report(|path| (path == busy).then(|| "busy".to_string()));
+        assert_eq!(report.upstreams.len(), 2);
+        let upstream = &report.upstreams[0];
+        assert_eq!(upstream.name.as_deref(), Some("busy"));
+        assert_eq!(
+            upstream.usage,
+            UsageStats {
+                identity_requests: 1,
+                sign_attempts: 2,
+                sign_successes: 1,
+                sign_failures: 1,
+                avg_latency_ms: Some(6),
+                max_latency_ms: Some(10),
+            }
+        );
+        assert_eq!(report.upstreams[1].path, "/tmp/idle.sock");
+        assert_eq!(report.upstreams[1].usage.avg_latency_ms, None);
+
+        let key = &report.keys[0];
+        assert_eq!(key.fingerprint, fingerprint);
+        assert_eq!(key.comment.as_deref(), Some("test-key"));
+        assert_eq!(key.upstream.as_deref(), Some("/tmp/busy.sock"));
+        assert_eq!(key.usage.identity_requests, 1);
+        assert_eq!(key.usage.sign_attempts, 3);
+        assert_eq!(key.usage.sign_failures, 2);
+        assert_eq!(key.usage.max_latency_ms, Some(10));
+        assert_eq!(report.keys.len(), 1);
+    }
+
+    #[test]
+    fn test_retain_upstreams() {
+        let gone = PathBuf::from("/tmp/gone.sock");
+        let kept = PathBuf::from("/tmp/kept.sock");
+        let mut metrics = Metrics::default();
+
+        metrics.record_identities(&gone, &[identity()], None);
+        metrics.record_identities(&kept, &[], None);
+        metrics.retain_upstreams(|path| path == kept);
+
+        let report = metrics.report(|_| None);
+        assert_eq!(report.upstreams.len(), 1);
+        assert_eq!(report.upstreams[0].path, "/tmp/kept.sock");
+        assert!(report.keys.is_empty());
+    }
+}
@@ -0,0 +1,454 @@
+//! Attribution of forwarded agent sockets to the SSH sessions that created them.
+//!
+//! sshd listens on a forwarded agent socket from the process serving the session, which also
+//! holds the client's TCP connection. On Linux, the socket's inode is looked up in
+//! `/proc/net/unix`, the process holding it is found among the `/proc/*/fd` links, and the
+//! remote address comes from the TCP connection among that process's other sockets. Only our
+//! own processes can be inspected, which is all we need. Elsewhere, sockets aren't attributed.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Flag in `/proc/net/unix` marking listening sockets (`__SO_ACCEPTCON`)
+const LISTENING: u32 = 0x10000;
+
+/// State of established connections in `/proc/net/tcp`
+const TCP_ESTABLISHED: &str = "01";
+
+/// The process listening on a forwarded agent socket, and the session it serves
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SshSession {
+    /// Process ID of the sshd session process
+    pub pid: u32,
+    /// Address the SSH client connected from
+    #[serde(default)]
+    pub remote_addr: Option<SocketAddr>,
+    /// When the session process started
+    #[serde(default)]
+    pub login_time: Option<DateTime<Utc>>,
+    /// Terminal of the session, like `pts/3`; none for sessions without one
+    #[serde(default)]
+    pub tty: Option<String>,
+}
+
+impl fmt::Display for SshSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PID {}", self.pid)?;
+        if let Some(remote_addr) = self.remote_addr {
+            write!(f, " from {remote_addr}")?;
+        }
+        if let Some(tty) = &self.tty {
+            write!(f, " on {tty}")?;
+        }
+        if let Some(login_time) = self.login_time {
+            write!(f, ", logged in {}", login_time.to_rfc3339())?;
+        }
+        Ok(())
+    }
+}
+
+/// Find the session serving the forwarded agent socket at `socket_path`, if it can be told
+pub fn find_session(socket_path: &Path) -> Option<SshSession> {
+    if cfg!(target_os = "linux") {
+        ProcFs::new("/proc").find_session(socket_path)
+    } else {
+        None
+    }
+}
+
+/// Find the sessions serving the forwarded agent sockets at `socket_paths`
+///
+/// `/proc` is searched on a blocking thread, so that the async runtime keeps serving clients.
+pub async fn find_sessions(socket_paths: Vec<PathBuf>) -> Vec<(PathBuf, Option<SshSession>)> {
+    let lookup = tokio::task::spawn_blocking(move || {
+        socket_paths
+            .into_iter()
+            .map(|path| {
+                let session = find_session(&path);
+                (path, session)
+            })
+            .collect()
+    });
+    lookup.await.unwrap_or_else(|e| {
+        log::warn!("Failed to look up SSH sessions of forwarded agents: {e}");
+        vec![]
+    })
+}
+
+/// Find the session serving the forwarded agent socket at `socket_path`, searching `/proc` on a
+/// blocking thread
+pub async fn find_session_async(socket_path: PathBuf) -> Option<SshSession> {
+    find_sessions(vec![socket_path])
+        .await
+        .pop()
+        .and_then(|(_, session)| session)
+}
+
+/// A procfs mount
+struct ProcFs {
+    root: PathBuf,
+}
+
+impl ProcFs {
+    fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn read(&self, path: impl AsRef<Path>) -> Option<String> {
+        std::fs::read_to_string(self.root.join(path)).ok()
+    }
+
+    fn find_session(&self, socket_path: &Path) -> Option<SshSession> {
+        // The kernel lists the path the socket was bound to, which symlinks lead to
+        let socket_path = socket_path.canonicalize().ok()?;
+        let inode = listening_inode(&self.read("net/unix")?, &socket_path)?;
+        let pid = self.process_holding(inode)?;
+        let stat = self
+            .read(format!("{pid}/stat"))
+            .and_then(|s| ProcessStat::parse(&s));
+
+        let tty = stat
+            .as_ref()
+            .and_then(|stat| tty_name(stat.tty_nr))
+            .or_else(|| {
+                self.read(format!("{pid}/cmdline"))
+                    .and_then(|c| cmdline_tty(&c))
+            });
+
+        Some(SshSession {
+            pid,
+            remote_addr: self.remote_addr(pid),
+            login_time: stat.and_then(|stat| self.start_time(stat.start_ticks)),
+            tty,
+        })
+    }
+
+    /// The lowest PID among the processes with the socket `inode` open
+    fn process_holding(&self, inode: u64) -> Option<u32> {
+        let mut pids: Vec<u32> = std::fs::read_dir(&self.root)
+            .ok()?
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+            .collect();
+        pids.sort_unstable();
+        pids.into_iter()
+            .find(|pid| self.socket_inodes(*pid).contains(&inode))
+    }
+
+    /// Inodes of the sockets process `pid` has open; none if we may not look
+    fn socket_inodes(&self, pid: u32) -> Vec<u64> {
+        let Ok(fds) = std::fs::read_dir(self.root.join(format!("{pid}/fd"))) else {
+            return Vec::new();
+        };
+        fds.flatten()
+            .filter_map(|fd| {
+                let target = std::fs::read_link(fd.path()).ok()?;
+                target
+                    .to_str()?
+                    .strip_prefix("socket:[")?
+                    .strip_suffix(']')?
+                    .parse()
+                    .ok()
+            })
+            .collect()
+    }
+
+    /// Remote end of an established TCP connection held by process `pid`
+    fn remote_addr(&self, pid: u32) -> Option<SocketAddr> {
+        let inodes = self.socket_inodes(pid);
+        ["net/tcp", "net/tcp6"]
+            .into_iter()
+            .filter_map(|table| self.read(table))
+            .find_map(|table| {
+                inodes
+                    .iter()
+                    .find_map(|inode| established_remote(&table, *inode))
+            })
+    }
+
+    /// Wall-clock time of `start_ticks` clock ticks after boot
+    fn start_time(&self, start_ticks: u64) -> Option<DateTime<Utc>> {
+        let boot_time: u64 = self
+            .read("stat")?
+            .lines()
+            .find_map(|line| line.strip_prefix("btime "))?
+            .trim()
+            .parse()
+            .ok()?;
+        let since_boot = Duration::from_millis(start_ticks * 1000 / clock_ticks_per_second());
+        Some((UNIX_EPOCH + Duration::from_secs(boot_time) + since_boot).into())
+    }
+}
+
+fn clock_ticks_per_second() -> u64 {
+    // SAFETY: sysconf has no preconditions
+    match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => ticks as u64,
+        _ => 100,
+    }
+}
+
+/// Inode of the socket listening on `path`, from the contents of `/proc/net/unix`
+fn listening_inode(net_unix: &str, path: &Path) -> Option<u64> {
+    net_unix.lines().skip(1).find_map(|line| {
+        // Num RefCount Protocol Flags Type St Inode Path, where the path may contain spaces
+        let mut fields = Vec::with_capacity(7);
+        let mut rest = line;
+        for _ in 0..7 {
+            rest = rest.trim_start();
+            let end = rest.find(char::is_whitespace)?;
+            fields.push(&rest[..end]);
+            rest = &rest[end..];
+        }
+        let listening = u32::from_str_radix(fields[3], 16).is_ok_and(|f| f & LISTENING != 0);
+        (listening && Path::new(rest.trim()) == path)
+            .then(|| fields[6].parse().ok())
+            .flatten()
+    })
+}
+
+/// Remote address of the established connection with socket `inode`, from the contents of
+/// `/proc/net/tcp` or `/proc/net/tcp6`
+fn established_remote(net_tcp: &str, inode: u64) -> Option<SocketAddr> {
+    net_tcp.lines().skip(1).find_map(|line| {
+        // sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 10 || fields[3] != TCP_ESTABLISHED || fields[9].parse() != Ok(inode) {
+            return None;
+        }
+        parse_tcp_addr(fields[2])
+    })
+}
+
+/// Parse an `ADDRESS:PORT` pair of hex numbers from `/proc/net/tcp{,6}`
+///
+/// Addresses are printed as 32-bit words in host byte order.
+fn parse_tcp_addr(text: &str) -> Option<SocketAddr> {
+    let (addr, port) = text.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut bytes = Vec::with_capacity(16);
+    for i in (0..addr.len()).step_by(8) {
+        let word = u32::from_str_radix(addr.get(i..i + 8)?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+    let ip = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?).to_canonical(),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// The fields of `/proc/PID/stat` we need
+struct ProcessStat {
+    tty_nr: u32,
+    start_ticks: u64,
+}
+
+impl ProcessStat {
+    fn parse(stat: &str) -> Option<Self> {
+        // The command name in parentheses may contain spaces and parentheses itself
+        let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+        Some(Self {
+            tty_nr: fields.get(4)?.parse().ok()?,
+            start_ticks: fields.get(19)?.parse().ok()?,
+        })
+    }
+}
+
+/// Name of the terminal with device number `tty_nr`, for pseudo-terminals and virtual consoles
+fn tty_name(tty_nr: u32) -> Option<String> {
+    let major = (tty_nr >> 8) & 0xfff;
+    let minor = (tty_nr & 0xff) | ((tty_nr >> 12) & 0xfff00);
+    match major {
+        136..=143 => Some(format!("pts/{}", (major - 136) * 256 + minor)),
+        4 if minor < 64 => Some(format!("tty{minor}")),
+        _ => None,
+    }
+}
+
+/// Terminal named in an sshd process title like `sshd: user@pts/3`
+fn cmdline_tty(cmdline: &str) -> Option<String> {
+    let title = cmdline.split('\0').next()?.trim();
+    if !title.starts_with("sshd") {
+        return None;
+    }
+    let (_, tty) = title.rsplit_once('@')?;
+    (tty != "notty" && !tty.is_empty()).then(|| tty.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_tables() {
+        let net_unix = "\
+Num       RefCount Protocol Flags    Type St Inode Path
+0000000000000000: 00000003 00000000 00000000 0001 03 5001 /tmp/ssh-abc/agent.42
+0000000000000000: 00000002 00000000 00010000 0001 01 5000 /tmp/ssh-abc/agent.42
+0000000000000000: 00000002 00000000 00010000 0001 01 5002 /tmp/with space/agent.1
+0000000000000000: 00000002 00000000 00000000 0002 01 5003
+";
+        assert_eq!(
+            listening_inode(net_unix, Path::new("/tmp/ssh-abc/agent.42")),
+            Some(5000)
+        );
+        assert_eq!(
+            listening_inode(net_unix, Path::new("/tmp/with space/agent.1")),
+            Some(5002)
+        );
+        assert_eq!(listening_inode(net_unix, Path::new("/tmp/other")), None);
+
+        let local = u32::from_ne_bytes([192, 0, 2, 1]);
+        let remote = u32::from_ne_bytes([203, 0, 113, 5]);
+        let net_tcp = format!(
+            "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: {local:08X}:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 6000 1
+   1: {local:08X}:0016 {remote:08X}:C822 01 00000000:00000000 02:00094B12 00000000     0        0 6001 4
+"
+        );
+        assert_eq!(established_remote(&net_tcp, 6000), None);
+        assert_eq!(
+            established_remote(&net_tcp, 6001),
+            Some("203.0.113.5:51234".parse().unwrap())
+        );
+
+        let words: Vec<String> = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 7)
+            .octets()
+            .chunks(4)
+            .map(|w| format!("{:08X}", u32::from_ne_bytes(w.try_into().unwrap())))
+            .collect();
+        assert_eq!(
+            parse_tcp_addr(&format!("{}:0016", words.concat())),
+            Some("[2001:db8::7]:22".parse().unwrap())
+        );
+        let mapped: Vec<String> = Ipv4Addr::new(198, 51, 100, 2)
+            .to_ipv6_mapped()
+            .octets()
+            .chunks(4)
+            .map(|w| format!("{:08X}", u32::from_ne_bytes(w.try_into().unwrap())))
+            .collect();
+        assert_eq!(
+            parse_tcp_addr(&format!("{}:0016", mapped.concat())),
+            Some("198.51.100.2:22".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_process_details() {
+        let stat = ProcessStat::parse(
+            "4242 (sshd: a (b)) S 1 4242 4242 34817 4242 4194560 1 0 0 0 0 0 0 0 20 0 1 0 12345 0",
+        )
+        .unwrap();
+        assert_eq!(stat.tty_nr, 34817);
+        assert_eq!(stat.start_ticks, 12345);
+        assert_eq!(tty_name(34817).as_deref(), Some("pts/1"));
+        assert_eq!(tty_name(0), None);
+
+        assert_eq!(
+            cmdline_tty("sshd: alice@pts/3\0\0").as_deref(),
+            Some("pts/3")
+        );
+        assert_eq!(
+            cmdline_tty("sshd-session: alice@pts/0").as_deref(),
+            Some("pts/0")
+        );
+        assert_eq!(cmdline_tty("sshd: alice@notty"), None);
+        assert_eq!(cmdline_tty("/usr/bin/ssh-agent\0-D\0"), None);
+    }
+
+    #[test]
+    fn test_find_session_in_proc_tree() {
+        use std::os::unix::fs::symlink;
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let proc_root = temp_dir.path().join("proc");
+        std::fs::create_dir(temp_dir.path().join("ssh-abc")).unwrap();
+        let socket_path = temp_dir
+            .path()
+            .join("ssh-abc")
+            .canonicalize()
+            .unwrap()
+            .join("agent.42");
+        std::fs::write(&socket_path, "").unwrap();
+
+        std::fs::create_dir_all(proc_root.join("net")).unwrap();
+        std::fs::create_dir_all(proc_root.join("4242/fd")).unwrap();
+        std::fs::create_dir_all(proc_root.join("5000/fd")).unwrap();
+        std::fs::write(
+            proc_root.join("net/unix"),
+            format!(
+                "Num RefCount Protocol Flags Type St Inode Path\n\
+                 0000000000000000: 00000002 00000000 00010000 0001 01 7000 {}\n",
+                socket_path.display()
+            ),
+        )
+        .unwrap();
+        let remote = u32::from_ne_bytes([203, 0, 113, 5]);
+        std::fs::write(
+            proc_root.join("net/tcp"),
+            format!(
+                "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n   \
+                 0: 0100007F:0016 {remote:08X}:C822 01 00000000:00000000 00:00000000 00000000 0 0 7001 1\n"
+            ),
+        )
+        .unwrap();
+        std::fs::write(proc_root.join("stat"), "cpu 1 2 3\nbtime 1700000000\n").unwrap();
+        let ticks = clock_ticks_per_second();
+        std::fs::write(
+            proc_root.join("4242/stat"),
+            format!(
+                "4242 (sshd) S 1 4242 4242 0 -1 0 0 0 0 0 0 0 0 0 20 0 1 0 {} 0",
+                60 * ticks
+            ),
+        )
+        .unwrap();
+        std::fs::write(proc_root.join("4242/cmdline"), "sshd: alice@pts/3\0").unwrap();
+        symlink("socket:[7001]", proc_root.join("4242/fd/3")).unwrap();
+        symlink("socket:[7000]", proc_root.join("4242/fd/9")).unwrap();
+        symlink("/dev/null", proc_root.join("5000/fd/0")).unwrap();
+
+        // Found through a symlink too
+        let link = temp_dir.path().join("link");
+        symlink(&socket_path, &link).unwrap();
+
+        let session = ProcFs::new(&proc_root).find_session(&link).unwrap();
+        assert_eq!(
+            session,
+            SshSession {
+                pid: 4242,
+                remote_addr: Some("203.0.113.5:51234".parse().unwrap()),
+                login_time: Some(DateTime::from_timestamp(1700000060, 0).unwrap()),
+                tty: Some("pts/3".to_string()),
+            }
+        );
+        assert_eq!(
+            session.to_string(),
+            "PID 4242 from 203.0.113.5:51234 on pts/3, logged in 2023-11-14T22:14:20+00:00"
+        );
+
+        assert_eq!(
+            ProcFs::new(&proc_root).find_session(&proc_root.join("stat")),
+            None
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_find_own_listener() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let socket_path = temp_dir.path().join("agent.sock");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+
+        let session = find_session(&socket_path).unwrap();
+        assert_eq!(session.pid, std::process::id());
+        assert_eq!(session.remote_addr, None);
+        assert!(session.login_time.is_some());
+    }
+}
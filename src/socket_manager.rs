@@ -1,55 +1,353 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::time::SystemTime;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
 
-use crate::control::{SocketInfo, SocketSource};
+use crate::control::{BreakerInfo, BreakerState, SocketInfo, SocketSource};
+use crate::expand;
+use crate::health::HealthProbe;
+use crate::session::SshSession;
 
 /// Manages both configured and watched sockets with proper ordering
 #[derive(Debug, Clone)]
 pub struct SocketManager {
-    configured_sockets: Vec<PathBuf>,
+    configured_sockets: Vec<Upstream>,
+    /// Configured sockets with glob patterns replaced by the sockets they currently match
+    expanded_sockets: Vec<Upstream>,
     watched_sockets: HashMap<PathBuf, WatchedSocket>,
     /// When the daemon started (for uptime calculation)
     daemon_start_time: SystemTime,
     /// Last time a health check was performed
     last_health_check: Option<SystemTime>,
+    /// How long vanished watched sockets keep their place for a reconnecting session
+    grace_period: Duration,
+    /// How sockets are ordered
+    ordering: OrderingStrategy,
+    /// Last time each socket's agent signed successfully
+    last_signed: HashMap<PathBuf, SystemTime>,
+    /// Positions sockets were pinned to at runtime (1 = first)
+    pins: HashMap<PathBuf, usize>,
+    /// Priorities set at runtime, taking precedence over the ordering strategy
+    priority_overrides: HashMap<PathBuf, PriorityOverride>,
+    /// Outcome of the latest health check of each socket
+    health: HashMap<PathBuf, HealthProbe>,
+    /// Circuit breakers of sockets whose agent failed
+    breakers: HashMap<PathBuf, Breaker>,
+    /// Sockets disabled at runtime, which keep their place but aren't used
+    disabled: HashSet<PathBuf>,
+    /// Notified whenever the state that `export_state` captures changes
+    changes: Arc<Notify>,
+}
+
+/// How long an upstream agent is skipped after it fails; doubles with each further failure
+pub const BREAKER_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Longest an upstream agent is skipped after failing
+pub const BREAKER_MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How upstream sockets are ordered, for listing keys, signing and adding keys
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OrderingStrategy {
+    /// Watched sockets, newest session first, then configured sockets by priority
+    #[default]
+    NewestFirst,
+    /// Watched sockets, oldest session first, then configured sockets by priority
+    OldestFirst,
+    /// Configured sockets by priority, then watched sockets, newest session first
+    ConfiguredFirst,
+    /// All sockets by priority, with watched sockets at priority 0; ties are newest first
+    Priority,
+    /// Sockets whose agent most recently signed successfully first, then newest first
+    RecentSign,
+}
+
+impl OrderingStrategy {
+    const ALL: [OrderingStrategy; 5] = [
+        OrderingStrategy::NewestFirst,
+        OrderingStrategy::OldestFirst,
+        OrderingStrategy::ConfiguredFirst,
+        OrderingStrategy::Priority,
+        OrderingStrategy::RecentSign,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            OrderingStrategy::NewestFirst => "newest-first",
+            OrderingStrategy::OldestFirst => "oldest-first",
+            OrderingStrategy::ConfiguredFirst => "configured-first",
+            OrderingStrategy::Priority => "priority",
+            OrderingStrategy::RecentSign => "recent-sign",
+        }
+    }
+}
+
+impl std::fmt::Display for OrderingStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for OrderingStrategy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|strategy| strategy.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(|s| s.name()).collect();
+                format!(
+                    "unknown socket order {name:?}, expected one of {}",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Errors from overriding the priority of a socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PriorityOverrideError {
+    /// The socket isn't in the order
+    NotInOrder,
+    /// The override would last past the latest time that can be represented
+    DurationTooLong(Duration),
+}
+
+impl std::fmt::Display for PriorityOverrideError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PriorityOverrideError::NotInOrder => write!(f, "socket is not in the order"),
+            PriorityOverrideError::DurationTooLong(duration) => {
+                write!(f, "duration of {}s is too long", duration.as_secs())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PriorityOverrideError {}
+
+/// A socket in the ordering
+#[derive(Clone, Copy)]
+enum OrderedSocket<'a> {
+    Watched(&'a WatchedSocket),
+    Configured(&'a Upstream),
+}
+
+impl OrderedSocket<'_> {
+    fn path(&self) -> &Path {
+        match self {
+            OrderedSocket::Watched(socket) => &socket.path,
+            OrderedSocket::Configured(upstream) => &upstream.path,
+        }
+    }
+
+    fn priority(&self) -> i32 {
+        match self {
+            OrderedSocket::Watched(_) => 0,
+            OrderedSocket::Configured(upstream) => upstream.priority,
+        }
+    }
+}
+
+/// An upstream agent from the configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Upstream {
+    /// Name to refer to the agent by, in place of its socket path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Socket path of the agent
+    pub path: PathBuf,
+    /// Agents with a higher priority are asked first; ties keep their configured order
+    #[serde(default)]
+    pub priority: i32,
+    /// Disabled agents stay in the configuration but aren't used
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Seconds to wait for the agent to answer a request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// Free-form labels, shown alongside the agent
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Upstream {
+    /// This upstream, or a copy for each existing socket matching it if its path is a glob
+    /// pattern
+    pub fn expand(&self) -> Vec<Upstream> {
+        if !expand::is_glob(&self.path) {
+            return vec![self.clone()];
+        }
+        expand::expand_glob(&self.path)
+            .into_iter()
+            .map(|path| Upstream {
+                path,
+                ..self.clone()
+            })
+            .collect()
+    }
+}
+
+impl From<PathBuf> for Upstream {
+    /// An entry of the plain `agent_sock_paths` list, with default settings
+    fn from(path: PathBuf) -> Self {
+        Self {
+            name: None,
+            path,
+            priority: 0,
+            enabled: true,
+            timeout: None,
+            labels: vec![],
+        }
+    }
 }
 
 /// Represents a watched socket with metadata
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WatchedSocket {
     path: PathBuf,
     created_at: SystemTime,
-    /// Whether socket was healthy at last check
-    last_healthy: Option<bool>,
-    /// Last time this socket was health checked
+    /// SSH session serving the socket, if it could be found
+    #[serde(default)]
+    session: Option<SshSession>,
+    /// When the socket vanished, if it did; it's kept in case its session comes back
+    #[serde(default)]
+    gone_since: Option<SystemTime>,
+    /// Start of the vanished socket from the same client whose place this one took
+    #[serde(default)]
+    inherited_start: Option<DateTime<Utc>>,
+}
+
+/// Priority of a socket set at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct PriorityOverride {
+    priority: i32,
+    /// When the override lapses, if ever
+    until: Option<SystemTime>,
+}
+
+impl PriorityOverride {
+    fn active(&self) -> bool {
+        self.until.is_none_or(|until| until > SystemTime::now())
+    }
+}
+
+/// Failures in a row of an upstream agent, which is skipped until `retry_at`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Breaker {
+    failures: u32,
+    retry_at: SystemTime,
+}
+
+impl Breaker {
+    fn state(&self) -> BreakerState {
+        if self.retry_at > SystemTime::now() {
+            BreakerState::Open
+        } else {
+            BreakerState::HalfOpen
+        }
+    }
+}
+
+/// How long an upstream agent is skipped after failing `failures` times in a row
+fn backoff(failures: u32) -> Duration {
+    BREAKER_BACKOFF
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(BREAKER_MAX_BACKOFF)
+}
+
+/// Runtime state of a SocketManager that doesn't come from configuration, so it can be handed
+/// over to another daemon process
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SocketManagerState {
+    daemon_start_time: SystemTime,
     last_health_check: Option<SystemTime>,
-    /// Number of keys from this socket (if known)
-    key_count: Option<usize>,
+    watched_sockets: Vec<WatchedSocket>,
+    #[serde(default)]
+    last_signed: HashMap<PathBuf, SystemTime>,
+    #[serde(default)]
+    pins: HashMap<PathBuf, usize>,
+    #[serde(default)]
+    priority_overrides: HashMap<PathBuf, PriorityOverride>,
+    #[serde(default)]
+    health: HashMap<PathBuf, HealthProbe>,
+    #[serde(default)]
+    disabled: HashSet<PathBuf>,
+}
+
+impl SocketManagerState {
+    /// Paths of the watched sockets
+    pub fn watched_paths(&self) -> Vec<PathBuf> {
+        self.watched_sockets
+            .iter()
+            .map(|s| s.path.clone())
+            .collect()
+    }
 }
 
 impl WatchedSocket {
-    fn new(path: PathBuf) -> Self {
+    fn new(path: PathBuf, session: Option<SshSession>) -> Self {
         Self {
+            session,
             path,
             created_at: SystemTime::now(),
-            last_healthy: None,
-            last_health_check: None,
-            key_count: None,
+            gone_since: None,
+            inherited_start: None,
         }
     }
+
+    /// When the socket's session started, or else when the socket was added; for a socket that
+    /// took the place of a vanished one, when that one started
+    fn started_at(&self) -> DateTime<Utc> {
+        self.inherited_start
+            .or_else(|| self.session.as_ref().and_then(|session| session.login_time))
+            .unwrap_or_else(|| self.created_at.into())
+    }
+
+    /// Whether the socket vanished more than `grace_period` ago
+    fn expired(&self, grace_period: Duration) -> bool {
+        self.gone_since
+            .is_some_and(|since| since.elapsed().unwrap_or_default() >= grace_period)
+    }
+
+    /// Address of the client the socket's session came from
+    fn client(&self) -> Option<IpAddr> {
+        self.session
+            .as_ref()
+            .and_then(|session| session.remote_addr)
+            .map(|addr| addr.ip())
+    }
 }
 
 impl SocketManager {
     /// Create a new SocketManager with configured sockets
-    pub fn new(configured_sockets: Vec<PathBuf>) -> Self {
+    pub fn new(configured_sockets: Vec<Upstream>) -> Self {
         let manager = Self {
+            expanded_sockets: expand_all(&configured_sockets),
             configured_sockets,
             watched_sockets: HashMap::new(),
             daemon_start_time: SystemTime::now(),
             last_health_check: None,
+            grace_period: Duration::ZERO,
+            ordering: OrderingStrategy::default(),
+            last_signed: HashMap::new(),
+            pins: HashMap::new(),
+            priority_overrides: HashMap::new(),
+            health: HashMap::new(),
+            breakers: HashMap::new(),
+            disabled: HashSet::new(),
+            changes: Arc::new(Notify::new()),
         };
         manager.log_state("Initialized socket manager");
         manager
@@ -68,73 +366,407 @@ impl SocketManager {
             .unwrap_or(0)
     }
 
-    /// Get ordered list of sockets: watched (newest first) + configured
+    /// Keep watched sockets that vanish in place for `grace_period`, so that a session that
+    /// reconnects from the same client in the meantime gets its place back
+    pub fn set_grace_period(&mut self, grace_period: Duration) {
+        self.grace_period = grace_period;
+    }
+
+    /// Order sockets with `strategy` from now on
+    pub fn set_ordering(&mut self, strategy: OrderingStrategy) {
+        if self.ordering != strategy {
+            self.ordering = strategy;
+            self.log_state(format!(
+                "Active sockets after switching to {strategy} ordering"
+            ));
+        }
+    }
+
+    /// The strategy sockets are ordered with
+    pub fn ordering(&self) -> OrderingStrategy {
+        self.ordering
+    }
+
+    /// Get ordered list of sockets, by the ordering strategy
+    ///
+    /// Vanished watched sockets, disabled sockets and sockets skipped after their agent failed
+    /// are left out.
     pub fn get_ordered_sockets(&self) -> Vec<PathBuf> {
-        let mut result = Vec::new();
+        let mut sockets = self.get_active_sockets();
+        sockets.retain(|path| !self.disabled.contains(path) && !self.is_skipped(path));
+        sockets
+    }
+
+    /// Like `get_ordered_sockets`, but including disabled sockets and sockets skipped after their
+    /// agent failed
+    pub fn get_active_sockets(&self) -> Vec<PathBuf> {
+        self.ordered()
+            .into_iter()
+            .filter(|socket| !matches!(socket, OrderedSocket::Watched(s) if s.gone_since.is_some()))
+            .map(|socket| socket.path().to_path_buf())
+            .collect()
+    }
+
+    /// Watched sockets and enabled configured sockets, by the ordering strategy
+    ///
+    /// Vanished sockets are included until their grace period is over.
+    fn ordered(&self) -> Vec<OrderedSocket<'_>> {
+        // Watched newest first: by login time of their SSH session if known, or else by when
+        // they were added
+        let mut watched: Vec<_> = self
+            .watched_sockets
+            .values()
+            .filter(|s| !s.expired(self.grace_period))
+            .collect();
+        watched.sort_by(|a, b| a.started_at().cmp(&b.started_at()).reverse());
+        let watched = watched.into_iter().map(OrderedSocket::Watched);
+
+        // Configured highest priority first, ties in configured order
+        let mut configured: Vec<_> = self.expanded_sockets.iter().filter(|u| u.enabled).collect();
+        configured.sort_by_key(|u| std::cmp::Reverse(u.priority));
+        let configured = configured.into_iter().map(OrderedSocket::Configured);
+
+        let mut ordered: Vec<_> = match self.ordering {
+            OrderingStrategy::OldestFirst => watched.rev().chain(configured).collect(),
+            OrderingStrategy::ConfiguredFirst => configured.chain(watched).collect(),
+            _ => watched.chain(configured).collect(),
+        };
+        // Stable sorts, so that ties keep the newest-first order
+        match self.ordering {
+            OrderingStrategy::Priority => ordered.sort_by_key(|socket| {
+                std::cmp::Reverse(
+                    self.priority_override(socket.path())
+                        .unwrap_or_else(|| socket.priority()),
+                )
+            }),
+            OrderingStrategy::RecentSign => ordered.sort_by_key(|socket| {
+                std::cmp::Reverse(self.last_signed.get(socket.path()).copied())
+            }),
+            _ => {}
+        }
+        // Priority overrides replace the priority for priority ordering; otherwise they put
+        // sockets before (positive) or after (negative) the ones without an override
+        if self.ordering != OrderingStrategy::Priority {
+            ordered.sort_by_key(|socket| {
+                std::cmp::Reverse(self.priority_override(socket.path()).unwrap_or(0))
+            });
+        }
+
+        // Pinned sockets go to their positions last, lowest position first
+        let mut pinned = Vec::new();
+        ordered.retain(|socket| match self.pins.get(socket.path()) {
+            Some(&position) => {
+                pinned.push((position, *socket));
+                false
+            }
+            None => true,
+        });
+        pinned.sort_by_key(|(position, _)| *position);
+        for (position, socket) in pinned {
+            let index = position.saturating_sub(1).min(ordered.len());
+            ordered.insert(index, socket);
+        }
+        ordered
+    }
+
+    /// The priority set for `path` at runtime, unless it lapsed
+    fn priority_override(&self, path: &Path) -> Option<i32> {
+        self.priority_overrides
+            .get(path)
+            .filter(|o| o.active())
+            .map(|o| o.priority)
+    }
+
+    /// Position of a socket in the order (1 = first), if it's in there
+    pub fn position(&self, path: &Path) -> Option<usize> {
+        self.ordered()
+            .iter()
+            .position(|socket| socket.path() == path)
+            .map(|index| index + 1)
+    }
+
+    /// Pin a socket to `position` in the order (1 = first) until it's unpinned, returning the
+    /// position it ends up at, or None if it isn't in the order
+    pub fn pin(&mut self, path: &Path, position: usize) -> Option<usize> {
+        self.position(path)?;
+        let position = position.max(1);
+        log::info!("Pinning socket at position {position}: {}", path.display());
+        self.pins.insert(path.to_path_buf(), position);
+        self.changes.notify_one();
+        self.log_state(format!("Active sockets after pinning {}", path.display()));
+        self.position(path)
+    }
+
+    /// Unpin a socket, returning whether it was pinned
+    pub fn unpin(&mut self, path: &Path) -> bool {
+        if self.pins.remove(path).is_none() {
+            return false;
+        }
+        log::info!("Unpinned socket: {}", path.display());
+        self.changes.notify_one();
+        self.log_state(format!("Active sockets after unpinning {}", path.display()));
+        true
+    }
+
+    /// Move a socket `offset` places down the order (up if negative) and pin it there,
+    /// returning its new position, or None if it isn't in the order
+    pub fn move_by(&mut self, path: &Path, offset: isize) -> Option<usize> {
+        let last = self.ordered().len();
+        let position = self.position(path)?.saturating_add_signed(offset);
+        self.pin(path, position.clamp(1, last))
+    }
+
+    /// Override the priority of a socket, for `duration` or until it's cleared, returning the
+    /// position it ends up at
+    pub fn set_priority_override(
+        &mut self,
+        path: &Path,
+        priority: i32,
+        duration: Option<Duration>,
+    ) -> Result<usize, PriorityOverrideError> {
+        self.position(path)
+            .ok_or(PriorityOverrideError::NotInOrder)?;
+        let until = duration
+            .map(|d| {
+                SystemTime::now()
+                    .checked_add(d)
+                    .ok_or(PriorityOverrideError::DurationTooLong(d))
+            })
+            .transpose()?;
+        match duration {
+            Some(d) => log::info!(
+                "Overriding priority of {} with {priority} for {}s",
+                path.display(),
+                d.as_secs()
+            ),
+            None => log::info!("Overriding priority of {} with {priority}", path.display()),
+        }
+        self.priority_overrides
+            .insert(path.to_path_buf(), PriorityOverride { priority, until });
+        self.changes.notify_one();
+        self.log_state(format!(
+            "Active sockets after overriding priority of {}",
+            path.display()
+        ));
+        self.position(path).ok_or(PriorityOverrideError::NotInOrder)
+    }
+
+    /// Drop the priority override of a socket, returning whether it had one
+    pub fn clear_priority_override(&mut self, path: &Path) -> bool {
+        if self.priority_overrides.remove(path).is_none() {
+            return false;
+        }
+        log::info!("Cleared priority override of {}", path.display());
+        self.changes.notify_one();
+        self.log_state(format!(
+            "Active sockets after clearing priority override of {}",
+            path.display()
+        ));
+        true
+    }
+
+    /// Stop using a socket for listing keys, signing and adding keys, while keeping it and its
+    /// place; returns whether it was enabled, or None if it isn't in the order
+    pub fn disable(&mut self, path: &Path) -> Option<bool> {
+        self.position(path)?;
+        if !self.disabled.insert(path.to_path_buf()) {
+            return Some(false);
+        }
+        log::info!("Disabled socket: {}", path.display());
+        self.changes.notify_one();
+        self.log_state(format!("Active sockets after disabling {}", path.display()));
+        Some(true)
+    }
 
-        // Add watched sockets sorted by newest first
-        let mut watched: Vec<_> = self.watched_sockets.values().collect();
-        watched.sort_by(|a, b| b.created_at.cmp(&a.created_at));
-        result.extend(watched.iter().map(|s| s.path.clone()));
+    /// Use a disabled socket again, returning whether it was disabled
+    pub fn enable(&mut self, path: &Path) -> bool {
+        if !self.disabled.remove(path) {
+            return false;
+        }
+        log::info!("Enabled socket: {}", path.display());
+        self.changes.notify_one();
+        self.log_state(format!("Active sockets after enabling {}", path.display()));
+        true
+    }
 
-        // Add configured sockets in order
-        result.extend(self.configured_sockets.iter().cloned());
+    /// Whether the socket was disabled at runtime
+    pub fn is_disabled(&self, path: &Path) -> bool {
+        self.disabled.contains(path)
+    }
 
-        result
+    /// Drop pins, priority overrides, health, circuit breaker, disabling and signing time of a
+    /// socket that's gone for good
+    fn forget_socket(&mut self, path: &Path) {
+        self.last_signed.remove(path);
+        self.disabled.remove(path);
+        self.pins.remove(path);
+        self.priority_overrides.remove(path);
+        self.health.remove(path);
+        self.breakers.remove(path);
     }
 
     /// Get detailed socket information for all sockets
     pub fn get_socket_info(&self) -> Vec<SocketInfo> {
-        let mut result = Vec::new();
-        let mut order = 1;
-
-        // Add watched sockets sorted by newest first
-        let mut watched: Vec<_> = self.watched_sockets.values().collect();
-        watched.sort_by(|a, b| b.created_at.cmp(&a.created_at));
-
-        for socket in watched {
-            result.push(SocketInfo {
-                path: socket.path.display().to_string(),
-                source: SocketSource::Watched,
-                added_at: Some(format_system_time(socket.created_at)),
-                healthy: socket.last_healthy.unwrap_or(socket.path.exists()),
-                last_health_check: socket.last_health_check.map(format_system_time),
-                key_count: socket.key_count,
-                order,
-            });
-            order += 1;
+        self.ordered()
+            .into_iter()
+            .zip(1..)
+            .map(|(socket, order)| {
+                let path = socket.path();
+                let health = self.health.get(path);
+                let priority_override = self.priority_overrides.get(path).filter(|o| o.active());
+                let mut info = SocketInfo {
+                    path: path.display().to_string(),
+                    name: None,
+                    source: SocketSource::Configured,
+                    added_at: None,
+                    // Until it's checked, a socket is assumed to work if it's there
+                    healthy: health.map_or(path.exists(), HealthProbe::healthy),
+                    last_health_check: health.map(|h| format_system_time(h.checked_at)),
+                    key_count: health.and_then(|h| h.key_count),
+                    latency_ms: health
+                        .and_then(|h| h.latency)
+                        .map(|latency| latency.as_millis() as u64),
+                    health_error: health.and_then(|h| h.error.clone()),
+                    breaker: self.breaker(path),
+                    labels: vec![],
+                    session: None,
+                    gone: false,
+                    disabled: self.disabled.contains(path),
+                    last_signed: self.last_signed.get(path).copied().map(format_system_time),
+                    pinned: self.pins.get(path).copied(),
+                    priority_override: priority_override.map(|o| o.priority),
+                    priority_override_until: priority_override
+                        .and_then(|o| o.until)
+                        .map(format_system_time),
+                    order,
+                };
+                match socket {
+                    OrderedSocket::Watched(socket) => {
+                        info.source = SocketSource::Watched;
+                        info.added_at = Some(format_system_time(socket.created_at));
+                        info.session = socket.session.clone();
+                        info.gone = socket.gone_since.is_some();
+                        info.healthy &= !info.gone;
+                    }
+                    OrderedSocket::Configured(upstream) => {
+                        info.name = upstream.name.clone();
+                        info.labels = upstream.labels.clone();
+                    }
+                }
+                info
+            })
+            .collect()
+    }
+
+    /// Note that the agent at `path` just signed successfully
+    pub fn record_sign(&mut self, path: &Path) {
+        let most_recent = self.last_signed.iter().max_by_key(|(_, at)| **at);
+        let reordered = most_recent.is_none_or(|(signer, _)| signer != path);
+        self.last_signed
+            .insert(path.to_path_buf(), SystemTime::now());
+        // Another signature by the latest signer doesn't change the order worth saving
+        if reordered {
+            self.changes.notify_one();
         }
+    }
 
-        // Add configured sockets
-        for path in &self.configured_sockets {
-            result.push(SocketInfo {
-                path: path.display().to_string(),
-                source: SocketSource::Configured,
-                added_at: None,
-                healthy: path.exists(),
-                last_health_check: None,
-                key_count: None,
-                order,
-            });
-            order += 1;
+    /// Note that a request to the agent at `path` failed, skipping the agent for a while
+    ///
+    /// Failures while the agent is skipped already are of requests made before it was, so they
+    /// don't count.
+    pub fn record_failure(&mut self, path: &Path) {
+        if self.is_skipped(path) {
+            return;
+        }
+        let failures = self.breakers.get(path).map_or(0, |b| b.failures) + 1;
+        let backoff = backoff(failures);
+        log::warn!(
+            "Skipping upstream agent {} for {:?} after {} failure(s) in a row",
+            path.display(),
+            backoff,
+            failures
+        );
+        self.breakers.insert(
+            path.to_path_buf(),
+            Breaker {
+                failures,
+                retry_at: SystemTime::now() + backoff,
+            },
+        );
+    }
+
+    /// Note that a request to the agent at `path` succeeded, so it's no longer skipped
+    pub fn record_success(&mut self, path: &Path) {
+        if self.breakers.remove(path).is_some() {
+            log::info!("Upstream agent answers again: {}", path.display());
         }
+    }
 
-        result
+    /// Check if the agent at `path` is skipped for a while, since it failed
+    pub fn is_skipped(&self, path: &Path) -> bool {
+        self.breakers
+            .get(path)
+            .is_some_and(|b| b.state() == BreakerState::Open)
     }
 
-    /// Update health status for a socket
-    pub fn update_socket_health(
-        &mut self,
-        path: &PathBuf,
-        healthy: bool,
-        key_count: Option<usize>,
-    ) {
-        if let Some(socket) = self.watched_sockets.get_mut(path) {
-            socket.last_healthy = Some(healthy);
-            socket.last_health_check = Some(SystemTime::now());
-            socket.key_count = key_count;
+    /// Circuit breaker state of the agent at `path`
+    pub fn breaker(&self, path: &Path) -> BreakerInfo {
+        let Some(breaker) = self.breakers.get(path) else {
+            return BreakerInfo::default();
+        };
+        let state = breaker.state();
+        BreakerInfo {
+            state,
+            failures: breaker.failures,
+            next_retry: (state == BreakerState::Open).then(|| format_system_time(breaker.retry_at)),
+        }
+    }
+
+    /// Sockets skipped after their agent failed that are due for a retry, in socket order
+    pub fn due_for_retry(&self) -> Vec<PathBuf> {
+        self.get_active_sockets()
+            .into_iter()
+            .filter(|path| {
+                self.breakers
+                    .get(path)
+                    .is_some_and(|b| b.state() == BreakerState::HalfOpen)
+            })
+            .collect()
+    }
+
+    /// Record the outcome of a health check of the agent at `path`
+    pub fn record_health(&mut self, path: &Path, probe: HealthProbe) {
+        if probe.healthy() {
+            self.record_success(path);
+        } else {
+            self.record_failure(path);
+        }
+        let was_healthy = self.health.get(path).map(HealthProbe::healthy);
+        match (was_healthy, probe.healthy()) {
+            (Some(false), true) => {
+                log::info!("Upstream agent is healthy again: {}", path.display())
+            }
+            (Some(true) | None, false) => log::warn!(
+                "Upstream agent {} is unhealthy: {}",
+                path.display(),
+                probe.error.as_deref().unwrap_or("unknown error")
+            ),
+            _ => {}
         }
-        self.last_health_check = Some(SystemTime::now());
+        // Only a different outcome is worth saving, not the time and latency of every probe
+        let changed = self.health.get(path).is_none_or(|previous| {
+            previous.healthy() != probe.healthy() || previous.key_count != probe.key_count
+        });
+        self.last_health_check = Some(probe.checked_at);
+        self.health.insert(path.to_path_buf(), probe);
+        if changed {
+            self.changes.notify_one();
+        }
+    }
+
+    /// Outcome of the latest health check of the agent at `path`
+    pub fn health(&self, path: &Path) -> Option<&HealthProbe> {
+        self.health.get(path)
     }
 
     /// Get last health check time
@@ -142,28 +774,139 @@ impl SocketManager {
         self.last_health_check
     }
 
-    /// Add a watched socket
-    pub fn add_watched(&mut self, path: PathBuf) -> bool {
-        if self.watched_sockets.contains_key(&path) {
-            log::debug!("Socket already watched: {}", path.display());
-            return false;
+    /// Add a watched socket, served by `session` as found by [`crate::session::find_sessions`]
+    ///
+    /// A vanished socket at the same path comes back in its old place. A new socket from the same
+    /// client as a vanished one takes over that one's place.
+    pub fn add_watched(&mut self, path: PathBuf, session: Option<SshSession>) -> bool {
+        self.expire_gone();
+        if let Some(socket) = self.watched_sockets.get_mut(&path) {
+            if socket.gone_since.take().is_none() {
+                log::debug!("Socket already watched: {}", path.display());
+                return false;
+            }
+            log::info!("Vanished watched socket is back: {}", path.display());
+            self.changes.notify_one();
+            self.log_state(format!(
+                "Active sockets after forwarded agent {} came back",
+                path.display()
+            ));
+            return true;
         }
 
-        log::info!("Adding watched socket: {}", path.display());
-        let log_path = path.clone();
-        let socket = WatchedSocket::new(path.clone());
-        self.watched_sockets.insert(path, socket);
+        self.insert_watched(WatchedSocket::new(path, session));
+        true
+    }
+
+    /// Add a new watched socket, taking the place of a vanished one from the same client if
+    /// there is one
+    fn insert_watched(&mut self, mut socket: WatchedSocket) {
+        let path = socket.path.clone();
+        match &socket.session {
+            Some(session) => {
+                log::info!("Adding watched socket: {} ({session})", path.display())
+            }
+            None => log::info!("Adding watched socket: {}", path.display()),
+        }
+        if let Some(previous) = self.take_gone_from(socket.client()) {
+            log::info!(
+                "Session from {} reconnected; {} takes the place of {}",
+                socket.client().map(|ip| ip.to_string()).unwrap_or_default(),
+                path.display(),
+                previous.path.display()
+            );
+            socket.inherited_start = Some(previous.started_at());
+            if let Some(position) = self.pins.remove(&previous.path) {
+                self.pins.insert(path.clone(), position);
+            }
+            if let Some(priority) = self.priority_overrides.remove(&previous.path) {
+                self.priority_overrides.insert(path.clone(), priority);
+            }
+            if self.disabled.remove(&previous.path) {
+                self.disabled.insert(path.clone());
+            }
+        }
+        self.watched_sockets.insert(path.clone(), socket);
+        self.changes.notify_one();
         self.log_state(format!(
             "Active sockets after adding forwarded agent {}",
-            log_path.display()
+            path.display()
         ));
-        true
+    }
+
+    /// Remove the most recently vanished socket whose session came from `client`
+    fn take_gone_from(&mut self, client: Option<IpAddr>) -> Option<WatchedSocket> {
+        let client = client?;
+        let path = self
+            .watched_sockets
+            .values()
+            .filter(|s| s.gone_since.is_some() && s.client() == Some(client))
+            .max_by_key(|s| s.gone_since)?
+            .path
+            .clone();
+        self.watched_sockets.remove(&path)
+    }
+
+    /// Mark a watched socket as vanished, keeping its place for the grace period; without one,
+    /// it's removed right away
+    pub fn mark_gone(&mut self, path: &PathBuf) -> bool {
+        if self.grace_period.is_zero() {
+            return self.remove_watched(path);
+        }
+        match self.watched_sockets.get_mut(path) {
+            Some(socket) if socket.gone_since.is_none() => {
+                socket.gone_since = Some(SystemTime::now());
+                self.changes.notify_one();
+                log::info!(
+                    "Watched socket vanished, keeping its place for {}s: {}",
+                    self.grace_period.as_secs(),
+                    path.display()
+                );
+                self.log_state(format!(
+                    "Active sockets after forwarded agent {} vanished",
+                    path.display()
+                ));
+                true
+            }
+            _ => {
+                log::debug!("Socket not found in watched list: {}", path.display());
+                false
+            }
+        }
+    }
+
+    /// Remove the vanished sockets whose grace period is over, returning their paths, and drop
+    /// lapsed priority overrides
+    fn expire_gone(&mut self) -> Vec<PathBuf> {
+        self.priority_overrides.retain(|path, priority| {
+            if !priority.active() {
+                log::info!("Priority override of {} lapsed", path.display());
+            }
+            priority.active()
+        });
+
+        let grace_period = self.grace_period;
+        let mut expired = Vec::new();
+        self.watched_sockets.retain(|path, socket| {
+            if !socket.expired(grace_period) {
+                return true;
+            }
+            log::info!("Removing vanished watched socket: {}", path.display());
+            expired.push(path.clone());
+            false
+        });
+        for path in &expired {
+            self.forget_socket(path);
+        }
+        expired
     }
 
     /// Remove a watched socket
     pub fn remove_watched(&mut self, path: &PathBuf) -> bool {
         if self.watched_sockets.remove(path).is_some() {
             log::info!("Removed watched socket: {}", path.display());
+            self.forget_socket(path);
+            self.changes.notify_one();
             self.log_state(format!(
                 "Active sockets after removing forwarded agent {}",
                 path.display()
@@ -177,35 +920,87 @@ impl SocketManager {
 
     /// Validate all sockets and remove non-existent ones
     /// Returns list of removed socket paths
+    ///
+    /// Non-existent watched sockets are marked as vanished instead, if there is a grace period.
     pub fn validate_and_cleanup(&mut self) -> Vec<PathBuf> {
-        let mut removed = Vec::new();
+        let mut removed = self.expand_configured();
+        let mut vanished = false;
 
         // Check watched sockets
-        self.watched_sockets.retain(|path, _| {
-            if path.exists() {
+        let grace_period = self.grace_period;
+        self.watched_sockets.retain(|path, socket| {
+            if path.exists() || socket.gone_since.is_some() {
                 true
-            } else {
+            } else if grace_period.is_zero() {
                 log::info!("Removing non-existent watched socket: {}", path.display());
                 removed.push(path.clone());
                 false
+            } else {
+                log::info!("Watched socket vanished: {}", path.display());
+                socket.gone_since = Some(SystemTime::now());
+                vanished = true;
+                true
             }
         });
+        for path in &removed {
+            self.forget_socket(path);
+        }
+        removed.extend(self.expire_gone());
 
-        if !removed.is_empty() {
+        if !removed.is_empty() || vanished {
+            self.changes.notify_one();
             self.log_state("Active sockets after cleanup");
         }
 
         removed
     }
 
-    /// Get count of watched sockets
+    /// Re-evaluate glob patterns among the configured sockets, returning the paths that no
+    /// longer match
+    fn expand_configured(&mut self) -> Vec<PathBuf> {
+        let expanded = expand_all(&self.configured_sockets);
+        let is_in = |list: &[Upstream], path: &PathBuf| list.iter().any(|u| &u.path == path);
+
+        let added: Vec<_> = expanded
+            .iter()
+            .filter(|u| !is_in(&self.expanded_sockets, &u.path))
+            .map(|u| u.path.clone())
+            .collect();
+        let removed: Vec<_> = self
+            .expanded_sockets
+            .iter()
+            .filter(|u| !is_in(&expanded, &u.path))
+            .map(|u| u.path.clone())
+            .collect();
+        if added.is_empty() && removed.is_empty() {
+            return removed;
+        }
+
+        for path in &added {
+            log::info!("Configured pattern now matches socket: {}", path.display());
+        }
+        for path in &removed {
+            log::info!(
+                "Configured pattern no longer matches socket: {}",
+                path.display()
+            );
+        }
+        self.expanded_sockets = expanded;
+        self.log_state("Active sockets after re-evaluating configured patterns");
+        removed
+    }
+
+    /// Get count of watched sockets, not counting vanished ones
     pub fn watched_count(&self) -> usize {
-        self.watched_sockets.len()
+        self.watched_sockets
+            .values()
+            .filter(|s| s.gone_since.is_none())
+            .count()
     }
 
     /// Get count of configured sockets
     pub fn configured_count(&self) -> usize {
-        self.configured_sockets.len()
+        self.expanded_sockets.len()
     }
 
     /// Get total count of all sockets
@@ -213,27 +1008,163 @@ impl SocketManager {
         self.watched_count() + self.configured_count()
     }
 
-    /// Check if a path is already being watched
+    /// Check if a path is already being watched, and hasn't vanished
     pub fn is_watched(&self, path: &PathBuf) -> bool {
-        self.watched_sockets.contains_key(path)
+        self.watched_sockets
+            .get(path)
+            .is_some_and(|s| s.gone_since.is_none())
     }
 
     /// Check if a path is in the configured list
     pub fn is_configured(&self, path: &PathBuf) -> bool {
-        self.configured_sockets.contains(path)
+        self.expanded_sockets.iter().any(|u| &u.path == path)
+    }
+
+    /// Check if a path is watched, even if vanished, or configured
+    pub fn is_known(&self, path: &Path) -> bool {
+        self.watched_sockets.contains_key(path)
+            || self.expanded_sockets.iter().any(|u| u.path == path)
     }
 
     /// Update the configured sockets list
-    pub fn update_configured(&mut self, configured_sockets: Vec<PathBuf>) {
+    pub fn update_configured(&mut self, configured_sockets: Vec<Upstream>) {
+        self.expanded_sockets = expand_all(&configured_sockets);
         self.configured_sockets = configured_sockets;
         self.log_state("Active sockets after configuration update");
     }
 
-    /// Get the configured sockets list
-    pub fn configured_sockets(&self) -> &[PathBuf] {
+    /// Get the configured sockets list, as configured
+    pub fn configured_sockets(&self) -> &[Upstream] {
         &self.configured_sockets
     }
 
+    /// Socket path of the configured upstream called `name_or_path`, or else the path itself
+    pub fn resolve(&self, name_or_path: &str) -> PathBuf {
+        self.expanded_sockets
+            .iter()
+            .find(|u| u.name.as_deref() == Some(name_or_path))
+            .map(|u| u.path.clone())
+            .unwrap_or_else(|| PathBuf::from(name_or_path))
+    }
+
+    /// Name of the configured upstream at `path`, if it has one
+    pub fn upstream_name(&self, path: &Path) -> Option<String> {
+        self.expanded_sockets
+            .iter()
+            .find(|u| u.path == path)
+            .and_then(|u| u.name.clone())
+    }
+
+    /// How long to wait for the agent at `path` to answer, if it's limited
+    pub fn upstream_timeout(&self, path: &Path) -> Option<Duration> {
+        self.expanded_sockets
+            .iter()
+            .find(|u| u.path == path)
+            .and_then(|u| u.timeout)
+            .map(Duration::from_secs)
+    }
+
+    /// Capture the watched sockets (with their timestamps and health) and daemon start time
+    pub fn export_state(&self) -> SocketManagerState {
+        SocketManagerState {
+            daemon_start_time: self.daemon_start_time,
+            last_health_check: self.last_health_check,
+            watched_sockets: self.watched_sockets.values().cloned().collect(),
+            last_signed: self.last_signed.clone(),
+            pins: self.pins.clone(),
+            priority_overrides: self.priority_overrides.clone(),
+            health: self.health.clone(),
+            disabled: self.disabled.clone(),
+        }
+    }
+
+    /// Take over state exported by another SocketManager, replacing all watched sockets
+    ///
+    /// Configured sockets are left alone, since they come from this process's configuration.
+    pub fn restore_state(&mut self, state: SocketManagerState) {
+        self.daemon_start_time = state.daemon_start_time;
+        self.last_health_check = state.last_health_check;
+        self.watched_sockets = state
+            .watched_sockets
+            .into_iter()
+            .map(|s| (s.path.clone(), s))
+            .collect();
+        self.last_signed = state.last_signed;
+        self.pins = state.pins;
+        self.priority_overrides = state.priority_overrides;
+        self.health = state.health;
+        self.disabled = state.disabled;
+        self.changes.notify_one();
+        self.log_state("Restored socket manager state");
+    }
+
+    /// Take over state saved by an earlier daemon, keeping the watched sockets that still exist
+    /// and are `accept`ed, and the runtime placement, disabling and signing times of sockets that
+    /// are watched or configured now; returns the number of watched sockets taken over
+    ///
+    /// Unlike `restore_state`, this keeps the daemon start time, and takes the SSH sessions of the
+    /// sockets from `sessions`, as found again by [`crate::session::find_sessions`].
+    pub fn reinstate_state(
+        &mut self,
+        state: SocketManagerState,
+        mut sessions: HashMap<PathBuf, SshSession>,
+        accept: impl Fn(&Path) -> bool,
+    ) -> usize {
+        let mut reinstated = 0;
+        for mut socket in state.watched_sockets {
+            let path = socket.path.clone();
+            if self.is_known(&path) {
+                continue;
+            }
+            if !path.exists() || !accept(&path) {
+                log::info!("Not reinstating saved socket: {}", path.display());
+                continue;
+            }
+            log::info!("Reinstating saved socket: {}", path.display());
+            socket.gone_since = None;
+            socket.session = sessions.remove(&path);
+            self.watched_sockets.insert(path, socket);
+            reinstated += 1;
+        }
+
+        let known = |path: &PathBuf| self.is_known(path);
+        let last_signed: Vec<_> = state
+            .last_signed
+            .into_iter()
+            .filter(|(path, _)| known(path))
+            .collect();
+        let pins: Vec<_> = state
+            .pins
+            .into_iter()
+            .filter(|(path, _)| known(path))
+            .collect();
+        let priority_overrides: Vec<_> = state
+            .priority_overrides
+            .into_iter()
+            .filter(|(path, priority)| known(path) && priority.active())
+            .collect();
+        let health: Vec<_> = state
+            .health
+            .into_iter()
+            .filter(|(path, _)| known(path))
+            .collect();
+        let disabled: Vec<_> = state.disabled.into_iter().filter(known).collect();
+        self.last_signed.extend(last_signed);
+        self.pins.extend(pins);
+        self.priority_overrides.extend(priority_overrides);
+        self.health.extend(health);
+        self.disabled.extend(disabled);
+        self.last_health_check = self.last_health_check.or(state.last_health_check);
+
+        self.log_state("Reinstated saved socket state");
+        reinstated
+    }
+
+    /// Notified whenever the state that `export_state` captures changes
+    pub fn changes(&self) -> Arc<Notify> {
+        self.changes.clone()
+    }
+
     /// Log the current socket ordering to aid debugging
     pub fn log_state(&self, context: impl AsRef<str>) {
         let context = context.as_ref();
@@ -265,6 +1196,17 @@ impl SocketManager {
     }
 }
 
+/// Expand glob patterns in `upstreams`, keeping only the first entry for each socket
+fn expand_all(upstreams: &[Upstream]) -> Vec<Upstream> {
+    let mut expanded: Vec<Upstream> = Vec::new();
+    for upstream in upstreams.iter().flat_map(Upstream::expand) {
+        if !expanded.iter().any(|u| u.path == upstream.path) {
+            expanded.push(upstream);
+        }
+    }
+    expanded
+}
+
 /// Format a SystemTime as ISO 8601 string
 fn format_system_time(time: SystemTime) -> String {
     let datetime: DateTime<Utc> = time.into();
@@ -275,7 +1217,10 @@ fn format_system_time(time: SystemTime) -> String {
 mod tests {
     use super::*;
     use std::thread;
-    use std::time::Duration;
+
+    fn upstreams(paths: &[PathBuf]) -> Vec<Upstream> {
+        paths.iter().cloned().map(Upstream::from).collect()
+    }
 
     #[test]
     fn test_new_socket_manager() {
@@ -283,7 +1228,7 @@ mod tests {
             PathBuf::from("/tmp/agent1.sock"),
             PathBuf::from("/tmp/agent2.sock"),
         ];
-        let manager = SocketManager::new(configured.clone());
+        let manager = SocketManager::new(upstreams(&configured));
         assert_eq!(manager.configured_count(), 2);
         assert_eq!(manager.watched_count(), 0);
         assert_eq!(manager.get_ordered_sockets(), configured);
@@ -292,15 +1237,15 @@ mod tests {
     #[test]
     fn test_add_watched_socket() {
         let configured = vec![PathBuf::from("/tmp/configured.sock")];
-        let mut manager = SocketManager::new(configured);
+        let mut manager = SocketManager::new(upstreams(&configured));
 
         let watched = PathBuf::from("/tmp/watched.sock");
-        assert!(manager.add_watched(watched.clone()));
+        assert!(manager.add_watched(watched.clone(), None));
         assert_eq!(manager.watched_count(), 1);
         assert!(manager.is_watched(&watched));
 
         // Adding same socket again should return false
-        assert!(!manager.add_watched(watched));
+        assert!(!manager.add_watched(watched, None));
         assert_eq!(manager.watched_count(), 1);
     }
 
@@ -309,7 +1254,7 @@ mod tests {
         let mut manager = SocketManager::new(vec![]);
         let watched = PathBuf::from("/tmp/watched.sock");
 
-        manager.add_watched(watched.clone());
+        manager.add_watched(watched.clone(), None);
         assert_eq!(manager.watched_count(), 1);
 
         assert!(manager.remove_watched(&watched));
@@ -325,14 +1270,14 @@ mod tests {
             PathBuf::from("/tmp/configured1.sock"),
             PathBuf::from("/tmp/configured2.sock"),
         ];
-        let mut manager = SocketManager::new(configured.clone());
+        let mut manager = SocketManager::new(upstreams(&configured));
 
         let watched1 = PathBuf::from("/tmp/watched1.sock");
         let watched2 = PathBuf::from("/tmp/watched2.sock");
 
-        manager.add_watched(watched1.clone());
+        manager.add_watched(watched1.clone(), None);
         thread::sleep(Duration::from_millis(10));
-        manager.add_watched(watched2.clone());
+        manager.add_watched(watched2.clone(), None);
 
         let ordered = manager.get_ordered_sockets();
 
@@ -344,17 +1289,135 @@ mod tests {
         assert_eq!(ordered[3], configured[1]);
     }
 
+    #[test]
+    fn test_ordering_by_session_login_time() {
+        let mut manager = SocketManager::new(vec![]);
+        let older_session = PathBuf::from("/tmp/ssh-older/agent.1");
+        let newer_session = PathBuf::from("/tmp/ssh-newer/agent.1");
+        let unattributed = PathBuf::from("/tmp/ssh-unknown/agent.1");
+
+        let now = Utc::now();
+        let session = |minutes_ago| {
+            Some(SshSession {
+                pid: 4242,
+                remote_addr: Some("203.0.113.5:51234".parse().unwrap()),
+                login_time: Some(now - chrono::Duration::minutes(minutes_ago)),
+                tty: Some("pts/3".to_string()),
+            })
+        };
+
+        // Added in the opposite order of their logins, as when found by the startup scan
+        manager.add_watched(newer_session.clone(), session(5));
+        manager.add_watched(older_session.clone(), session(60));
+        manager.add_watched(unattributed.clone(), None);
+
+        assert_eq!(
+            manager.get_ordered_sockets(),
+            vec![
+                unattributed.clone(),
+                newer_session.clone(),
+                older_session.clone()
+            ]
+        );
+        let info = manager.get_socket_info();
+        assert_eq!(info[0].session, None);
+        assert_eq!(
+            info[1].session.as_ref().and_then(|s| s.remote_addr),
+            Some("203.0.113.5:51234".parse().unwrap())
+        );
+        assert_eq!(info[2].path, older_session.display().to_string());
+    }
+
+    /// A watched socket with a session from `remote_addr` that started `minutes_ago`
+    fn session_socket(path: &str, remote_addr: &str, minutes_ago: i64) -> WatchedSocket {
+        let session = SshSession {
+            pid: 4242,
+            remote_addr: Some(remote_addr.parse().unwrap()),
+            login_time: Some(Utc::now() - chrono::Duration::minutes(minutes_ago)),
+            tty: None,
+        };
+        WatchedSocket::new(PathBuf::from(path), Some(session))
+    }
+
+    #[test]
+    fn test_grace_period_for_vanished_sockets() {
+        let configured = PathBuf::from("/tmp/configured.sock");
+        let mut manager = SocketManager::new(upstreams(std::slice::from_ref(&configured)));
+        manager.set_grace_period(Duration::from_secs(60));
+        let laptop = PathBuf::from("/tmp/ssh-laptop/agent.1");
+        let desktop = PathBuf::from("/tmp/ssh-desktop/agent.1");
+        manager.insert_watched(session_socket(
+            "/tmp/ssh-laptop/agent.1",
+            "203.0.113.5:50000",
+            60,
+        ));
+        manager.insert_watched(session_socket(
+            "/tmp/ssh-desktop/agent.1",
+            "198.51.100.2:50000",
+            30,
+        ));
+        assert_eq!(
+            manager.get_ordered_sockets(),
+            vec![desktop.clone(), laptop.clone(), configured.clone()]
+        );
+
+        // A vanished socket isn't used, but keeps its place in the list
+        assert!(manager.mark_gone(&laptop));
+        assert!(!manager.mark_gone(&laptop));
+        assert!(!manager.is_watched(&laptop));
+        assert_eq!(manager.watched_count(), 1);
+        assert_eq!(
+            manager.get_ordered_sockets(),
+            vec![desktop.clone(), configured.clone()]
+        );
+        let info = manager.get_socket_info();
+        assert_eq!(info[1].path, laptop.display().to_string());
+        assert!(info[1].gone && !info[1].healthy);
+
+        // The laptop's new session gets its place back, ahead of newer sessions it would be
+        // behind otherwise
+        let reconnected = PathBuf::from("/tmp/ssh-laptop2/agent.1");
+        manager.insert_watched(session_socket(
+            "/tmp/ssh-laptop2/agent.1",
+            "203.0.113.5:50001",
+            0,
+        ));
+        assert_eq!(
+            manager.get_ordered_sockets(),
+            vec![desktop.clone(), reconnected.clone(), configured.clone()]
+        );
+        assert_eq!(manager.get_socket_info().len(), 3);
+
+        // A vanished socket that comes back at the same path is in use again
+        assert!(manager.mark_gone(&desktop));
+        assert!(manager.add_watched(desktop.clone(), None));
+        assert!(!manager.add_watched(desktop.clone(), None));
+        assert_eq!(manager.watched_count(), 2);
+
+        // Past the grace period, vanished sockets are dropped
+        manager.set_grace_period(Duration::from_millis(1));
+        assert!(manager.mark_gone(&desktop));
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(manager.get_socket_info().len(), 2);
+        assert_eq!(manager.validate_and_cleanup(), vec![desktop]);
+
+        // Without a grace period, they're dropped right away
+        manager.set_grace_period(Duration::ZERO);
+        assert!(manager.mark_gone(&reconnected));
+        assert_eq!(manager.get_ordered_sockets(), vec![configured]);
+    }
+
     #[test]
     fn test_update_configured() {
         let initial = vec![PathBuf::from("/tmp/initial.sock")];
-        let mut manager = SocketManager::new(initial);
+        let mut manager = SocketManager::new(upstreams(&initial));
         assert_eq!(manager.configured_count(), 1);
 
         let updated = vec![
             PathBuf::from("/tmp/updated1.sock"),
             PathBuf::from("/tmp/updated2.sock"),
         ];
-        manager.update_configured(updated.clone());
+        manager.update_configured(upstreams(&updated));
         assert_eq!(manager.configured_count(), 2);
         assert_eq!(manager.get_ordered_sockets(), updated);
     }
@@ -370,7 +1433,7 @@ mod tests {
         std::fs::File::create(&temp_path).unwrap();
 
         let mut manager = SocketManager::new(vec![]);
-        manager.add_watched(temp_path.clone());
+        manager.add_watched(temp_path.clone(), None);
         assert_eq!(manager.watched_count(), 1);
 
         // File exists, should not be removed
@@ -398,10 +1461,10 @@ mod tests {
     #[test]
     fn test_total_count() {
         let configured = vec![PathBuf::from("/tmp/c1.sock"), PathBuf::from("/tmp/c2.sock")];
-        let mut manager = SocketManager::new(configured);
+        let mut manager = SocketManager::new(upstreams(&configured));
         assert_eq!(manager.total_count(), 2);
 
-        manager.add_watched(PathBuf::from("/tmp/w1.sock"));
+        manager.add_watched(PathBuf::from("/tmp/w1.sock"), None);
         assert_eq!(manager.total_count(), 3);
     }
 
@@ -417,8 +1480,8 @@ mod tests {
         std::fs::File::create(&configured_path).unwrap();
         std::fs::File::create(&watched_path).unwrap();
 
-        let mut manager = SocketManager::new(vec![configured_path.clone()]);
-        manager.add_watched(watched_path.clone());
+        let mut manager = SocketManager::new(vec![configured_path.clone().into()]);
+        manager.add_watched(watched_path.clone(), None);
 
         let info = manager.get_socket_info();
         assert_eq!(info.len(), 2);
@@ -437,32 +1500,564 @@ mod tests {
     }
 
     #[test]
-    fn test_update_socket_health() {
-        let mut manager = SocketManager::new(vec![]);
+    fn test_export_restore_state() {
+        let configured = vec![PathBuf::from("/tmp/configured.sock")];
+        let mut manager = SocketManager::new(upstreams(&configured));
+        manager.add_watched(PathBuf::from("/tmp/watched1.sock"), None);
+        thread::sleep(Duration::from_millis(10));
+        manager.add_watched(PathBuf::from("/tmp/watched2.sock"), None);
+        manager.record_health(Path::new("/tmp/watched1.sock"), healthy_probe(2));
+
+        // Round-trip through JSON, as when handing over to an upgraded daemon
+        let json = serde_json::to_string(&manager.export_state()).unwrap();
+        let state: SocketManagerState = serde_json::from_str(&json).unwrap();
+
+        let mut restored = SocketManager::new(upstreams(&configured));
+        thread::sleep(Duration::from_millis(10));
+        restored.restore_state(state);
+
+        assert_eq!(
+            restored.get_ordered_sockets(),
+            manager.get_ordered_sockets()
+        );
+        assert_eq!(restored.get_socket_info(), manager.get_socket_info());
+        assert_eq!(restored.daemon_start_time(), manager.daemon_start_time());
+    }
+
+    #[test]
+    fn test_reinstate_state() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let kept = temp_dir.path().join("kept.sock");
+        let refused = temp_dir.path().join("refused.sock");
+        let _listeners =
+            [&kept, &refused].map(|path| std::os::unix::net::UnixListener::bind(path).unwrap());
+        let vanished = temp_dir.path().join("vanished.sock");
+        let configured = vec![PathBuf::from("/tmp/configured.sock")];
+
+        let mut manager = SocketManager::new(upstreams(&configured));
+        for path in [&kept, &refused, &vanished] {
+            manager.add_watched(path.clone(), None);
+        }
+        manager.record_health(&kept, healthy_probe(2));
+        manager.pin(&kept, 2);
+        manager.pin(&vanished, 1);
+        manager.record_sign(&kept);
+        manager.record_sign(&vanished);
+        manager
+            .set_priority_override(&configured[0], 10, None)
+            .unwrap();
+        let state = manager.export_state();
+
+        thread::sleep(Duration::from_millis(10));
+        let mut reinstated = SocketManager::new(upstreams(&configured));
+        let changes = reinstated.changes();
+        let session = SshSession {
+            pid: 4242,
+            remote_addr: None,
+            login_time: None,
+            tty: None,
+        };
+        let sessions = HashMap::from([(kept.clone(), session.clone())]);
+        assert_eq!(
+            reinstated.reinstate_state(state, sessions, |path| path != refused),
+            1
+        );
+
+        assert_eq!(
+            reinstated.get_ordered_sockets(),
+            [configured[0].clone(), kept.clone()]
+        );
+        let info = reinstated.get_socket_info();
+        assert_eq!(info[1].key_count, Some(2));
+        assert_eq!(info[1].pinned, Some(2));
+        assert_eq!(info[1].session, Some(session));
+        assert_eq!(info[0].priority_override, Some(10));
+        // Only sockets that are still around keep their signing times
+        assert!(reinstated.last_signed.contains_key(&kept));
+        assert!(!reinstated.last_signed.contains_key(&vanished));
+        // This is a new daemon, which only took over the sockets
+        assert!(reinstated.daemon_start_time() > manager.daemon_start_time());
+
+        // Changes are announced, for saving the state
+        reinstated.unpin(&kept);
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(async {
+                tokio::time::timeout(Duration::from_secs(1), changes.notified()).await
+            })
+            .expect("no change notification");
+    }
+
+    fn healthy_probe(key_count: usize) -> HealthProbe {
+        HealthProbe {
+            status: crate::control::SocketHealthStatus::Healthy,
+            key_count: Some(key_count),
+            latency: Some(Duration::from_millis(4)),
+            error: None,
+            checked_at: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn test_record_health() {
+        let configured = PathBuf::from("/tmp/configured.sock");
+        let mut manager = SocketManager::new(upstreams(std::slice::from_ref(&configured)));
         let path = PathBuf::from("/tmp/test.sock");
 
-        manager.add_watched(path.clone());
+        manager.add_watched(path.clone(), None);
 
         // Initially no health check
         let info = manager.get_socket_info();
         assert!(info[0].last_health_check.is_none());
         assert!(info[0].key_count.is_none());
 
-        // Update health
-        manager.update_socket_health(&path, true, Some(3));
+        // Configured sockets are checked too
+        for socket in [&path, &configured] {
+            manager.record_health(socket, healthy_probe(3));
+        }
+        for info in manager.get_socket_info() {
+            assert!(info.last_health_check.is_some());
+            assert_eq!(info.key_count, Some(3));
+            assert_eq!(info.latency_ms, Some(4));
+            assert!(info.healthy);
+        }
 
+        manager.record_health(
+            &configured,
+            HealthProbe {
+                status: crate::control::SocketHealthStatus::ConnectionFailed,
+                key_count: None,
+                latency: None,
+                error: Some("Socket file exists but refuses connections".to_string()),
+                checked_at: SystemTime::now(),
+            },
+        );
         let info = manager.get_socket_info();
-        assert!(info[0].last_health_check.is_some());
-        assert_eq!(info[0].key_count, Some(3));
-        assert!(info[0].healthy);
+        assert!(!info[1].healthy);
+        assert_eq!(
+            info[1].health_error.as_deref(),
+            Some("Socket file exists but refuses connections")
+        );
+        assert!(!manager.health(&configured).unwrap().healthy());
+
+        // Health goes with the socket
+        manager.remove_watched(&path);
+        assert!(manager.health(&path).is_none());
+    }
+
+    /// Whether a change was announced since the last call
+    fn take_change(changes: &Notify) -> bool {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(async {
+                tokio::time::timeout(Duration::from_millis(10), changes.notified()).await
+            })
+            .is_ok()
+    }
+
+    #[test]
+    fn test_changes_worth_saving() {
+        let (a, b) = (PathBuf::from("/tmp/a.sock"), PathBuf::from("/tmp/b.sock"));
+        let mut manager = SocketManager::new(upstreams(&[a.clone(), b.clone()]));
+        let changes = manager.changes();
+        take_change(&changes);
+
+        // Probes only count if their outcome differs
+        manager.record_health(&a, healthy_probe(2));
+        assert!(take_change(&changes));
+        manager.record_health(&a, healthy_probe(2));
+        assert!(!take_change(&changes));
+        manager.record_health(&a, healthy_probe(3));
+        assert!(take_change(&changes));
+
+        // Signatures only count if they change which agent signed last
+        manager.record_sign(&a);
+        assert!(take_change(&changes));
+        manager.record_sign(&a);
+        assert!(!take_change(&changes));
+        manager.record_sign(&b);
+        assert!(take_change(&changes));
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let failing = PathBuf::from("/tmp/failing.sock");
+        let other = PathBuf::from("/tmp/other.sock");
+        let mut manager = SocketManager::new(upstreams(&[failing.clone(), other.clone()]));
+        assert_eq!(manager.breaker(&failing), BreakerInfo::default());
+
+        // A failed agent is skipped, but still listed
+        manager.record_failure(&failing);
+        assert!(manager.is_skipped(&failing));
+        assert_eq!(manager.get_ordered_sockets(), vec![other.clone()]);
+        assert_eq!(manager.get_active_sockets().len(), 2);
+        let info = manager.get_socket_info();
+        assert_eq!(info[0].breaker.state, BreakerState::Open);
+        assert_eq!(info[0].breaker.failures, 1);
+        assert!(info[0].breaker.next_retry.is_some());
+        assert!(manager.due_for_retry().is_empty());
+
+        // Failures of requests made before it was skipped don't count
+        manager.record_failure(&failing);
+        assert_eq!(manager.breaker(&failing).failures, 1);
+
+        // Once due, it's tried again
+        manager.breakers.get_mut(&failing).unwrap().retry_at = SystemTime::now();
+        assert_eq!(manager.breaker(&failing).state, BreakerState::HalfOpen);
+        assert_eq!(manager.breaker(&failing).next_retry, None);
+        assert!(!manager.is_skipped(&failing));
+        assert_eq!(manager.get_ordered_sockets().len(), 2);
+        assert_eq!(manager.due_for_retry(), vec![failing.clone()]);
+
+        // Failing the retry doubles the backoff
+        manager.record_failure(&failing);
+        let breaker = manager.breakers[&failing];
+        assert_eq!(breaker.failures, 2);
+        let backoff = breaker.retry_at.duration_since(SystemTime::now()).unwrap();
+        assert!(backoff > BREAKER_BACKOFF && backoff <= 2 * BREAKER_BACKOFF);
+
+        // Answering closes the breaker
+        manager.record_health(&failing, healthy_probe(1));
+        assert_eq!(manager.breaker(&failing), BreakerInfo::default());
+        assert_eq!(manager.get_ordered_sockets().len(), 2);
+    }
+
+    #[test]
+    fn test_disable_socket() {
+        let configured = PathBuf::from("/tmp/configured.sock");
+        let watched = PathBuf::from("/tmp/watched.sock");
+        let mut manager = SocketManager::new(upstreams(std::slice::from_ref(&configured)));
+        manager.add_watched(watched.clone(), None);
+
+        assert_eq!(manager.disable(&configured), Some(true));
+        assert_eq!(manager.disable(&configured), Some(false));
+        assert_eq!(manager.disable(Path::new("/tmp/unknown.sock")), None);
+        assert_eq!(manager.get_ordered_sockets(), vec![watched.clone()]);
+
+        // Disabled sockets keep their place in the listing
+        let info = manager.get_socket_info();
+        assert_eq!(info.len(), 2);
+        assert!(!info[0].disabled);
+        assert!(info[1].disabled);
+        assert_eq!(info[1].order, 2);
+
+        // Kept across reloads and handovers
+        manager.update_configured(upstreams(std::slice::from_ref(&configured)));
+        assert!(manager.is_disabled(&configured));
+        let mut restored = SocketManager::new(upstreams(std::slice::from_ref(&configured)));
+        restored.restore_state(manager.export_state());
+        assert!(restored.is_disabled(&configured));
+
+        assert!(manager.enable(&configured));
+        assert!(!manager.enable(&configured));
+        assert_eq!(manager.get_ordered_sockets().len(), 2);
+
+        // Disabling goes with the socket
+        manager.disable(&watched);
+        manager.remove_watched(&watched);
+        assert!(!manager.is_disabled(&watched));
+    }
+
+    #[test]
+    fn test_breaker_backoff() {
+        assert_eq!(backoff(1), BREAKER_BACKOFF);
+        assert_eq!(backoff(2), 2 * BREAKER_BACKOFF);
+        assert_eq!(backoff(4), 8 * BREAKER_BACKOFF);
+        assert_eq!(backoff(20), BREAKER_MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), BREAKER_MAX_BACKOFF);
     }
 
     #[test]
     fn test_is_configured() {
         let path = PathBuf::from("/tmp/test.sock");
-        let manager = SocketManager::new(vec![path.clone()]);
+        let manager = SocketManager::new(vec![path.clone().into()]);
 
         assert!(manager.is_configured(&path));
         assert!(!manager.is_configured(&PathBuf::from("/tmp/other.sock")));
     }
+
+    #[test]
+    fn test_upstream_priority_and_enabled() {
+        let upstream = |path: &str, priority, enabled| Upstream {
+            priority,
+            enabled,
+            ..Upstream::from(PathBuf::from(path))
+        };
+        let mut manager = SocketManager::new(vec![
+            upstream("/tmp/low.sock", -1, true),
+            upstream("/tmp/default1.sock", 0, true),
+            upstream("/tmp/disabled.sock", 10, false),
+            upstream("/tmp/high.sock", 5, true),
+            upstream("/tmp/default2.sock", 0, true),
+        ]);
+        manager.add_watched(PathBuf::from("/tmp/watched.sock"), None);
+
+        // Watched sockets still come first; ties keep their configured order
+        let expected: Vec<_> = [
+            "/tmp/watched.sock",
+            "/tmp/high.sock",
+            "/tmp/default1.sock",
+            "/tmp/default2.sock",
+            "/tmp/low.sock",
+        ]
+        .map(PathBuf::from)
+        .into();
+        assert_eq!(manager.get_ordered_sockets(), expected);
+
+        let info = manager.get_socket_info();
+        let paths: Vec<_> = info.iter().map(|s| PathBuf::from(&s.path)).collect();
+        assert_eq!(paths, expected);
+
+        // Disabled upstreams are still configured, e.g. can't be added at runtime
+        assert!(manager.is_configured(&PathBuf::from("/tmp/disabled.sock")));
+    }
+
+    #[test]
+    fn test_ordering_strategies() {
+        let upstream = |path: &str, priority| Upstream {
+            priority,
+            ..Upstream::from(PathBuf::from(path))
+        };
+        let mut manager = SocketManager::new(vec![
+            upstream("/tmp/low.sock", -1),
+            upstream("/tmp/default.sock", 0),
+            upstream("/tmp/high.sock", 5),
+        ]);
+        manager.add_watched(PathBuf::from("/tmp/watched1.sock"), None);
+        thread::sleep(Duration::from_millis(10));
+        manager.add_watched(PathBuf::from("/tmp/watched2.sock"), None);
+
+        let order = |manager: &SocketManager| -> Vec<String> {
+            let ordered = manager.get_ordered_sockets();
+            let info = manager.get_socket_info();
+            // Listing is consistent with the ordering used for keys and signing
+            assert_eq!(
+                info.iter()
+                    .map(|s| PathBuf::from(&s.path))
+                    .collect::<Vec<_>>(),
+                ordered
+            );
+            assert!(info.iter().zip(1..).all(|(s, order)| s.order == order));
+            ordered
+                .iter()
+                .map(|p| p.file_stem().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+
+        assert_eq!(manager.ordering(), OrderingStrategy::NewestFirst);
+        assert_eq!(
+            order(&manager),
+            ["watched2", "watched1", "high", "default", "low"]
+        );
+
+        manager.set_ordering("oldest-first".parse().unwrap());
+        assert_eq!(
+            order(&manager),
+            ["watched1", "watched2", "high", "default", "low"]
+        );
+
+        manager.set_ordering(OrderingStrategy::ConfiguredFirst);
+        assert_eq!(
+            order(&manager),
+            ["high", "default", "low", "watched2", "watched1"]
+        );
+
+        manager.set_ordering(OrderingStrategy::Priority);
+        assert_eq!(
+            order(&manager),
+            ["high", "watched2", "watched1", "default", "low"]
+        );
+
+        manager.set_ordering(OrderingStrategy::RecentSign);
+        manager.record_sign(Path::new("/tmp/low.sock"));
+        thread::sleep(Duration::from_millis(10));
+        manager.record_sign(Path::new("/tmp/watched1.sock"));
+        assert_eq!(
+            order(&manager),
+            ["watched1", "low", "watched2", "high", "default"]
+        );
+        assert!(manager.get_socket_info()[0].last_signed.is_some());
+
+        // A socket that's gone for good doesn't keep its signing time
+        let watched1 = PathBuf::from("/tmp/watched1.sock");
+        assert!(manager.remove_watched(&watched1));
+        assert!(!manager.last_signed.contains_key(&watched1));
+
+        assert_eq!(
+            "random".parse::<OrderingStrategy>(),
+            Err(
+                "unknown socket order \"random\", expected one of newest-first, oldest-first, \
+                 configured-first, priority, recent-sign"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            serde_json::to_string(&OrderingStrategy::RecentSign).unwrap(),
+            "\"recent-sign\""
+        );
+    }
+
+    #[test]
+    fn test_runtime_placement() {
+        let mut manager = SocketManager::new(upstreams(&[
+            PathBuf::from("/tmp/a.sock"),
+            PathBuf::from("/tmp/b.sock"),
+            PathBuf::from("/tmp/c.sock"),
+        ]));
+        let order = |manager: &SocketManager| -> Vec<String> {
+            let ordered = manager.get_ordered_sockets();
+            let info = manager.get_socket_info();
+            assert_eq!(
+                info.iter()
+                    .map(|s| PathBuf::from(&s.path))
+                    .collect::<Vec<_>>(),
+                ordered
+            );
+            ordered
+                .iter()
+                .map(|p| p.file_stem().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        let (a, b, c) = (
+            Path::new("/tmp/a.sock"),
+            Path::new("/tmp/b.sock"),
+            Path::new("/tmp/c.sock"),
+        );
+
+        // Priority overrides go before (or after) sockets without one
+        assert_eq!(manager.set_priority_override(c, 10, None), Ok(1));
+        assert_eq!(order(&manager), ["c", "a", "b"]);
+        assert_eq!(manager.get_socket_info()[0].priority_override, Some(10));
+        assert_eq!(manager.set_priority_override(a, -1, None), Ok(3));
+        assert_eq!(order(&manager), ["c", "b", "a"]);
+
+        // Overrides that would outlast the clock are refused, as are ones for unknown sockets
+        let forever = Duration::from_secs(u64::MAX);
+        assert_eq!(
+            manager.set_priority_override(b, 5, Some(forever)),
+            Err(PriorityOverrideError::DurationTooLong(forever))
+        );
+        assert!(!manager.priority_overrides.contains_key(b));
+        assert_eq!(
+            manager.set_priority_override(Path::new("/tmp/unknown.sock"), 5, None),
+            Err(PriorityOverrideError::NotInOrder)
+        );
+
+        // With priority ordering they replace the priority
+        manager.set_ordering(OrderingStrategy::Priority);
+        assert_eq!(order(&manager), ["c", "b", "a"]);
+        manager.set_ordering(OrderingStrategy::NewestFirst);
+        assert!(manager.clear_priority_override(a));
+        assert!(!manager.clear_priority_override(a));
+
+        // Temporary overrides lapse
+        let info = |manager: &SocketManager, path: &Path| {
+            manager
+                .get_socket_info()
+                .into_iter()
+                .find(|s| Path::new(&s.path) == path)
+                .unwrap()
+        };
+        assert_eq!(
+            manager.set_priority_override(b, 20, Some(Duration::from_millis(50))),
+            Ok(1)
+        );
+        assert!(info(&manager, b).priority_override_until.is_some());
+        assert_eq!(order(&manager), ["b", "c", "a"]);
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(order(&manager), ["c", "a", "b"]);
+        assert_eq!(info(&manager, b).priority_override, None);
+
+        // Pins go before everything else
+        assert_eq!(manager.pin(b, 1), Some(1));
+        assert_eq!(order(&manager), ["b", "c", "a"]);
+        assert_eq!(info(&manager, b).pinned, Some(1));
+        assert_eq!(manager.move_by(b, 1), Some(2));
+        assert_eq!(order(&manager), ["c", "b", "a"]);
+        assert_eq!(manager.move_by(b, 10), Some(3));
+        assert_eq!(manager.move_by(b, -10), Some(1));
+        assert_eq!(manager.pin(Path::new("/tmp/unknown.sock"), 1), None);
+        assert_eq!(manager.move_by(Path::new("/tmp/unknown.sock"), 1), None);
+
+        // Runtime placement is handed over with the rest of the state
+        let mut restored = SocketManager::new(manager.configured_sockets().to_vec());
+        restored.restore_state(manager.export_state());
+        assert_eq!(order(&restored), ["b", "c", "a"]);
+
+        assert!(manager.unpin(b));
+        assert!(!manager.unpin(b));
+        assert!(manager.clear_priority_override(c));
+        assert_eq!(order(&manager), ["a", "b", "c"]);
+
+        // Removed sockets lose their placement
+        let watched = PathBuf::from("/tmp/watched.sock");
+        manager.add_watched(watched.clone(), None);
+        assert_eq!(manager.pin(&watched, 3), Some(3));
+        manager.remove_watched(&watched);
+        manager.add_watched(watched.clone(), None);
+        assert_eq!(manager.position(&watched), Some(1));
+    }
+
+    #[test]
+    fn test_upstream_names() {
+        let manager = SocketManager::new(vec![Upstream {
+            name: Some("yubikey".to_string()),
+            timeout: Some(3),
+            labels: vec!["hardware".to_string()],
+            ..Upstream::from(PathBuf::from("/tmp/yubikey.sock"))
+        }]);
+        let path = PathBuf::from("/tmp/yubikey.sock");
+
+        assert_eq!(manager.resolve("yubikey"), path);
+        assert_eq!(
+            manager.resolve("/tmp/other.sock"),
+            PathBuf::from("/tmp/other.sock")
+        );
+        assert_eq!(manager.upstream_name(&path), Some("yubikey".to_string()));
+        assert_eq!(
+            manager.upstream_timeout(&path),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(manager.upstream_timeout(Path::new("/tmp/other.sock")), None);
+
+        let info = manager.get_socket_info();
+        assert_eq!(info[0].name.as_deref(), Some("yubikey"));
+        assert_eq!(info[0].labels, vec!["hardware".to_string()]);
+    }
+
+    #[test]
+    fn test_upstream_deserialization() {
+        let upstream: Upstream = toml::from_str(r#"path = "/tmp/agent.sock""#).unwrap();
+        assert_eq!(upstream, Upstream::from(PathBuf::from("/tmp/agent.sock")));
+
+        // Misspelled settings must not be silently ignored
+        assert!(toml::from_str::<Upstream>("path = \"/tmp/a.sock\"\npriorty = 1").is_err());
+    }
+
+    #[test]
+    fn test_glob_patterns_reevaluated_on_cleanup() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let first = temp_dir.path().join("a.sock");
+        let second = temp_dir.path().join("b.sock");
+        std::fs::File::create(&first).unwrap();
+
+        let mut manager = SocketManager::new(vec![Upstream {
+            name: Some("globbed".to_string()),
+            ..Upstream::from(temp_dir.path().join("*.sock"))
+        }]);
+        assert_eq!(manager.get_ordered_sockets(), vec![first.clone()]);
+
+        std::fs::File::create(&second).unwrap();
+        std::fs::remove_file(&first).unwrap();
+        assert_eq!(manager.validate_and_cleanup(), vec![first]);
+        assert_eq!(manager.get_ordered_sockets(), vec![second.clone()]);
+
+        // Matches inherit the settings of their pattern
+        assert_eq!(manager.upstream_name(&second), Some("globbed".to_string()));
+        assert!(manager.is_configured(&second));
+    }
 }
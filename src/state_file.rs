@@ -0,0 +1,163 @@
+//! Keeping the runtime state of the socket manager in a file, so that sockets added at runtime,
+//! pins and priority overrides survive a restart.
+//!
+//! The file is JSON, written to a temporary file next to it and renamed into place, so that a
+//! crash never leaves a partial file behind.
+
+use std::fmt;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::socket_manager::SocketManagerState;
+
+/// Errors from reading or writing a state file
+#[derive(Debug)]
+pub enum StateFileError {
+    /// The state file couldn't be read or written
+    Io { path: PathBuf, error: io::Error },
+    /// The state file isn't valid
+    Parse {
+        path: PathBuf,
+        error: serde_json::Error,
+    },
+}
+
+impl fmt::Display for StateFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateFileError::Io { path, error } => {
+                write!(f, "Cannot access state file {}: {error}", path.display())
+            }
+            StateFileError::Parse { path, error } => {
+                write!(f, "Invalid state file {}: {error}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for StateFileError {}
+
+/// A file the socket manager state is kept in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the saved state, or None if nothing was saved yet
+    pub fn load(&self) -> Result<Option<SocketManagerState>, StateFileError> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(self.io_error(error)),
+        };
+        serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|error| StateFileError::Parse {
+                path: self.path.clone(),
+                error,
+            })
+    }
+
+    /// Replace the saved state with `state`
+    ///
+    /// The file and the directory it's created in are only accessible to the current user,
+    /// since the state names the sockets of SSH sessions.
+    pub fn save(&self, state: &SocketManagerState) -> Result<(), StateFileError> {
+        if let Some(dir) = self.path.parent() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)
+                .map_err(|e| self.io_error(e))?;
+        }
+        let json = serde_json::to_string_pretty(state).map_err(|error| StateFileError::Parse {
+            path: self.path.clone(),
+            error,
+        })?;
+
+        let mut tmp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = self.path.with_file_name(tmp_name);
+        let written = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(json.as_bytes())?;
+                file.write_all(b"\n")?;
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&tmp_path, &self.path));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(self.io_error(e));
+        }
+        Ok(())
+    }
+
+    fn io_error(&self, error: io::Error) -> StateFileError {
+        StateFileError::Io {
+            path: self.path.clone(),
+            error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket_manager::SocketManager;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = StateFile::new(dir.path().join("state").join("state.json"));
+        assert!(file.load().unwrap().is_none());
+
+        let mut manager = SocketManager::new(vec![]);
+        manager.add_watched(PathBuf::from("/tmp/watched.sock"), None);
+        file.save(&manager.export_state()).unwrap();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(file.path()), 0o600);
+        assert_eq!(mode(file.path().parent().unwrap()), 0o700);
+
+        let mut restored = SocketManager::new(vec![]);
+        restored.restore_state(file.load().unwrap().unwrap());
+        assert!(restored.is_watched(&PathBuf::from("/tmp/watched.sock")));
+
+        // Saving again replaces the file
+        restored.remove_watched(&PathBuf::from("/tmp/watched.sock"));
+        file.save(&restored.export_state()).unwrap();
+        let mut restored = SocketManager::new(vec![]);
+        restored.restore_state(file.load().unwrap().unwrap());
+        assert_eq!(restored.watched_count(), 0);
+        assert_eq!(fs::read_dir(dir.path().join("state")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_invalid_state_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = StateFile::new(dir.path().join("state.json"));
+        fs::write(file.path(), "{not json").unwrap();
+        let err = file.load().unwrap_err();
+        assert!(matches!(err, StateFileError::Parse { .. }));
+        assert!(err
+            .to_string()
+            .starts_with(&format!("Invalid state file {}: ", file.path().display())));
+    }
+}
@@ -1,49 +1,265 @@
 use notify::{Event, EventKind, RecursiveMode};
 use notify_debouncer_full::{new_debouncer, DebounceEventResult, Debouncer, RecommendedCache};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex as StdMutex};
 use std::time::Duration;
 use tokio::sync::mpsc;
-
-#[derive(Clone, Copy)]
-enum NamePattern {
-    Prefix(&'static str),
-    Exact(&'static str),
+use tokio::task::JoinHandle;
+
+use crate::expand;
+
+/// Pattern for a directory or file name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NamePattern {
+    /// Names starting with the string
+    Prefix(Cow<'static, str>),
+    /// Exactly the string
+    Exact(Cow<'static, str>),
+    /// Names matching the shell-style wildcard pattern
+    Glob(Cow<'static, str>),
 }
 
 impl NamePattern {
     fn matches(&self, candidate: &str) -> bool {
         match self {
-            NamePattern::Prefix(prefix) => candidate.starts_with(prefix),
-            NamePattern::Exact(exact) => candidate == *exact,
+            NamePattern::Prefix(prefix) => candidate.starts_with(prefix.as_ref()),
+            NamePattern::Exact(exact) => candidate == exact,
+            NamePattern::Glob(glob) => expand::glob_match(glob, candidate),
         }
     }
 }
 
-#[derive(Clone, Copy)]
-struct ForwardedAgentPattern {
-    dir_pattern: NamePattern,
-    file_pattern: NamePattern,
+/// Forwarded agent sockets: files matching `file` in directories matching `dir`
+///
+/// Configured either as a table of two [`NamePattern`]s, or as a `DIR/FILE` string of two
+/// glob patterns.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "PatternSpec")]
+pub struct ForwardedAgentPattern {
+    pub dir: NamePattern,
+    pub file: NamePattern,
 }
 
 impl ForwardedAgentPattern {
-    const fn new(dir_pattern: NamePattern, file_pattern: NamePattern) -> Self {
-        Self {
-            dir_pattern,
-            file_pattern,
+    pub const fn new(dir: NamePattern, file: NamePattern) -> Self {
+        Self { dir, file }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PatternSpec {
+    Globs(String),
+    #[serde(rename_all = "lowercase")]
+    Table {
+        dir: NamePattern,
+        file: NamePattern,
+    },
+}
+
+impl TryFrom<PatternSpec> for ForwardedAgentPattern {
+    type Error = String;
+
+    fn try_from(spec: PatternSpec) -> Result<Self, Self::Error> {
+        match spec {
+            PatternSpec::Globs(globs) => globs.parse(),
+            PatternSpec::Table { dir, file } => Ok(Self::new(dir, file)),
+        }
+    }
+}
+
+impl std::str::FromStr for ForwardedAgentPattern {
+    type Err = String;
+
+    /// Parse a `DIR/FILE` pair of glob patterns
+    fn from_str(globs: &str) -> Result<Self, Self::Err> {
+        match globs.split_once('/') {
+            Some((dir, file)) if !dir.is_empty() && !file.is_empty() && !file.contains('/') => {
+                Ok(Self::new(
+                    NamePattern::Glob(dir.to_string().into()),
+                    NamePattern::Glob(file.to_string().into()),
+                ))
+            }
+            _ => Err(format!(
+                "forwarded agent pattern {globs:?} is not of the form DIR/FILE"
+            )),
         }
     }
 }
 
 const FORWARDED_AGENT_PATTERNS: &[ForwardedAgentPattern] = &[
-    ForwardedAgentPattern::new(NamePattern::Prefix("ssh-"), NamePattern::Prefix("agent.")),
     ForwardedAgentPattern::new(
-        NamePattern::Prefix("auth-agent"),
-        NamePattern::Exact("listener.sock"),
+        NamePattern::Prefix(Cow::Borrowed("ssh-")),
+        NamePattern::Prefix(Cow::Borrowed("agent.")),
+    ),
+    ForwardedAgentPattern::new(
+        NamePattern::Prefix(Cow::Borrowed("auth-agent")),
+        NamePattern::Exact(Cow::Borrowed("listener.sock")),
     ),
 ];
 
+/// Directory that sshd creates forwarded agent directories in
+const DEFAULT_ROOT: &str = "/tmp";
+
+/// Why a forwarded agent socket isn't trusted
+#[derive(Debug)]
+pub enum OwnershipError {
+    /// The socket or its directory belongs to another user
+    ForeignOwner { path: PathBuf, uid: u32 },
+    /// Other users have access to the socket or its directory
+    Accessible { path: PathBuf, mode: u32 },
+    /// The socket or its directory couldn't be inspected
+    Io(PathBuf, std::io::Error),
+}
+
+impl std::fmt::Display for OwnershipError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OwnershipError::ForeignOwner { path, uid } => {
+                write!(f, "{} is owned by another user (uid {uid})", path.display())
+            }
+            OwnershipError::Accessible { path, mode } => write!(
+                f,
+                "{} is accessible by other users (mode {:04o})",
+                path.display(),
+                mode & 0o7777
+            ),
+            OwnershipError::Io(path, e) => write!(f, "Cannot inspect {}: {e}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for OwnershipError {}
+
+/// Check that the socket at `path` and its directory are owned by the current user, and that
+/// nobody else has access to them
+///
+/// Symlinks are followed, so a symlinked socket is checked where it leads.
+pub fn verify_ownership(path: &Path) -> Result<(), OwnershipError> {
+    use std::os::unix::fs::MetadataExt;
+
+    let uid = crate::instance::current_uid();
+    for path in path.parent().into_iter().chain([path]) {
+        let metadata =
+            std::fs::metadata(path).map_err(|e| OwnershipError::Io(path.to_path_buf(), e))?;
+        if metadata.uid() != uid {
+            return Err(OwnershipError::ForeignOwner {
+                path: path.to_path_buf(),
+                uid: metadata.uid(),
+            });
+        }
+        if metadata.mode() & 0o077 != 0 {
+            return Err(OwnershipError::Accessible {
+                path: path.to_path_buf(),
+                mode: metadata.mode(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Where to look for forwarded agent sockets: in directories directly inside one of the roots,
+/// matching a pattern
+///
+/// Found sockets are only used if they pass [`verify_ownership`], or match one of the `trusted`
+/// path patterns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discovery {
+    pub roots: Vec<PathBuf>,
+    pub patterns: Vec<ForwardedAgentPattern>,
+    pub trusted: Vec<PathBuf>,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self {
+            roots: vec![PathBuf::from(DEFAULT_ROOT)],
+            patterns: FORWARDED_AGENT_PATTERNS.to_vec(),
+            trusted: Vec::new(),
+        }
+    }
+}
+
+impl Discovery {
+    /// The default roots and patterns, followed by `roots` and `patterns`
+    pub fn with_extra(roots: &[PathBuf], patterns: &[ForwardedAgentPattern]) -> Self {
+        let mut discovery = Self::default();
+        for root in roots {
+            if !discovery.roots.contains(root) {
+                discovery.roots.push(root.clone());
+            }
+        }
+        for pattern in patterns {
+            if !discovery.patterns.contains(pattern) {
+                discovery.patterns.push(pattern.clone());
+            }
+        }
+        discovery
+    }
+
+    fn is_root(&self, path: &Path) -> bool {
+        self.roots.iter().any(|root| root == path)
+    }
+
+    /// Whether `path` is a directory in a root that may hold forwarded agents
+    pub fn is_agent_dir(&self, path: &Path) -> bool {
+        self.dir_patterns(path).next().is_some()
+    }
+
+    /// Patterns whose directory name matches `path`, a directory in a root
+    fn dir_patterns<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> impl Iterator<Item = &'a ForwardedAgentPattern> + 'a {
+        let name = path
+            .parent()
+            .filter(|parent| self.is_root(parent))
+            .and(path.file_name())
+            .and_then(|n| n.to_str());
+        self.patterns
+            .iter()
+            .filter(move |pattern| name.is_some_and(|name| pattern.dir.matches(name)))
+    }
+
+    /// Check that the socket at `path` may be used: it's trusted, or only we have access to it
+    pub fn verify(&self, path: &Path) -> Result<(), OwnershipError> {
+        if self
+            .trusted
+            .iter()
+            .any(|pattern| expand::path_matches(pattern, path))
+        {
+            return Ok(());
+        }
+        verify_ownership(path)
+    }
+
+    /// Whether the socket at `path` may be used, logging why if not
+    fn accepts(&self, path: &Path) -> bool {
+        match self.verify(path) {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("Ignoring forwarded agent {}: {e}", path.display());
+                false
+            }
+        }
+    }
+
+    /// Whether `path` matches a forwarded agent pattern
+    pub fn is_forwarded_agent(&self, path: &Path) -> bool {
+        let (Some(dir), Some(file_name)) =
+            (path.parent(), path.file_name().and_then(|n| n.to_str()))
+        else {
+            return false;
+        };
+        self.dir_patterns(dir)
+            .any(|pattern| pattern.file.matches(file_name))
+    }
+}
+
 /// Events emitted by the file watcher
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum WatchEvent {
@@ -53,19 +269,18 @@ pub enum WatchEvent {
     Removed(PathBuf),
 }
 
-/// Check if a directory name matches SSH agent directory patterns
-fn should_watch_directory(path: &Path) -> bool {
-    path.file_name()
-        .and_then(|n| n.to_str())
-        .map(|name| name.starts_with("ssh-") || name.starts_with("auth-agent"))
-        .unwrap_or(false)
-}
+type SmartDebouncer = Debouncer<notify::RecommendedWatcher, RecommendedCache>;
 
-/// Smart watcher that selectively watches directories in /tmp
+/// Smart watcher that selectively watches directories in the discovery roots
 /// to avoid permission errors on restricted directories
+///
+/// Agent directories created while watching are watched as soon as they appear.
 pub struct SmartWatcher {
-    debouncer: Debouncer<notify::RecommendedWatcher, RecommendedCache>,
+    debouncer: Arc<StdMutex<SmartDebouncer>>,
     watched_dirs: Arc<StdMutex<HashSet<PathBuf>>>,
+    discovery: Arc<Discovery>,
+    /// Watches the directories the debouncer reports as new
+    registrar: JoinHandle<()>,
 }
 
 impl SmartWatcher {
@@ -76,67 +291,93 @@ impl SmartWatcher {
 
     /// Try to add a directory to the watch list
     pub fn try_watch_directory(&mut self, path: &Path) -> bool {
-        if !should_watch_directory(path) {
-            return false;
-        }
-
-        let mut watched = self.watched_dirs.lock().unwrap();
-        if watched.contains(path) {
-            return true; // Already watching
-        }
-
-        match self.debouncer.watch(path, RecursiveMode::Recursive) {
-            Ok(_) => {
-                log::debug!("Now watching directory: {}", path.display());
-                watched.insert(path.to_path_buf());
-                true
-            }
-            Err(e) => {
-                log::debug!("Cannot watch {}: {}", path.display(), e);
-                false
-            }
-        }
+        watch_directory(&self.debouncer, &self.watched_dirs, &self.discovery, path)
     }
 
     /// Remove a directory from the watch list
     pub fn unwatch_directory(&mut self, path: &Path) {
         let mut watched = self.watched_dirs.lock().unwrap();
         if watched.remove(path) {
-            if let Err(e) = self.debouncer.unwatch(path) {
+            if let Err(e) = self.debouncer.lock().unwrap().unwatch(path) {
                 log::debug!("Error unwatching {}: {}", path.display(), e);
             }
         }
     }
 }
 
-/// Check if a path matches a forwarded SSH agent pattern
-/// Supported patterns:
-///   * /tmp/ssh-*/agent.*
-///   * /tmp/auth-agent*/listener.sock
-pub fn is_ssh_forwarded_agent(path: &Path) -> bool {
-    if !path.starts_with(Path::new("/tmp")) {
+impl Drop for SmartWatcher {
+    fn drop(&mut self) {
+        // The registrar holds on to the debouncer, whose callback holds on to the registrar's
+        // channel
+        self.registrar.abort();
+    }
+}
+
+/// Watch the agent directory `path` recursively, returning whether it's watched
+fn watch_directory(
+    debouncer: &StdMutex<SmartDebouncer>,
+    watched_dirs: &StdMutex<HashSet<PathBuf>>,
+    discovery: &Discovery,
+    path: &Path,
+) -> bool {
+    if !discovery.is_agent_dir(path) {
         return false;
     }
 
-    // Get parent directory name
-    let parent_name = match path
-        .parent()
-        .and_then(|p| p.file_name())
-        .and_then(|n| n.to_str())
+    let mut watched = watched_dirs.lock().unwrap();
+    if watched.contains(path) {
+        return true; // Already watching
+    }
+
+    match debouncer
+        .lock()
+        .unwrap()
+        .watch(path, RecursiveMode::Recursive)
     {
-        Some(name) => name,
-        None => return false,
-    };
+        Ok(_) => {
+            log::debug!("Now watching directory: {}", path.display());
+            watched.insert(path.to_path_buf());
+            true
+        }
+        Err(e) => {
+            log::debug!("Cannot watch {}: {}", path.display(), e);
+            false
+        }
+    }
+}
 
-    // Get the file name
-    let file_name = match path.file_name().and_then(|n| n.to_str()) {
-        Some(name) => name,
-        None => return false,
-    };
+/// Watch each new agent directory received on `new_dirs`, reporting the sockets already in it
+///
+/// Sockets created before the directory is watched would otherwise be missed, as sshd creates
+/// the socket right after its directory.
+async fn register_new_directories(
+    mut new_dirs: mpsc::UnboundedReceiver<PathBuf>,
+    debouncer: Arc<StdMutex<SmartDebouncer>>,
+    watched_dirs: Arc<StdMutex<HashSet<PathBuf>>>,
+    discovery: Arc<Discovery>,
+    tx: mpsc::UnboundedSender<WatchEvent>,
+) {
+    while let Some(dir) = new_dirs.recv().await {
+        if !watch_directory(&debouncer, &watched_dirs, &discovery, &dir) {
+            continue;
+        }
+        log::info!("Watching new SSH agent directory: {}", dir.display());
 
-    FORWARDED_AGENT_PATTERNS.iter().any(|pattern| {
-        pattern.dir_pattern.matches(parent_name) && pattern.file_pattern.matches(file_name)
-    })
+        for agent in agents_in_directory(&discovery, &dir).await {
+            log::debug!("Detected new SSH forwarded agent: {}", agent.display());
+            if let Err(e) = tx.send(WatchEvent::Added(agent.clone())) {
+                log::error!("Failed to send Added event for {}: {}", agent.display(), e);
+            }
+        }
+    }
+}
+
+/// Check if a path matches a forwarded SSH agent pattern of the default [`Discovery`]
+/// Supported patterns:
+///   * /tmp/ssh-*/agent.*
+///   * /tmp/auth-agent*/listener.sock
+pub fn is_ssh_forwarded_agent(path: &Path) -> bool {
+    Discovery::default().is_forwarded_agent(path)
 }
 
 /// Start watching /tmp directory for SSH forwarded agents
@@ -175,20 +416,27 @@ pub async fn watch_tmp_directory(
     Ok(debouncer)
 }
 
-/// Start smart watching of /tmp directory for SSH forwarded agents
+/// Start smart watching of the discovery roots for SSH forwarded agents
 ///
-/// This watches /tmp non-recursively, then selectively watches only
-/// ssh-* and auth-agent* subdirectories to avoid permission errors
-/// on restricted directories like /tmp/systemd-private-*.
+/// This watches each root non-recursively, then selectively watches only
+/// directories matching a pattern (ssh-* and auth-agent* by default) to avoid
+/// permission errors on restricted directories like /tmp/systemd-private-*.
+/// Roots that can't be watched are skipped, unless none can.
 pub async fn watch_tmp_directory_smart(
+    discovery: Discovery,
     tx: mpsc::UnboundedSender<WatchEvent>,
 ) -> Result<SmartWatcher, notify::Error> {
-    let tmp_path = Path::new("/tmp");
+    let discovery = Arc::new(discovery);
     let watched_dirs = Arc::new(StdMutex::new(HashSet::new()));
     let watched_dirs_clone = watched_dirs.clone();
+    let discovery_clone = discovery.clone();
     let tx_clone = tx.clone();
+    let (new_dirs_tx, new_dirs) = mpsc::unbounded_channel();
 
-    log::info!("Starting smart file watcher on /tmp for SSH forwarded agents");
+    log::info!(
+        "Starting smart file watcher on {} for SSH forwarded agents",
+        display_roots(&discovery)
+    );
 
     // Create debounced watcher (200ms debounce time)
     let debouncer = new_debouncer(
@@ -197,7 +445,13 @@ pub async fn watch_tmp_directory_smart(
         move |result: DebounceEventResult| match result {
             Ok(events) => {
                 for event in events {
-                    handle_smart_event(event.event, &tx_clone, &watched_dirs_clone);
+                    handle_smart_event(
+                        event.event,
+                        &tx_clone,
+                        &new_dirs_tx,
+                        &watched_dirs_clone,
+                        &discovery_clone,
+                    );
                 }
             }
             Err(errors) => {
@@ -208,22 +462,50 @@ pub async fn watch_tmp_directory_smart(
         },
     )?;
 
+    let debouncer = Arc::new(StdMutex::new(debouncer));
+    let registrar = tokio::spawn(register_new_directories(
+        new_dirs,
+        debouncer.clone(),
+        watched_dirs.clone(),
+        discovery.clone(),
+        tx,
+    ));
     let mut watcher = SmartWatcher {
         debouncer,
         watched_dirs,
+        discovery: discovery.clone(),
+        registrar,
     };
 
-    // Watch /tmp NON-recursively for new directory creation
-    watcher
-        .debouncer
-        .watch(tmp_path, RecursiveMode::NonRecursive)?;
+    // Watch the roots NON-recursively for new directory creation
+    let mut last_error = None;
+    let mut watched_roots = 0;
+    for root in &discovery.roots {
+        let result = watcher
+            .debouncer
+            .lock()
+            .unwrap()
+            .watch(root, RecursiveMode::NonRecursive);
+        match result {
+            Ok(()) => watched_roots += 1,
+            Err(e) => {
+                log::warn!("Cannot watch {} for forwarded agents: {e}", root.display());
+                last_error = Some(e);
+            }
+        }
+    }
+    if let (0, Some(e)) = (watched_roots, last_error) {
+        return Err(e);
+    }
 
-    // Selectively watch existing ssh-*/auth-agent* directories
-    if let Ok(entries) = std::fs::read_dir(tmp_path) {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if path.is_dir() && should_watch_directory(&path) {
-                watcher.try_watch_directory(&path);
+    // Selectively watch existing directories matching the patterns
+    for root in &discovery.roots {
+        if let Ok(entries) = std::fs::read_dir(root) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() && discovery.is_agent_dir(&path) {
+                    watcher.try_watch_directory(&path);
+                }
             }
         }
     }
@@ -238,23 +520,22 @@ pub async fn watch_tmp_directory_smart(
 fn handle_smart_event(
     event: Event,
     tx: &mpsc::UnboundedSender<WatchEvent>,
+    new_dirs: &mpsc::UnboundedSender<PathBuf>,
     watched_dirs: &Arc<StdMutex<HashSet<PathBuf>>>,
+    discovery: &Discovery,
 ) {
-    let tmp_path = Path::new("/tmp");
-
     match event.kind {
-        // Handle directory creation in /tmp - we may need to start watching it
+        // Handle directory creation in a root - we may need to start watching it
         EventKind::Create(notify::event::CreateKind::Folder) => {
             for path in &event.paths {
-                // Check if this is a new directory directly in /tmp
-                if path.parent() == Some(tmp_path) && should_watch_directory(path) {
-                    // We can't modify the debouncer from here (it's in the callback)
-                    // but the scan_existing_agents() call will pick up new directories
-                    // and we can trigger a manual re-scan via the control socket
-                    log::info!(
-                        "New SSH agent directory detected: {} (will be picked up on next scan)",
-                        path.display()
-                    );
+                // Check if this is a new agent directory directly in a root
+                if discovery.is_agent_dir(path) {
+                    // We can't modify the debouncer from here (it's in the callback),
+                    // so the registrar task starts watching it
+                    log::debug!("New SSH agent directory detected: {}", path.display());
+                    if let Err(e) = new_dirs.send(path.clone()) {
+                        log::error!("Failed to register directory {}: {}", path.display(), e);
+                    }
                 }
             }
         }
@@ -262,7 +543,7 @@ fn handle_smart_event(
         // Handle socket creation/modification
         EventKind::Create(_) | EventKind::Modify(_) => {
             for path in &event.paths {
-                if is_ssh_forwarded_agent(path) && path.exists() {
+                if discovery.is_forwarded_agent(path) && path.exists() && discovery.accepts(path) {
                     log::debug!("Detected new SSH forwarded agent: {}", path.display());
                     if let Err(e) = tx.send(WatchEvent::Added(path.clone())) {
                         log::error!("Failed to send Added event for {}: {}", path.display(), e);
@@ -275,7 +556,7 @@ fn handle_smart_event(
         EventKind::Remove(_) => {
             for path in &event.paths {
                 // Check if an entire watched directory was removed
-                if path.parent() == Some(tmp_path) {
+                if discovery.is_agent_dir(path) {
                     let mut watched = watched_dirs.lock().unwrap();
                     if watched.remove(path) {
                         log::debug!("Watched directory removed: {}", path.display());
@@ -283,7 +564,7 @@ fn handle_smart_event(
                 }
 
                 // Check if it's a socket being removed
-                if is_ssh_forwarded_agent(path) {
+                if discovery.is_forwarded_agent(path) {
                     log::debug!("Detected removed SSH forwarded agent: {}", path.display());
                     if let Err(e) = tx.send(WatchEvent::Removed(path.clone())) {
                         log::error!("Failed to send Removed event for {}: {}", path.display(), e);
@@ -298,6 +579,16 @@ fn handle_smart_event(
     }
 }
 
+/// The discovery roots, for log messages
+fn display_roots(discovery: &Discovery) -> String {
+    discovery
+        .roots
+        .iter()
+        .map(|root| root.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 /// Handle a file system event
 fn handle_event(event: Event, tx: &mpsc::UnboundedSender<WatchEvent>) {
     match event.kind {
@@ -327,71 +618,211 @@ fn handle_event(event: Event, tx: &mpsc::UnboundedSender<WatchEvent>) {
     }
 }
 
-/// Scan /tmp directory for existing SSH forwarded agents
+/// Symlinks followed before giving up on resolving a configuration file, as for the kernel's
+/// ELOOP limit
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Watches configuration files, sending `()` on `tx` whenever they may have changed
+///
+/// Parent directories are watched rather than the files themselves, so that editors which save
+/// by renaming a new file over the old one are noticed, as are symlinks being repointed (such
+/// as those home-manager creates into the Nix store). Call [`ConfigWatcher::refresh`] after each
+/// change to follow the symlinks to their new targets. Watched paths that are directories, like
+/// a drop-in directory, also report changes to the files in them.
+pub struct ConfigWatcher {
+    debouncer: Debouncer<notify::RecommendedWatcher, RecommendedCache>,
+    paths: Vec<PathBuf>,
+    /// The watched paths and each symlink target on the way to the actual files
+    links: Arc<StdMutex<HashSet<PathBuf>>>,
+    watched_dirs: HashSet<PathBuf>,
+}
+
+impl ConfigWatcher {
+    /// Watch `paths` instead of the paths watched so far
+    pub fn set_paths(&mut self, paths: Vec<PathBuf>) {
+        self.paths = paths;
+        self.refresh();
+    }
+
+    /// Follow the watched paths' symlinks again, watching where they now lead
+    pub fn refresh(&mut self) {
+        let links: Vec<PathBuf> = self.paths.iter().flat_map(|p| symlink_chain(p)).collect();
+        let dirs: HashSet<PathBuf> = links
+            .iter()
+            .filter_map(|link| link.parent())
+            .map(Path::to_path_buf)
+            .chain(links.iter().filter(|link| link.is_dir()).cloned())
+            .collect();
+
+        for dir in self.watched_dirs.difference(&dirs) {
+            if let Err(e) = self.debouncer.unwatch(dir) {
+                log::debug!("Error unwatching {}: {}", dir.display(), e);
+            }
+        }
+        self.watched_dirs.retain(|dir| dirs.contains(dir));
+        for dir in dirs {
+            if self.watched_dirs.contains(&dir) {
+                continue;
+            }
+            match self.debouncer.watch(&dir, RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    log::debug!("Watching {} for configuration changes", dir.display());
+                    self.watched_dirs.insert(dir);
+                }
+                Err(e) => log::debug!("Cannot watch {}: {}", dir.display(), e),
+            }
+        }
+
+        *self.links.lock().unwrap() = links.into_iter().collect();
+    }
+}
+
+/// Start watching the configuration file at `path`
+///
+/// Fails if the directory containing `path` can't be watched.
+pub fn watch_config_file(
+    path: &Path,
+    tx: mpsc::UnboundedSender<()>,
+) -> Result<ConfigWatcher, notify::Error> {
+    let links = Arc::new(StdMutex::new(HashSet::new()));
+    let links_clone = links.clone();
+
+    let debouncer = new_debouncer(
+        Duration::from_millis(200),
+        None,
+        move |result: DebounceEventResult| match result {
+            Ok(events) => {
+                let links = links_clone.lock().unwrap();
+                let changed = events
+                    .iter()
+                    // Reading the file on reload must not trigger another one
+                    .filter(|event| !matches!(event.event.kind, EventKind::Access(_)))
+                    .flat_map(|event| &event.event.paths)
+                    .any(|path| {
+                        links.contains(path) || path.parent().is_some_and(|p| links.contains(p))
+                    });
+                if changed {
+                    log::debug!("Configuration file changed");
+                    let _ = tx.send(());
+                }
+            }
+            Err(errors) => {
+                for error in errors {
+                    log::error!("Configuration file watcher error: {error:?}");
+                }
+            }
+        },
+    )?;
+
+    let mut watcher = ConfigWatcher {
+        debouncer,
+        paths: vec![path.to_path_buf()],
+        links,
+        watched_dirs: HashSet::new(),
+    };
+    if let Some(dir) = path.parent() {
+        watcher.debouncer.watch(dir, RecursiveMode::NonRecursive)?;
+        watcher.watched_dirs.insert(dir.to_path_buf());
+    }
+    watcher.refresh();
+
+    log::info!("Watching {} for changes", path.display());
+    Ok(watcher)
+}
+
+/// `path` followed by the target of each symlink on the way to the file it resolves to
+fn symlink_chain(path: &Path) -> Vec<PathBuf> {
+    let mut chain = vec![path.to_path_buf()];
+    let mut current = path.to_path_buf();
+    for _ in 0..MAX_SYMLINK_HOPS {
+        let Ok(target) = std::fs::read_link(&current) else {
+            break;
+        };
+        // Relative targets are relative to the symlink's directory; joining an absolute target
+        // replaces the path
+        current = match current.parent() {
+            Some(dir) => dir.join(target),
+            None => target,
+        };
+        chain.push(current.clone());
+    }
+    chain
+}
+
+/// Scan the discovery roots for existing SSH forwarded agents
 /// This should be called once at startup to detect any existing sockets
-pub async fn scan_existing_agents() -> Result<Vec<PathBuf>, std::io::Error> {
+///
+/// Directories that can't be read, such as other users' agent directories, are skipped. Fails
+/// only if none of the roots can be read.
+pub async fn scan_existing_agents(discovery: &Discovery) -> Result<Vec<PathBuf>, std::io::Error> {
     use tokio::fs;
 
     let mut agents = Vec::new();
-    let tmp_path = Path::new("/tmp");
-
-    log::debug!("Scanning /tmp for existing SSH forwarded agents");
+    let mut last_error = None;
+    let mut scanned_roots = 0;
 
-    let mut entries = fs::read_dir(tmp_path).await?;
+    log::debug!(
+        "Scanning {} for existing SSH forwarded agents",
+        display_roots(discovery)
+    );
 
-    while let Some(entry) = entries.next_entry().await? {
-        let path = entry.path();
+    for root in &discovery.roots {
+        let mut entries = match fs::read_dir(root).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::debug!("Cannot scan {}: {e}", root.display());
+                last_error = Some(e);
+                continue;
+            }
+        };
+        scanned_roots += 1;
 
-        // Check if it's a directory matching ssh-*
-        if path.is_dir() {
-            if let Some(dir_name) = path.file_name().and_then(|n| n.to_str()) {
-                for pattern in FORWARDED_AGENT_PATTERNS {
-                    if !pattern.dir_pattern.matches(dir_name) {
-                        continue;
-                    }
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            // Check if it's a directory matching a pattern
+            if !path.is_dir() || !discovery.is_agent_dir(&path) {
+                continue;
+            }
 
-                    match pattern.file_pattern {
-                        NamePattern::Exact(file_name) => {
-                            let candidate = path.join(file_name);
-                            if candidate.exists() {
-                                log::debug!(
-                                    "Found existing SSH forwarded agent: {}",
-                                    candidate.display()
-                                );
-                                agents.push(candidate);
-                            }
-                        }
-                        NamePattern::Prefix(prefix) => {
-                            let mut agent_entries = fs::read_dir(&path).await?;
-                            while let Some(agent_entry) = agent_entries.next_entry().await? {
-                                let agent_path = agent_entry.path();
-                                if let Some(entry_name) =
-                                    agent_path.file_name().and_then(|n| n.to_str())
-                                {
-                                    if entry_name.starts_with(prefix)
-                                        && agent_path.exists()
-                                        && is_ssh_forwarded_agent(&agent_path)
-                                    {
-                                        log::debug!(
-                                            "Found existing SSH forwarded agent: {}",
-                                            agent_path.display()
-                                        );
-                                        agents.push(agent_path);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    // Continue checking other patterns, since multiple could match same directory
+            for agent_path in agents_in_directory(discovery, &path).await {
+                if !agents.contains(&agent_path) {
+                    log::debug!(
+                        "Found existing SSH forwarded agent: {}",
+                        agent_path.display()
+                    );
+                    agents.push(agent_path);
                 }
             }
         }
     }
 
+    if let (0, Some(e)) = (scanned_roots, last_error) {
+        return Err(e);
+    }
+
     log::info!("Found {} existing SSH forwarded agents", agents.len());
     Ok(agents)
 }
 
+/// Trusted forwarded agents in the agent directory `dir`; none if it can't be read
+async fn agents_in_directory(discovery: &Discovery, dir: &Path) -> Vec<PathBuf> {
+    let mut agents = Vec::new();
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::debug!("Cannot scan {}: {e}", dir.display());
+            return agents;
+        }
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if discovery.is_forwarded_agent(&path) && path.exists() && discovery.accepts(&path) {
+            agents.push(path);
+        }
+    }
+    agents
+}
+
 /// Watcher mode - either smart file watching or polling fallback
 pub enum WatchMode {
     /// Using smart file watcher (inotify/FSEvents)
@@ -412,8 +843,11 @@ pub struct WatchResult {
 ///
 /// Tries to start the smart file watcher first. If that fails,
 /// returns Polling mode instead with the error reason.
-pub async fn start_watching(tx: mpsc::UnboundedSender<WatchEvent>) -> WatchResult {
-    match watch_tmp_directory_smart(tx).await {
+pub async fn start_watching(
+    discovery: Discovery,
+    tx: mpsc::UnboundedSender<WatchEvent>,
+) -> WatchResult {
+    match watch_tmp_directory_smart(discovery, tx).await {
         Ok(watcher) => WatchResult {
             mode: WatchMode::Smart(watcher),
             fallback_reason: None,
@@ -431,9 +865,10 @@ pub async fn start_watching(tx: mpsc::UnboundedSender<WatchEvent>) -> WatchResul
 /// Run polling mode to detect changes to SSH forwarded agents
 ///
 /// This is a fallback when file watching fails (e.g., due to permissions).
-/// It periodically scans /tmp for SSH agent sockets and compares with
-/// the known set.
+/// It periodically scans the discovery roots for SSH agent sockets and
+/// compares with the known set.
 pub async fn run_polling_loop(
+    discovery: Discovery,
     tx: mpsc::UnboundedSender<WatchEvent>,
     interval: Duration,
     mut shutdown: tokio::sync::broadcast::Receiver<()>,
@@ -448,7 +883,7 @@ pub async fn run_polling_loop(
     let mut known_agents: HashSet<PathBuf> = HashSet::new();
 
     // Initial scan
-    if let Ok(agents) = scan_existing_agents().await {
+    if let Ok(agents) = scan_existing_agents(&discovery).await {
         for agent in agents {
             known_agents.insert(agent);
         }
@@ -461,7 +896,7 @@ pub async fn run_polling_loop(
     loop {
         tokio::select! {
             _ = ticker.tick() => {
-                match scan_existing_agents().await {
+                match scan_existing_agents(&discovery).await {
                     Ok(current_agents) => {
                         let current_set: HashSet<PathBuf> = current_agents.into_iter().collect();
 
@@ -597,7 +1032,7 @@ mod tests {
     async fn test_scan_existing_agents_empty_tmp() {
         // This test might fail in environments where /tmp has SSH agents
         // It's more of a smoke test to ensure the function doesn't panic
-        match scan_existing_agents().await {
+        match scan_existing_agents(&Discovery::default()).await {
             Ok(agents) => {
                 // Should succeed, might find 0 or more agents
                 log::debug!("Found {} agents", agents.len());
@@ -612,22 +1047,341 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_symlink_chain() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let target = temp_dir.path().join("store/config.toml");
+        let link = temp_dir.path().join("link.toml");
+        let config = temp_dir.path().join("config.toml");
+        std::fs::create_dir(temp_dir.path().join("store")).unwrap();
+        std::fs::write(&target, "").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        std::os::unix::fs::symlink("link.toml", &config).unwrap();
+
+        assert_eq!(
+            symlink_chain(&config),
+            vec![config.clone(), temp_dir.path().join("link.toml"), target]
+        );
+    }
+
+    /// Wait for a change notification, then swallow any more for the same change
+    async fn config_changed(rx: &mut mpsc::UnboundedReceiver<()>) -> bool {
+        let changed = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .is_ok();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        while rx.try_recv().is_ok() {}
+        changed
+    }
+
+    #[tokio::test]
+    async fn test_watch_config_file_follows_symlinks() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let store = temp_dir.path().join("store");
+        std::fs::create_dir(&store).unwrap();
+        let config = temp_dir.path().join("config.toml");
+        std::fs::write(store.join("1.toml"), "log_level = \"warn\"").unwrap();
+        std::os::unix::fs::symlink(store.join("1.toml"), &config).unwrap();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut watcher = watch_config_file(&config, tx).unwrap();
+
+        // Repointing the symlink atomically, as home-manager does
+        std::fs::write(store.join("2.toml"), "log_level = \"info\"").unwrap();
+        let new_link = temp_dir.path().join("config.toml.tmp");
+        std::os::unix::fs::symlink(store.join("2.toml"), &new_link).unwrap();
+        std::fs::rename(&new_link, &config).unwrap();
+        assert!(config_changed(&mut rx).await);
+
+        // Editing the file the symlink now points to
+        watcher.refresh();
+        std::fs::write(store.join("2.toml"), "log_level = \"debug\"").unwrap();
+        assert!(config_changed(&mut rx).await);
+
+        // Saving by renaming a new file over the config, as many editors do
+        watcher.refresh();
+        let new_file = temp_dir.path().join(".config.toml.swp");
+        std::fs::write(&new_file, "log_level = \"error\"").unwrap();
+        std::fs::rename(&new_file, &config).unwrap();
+        assert!(config_changed(&mut rx).await);
+
+        // Reading the config, as a reload does, isn't a change
+        std::fs::read_to_string(&config).unwrap();
+        assert!(tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_watch_config_drop_in_dir() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = temp_dir.path().join("config.toml");
+        let drop_in_dir = temp_dir.path().join("config.d");
+        std::fs::write(&config, "").unwrap();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut watcher = watch_config_file(&config, tx).unwrap();
+        watcher.set_paths(vec![config.clone(), drop_in_dir.clone()]);
+
+        // Creating the drop-in directory, then a file in it
+        std::fs::create_dir(&drop_in_dir).unwrap();
+        assert!(config_changed(&mut rx).await);
+        watcher.refresh();
+        std::fs::write(drop_in_dir.join("10-local.toml"), "watch_config = true").unwrap();
+        assert!(config_changed(&mut rx).await);
+
+        // Other files next to the configuration aren't watched
+        std::fs::write(temp_dir.path().join("notes.txt"), "").unwrap();
+        assert!(!config_changed(&mut rx).await);
+    }
+
     #[test]
     fn test_should_watch_directory() {
+        let should_watch_directory =
+            |path: &str| Discovery::default().is_agent_dir(Path::new(path));
+
         // Should match ssh-* directories
-        assert!(should_watch_directory(Path::new("/tmp/ssh-abc123")));
-        assert!(should_watch_directory(Path::new("/tmp/ssh-XXXXXX")));
+        assert!(should_watch_directory("/tmp/ssh-abc123"));
+        assert!(should_watch_directory("/tmp/ssh-XXXXXX"));
 
         // Should match auth-agent* directories
-        assert!(should_watch_directory(Path::new("/tmp/auth-agent123456")));
-        assert!(should_watch_directory(Path::new("/tmp/auth-agent999")));
+        assert!(should_watch_directory("/tmp/auth-agent123456"));
+        assert!(should_watch_directory("/tmp/auth-agent999"));
 
         // Should NOT match other directories
-        assert!(!should_watch_directory(Path::new(
-            "/tmp/systemd-private-abc"
-        )));
-        assert!(!should_watch_directory(Path::new("/tmp/snap-private-tmp")));
-        assert!(!should_watch_directory(Path::new("/tmp/random-dir")));
-        assert!(!should_watch_directory(Path::new("/tmp/.X11-unix")));
+        assert!(!should_watch_directory("/tmp/systemd-private-abc"));
+        assert!(!should_watch_directory("/tmp/snap-private-tmp"));
+        assert!(!should_watch_directory("/tmp/random-dir"));
+        assert!(!should_watch_directory("/tmp/.X11-unix"));
+
+        // Only directly in a root
+        assert!(!should_watch_directory("/tmp/nested/ssh-abc123"));
+        assert!(!should_watch_directory("/var/tmp/ssh-abc123"));
+    }
+
+    /// Discovery in `root`, with an extra pattern matching `fwd-*/sock` and `relay*/agent.sock`
+    fn test_discovery(root: &Path) -> Discovery {
+        #[derive(Deserialize)]
+        struct Patterns {
+            patterns: Vec<ForwardedAgentPattern>,
+        }
+        let extra: Patterns = toml::from_str(
+            r#"
+            patterns = [
+                "fwd-*/sock",
+                { dir = { prefix = "relay" }, file = { exact = "agent.sock" } },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        Discovery {
+            roots: vec![root.to_path_buf()],
+            patterns: [FORWARDED_AGENT_PATTERNS.to_vec(), extra.patterns].concat(),
+            trusted: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_discovery_patterns() {
+        let root = Path::new("/run/user/1000");
+        let discovery = test_discovery(root);
+
+        assert!(discovery.is_forwarded_agent(&root.join("ssh-abc/agent.1")));
+        assert!(discovery.is_forwarded_agent(&root.join("fwd-1/sock")));
+        assert!(discovery.is_forwarded_agent(&root.join("relay-x/agent.sock")));
+        assert!(!discovery.is_forwarded_agent(&root.join("fwd-1/sock2")));
+        assert!(!discovery.is_forwarded_agent(&root.join("relay-x/agent.sock.bak")));
+        assert!(!discovery.is_forwarded_agent(Path::new("/tmp/fwd-1/sock")));
+
+        assert!("fwd-*".parse::<ForwardedAgentPattern>().is_err());
+        assert!("a/b/c".parse::<ForwardedAgentPattern>().is_err());
+        assert!(toml::from_str::<ForwardedAgentPattern>(
+            "dir = { suffix = 'x' }\nfile = { exact = 'y' }"
+        )
+        .is_err());
+
+        // Extra roots and patterns come after the defaults, without duplicates
+        let discovery = Discovery::with_extra(
+            &[PathBuf::from("/tmp"), root.to_path_buf()],
+            &FORWARDED_AGENT_PATTERNS[..1],
+        );
+        assert_eq!(
+            discovery.roots,
+            vec![PathBuf::from("/tmp"), root.to_path_buf()]
+        );
+        assert_eq!(discovery.patterns, FORWARDED_AGENT_PATTERNS);
+    }
+
+    /// Create `path` and its missing parents with mode 0700
+    fn create_private_dir(path: &Path) {
+        use std::os::unix::fs::DirBuilderExt;
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(path)
+            .unwrap();
+    }
+
+    /// Create an empty file at `path` with mode 0600
+    fn create_private_file(path: &Path) {
+        use std::os::unix::fs::OpenOptionsExt;
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .unwrap();
+    }
+
+    /// Create the agent directories and sockets in `root` for the discovery tests
+    fn create_agents(root: &Path, paths: &[&str]) -> Vec<PathBuf> {
+        paths
+            .iter()
+            .map(|path| {
+                let path = root.join(path);
+                create_private_dir(path.parent().unwrap());
+                create_private_file(&path);
+                path
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_scan_existing_agents_in_root() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let agents = create_agents(root, &["ssh-abc/agent.1", "fwd-1/sock", "relay/agent.sock"]);
+        create_agents(
+            root,
+            &["other/agent.1", "ssh-abc/notes", "fwd-1/nested/sock"],
+        );
+
+        let mut found = scan_existing_agents(&test_discovery(root)).await.unwrap();
+        found.sort();
+        let mut expected = agents;
+        expected.sort();
+        assert_eq!(found, expected);
+
+        let missing = Discovery {
+            roots: vec![root.join("missing")],
+            patterns: vec![],
+            trusted: vec![],
+        };
+        assert!(scan_existing_agents(&missing).await.is_err());
+    }
+
+    async fn next_event(rx: &mut mpsc::UnboundedReceiver<WatchEvent>) -> Option<WatchEvent> {
+        tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[tokio::test]
+    async fn test_scan_skips_accessible_agents() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let agents = create_agents(root, &["ssh-abc/agent.1", "ssh-def/agent.1", "fwd-1/sock"]);
+        std::fs::set_permissions(root.join("ssh-def"), PermissionsExt::from_mode(0o755)).unwrap();
+        std::fs::set_permissions(&agents[2], PermissionsExt::from_mode(0o660)).unwrap();
+
+        assert!(verify_ownership(&agents[0]).is_ok());
+        assert!(matches!(
+            verify_ownership(&agents[1]),
+            Err(OwnershipError::Accessible { path, mode }) if path == root.join("ssh-def") && mode & 0o777 == 0o755
+        ));
+        assert!(matches!(
+            verify_ownership(&agents[2]),
+            Err(OwnershipError::Accessible { path, .. }) if path == agents[2]
+        ));
+
+        let mut discovery = test_discovery(root);
+        assert_eq!(
+            scan_existing_agents(&discovery).await.unwrap(),
+            vec![agents[0].clone()]
+        );
+
+        discovery.trusted = vec![root.join("ssh-*/agent.*")];
+        let mut found = scan_existing_agents(&discovery).await.unwrap();
+        found.sort();
+        assert_eq!(found, agents[..2]);
+    }
+
+    #[tokio::test]
+    async fn test_smart_watcher_in_root() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_private_dir(&root.join("fwd-1"));
+        create_private_dir(&root.join("other"));
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let watcher = watch_tmp_directory_smart(test_discovery(root), tx)
+            .await
+            .unwrap();
+        assert_eq!(watcher.watched_directories(), vec![root.join("fwd-1")]);
+
+        std::fs::write(root.join("other/sock"), "").unwrap();
+        let agent = create_agents(root, &["fwd-1/sock"]).remove(0);
+        assert_eq!(
+            next_event(&mut rx).await,
+            Some(WatchEvent::Added(agent.clone()))
+        );
+
+        std::fs::remove_file(&agent).unwrap();
+        assert_eq!(next_event(&mut rx).await, Some(WatchEvent::Removed(agent)));
+    }
+
+    #[tokio::test]
+    async fn test_smart_watcher_new_directory() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let watcher = watch_tmp_directory_smart(test_discovery(root), tx)
+            .await
+            .unwrap();
+        assert!(watcher.watched_directories().is_empty());
+
+        // The socket usually appears before the new directory is watched
+        create_private_dir(&root.join("fwd-2"));
+        let agent = root.join("fwd-2/sock");
+        create_private_file(&agent);
+        assert_eq!(
+            next_event(&mut rx).await,
+            Some(WatchEvent::Added(agent.clone()))
+        );
+        assert_eq!(watcher.watched_directories(), vec![root.join("fwd-2")]);
+    }
+
+    #[tokio::test]
+    async fn test_polling_loop_in_root() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (shutdown, _) = tokio::sync::broadcast::channel(1);
+        let polling = tokio::spawn(run_polling_loop(
+            test_discovery(root),
+            tx,
+            Duration::from_millis(100),
+            shutdown.subscribe(),
+        ));
+
+        // Give the loop time for its initial scan
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let agent = create_agents(root, &["relay/agent.sock"]).remove(0);
+        assert_eq!(
+            next_event(&mut rx).await,
+            Some(WatchEvent::Added(agent.clone()))
+        );
+
+        std::fs::remove_file(&agent).unwrap();
+        assert_eq!(next_event(&mut rx).await, Some(WatchEvent::Removed(agent)));
+
+        shutdown.send(()).unwrap();
+        polling.await.unwrap();
     }
 }
@@ -1,15 +1,29 @@
+//! Helpers for running OpenSSH and mux agents in integration tests
+// Not every test crate uses every helper
+#![allow(dead_code)]
+
 use std::{
     ffi::{OsStr, OsString},
-    fs,
+    fs::Permissions,
     io::{self, Write},
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::PathBuf,
     time::{Duration, Instant},
 };
 
 use duct::{cmd, unix::HandleExt, Handle};
-use tempfile::TempPath;
+use ssh_agent_lib::{
+    agent::{self, Session},
+    client,
+    error::AgentError,
+    proto::{AddIdentity, Identity, RemoveIdentity, SignRequest},
+    ssh_key::{PublicKey, Signature},
+};
+use tempfile::{NamedTempFile, TempDir};
 
 const AGENT_TIMEOUT: Duration = Duration::from_secs(2);
 const AGENT_POLL: Duration = Duration::from_micros(100);
+const SIGHUP: std::ffi::c_int = 1;
 const SIGTERM: std::ffi::c_int = 15;
 
 pub enum SshAgentType {
@@ -17,11 +31,14 @@ pub enum SshAgentType {
     Mux,
 }
 
-#[allow(dead_code)]
 #[derive(Debug)]
 pub struct SshAgentInstance {
     pub handle: Handle,
-    pub sock_path: TempPath,
+    pub sock_path: PathBuf,
+    /// Private (0700) directory holding the socket, as the mux requires
+    sock_dir: TempDir,
+    /// Config file for mux agents, kept around for the daemon to re-read
+    config_file: Option<NamedTempFile>,
 }
 
 fn map_binary_notfound_error(binary_name: &str, err: io::Error) -> io::Error {
@@ -41,12 +58,11 @@ impl SshAgentInstance {
         I: IntoIterator<Item = A> + Clone + Send + Sync + 'static,
         A: AsRef<OsStr>,
     {
-        let sock_path = tempfile::Builder::new()
+        let sock_dir = tempfile::Builder::new()
             .prefix("agent_")
-            .suffix(".sock")
-            .tempfile_in(env!("CARGO_TARGET_TMPDIR"))?
-            .into_temp_path();
-        fs::remove_file(&sock_path)?;
+            .permissions(Permissions::from_mode(0o700))
+            .tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+        let sock_path = sock_dir.path().join("agent.sock");
 
         let cmd = match agent_type {
             SshAgentType::OpenSsh => cmd!("ssh-agent", "-d", "-a", &sock_path),
@@ -70,7 +86,8 @@ impl SshAgentInstance {
             })
             .start()?;
         let agent_start_time = Instant::now();
-        while !sock_path.exists() {
+        // The socket file appears just before the agent starts listening on it
+        while UnixStream::connect(&sock_path).is_err() {
             std::thread::sleep(AGENT_POLL);
             if agent_start_time.elapsed() >= AGENT_TIMEOUT {
                 return Err(io::Error::new(
@@ -80,7 +97,12 @@ impl SshAgentInstance {
             }
         }
 
-        Ok(Self { handle, sock_path })
+        Ok(Self {
+            handle,
+            sock_path,
+            sock_dir,
+            config_file: None,
+        })
     }
 
     pub fn new_openssh() -> io::Result<Self> {
@@ -102,8 +124,25 @@ impl SshAgentInstance {
         let mut config_args = vec![A::from(config_arg)];
         config_args.extend(args);
 
-        Self::new(SshAgentType::Mux, config_args)
-            .map_err(|e| map_binary_notfound_error(env!("CARGO_BIN_EXE_ssh-agent-mux"), e))
+        let mut agent = Self::new(SshAgentType::Mux, config_args)
+            .map_err(|e| map_binary_notfound_error(env!("CARGO_BIN_EXE_ssh-agent-mux"), e))?;
+        agent.config_file = Some(config_file);
+        Ok(agent)
+    }
+
+    /// Replace a mux agent's config file
+    pub fn write_config(&self, config: &str) -> io::Result<()> {
+        let config_file = self
+            .config_file
+            .as_ref()
+            .ok_or_else(|| io::Error::other("only mux agents have a config file"))?;
+        std::fs::write(config_file.path(), config)
+    }
+
+    /// Replace a mux agent's config file and tell it to reload
+    pub fn reload_with(&self, config: &str) -> io::Result<()> {
+        self.write_config(config)?;
+        self.handle.send_signal(SIGHUP)
     }
 
     pub fn add(&self, key: &str) -> io::Result<()> {
@@ -117,6 +156,78 @@ impl SshAgentInstance {
         Ok(())
     }
 
+    pub fn add_with_lifetime(&self, key: &str, lifetime_secs: u32) -> io::Result<()> {
+        // Add an ssh-key from stdin, constrained to expire after lifetime_secs
+        cmd!("ssh-add", "-q", "-t", lifetime_secs.to_string(), "--", "-")
+            .env("SSH_AUTH_SOCK", &self.sock_path)
+            .stdin_bytes(key)
+            .run()
+            .map_err(|e| map_binary_notfound_error("ssh-add", e))?;
+
+        Ok(())
+    }
+
+    pub fn add_with_confirm(&self, key: &str) -> io::Result<()> {
+        // Add an ssh-key from stdin, constrained to require confirmation for each use
+        cmd!("ssh-add", "-q", "-c", "--", "-")
+            .env("SSH_AUTH_SOCK", &self.sock_path)
+            .stdin_bytes(key)
+            .run()
+            .map_err(|e| map_binary_notfound_error("ssh-add", e))?;
+
+        Ok(())
+    }
+
+    /// Send a sign request for `pubkey` straight to this agent, without listing its keys first
+    pub fn request_signature(&self, pubkey: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let pubkey = PublicKey::from_openssh(pubkey)?.key_data().clone();
+        let stream = UnixStream::connect(&self.sock_path)?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(async move {
+            let mut client = client::connect(stream.into())?;
+            client
+                .sign(SignRequest {
+                    pubkey,
+                    data: b"data to sign".to_vec(),
+                    flags: 0,
+                })
+                .await?;
+            Ok(())
+        })
+    }
+
+    /// Sign some data with the key whose public half is `pubkey`, through this agent
+    pub fn sign(&self, pubkey: &str) -> io::Result<()> {
+        let dir = tempfile::Builder::new()
+            .prefix("ssh-sign_")
+            .tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+        let pubkey_path = dir.path().join("key.pub");
+        let data_path = dir.path().join("data");
+        std::fs::write(&pubkey_path, pubkey)?;
+        std::fs::write(&data_path, "data to sign")?;
+
+        cmd!(
+            "ssh-keygen",
+            "-q",
+            "-Y",
+            "sign",
+            "-n",
+            "file",
+            "-f",
+            &pubkey_path,
+            &data_path
+        )
+        .env("SSH_AUTH_SOCK", &self.sock_path)
+        .stdout_null()
+        .stderr_null()
+        .run()
+        .map_err(|e| map_binary_notfound_error("ssh-keygen", e))?;
+
+        Ok(())
+    }
+
     pub fn list(&self) -> io::Result<Vec<String>> {
         let output = cmd!("ssh-add", "-L")
             .env("SSH_AUTH_SOCK", &self.sock_path)
@@ -152,6 +263,87 @@ impl SshAgentInstance {
     }
 }
 
+/// An agent in front of an OpenSSH agent that refuses constrained adds, as many hardware-token and
+/// password-manager agents do
+#[derive(Debug)]
+pub struct ConstraintRejectingAgent {
+    pub sock_path: PathBuf,
+    /// OpenSSH agent holding the keys
+    pub upstream: SshAgentInstance,
+    sock_dir: TempDir,
+}
+
+impl ConstraintRejectingAgent {
+    /// Start the agent; with `allow_removal` false it also refuses to remove keys
+    pub fn new(allow_removal: bool) -> io::Result<Self> {
+        let upstream = SshAgentInstance::new_openssh()?;
+        let sock_dir = tempfile::Builder::new()
+            .prefix("agent_")
+            .permissions(Permissions::from_mode(0o700))
+            .tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+        let sock_path = sock_dir.path().join("agent.sock");
+
+        let listener = std::os::unix::net::UnixListener::bind(&sock_path)?;
+        listener.set_nonblocking(true)?;
+        let session = RejectConstraints {
+            upstream: upstream.sock_path.clone(),
+            allow_removal,
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        // Runs until the test process exits
+        std::thread::spawn(move || {
+            runtime.block_on(async move {
+                let listener = tokio::net::UnixListener::from_std(listener)?;
+                agent::listen(listener, session).await
+            })
+        });
+
+        Ok(Self {
+            sock_path,
+            upstream,
+            sock_dir,
+        })
+    }
+}
+
+#[derive(Clone)]
+struct RejectConstraints {
+    upstream: PathBuf,
+    allow_removal: bool,
+}
+
+impl RejectConstraints {
+    fn connect(&self) -> Result<Box<dyn Session>, AgentError> {
+        let stream = UnixStream::connect(&self.upstream)?;
+        client::connect(stream.into()).map_err(|e| AgentError::Other(e.to_string().into()))
+    }
+}
+
+// Constrained adds get the default implementation, which fails
+#[ssh_agent_lib::async_trait]
+impl Session for RejectConstraints {
+    async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+        self.connect()?.request_identities().await
+    }
+
+    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        self.connect()?.sign(request).await
+    }
+
+    async fn add_identity(&mut self, identity: AddIdentity) -> Result<(), AgentError> {
+        self.connect()?.add_identity(identity).await
+    }
+
+    async fn remove_identity(&mut self, identity: RemoveIdentity) -> Result<(), AgentError> {
+        if !self.allow_removal {
+            return Err(AgentError::Failure);
+        }
+        self.connect()?.remove_identity(identity).await
+    }
+}
+
 impl Drop for SshAgentInstance {
     fn drop(&mut self) {
         self.handle.send_signal(SIGTERM).expect("SIGTERM failed");
@@ -1,6 +1,16 @@
-use std::{ffi::OsString, io};
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, Read, Write},
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
 
-use harness::SshAgentInstance;
+use duct::cmd;
+use harness::{ConstraintRejectingAgent, SshAgentInstance};
+use ssh_agent_mux::control::ControlClient;
 
 mod harness;
 mod keys;
@@ -85,3 +95,1246 @@ fn mux_with_three_agents() -> TestResult {
 
     Ok(())
 }
+
+#[test]
+fn add_key_through_mux() -> TestResult {
+    let upstream = SshAgentInstance::new_openssh()?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = ["{}"]"##,
+            upstream.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    mux_agent.add(keys::TEST_KEY_ED25519)?;
+
+    // Key must land in the upstream agent and be visible through the mux
+    let upstream_keys = upstream.list()?;
+    assert_eq!(upstream_keys, vec![keys::TEST_KEY_ED25519_PUB.to_string()]);
+    let mux_keys = mux_agent.list()?;
+    assert_eq!(mux_keys, vec![keys::TEST_KEY_ED25519_PUB.to_string()]);
+
+    Ok(())
+}
+
+#[test]
+fn add_key_with_lifetime_through_mux() -> TestResult {
+    let upstream = SshAgentInstance::new_openssh()?;
+    upstream.add(keys::TEST_KEY_RSA)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = ["{}"]"##,
+            upstream.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    mux_agent.add_with_lifetime(keys::TEST_KEY_ED25519, 1)?;
+    let keys_before = mux_agent.list()?;
+    assert!(keys_before.iter().any(|k| k == keys::TEST_KEY_ED25519_PUB));
+
+    thread::sleep(Duration::from_millis(2500));
+
+    // Only the expiring key is gone
+    let keys_after = mux_agent.list()?;
+    assert_eq!(keys_after, vec![keys::TEST_KEY_RSA_PUB.to_string()]);
+
+    Ok(())
+}
+
+#[test]
+fn mux_enforces_lifetime_when_upstream_rejects_constraints() -> TestResult {
+    let upstream = ConstraintRejectingAgent::new(true)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = ["{}"]"##,
+            upstream.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    mux_agent.add_with_lifetime(keys::TEST_KEY_ED25519, 1)?;
+    assert_eq!(
+        mux_agent.list()?,
+        vec![keys::TEST_KEY_ED25519_PUB.to_string()]
+    );
+    mux_agent.request_signature(keys::TEST_KEY_ED25519_PUB)?;
+
+    thread::sleep(Duration::from_millis(2500));
+
+    // The mux removed the key from the upstream agent once its lifetime was over
+    assert!(upstream.upstream.list()?.is_empty());
+    assert!(mux_agent.list()?.is_empty());
+    assert!(mux_agent
+        .request_signature(keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn mux_hides_expired_key_that_upstream_keeps() -> TestResult {
+    let upstream = ConstraintRejectingAgent::new(false)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = ["{}"]"##,
+            upstream.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    mux_agent.add_with_lifetime(keys::TEST_KEY_ED25519, 1)?;
+    thread::sleep(Duration::from_millis(2500));
+
+    // Removal failed, so the key is still upstream but no longer usable through the mux
+    assert_eq!(
+        upstream.upstream.list()?,
+        vec![keys::TEST_KEY_ED25519_PUB.to_string()]
+    );
+    assert!(mux_agent.list()?.is_empty());
+    assert!(mux_agent
+        .request_signature(keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn mux_asks_for_confirmation_when_upstream_rejects_constraints() -> TestResult {
+    let upstream = ConstraintRejectingAgent::new(true)?;
+    // Records that it was asked, and refuses
+    let askpass_dir = tempfile::TempDir::new_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let askpass = askpass_dir.path().join("askpass");
+    let asked = askpass_dir.path().join("asked");
+    fs::write(
+        &askpass,
+        format!("#!/bin/sh\necho \"$1\" > '{}'\nexit 1\n", asked.display()),
+    )?;
+    fs::set_permissions(&askpass, fs::Permissions::from_mode(0o755))?;
+    std::env::set_var("SSH_ASKPASS", &askpass);
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = ["{}"]"##,
+            upstream.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    mux_agent.add_with_confirm(keys::TEST_KEY_ED25519)?;
+    assert_eq!(
+        mux_agent.list()?,
+        vec![keys::TEST_KEY_ED25519_PUB.to_string()]
+    );
+
+    assert!(mux_agent
+        .request_signature(keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+    let prompt = fs::read_to_string(&asked)?;
+    assert!(prompt.starts_with("Allow use of key SHA256:"), "{prompt}");
+
+    Ok(())
+}
+
+#[test]
+fn second_mux_instance_refuses_to_start() -> TestResult {
+    let mux_agent = SshAgentInstance::new_mux("", None::<OsString>)?;
+
+    let output = cmd!(
+        env!("CARGO_BIN_EXE_ssh-agent-mux"),
+        "serve",
+        "--listen",
+        &mux_agent.sock_path
+    )
+    .unchecked()
+    .stderr_to_stdout()
+    .stdout_capture()
+    .run()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(!output.status.success());
+    assert!(
+        stdout.contains(&format!(
+            "already running (PID {}",
+            mux_agent.handle.pids()[0]
+        )),
+        "Unexpected output: {stdout}"
+    );
+
+    // The running instance keeps serving
+    assert!(mux_agent.list()?.is_empty());
+
+    Ok(())
+}
+
+/// Wait for the daemon to listen on a (re)created socket
+fn wait_for_socket(path: &Path) -> TestResult {
+    let start = Instant::now();
+    while UnixStream::connect(path).is_err() {
+        if start.elapsed() > Duration::from_secs(5) {
+            return Err(format!("{} was not recreated", path.display()).into());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+#[test]
+fn mux_rebinds_deleted_sockets() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            "agent_sock_paths = [\"{}\"]\nhealth_check_interval = 1\n",
+            openssh_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    let control_path = mux_agent.sock_path.with_extension("ctl");
+
+    // A session connected before the sockets vanish must keep working
+    let mut session = UnixStream::connect(&mux_agent.sock_path)?;
+
+    fs::remove_file(&mux_agent.sock_path)?;
+    fs::remove_file(&control_path)?;
+    wait_for_socket(&mux_agent.sock_path)?;
+    wait_for_socket(&control_path)?;
+
+    assert_all_keys_in_agent(&mux_agent)?;
+    ControlClient::connect(&control_path)?.ping()?;
+
+    // SSH_AGENTC_REQUEST_IDENTITIES, answered by SSH_AGENT_IDENTITIES_ANSWER
+    session.write_all(&[0, 0, 0, 1, 11])?;
+    let mut header = [0; 5];
+    session.read_exact(&mut header)?;
+    assert_eq!(header[4], 12);
+
+    Ok(())
+}
+
+#[test]
+fn mux_upgrade_keeps_sockets_and_sessions() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let forwarded_agent = SshAgentInstance::new_openssh()?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            "agent_sock_paths = [\"{}\"]\n",
+            openssh_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    let control_path = mux_agent.sock_path.with_extension("ctl");
+
+    let mut control = ControlClient::connect(&control_path)?;
+    control.add_socket(&forwarded_agent.sock_path.to_string_lossy())?;
+    let old_pid = control.status()?.pid;
+    let sockets_before = control.list_sockets()?;
+
+    // A session connected before the upgrade is served to the end by the old daemon
+    let mut session = UnixStream::connect(&mux_agent.sock_path)?;
+
+    let output = cmd!(
+        env!("CARGO_BIN_EXE_ssh-agent-mux"),
+        "--control-socket",
+        &control_path,
+        "upgrade"
+    )
+    .stderr_to_stdout()
+    .stdout_capture()
+    .unchecked()
+    .run()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "Upgrade failed: {stdout}");
+
+    let mut control = ControlClient::connect(&control_path)?;
+    let new_pid = control.status()?.pid;
+    assert_ne!(new_pid, old_pid);
+    // The successor isn't managed by the harness, and holds on to its output pipe
+    let _successor = KillOnDrop(new_pid);
+    assert!(
+        stdout.contains(&new_pid.to_string()),
+        "Unexpected output: {stdout}"
+    );
+
+    // Sockets added at runtime survive, in the same order
+    let sockets_after = control.list_sockets()?;
+    let summary = |sockets: &[ssh_agent_mux::control::SocketInfo]| {
+        sockets
+            .iter()
+            .map(|s| (s.path.clone(), s.source, s.added_at.clone(), s.order))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(summary(&sockets_after), summary(&sockets_before));
+
+    assert_all_keys_in_agent(&mux_agent)?;
+
+    // SSH_AGENTC_REQUEST_IDENTITIES, answered by SSH_AGENT_IDENTITIES_ANSWER
+    session.write_all(&[0, 0, 0, 1, 11])?;
+    let mut header = [0; 5];
+    session.read_exact(&mut header)?;
+    assert_eq!(header[4], 12);
+
+    Ok(())
+}
+
+/// Terminates a process the harness doesn't know about when dropped
+struct KillOnDrop(u32);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        // SAFETY: plain kill(2) on a PID we started
+        unsafe { libc::kill(self.0 as libc::pid_t, libc::SIGTERM) };
+    }
+}
+
+#[test]
+fn mux_reloads_config_on_sighup() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let mux_agent = SshAgentInstance::new_mux("", None::<OsString>)?;
+    let control_path = mux_agent.sock_path.with_extension("ctl");
+    let moved_control_path = mux_agent.sock_path.with_file_name("moved.ctl");
+    assert!(mux_agent.list()?.is_empty());
+
+    mux_agent.reload_with(&format!(
+        "agent_sock_paths = [\"{}\"]\ncontrol_socket_path = \"{}\"\n",
+        openssh_agent.sock_path.display(),
+        moved_control_path.display()
+    ))?;
+    wait_for_socket(&moved_control_path)?;
+
+    assert_all_keys_in_agent(&mux_agent)?;
+    let status = ControlClient::connect(&moved_control_path)?.status()?;
+    assert_eq!(
+        status.control_socket,
+        moved_control_path.display().to_string()
+    );
+    assert!(!control_path.exists());
+
+    // An invalid config leaves the running one in place
+    mux_agent.reload_with("agent_sock_paths = \"not a list\"\n")?;
+    thread::sleep(Duration::from_millis(200));
+    assert_all_keys_in_agent(&mux_agent)?;
+    ControlClient::connect(&moved_control_path)?.ping()?;
+
+    Ok(())
+}
+
+#[test]
+fn mux_reloads_config_when_file_changes() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let mux_agent = SshAgentInstance::new_mux("watch_config = true\n", None::<OsString>)?;
+    assert!(mux_agent.list()?.is_empty());
+
+    mux_agent.write_config(&format!(
+        "watch_config = true\nagent_sock_paths = [\"{}\"]\n",
+        openssh_agent.sock_path.display()
+    ))?;
+
+    let start = Instant::now();
+    while mux_agent.list()?.is_empty() {
+        if start.elapsed() > Duration::from_secs(5) {
+            return Err("config change was not picked up".into());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_all_keys_in_agent(&mux_agent)?;
+
+    Ok(())
+}
+
+#[test]
+fn check_config_reports_problems() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let config_dir = tempfile::TempDir::new_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let config_path = config_dir.path().join("ssh-agent-mux.toml");
+    let missing_sock = config_dir.path().join("missing.sock");
+    let check_config = || {
+        cmd!(
+            env!("CARGO_BIN_EXE_ssh-agent-mux"),
+            "check-config",
+            "--config",
+            &config_path
+        )
+        .stderr_to_stdout()
+        .stdout_capture()
+        .unchecked()
+        .run()
+    };
+
+    fs::write(
+        &config_path,
+        format!(
+            "agent_sock_paths = [\"{}\"]\n",
+            openssh_agent.sock_path.display()
+        ),
+    )?;
+    let output = check_config()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "Unexpected failure: {stdout}");
+    assert!(
+        stdout.contains(&format!(
+            "✓ {} ({} keys)",
+            openssh_agent.sock_path.display(),
+            keys::PRIVATE.len()
+        )),
+        "Unexpected output: {stdout}"
+    );
+
+    fs::write(
+        &config_path,
+        format!(
+            "agent_sock_paths = [\"{}\"]\nwatch_for_ssh_forwarding = true\n",
+            missing_sock.display()
+        ),
+    )?;
+    let output = check_config()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success(), "Unexpected success: {stdout}");
+    assert!(
+        stdout.contains("✗ watch_for_ssh_forwarding"),
+        "Unexpected output: {stdout}"
+    );
+    assert!(
+        stdout.contains(&format!("✗ {}: missing", missing_sock.display())),
+        "Unexpected output: {stdout}"
+    );
+    assert!(
+        stdout.contains("2 problem(s) found."),
+        "Unexpected output: {stdout}"
+    );
+
+    // A name can't tell apart the sockets a glob matches
+    fs::write(
+        &config_path,
+        "[[upstream]]\nname = \"gpg\"\npath = \"/run/user/*/gnupg/S.gpg-agent.ssh\"\n",
+    )?;
+    let output = check_config()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success(), "Unexpected success: {stdout}");
+    assert!(
+        stdout.contains("Upstream agent gpg has glob path"),
+        "Unexpected output: {stdout}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn check_config_merges_includes_and_drop_ins() -> TestResult {
+    let agent_rsa = SshAgentInstance::new_openssh()?;
+    let agent_ed25519 = SshAgentInstance::new_openssh()?;
+    let config_dir = tempfile::TempDir::new_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let config_path = config_dir.path().join("ssh-agent-mux.toml");
+    let base_path = config_dir.path().join("base.toml");
+    let drop_in_path = config_dir.path().join("ssh-agent-mux.d/10-laptop.toml");
+    fs::create_dir(config_dir.path().join("ssh-agent-mux.d"))?;
+
+    fs::write(
+        &base_path,
+        format!(
+            "health_check_interval = 5\n[[upstream]]\nname = \"rsa\"\npath = \"{}\"\n",
+            agent_rsa.sock_path.display()
+        ),
+    )?;
+    fs::write(
+        &config_path,
+        "include = [\"base.toml\"]\nwatch_config = true\n",
+    )?;
+    fs::write(
+        &drop_in_path,
+        format!(
+            "health_check_interval = 30\n[[upstream]]\nname = \"ed25519\"\npath = \"{}\"\n",
+            agent_ed25519.sock_path.display()
+        ),
+    )?;
+
+    let output = cmd!(
+        env!("CARGO_BIN_EXE_ssh-agent-mux"),
+        "--json",
+        "check-config",
+        "--config",
+        &config_path,
+        "--log-level",
+        "debug"
+    )
+    .stdout_capture()
+    .run()?;
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+
+    let config_files: Vec<_> = report["config_files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f.as_str().unwrap())
+        .collect();
+    assert_eq!(
+        config_files,
+        [&base_path, &config_path, &drop_in_path].map(|p| p.to_str().unwrap())
+    );
+
+    let config = &report["config"];
+    assert_eq!(config["health_check_interval"], 30);
+    assert_eq!(config["watch_config"], true);
+    assert_eq!(config["log_level"], "debug");
+    let names: Vec<_> = config["upstream"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|u| u["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["rsa", "ed25519"]);
+
+    let sources = &report["sources"];
+    let file = |path: &std::path::Path| serde_json::json!([{ "file": path }]);
+    assert_eq!(sources["health_check_interval"], file(&drop_in_path));
+    assert_eq!(sources["watch_config"], file(&config_path));
+    assert_eq!(sources["log_level"], serde_json::json!(["command_line"]));
+    assert_eq!(
+        sources["upstream"],
+        serde_json::json!([{ "file": base_path }, { "file": drop_in_path }])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn check_config_applies_environment_overrides() -> TestResult {
+    let config_dir = tempfile::TempDir::new_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let config_path = config_dir.path().join("ssh-agent-mux.toml");
+    fs::write(
+        &config_path,
+        "log_level = \"info\"\nhealth_check_interval = 5\nagent_sock_paths = [\"/run/a.sock\"]\n",
+    )?;
+    let check_config = |interval: &str| {
+        cmd!(
+            env!("CARGO_BIN_EXE_ssh-agent-mux"),
+            "--json",
+            "check-config",
+            "--log-level",
+            "error"
+        )
+        .env("SSH_AGENT_MUX_CONFIG", &config_path)
+        .env("SSH_AGENT_MUX_LOG_LEVEL", "debug")
+        .env("SSH_AGENT_MUX_AGENT_SOCK_PATHS", "/run/b.sock:/run/c.sock")
+        .env("SSH_AGENT_MUX_WATCH_CONFIG", "1")
+        .env("SSH_AGENT_MUX_HEALTH_CHECK_INTERVAL", interval)
+        .stdout_capture()
+        .unchecked()
+        .run()
+    };
+
+    // An empty variable doesn't override the file
+    let output = check_config("")?;
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(report["config_file"], config_path.to_str().unwrap());
+
+    let config = &report["config"];
+    assert_eq!(config["log_level"], "error");
+    assert_eq!(
+        config["agent_sock_paths"],
+        serde_json::json!(["/run/b.sock", "/run/c.sock"])
+    );
+    assert_eq!(config["watch_config"], true);
+    assert_eq!(config["health_check_interval"], 5);
+
+    let sources = &report["sources"];
+    assert_eq!(sources["log_level"], serde_json::json!(["command_line"]));
+    assert_eq!(
+        sources["agent_sock_paths"],
+        serde_json::json!([
+            { "environment": "SSH_AGENT_MUX_AGENT_SOCK_PATHS" },
+            { "environment": "SSH_AGENT_MUX_AGENT_SOCK_PATHS" }
+        ])
+    );
+    assert_eq!(
+        sources["health_check_interval"],
+        serde_json::json!([{ "file": config_path }])
+    );
+
+    let output = check_config("soon")?;
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert!(!output.status.success());
+    assert_eq!(
+        report["error"],
+        "SSH_AGENT_MUX_HEALTH_CHECK_INTERVAL must be a number, not \"soon\""
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_with_named_upstreams() -> TestResult {
+    let agent_rsa = SshAgentInstance::new_openssh()?;
+    agent_rsa.add(keys::TEST_KEY_RSA)?;
+    let agent_ed25519 = SshAgentInstance::new_openssh()?;
+    agent_ed25519.add(keys::TEST_KEY_ED25519)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r#"
+[[upstream]]
+name = "rsa"
+path = "{}"
+labels = ["work"]
+
+[[upstream]]
+name = "ed25519"
+path = "{}"
+enabled = false
+"#,
+            agent_rsa.sock_path.display(),
+            agent_ed25519.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    let control_path = mux_agent.sock_path.with_extension("ctl");
+
+    // Disabled upstreams aren't used
+    assert_eq!(mux_agent.list()?, vec![keys::TEST_KEY_RSA_PUB.to_string()]);
+
+    let sockets = ControlClient::connect(&control_path)?.list_sockets()?;
+    assert_eq!(sockets.len(), 1);
+    assert_eq!(sockets[0].name.as_deref(), Some("rsa"));
+    assert_eq!(sockets[0].labels, vec!["work".to_string()]);
+
+    // Names are accepted in place of paths
+    let output = cmd!(
+        env!("CARGO_BIN_EXE_ssh-agent-mux"),
+        "--control-socket",
+        &control_path,
+        "remove",
+        "rsa"
+    )
+    .stderr_to_stdout()
+    .stdout_capture()
+    .unchecked()
+    .run()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success());
+    assert!(
+        stdout.contains(&format!(
+            "Cannot remove configured socket: {}",
+            agent_rsa.sock_path.display()
+        )),
+        "Unexpected output: {stdout}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_orders_by_most_recent_signature() -> TestResult {
+    let agent_rsa = SshAgentInstance::new_openssh()?;
+    agent_rsa.add(keys::TEST_KEY_RSA)?;
+    let agent_ed25519 = SshAgentInstance::new_openssh()?;
+    agent_ed25519.add(keys::TEST_KEY_ED25519)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            "socket_order = \"recent-sign\"\nagent_sock_paths = [\"{}\", \"{}\"]\n",
+            agent_rsa.sock_path.display(),
+            agent_ed25519.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    assert_eq!(
+        mux_agent.list()?,
+        vec![
+            keys::TEST_KEY_RSA_PUB.to_string(),
+            keys::TEST_KEY_ED25519_PUB.to_string()
+        ]
+    );
+
+    mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+    assert_eq!(
+        mux_agent.list()?,
+        vec![
+            keys::TEST_KEY_ED25519_PUB.to_string(),
+            keys::TEST_KEY_RSA_PUB.to_string()
+        ]
+    );
+
+    let mut control = ControlClient::connect(mux_agent.sock_path.with_extension("ctl"))?;
+    let sockets = control.list_sockets()?;
+    assert_eq!(
+        sockets[0].path,
+        agent_ed25519.sock_path.display().to_string()
+    );
+    assert!(sockets[0].last_signed.is_some());
+    assert!(sockets[1].last_signed.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn mux_reorders_sockets_at_runtime() -> TestResult {
+    let agent_rsa = SshAgentInstance::new_openssh()?;
+    agent_rsa.add(keys::TEST_KEY_RSA)?;
+    let agent_ed25519 = SshAgentInstance::new_openssh()?;
+    agent_ed25519.add(keys::TEST_KEY_ED25519)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r#"
+[[upstream]]
+name = "rsa"
+path = "{}"
+
+[[upstream]]
+name = "ed25519"
+path = "{}"
+"#,
+            agent_rsa.sock_path.display(),
+            agent_ed25519.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    let control_path = mux_agent.sock_path.with_extension("ctl");
+    let mux = |args: &[&str]| {
+        let mut full_args = vec![
+            "--control-socket".into(),
+            control_path.clone().into_os_string(),
+        ];
+        full_args.extend(args.iter().map(OsString::from));
+        cmd(env!("CARGO_BIN_EXE_ssh-agent-mux"), full_args)
+            .stdout_capture()
+            .read()
+    };
+
+    let output = mux(&["prioritize", "ed25519", "100", "--for", "3600"])?;
+    assert!(
+        output.contains("now at position 1"),
+        "Unexpected output: {output}"
+    );
+    assert_eq!(
+        mux_agent.list()?,
+        vec![
+            keys::TEST_KEY_ED25519_PUB.to_string(),
+            keys::TEST_KEY_RSA_PUB.to_string()
+        ]
+    );
+    let sockets = ControlClient::connect(&control_path)?.list_sockets()?;
+    assert_eq!(sockets[0].name.as_deref(), Some("ed25519"));
+    assert_eq!(sockets[0].priority_override, Some(100));
+    assert!(sockets[0].priority_override_until.is_some());
+
+    // Pins win over priority overrides
+    mux(&["move", "ed25519", "down"])?;
+    assert_eq!(
+        mux_agent.list()?,
+        vec![
+            keys::TEST_KEY_RSA_PUB.to_string(),
+            keys::TEST_KEY_ED25519_PUB.to_string()
+        ]
+    );
+    let sockets = ControlClient::connect(&control_path)?.list_sockets()?;
+    assert_eq!(sockets[1].pinned, Some(2));
+    assert_eq!(sockets[1].order, 2);
+
+    mux(&["unpin", "ed25519"])?;
+    mux(&["prioritize", "ed25519", "--clear"])?;
+    mux(&["pin", "rsa", "--position", "2"])?;
+    assert_eq!(
+        mux_agent.list()?,
+        vec![
+            keys::TEST_KEY_ED25519_PUB.to_string(),
+            keys::TEST_KEY_RSA_PUB.to_string()
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_disables_sockets_at_runtime() -> TestResult {
+    let agent_rsa = SshAgentInstance::new_openssh()?;
+    agent_rsa.add(keys::TEST_KEY_RSA)?;
+    let agent_ed25519 = SshAgentInstance::new_openssh()?;
+    agent_ed25519.add(keys::TEST_KEY_ED25519)?;
+    let config = |first: &Path, second: &Path| {
+        format!(
+            "agent_sock_paths = [\"{}\", \"{}\"]\n",
+            first.display(),
+            second.display()
+        )
+    };
+    let mux_agent = SshAgentInstance::new_mux(
+        &config(&agent_rsa.sock_path, &agent_ed25519.sock_path),
+        None::<OsString>,
+    )?;
+    let control_path = mux_agent.sock_path.with_extension("ctl");
+    let mux = |args: &[&str]| {
+        let mut full_args = vec![
+            "--control-socket".into(),
+            control_path.clone().into_os_string(),
+        ];
+        full_args.extend(args.iter().map(OsString::from));
+        cmd(env!("CARGO_BIN_EXE_ssh-agent-mux"), full_args)
+            .stdout_capture()
+            .read()
+    };
+    let rsa = agent_rsa.sock_path.to_string_lossy();
+
+    // The socket keeps its place, but its keys are neither listed nor used
+    mux_agent.sign(keys::TEST_KEY_RSA_PUB)?;
+    let output = mux(&["disable", &rsa])?;
+    assert!(
+        output.contains("Disabled socket"),
+        "Unexpected output: {output}"
+    );
+    assert_eq!(
+        mux_agent.list()?,
+        vec![keys::TEST_KEY_ED25519_PUB.to_string()]
+    );
+    assert!(mux_agent.sign(keys::TEST_KEY_RSA_PUB).is_err());
+    let sockets = ControlClient::connect(&control_path)?.list_sockets()?;
+    assert_eq!(sockets.len(), 2);
+    assert!(sockets[0].disabled);
+    assert!(mux(&["disable", &rsa]).is_err());
+
+    // Still disabled after a reload
+    mux_agent.reload_with(&config(&agent_ed25519.sock_path, &agent_rsa.sock_path))?;
+    let start = Instant::now();
+    let sockets = loop {
+        let sockets = ControlClient::connect(&control_path)?.list_sockets()?;
+        if sockets[0].path == agent_ed25519.sock_path.to_string_lossy() {
+            break sockets;
+        }
+        if start.elapsed() > Duration::from_secs(5) {
+            return Err("configuration wasn't reloaded".into());
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    assert!(sockets[1].disabled);
+    assert_eq!(
+        mux_agent.list()?,
+        vec![keys::TEST_KEY_ED25519_PUB.to_string()]
+    );
+
+    mux(&["enable", &rsa])?;
+    assert_eq!(
+        mux_agent.list()?,
+        vec![
+            keys::TEST_KEY_ED25519_PUB.to_string(),
+            keys::TEST_KEY_RSA_PUB.to_string()
+        ]
+    );
+    mux_agent.sign(keys::TEST_KEY_RSA_PUB)?;
+
+    Ok(())
+}
+
+#[test]
+fn mux_counts_agent_and_key_usage() -> TestResult {
+    let agent_rsa = SshAgentInstance::new_openssh()?;
+    agent_rsa.add(keys::TEST_KEY_RSA)?;
+    let agent_ed25519 = SshAgentInstance::new_openssh()?;
+    agent_ed25519.add(keys::TEST_KEY_ED25519)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            "[[upstream]]\nname = \"rsa\"\npath = \"{}\"\n\n[[upstream]]\npath = \"{}\"\n",
+            agent_rsa.sock_path.display(),
+            agent_ed25519.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    let control_path = mux_agent.sock_path.with_extension("ctl");
+
+    mux_agent.list()?;
+    mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+    mux_agent.sign(keys::TEST_KEY_ED25519_PUB)?;
+
+    let output = cmd!(
+        env!("CARGO_BIN_EXE_ssh-agent-mux"),
+        "--json",
+        "--control-socket",
+        &control_path,
+        "stats"
+    )
+    .stdout_capture()
+    .run()?;
+    let report: ssh_agent_mux::control::MetricsReport = serde_json::from_slice(&output.stdout)?;
+
+    // Most used first
+    assert_eq!(report.upstreams.len(), 2);
+    let ed25519 = &report.upstreams[0];
+    assert_eq!(ed25519.path, agent_ed25519.sock_path.display().to_string());
+    // ssh-keygen lists the keys before signing, too
+    assert!(ed25519.usage.identity_requests >= 1);
+    assert_eq!(ed25519.usage.sign_attempts, 2);
+    assert_eq!(ed25519.usage.sign_successes, 2);
+    assert!(ed25519.usage.max_latency_ms.is_some());
+    let rsa = &report.upstreams[1];
+    assert_eq!(rsa.name.as_deref(), Some("rsa"));
+    assert_eq!(rsa.usage.sign_attempts, 0);
+    assert_eq!(rsa.usage.identity_requests, ed25519.usage.identity_requests);
+
+    let key = &report.keys[0];
+    assert_eq!(key.comment.as_deref(), Some("integration-test-ed25519"));
+    assert_eq!(key.upstream, Some(ed25519.path.clone()));
+    assert_eq!(key.usage.sign_successes, 2);
+    assert_eq!(
+        report.keys[1].comment.as_deref(),
+        Some("integration-test-rsa")
+    );
+
+    let output = cmd!(
+        env!("CARGO_BIN_EXE_ssh-agent-mux"),
+        "--control-socket",
+        &control_path,
+        "stats"
+    )
+    .read()?;
+    assert!(
+        output.contains(&format!("rsa ({})", agent_rsa.sock_path.display())),
+        "Unexpected output: {output}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_keeps_runtime_state_across_restarts() -> TestResult {
+    let agent_rsa = SshAgentInstance::new_openssh()?;
+    agent_rsa.add(keys::TEST_KEY_RSA)?;
+    let agent_ed25519 = SshAgentInstance::new_openssh()?;
+    agent_ed25519.add(keys::TEST_KEY_ED25519)?;
+    let state_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let config = format!(
+        "persist_state = true\nstate_file = \"{}\"\nagent_sock_paths = [\"{}\"]\n",
+        state_dir.path().join("state.json").display(),
+        agent_rsa.sock_path.display()
+    );
+    let expected_keys = vec![
+        keys::TEST_KEY_RSA_PUB.to_string(),
+        keys::TEST_KEY_ED25519_PUB.to_string(),
+    ];
+
+    let mux_agent = SshAgentInstance::new_mux(&config, None::<OsString>)?;
+    let mut control = ControlClient::connect(mux_agent.sock_path.with_extension("ctl"))?;
+    control.add_socket(&agent_ed25519.sock_path.to_string_lossy())?;
+    control.pin_socket(&agent_rsa.sock_path.to_string_lossy(), 1)?;
+    assert_eq!(mux_agent.list()?, expected_keys);
+    drop(control);
+    drop(mux_agent);
+
+    let mux_agent = SshAgentInstance::new_mux(&config, None::<OsString>)?;
+    assert_eq!(mux_agent.list()?, expected_keys);
+    let sockets =
+        ControlClient::connect(mux_agent.sock_path.with_extension("ctl"))?.list_sockets()?;
+    assert_eq!(sockets.len(), 2);
+    assert_eq!(sockets[0].pinned, Some(1));
+    assert_eq!(
+        sockets[1].path,
+        agent_ed25519.sock_path.display().to_string()
+    );
+    drop(mux_agent);
+
+    // Sockets that went away in the meantime aren't reinstated
+    drop(agent_ed25519);
+    let mux_agent = SshAgentInstance::new_mux(&config, None::<OsString>)?;
+    assert_eq!(mux_agent.list()?, vec![keys::TEST_KEY_RSA_PUB.to_string()]);
+
+    Ok(())
+}
+
+#[test]
+fn mux_skips_upstream_that_times_out() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    // Accepts connections (through its backlog) but never answers
+    let hung_dir = tempfile::TempDir::new_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let hung_path = hung_dir.path().join("hung.sock");
+    let _hung_agent = std::os::unix::net::UnixListener::bind(&hung_path)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r#"
+[[upstream]]
+path = "{}"
+timeout = 1
+
+[[upstream]]
+path = "{}"
+"#,
+            hung_path.display(),
+            openssh_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    let start = Instant::now();
+    assert_all_keys_in_agent(&mux_agent)?;
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    Ok(())
+}
+
+#[test]
+fn mux_limits_upstream_without_timeout() -> TestResult {
+    use ssh_agent_mux::control::BreakerState;
+
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    // Accepts connections (through its backlog) but never answers
+    let hung_dir = tempfile::TempDir::new_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let hung_path = hung_dir.path().join("hung.sock");
+    let _hung_agent = std::os::unix::net::UnixListener::bind(&hung_path)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            "agent_sock_paths = [\"{}\", \"{}\"]\nhealth_check_interval = 0\n",
+            hung_path.display(),
+            openssh_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    let mut control = ControlClient::connect(mux_agent.sock_path.with_extension("ctl"))?;
+
+    let start = Instant::now();
+    assert_all_keys_in_agent(&mux_agent)?;
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert_eq!(control.list_sockets()?[0].breaker.state, BreakerState::Open);
+
+    Ok(())
+}
+
+#[test]
+fn mux_skips_upstream_that_fails_to_list_keys() -> TestResult {
+    use ssh_agent_mux::control::BreakerState;
+
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    // Hangs up on every connection without answering
+    let failing_dir = tempfile::TempDir::new_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let failing_path = failing_dir.path().join("failing.sock");
+    let failing_agent = std::os::unix::net::UnixListener::bind(&failing_path)?;
+    thread::spawn(move || {
+        for stream in failing_agent.incoming() {
+            drop(stream);
+        }
+    });
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            "agent_sock_paths = [\"{}\", \"{}\"]\nhealth_check_interval = 0\n",
+            failing_path.display(),
+            openssh_agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    let mut control = ControlClient::connect(mux_agent.sock_path.with_extension("ctl"))?;
+
+    assert_all_keys_in_agent(&mux_agent)?;
+    assert_eq!(control.list_sockets()?[0].breaker.state, BreakerState::Open);
+
+    Ok(())
+}
+
+#[test]
+fn mux_expands_globs_on_health_check() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let glob_dir = tempfile::TempDir::new_in(env!("CARGO_TARGET_TMPDIR"))?;
+    std::env::set_var("SSH_AGENT_MUX_TEST_GLOB_DIR", glob_dir.path());
+    let mux_agent = SshAgentInstance::new_mux(
+        "agent_sock_paths = [\"$SSH_AGENT_MUX_TEST_GLOB_DIR/*.sock\"]\nhealth_check_interval = 1\n",
+        None::<OsString>,
+    )?;
+    assert!(mux_agent.list()?.is_empty());
+
+    let wait_for_keys = |present: bool| -> TestResult {
+        let start = Instant::now();
+        while mux_agent.list()?.is_empty() == present {
+            if start.elapsed() > Duration::from_secs(5) {
+                return Err(format!("keys present: {}", !present).into());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    };
+
+    // New matches are picked up
+    let link = glob_dir.path().join("forwarded.sock");
+    std::os::unix::fs::symlink(&openssh_agent.sock_path, &link)?;
+    wait_for_keys(true)?;
+    assert_all_keys_in_agent(&mux_agent)?;
+
+    // Vanished ones are dropped
+    fs::remove_file(&link)?;
+    wait_for_keys(false)?;
+
+    Ok(())
+}
+
+#[test]
+fn mux_health_check_probes_configured_agents() -> TestResult {
+    let openssh_agent = make_openssh_agent_with_keys()?;
+    let stale_dir = tempfile::TempDir::new_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let stale = stale_dir.path().join("stale.sock");
+    // Left behind by an agent that's gone: the file is there, but nothing listens on it
+    drop(std::os::unix::net::UnixListener::bind(&stale)?);
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            "agent_sock_paths = [\"{}\", \"{}\"]\nhealth_check_interval = 1\n",
+            openssh_agent.sock_path.display(),
+            stale.display()
+        ),
+        None::<OsString>,
+    )?;
+    let mut control = ControlClient::connect(mux_agent.sock_path.with_extension("ctl"))?;
+
+    // The periodic health check asks every agent for its keys
+    let start = Instant::now();
+    let sockets = loop {
+        let sockets = control.list_sockets()?;
+        if sockets.iter().all(|s| s.last_health_check.is_some()) {
+            break sockets;
+        }
+        if start.elapsed() > Duration::from_secs(5) {
+            return Err("sockets weren't health checked".into());
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    assert!(sockets[0].healthy);
+    assert_eq!(sockets[0].key_count, Some(keys::PUBLIC.len()));
+    assert!(sockets[0].latency_ms.is_some());
+    assert!(!sockets[1].healthy);
+    assert_eq!(
+        sockets[1].health_error.as_deref(),
+        Some("Socket file exists but refuses connections")
+    );
+
+    let result = control.health_check()?;
+    assert_eq!((result.healthy_count, result.unhealthy_count), (1, 1));
+    assert_eq!(
+        result.sockets[1].status,
+        ssh_agent_mux::control::SocketHealthStatus::ConnectionFailed
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_skips_failing_agents_until_they_answer() -> TestResult {
+    use ssh_agent_mux::control::BreakerState;
+
+    let agent_rsa = SshAgentInstance::new_openssh()?;
+    agent_rsa.add(keys::TEST_KEY_RSA)?;
+    let agent_ed25519 = SshAgentInstance::new_openssh()?;
+    agent_ed25519.add(keys::TEST_KEY_ED25519)?;
+    let link_dir = tempfile::TempDir::new_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let link = link_dir.path().join("agent.sock");
+    // The agent isn't up yet
+    std::os::unix::fs::symlink(link_dir.path().join("missing.sock"), &link)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            "agent_sock_paths = [\"{}\", \"{}\"]\nhealth_check_interval = 0\n",
+            link.display(),
+            agent_rsa.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    let mut control = ControlClient::connect(mux_agent.sock_path.with_extension("ctl"))?;
+
+    // Failing to answer a client request gets the agent skipped
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_RSA_PUB]);
+    let breaker = control.list_sockets()?[0].breaker.clone();
+    assert_eq!(breaker.state, BreakerState::Open);
+    assert_eq!(breaker.failures, 1);
+    assert!(breaker.next_retry.is_some());
+
+    // Retried in the background, and used again once it answers
+    fs::remove_file(&link)?;
+    std::os::unix::fs::symlink(&agent_ed25519.sock_path, &link)?;
+    let start = Instant::now();
+    while control.list_sockets()?[0].breaker.state != BreakerState::Closed {
+        if start.elapsed() > Duration::from_secs(10) {
+            return Err("agent wasn't retried".into());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(
+        mux_agent.list()?,
+        [keys::TEST_KEY_ED25519_PUB, keys::TEST_KEY_RSA_PUB]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mux_signs_with_another_agent_while_one_is_skipped() -> TestResult {
+    use ssh_agent_mux::control::BreakerState;
+
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    let other_agent = SshAgentInstance::new_openssh()?;
+    other_agent.add(keys::TEST_KEY_ED25519)?;
+    let link_dir = tempfile::TempDir::new_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let link = link_dir.path().join("agent.sock");
+    std::os::unix::fs::symlink(&agent.sock_path, &link)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            "agent_sock_paths = [\"{}\", \"{}\"]\nhealth_check_interval = 0\n",
+            other_agent.sock_path.display(),
+            link.display()
+        ),
+        None::<OsString>,
+    )?;
+    let mut control = ControlClient::connect(mux_agent.sock_path.with_extension("ctl"))?;
+    // Listed by both, and remembered as the key of the last one
+    mux_agent.list()?;
+
+    // That agent goes away, and is skipped after a health check
+    fs::remove_file(&link)?;
+    std::os::unix::fs::symlink(link_dir.path().join("missing.sock"), &link)?;
+    control.health_check()?;
+    assert_eq!(control.list_sockets()?[1].breaker.state, BreakerState::Open);
+
+    mux_agent.request_signature(keys::TEST_KEY_ED25519_PUB)?;
+    let sockets = control.list_sockets()?;
+    assert!(sockets[0].last_signed.is_some());
+    assert_eq!(sockets[1].breaker.failures, 1);
+
+    Ok(())
+}
+
+#[test]
+fn mux_discovers_forwarded_agents_in_configured_roots() -> TestResult {
+    let agent_rsa = SshAgentInstance::new_openssh()?;
+    agent_rsa.add(keys::TEST_KEY_RSA)?;
+    let agent_ed25519 = SshAgentInstance::new_openssh()?;
+    agent_ed25519.add(keys::TEST_KEY_ED25519)?;
+    let root = tempfile::TempDir::new_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let agent_dir = root.path().join("fwd-1");
+    fs::create_dir(&agent_dir)?;
+    fs::set_permissions(&agent_dir, fs::Permissions::from_mode(0o700))?;
+    // Not a match for the configured pattern
+    fs::create_dir(root.path().join("other"))?;
+    std::os::unix::fs::symlink(&agent_ed25519.sock_path, root.path().join("other/agent.1"))?;
+    // Other users could have planted this one
+    let open_dir = root.path().join("fwd-open");
+    fs::create_dir(&open_dir)?;
+    fs::set_permissions(&open_dir, fs::Permissions::from_mode(0o777))?;
+    std::os::unix::fs::symlink(&agent_ed25519.sock_path, open_dir.join("agent.1"))?;
+
+    // Found by the scan at startup
+    std::os::unix::fs::symlink(&agent_rsa.sock_path, agent_dir.join("agent.1"))?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            "watch_for_ssh_forward = true\nforward_agent_roots = [\"{}\"]\nforward_agent_patterns = [\"fwd-*/agent.*\"]\n",
+            root.path().display()
+        ),
+        None::<OsString>,
+    )?;
+    assert_eq!(mux_agent.list()?, vec![keys::TEST_KEY_RSA_PUB.to_string()]);
+
+    // Found by the watcher
+    std::os::unix::fs::symlink(&agent_ed25519.sock_path, agent_dir.join("agent.2"))?;
+    let start = Instant::now();
+    while mux_agent.list()?.len() < 2 {
+        if start.elapsed() > Duration::from_secs(5) {
+            return Err("forwarded agent was not picked up".into());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let mut keys_in_agent = mux_agent.list()?;
+    keys_in_agent.sort();
+    let mut expected = vec![
+        keys::TEST_KEY_ED25519_PUB.to_string(),
+        keys::TEST_KEY_RSA_PUB.to_string(),
+    ];
+    expected.sort();
+    assert_eq!(keys_in_agent, expected);
+
+    Ok(())
+}
@@ -1,6 +1,7 @@
 use std::{
     ffi::OsString,
     fs, io,
+    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
     thread,
     time::Duration,
@@ -31,6 +32,8 @@ fn create_forwarded_agent_structure(
     // Clean up if it already exists
     let _ = fs::remove_dir_all(&ssh_dir);
     fs::create_dir(&ssh_dir)?;
+    // Like sshd, keep other users out; otherwise the mux ignores the socket
+    fs::set_permissions(&ssh_dir, fs::Permissions::from_mode(0o700))?;
 
     // Create symlink to agent socket with the forwarded naming pattern
     let forwarded_path = ssh_dir.join(format!("agent.{}", std::process::id()));
@@ -0,0 +1,188 @@
+//! Tests for systemd socket activation: the daemon must serve on listeners passed in via
+//! `LISTEN_FDS`/`LISTEN_FDNAMES` instead of binding its own sockets.
+#![cfg(feature = "systemd")]
+
+use std::{
+    fs,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::{fs::PermissionsExt, net::UnixListener, process::CommandExt},
+    },
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use duct::cmd;
+use harness::SshAgentInstance;
+use ssh_agent_mux::control::ControlClient;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Create a temporary directory private enough for the daemon to bind sockets in
+fn private_tempdir() -> std::io::Result<tempfile::TempDir> {
+    tempfile::Builder::new()
+        .permissions(fs::Permissions::from_mode(0o700))
+        .tempdir_in(env!("CARGO_TARGET_TMPDIR"))
+}
+
+/// Spawn the daemon with `listeners` passed as socket-activated fds, the way systemd does
+fn spawn_activated(
+    config_path: &Path,
+    listeners: &[(&UnixListener, &str)],
+) -> std::io::Result<Child> {
+    let fds: Vec<RawFd> = listeners.iter().map(|(l, _)| l.as_raw_fd()).collect();
+    let names = listeners
+        .iter()
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(":");
+
+    let mut command = Command::new("sh");
+    // LISTEN_PID must match the daemon's PID, which is only known in the child; `exec` keeps the
+    // shell's PID
+    command
+        .arg("-c")
+        .arg(r#"LISTEN_PID=$$ exec "$0" "$@""#)
+        .arg(env!("CARGO_BIN_EXE_ssh-agent-mux"))
+        .arg("serve")
+        .arg(format!("--config={}", config_path.display()))
+        .args(["--log-level", "trace"])
+        .env("LISTEN_FDS", fds.len().to_string())
+        .env("LISTEN_FDNAMES", names)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    // SAFETY: only async-signal-safe libc calls are made between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            // Move the fds out of the way first so that placing them can't clobber one another
+            let mut moved = Vec::with_capacity(fds.len());
+            for fd in &fds {
+                let new_fd = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, 100);
+                if new_fd < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                moved.push(new_fd);
+            }
+            for (i, fd) in moved.into_iter().enumerate() {
+                // dup2 clears FD_CLOEXEC on the target, so it survives exec
+                if libc::dup2(fd, SD_LISTEN_FDS_START + i as RawFd) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+
+    command.spawn()
+}
+
+fn stop_daemon(mut child: Child) -> std::io::Result<()> {
+    // SAFETY: plain syscall on a PID we own
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    child.wait()?;
+    Ok(())
+}
+
+/// List keys on `sock_path`, retrying until the daemon answers
+fn wait_for_keys(sock_path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    loop {
+        let output = cmd!("ssh-add", "-L")
+            .env("SSH_AUTH_SOCK", sock_path)
+            .unchecked()
+            .stdout_capture()
+            .stderr_null()
+            .run()?;
+        let stdout = String::from_utf8(output.stdout)?;
+        match output.status.code() {
+            Some(0) => return Ok(stdout.lines().map(Into::into).collect()),
+            Some(1) if stdout.starts_with("The agent has no identities.") => return Ok(vec![]),
+            _ if start.elapsed() < STARTUP_TIMEOUT => thread::sleep(Duration::from_millis(50)),
+            code => return Err(format!("ssh-add -L failed with {code:?}: {stdout}").into()),
+        }
+    }
+}
+
+#[test]
+fn serves_on_activated_agent_and_control_sockets() -> TestResult {
+    let upstream = SshAgentInstance::new_openssh()?;
+    upstream.add(keys::TEST_KEY_ED25519)?;
+
+    let temp_dir = private_tempdir()?;
+    let config_path = temp_dir.path().join("config.toml");
+    // The configured listen path must be ignored in favour of the activated socket
+    fs::write(
+        &config_path,
+        format!(
+            "agent_sock_paths = [\"{}\"]\nlisten_path = \"{}\"\n",
+            upstream.sock_path.display(),
+            temp_dir.path().join("unused.sock").display()
+        ),
+    )?;
+
+    let agent_path = temp_dir.path().join("activated.sock");
+    let control_path = temp_dir.path().join("activated.ctl");
+    let agent_listener = UnixListener::bind(&agent_path)?;
+    let control_listener = UnixListener::bind(&control_path)?;
+
+    let daemon = spawn_activated(
+        &config_path,
+        &[(&agent_listener, "agent"), (&control_listener, "control")],
+    )?;
+
+    let keys_result = wait_for_keys(&agent_path);
+    let ping_result = ControlClient::connect(&control_path).and_then(|mut c| c.status());
+    stop_daemon(daemon)?;
+
+    assert_eq!(keys_result?, vec![keys::TEST_KEY_ED25519_PUB.to_string()]);
+    let status = ping_result?;
+    assert_eq!(status.listening_on, agent_path.display().to_string());
+    assert_eq!(status.control_socket, control_path.display().to_string());
+    assert!(!temp_dir.path().join("unused.sock").exists());
+
+    // Socket files belong to the service manager and must survive daemon shutdown
+    assert!(agent_path.exists());
+    assert!(control_path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn binds_control_socket_when_only_agent_is_activated() -> TestResult {
+    let temp_dir = private_tempdir()?;
+    let config_path = temp_dir.path().join("config.toml");
+    let control_path = temp_dir.path().join("bound.ctl");
+    fs::write(
+        &config_path,
+        format!("control_socket_path = \"{}\"\n", control_path.display()),
+    )?;
+
+    let agent_path = temp_dir.path().join("activated.sock");
+    let agent_listener = UnixListener::bind(&agent_path)?;
+
+    let daemon = spawn_activated(&config_path, &[(&agent_listener, "agent")])?;
+
+    let keys_result = wait_for_keys(&agent_path);
+    let ping_result = ControlClient::connect(&control_path).and_then(|mut c| c.ping());
+    stop_daemon(daemon)?;
+
+    assert!(keys_result?.is_empty());
+    ping_result?;
+
+    // The daemon cleans up the control socket it bound itself, but not the inherited one
+    assert!(agent_path.exists());
+    assert!(!control_path.exists());
+
+    Ok(())
+}